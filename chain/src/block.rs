@@ -1,5 +1,6 @@
 //! Block and blockchain related functionality.
 pub mod cache;
+pub mod difficulty;
 pub mod store;
 pub use nakamoto_common::block::tree::*;
 
@@ -4,26 +4,45 @@
 //!
 #![warn(missing_docs)]
 
+mod map;
+#[cfg(feature = "parallel")]
+mod parallel;
+mod snapshot;
 #[cfg(test)]
 pub mod test;
+mod view;
 
 use std::cmp::Ordering;
-use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::io;
+use std::iter;
+use std::mem;
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::Duration;
 
 use bitcoin::blockdata::block::BlockHeader;
+use bitcoin::blockdata::constants;
+use bitcoin::consensus::encode::Encodable;
 use bitcoin::consensus::params::Params;
-use bitcoin::hash_types::BlockHash;
-use bitcoin::network::constants::Network;
+use bitcoin::hash_types::{BlockHash, Txid};
+use bitcoin::util::merkleblock::PartialMerkleTree;
 
 use nonempty::NonEmpty;
 
+use crate::block::difficulty::{self, DifficultyPolicy};
+
+use map::BlockMap;
+
+pub use view::ChainView;
+
 use nakamoto_common::block::tree::{self, BlockTree, Branch, Error, ImportResult};
 use nakamoto_common::block::{
     self,
     iter::Iter,
     store::Store,
     time::{self, Clock},
-    Bits, BlockTime, Height, Work,
+    BlockTime, Height, Target, Work,
 };
 
 /// A block that is being stored by the block cache.
@@ -32,6 +51,10 @@ struct CachedBlock {
     pub height: Height,
     pub hash: BlockHash,
     pub header: BlockHeader,
+    /// Cumulative proof-of-work of the chain up to and including this block.
+    pub chainwork: Work,
+    /// Arrival sequence number. Blocks seen later have greater numbers.
+    pub seq: u64,
 }
 
 impl std::ops::Deref for CachedBlock {
@@ -48,13 +71,129 @@ impl tree::Header for CachedBlock {
     }
 }
 
+/// A block that is not part of the active chain.
+#[derive(Debug, Clone, Copy)]
+struct Orphan {
+    header: BlockHeader,
+    /// Arrival sequence number. Blocks seen later have greater numbers.
+    seq: u64,
+    /// Cumulative work of the block's branch, if known, eg. because the block was rolled
+    /// back from the active chain. Since it only depends on the block's ancestors, it
+    /// doesn't have to be recomputed if the branch is activated again.
+    chainwork: Option<Work>,
+}
+
+impl std::ops::Deref for Orphan {
+    type Target = BlockHeader;
+
+    fn deref(&self) -> &Self::Target {
+        &self.header
+    }
+}
+
 /// A chain candidate, forking off the active chain.
 #[derive(Debug)]
 struct Candidate {
     tip: BlockHash,
     headers: Vec<BlockHeader>,
     fork_height: Height,
-    fork_hash: BlockHash,
+}
+
+/// Information about an orphan block, ie. a block that isn't on the active chain.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OrphanInfo {
+    /// Block hash.
+    pub hash: BlockHash,
+    /// Hash of the parent block.
+    pub prev: BlockHash,
+    /// Height of the active chain block that the orphan's branch forks off, if the branch
+    /// connects to the active chain. Otherwise, some ancestor of the orphan is missing.
+    pub fork_height: Option<Height>,
+    /// Whether the orphan is the tip of a viable fork, ie. its branch connects to the
+    /// active chain at or after the last checkpoint.
+    pub connects: bool,
+}
+
+/// A function called when the active chain is re-organized, with the height of the fork
+/// block, the disconnected headers and the connected headers, in ascending height order.
+pub type ReorgFn = dyn FnMut(Height, &[BlockHeader], &[BlockHeader]) + Send;
+
+/// A re-org callback, shared between clones of a cache.
+#[derive(Clone)]
+struct ReorgHook(Arc<Mutex<Box<ReorgFn>>>);
+
+impl fmt::Debug for ReorgHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("ReorgHook").finish()
+    }
+}
+
+/// A function called when a block is connected to, or disconnected from the active chain,
+/// with the block height and header.
+pub type BlockFn = dyn FnMut(Height, &BlockHeader) + Send;
+
+/// A block connection callback, shared between clones of a cache.
+#[derive(Clone)]
+struct BlockHook(Arc<Mutex<Box<BlockFn>>>);
+
+impl BlockHook {
+    fn new(f: impl FnMut(Height, &BlockHeader) + Send + 'static) -> Self {
+        Self(Arc::new(Mutex::new(Box::new(f))))
+    }
+
+    fn call(&self, height: Height, header: &BlockHeader) {
+        (self.0.lock().unwrap())(height, header)
+    }
+}
+
+impl fmt::Debug for BlockHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("BlockHook").finish()
+    }
+}
+
+/// How block timestamps are checked against the clock.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClockPolicy {
+    /// Reject blocks with a timestamp too far ahead of the network-adjusted time. This is the
+    /// default.
+    Enforce,
+    /// Don't check block timestamps against the clock, eg. because there is no reliable
+    /// clock, or because historical headers are replayed against a frozen clock. Timestamps
+    /// are still checked against the median time past.
+    Ignore,
+}
+
+/// A change to the active chain, sent to subscribers of a cache.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChainEvent {
+    /// The active chain was extended by a block, with the given height and hash.
+    TipExtended(Height, BlockHash),
+    /// The active chain was re-organized.
+    Reorg {
+        /// Height of the fork block, which is the last block both chains have in common.
+        from: Height,
+        /// Blocks disconnected from the active chain, in ascending height order.
+        disconnected: Vec<(Height, BlockHash)>,
+        /// Blocks connected to the active chain, in ascending height order.
+        connected: Vec<(Height, BlockHash)>,
+    },
+}
+
+/// Chain event subscribers, shared between clones of a cache.
+#[derive(Debug, Clone, Default)]
+struct Subscribers(Arc<Mutex<Vec<mpsc::Sender<ChainEvent>>>>);
+
+impl Subscribers {
+    /// Send an event to all subscribers. Subscribers that dropped their receiver are removed.
+    fn send(&self, event: ChainEvent) {
+        let mut senders = self.0.lock().unwrap();
+
+        if senders.is_empty() {
+            return;
+        }
+        senders.retain(|s| s.send(event.clone()).is_ok());
+    }
 }
 
 /// An implementation of [`BlockTree`] using a generic storage backend.
@@ -62,26 +201,296 @@ struct Candidate {
 ///
 /// [`BlockTree`]: ../../../nakamoto_common/block/tree/trait.BlockTree.html
 ///
+/// The active chain and its index are shared with the views taken with
+/// [`BlockCache::snapshot`], and copied on the next write.
+///
 #[derive(Debug, Clone)]
 pub struct BlockCache<S: Store> {
-    chain: NonEmpty<CachedBlock>,
-    headers: HashMap<BlockHash, Height>,
-    orphans: HashMap<BlockHash, BlockHeader>,
+    chain: Arc<NonEmpty<CachedBlock>>,
+    headers: Arc<BlockMap<Height>>,
+    orphans: BlockMap<Orphan>,
+    /// Orphan hashes, in arrival order.
+    orphans_order: BTreeMap<u64, BlockHash>,
+    /// Arrival sequence number of the next block seen.
+    seq: u64,
+    /// Maximum number of orphans kept in memory.
+    max_orphans: usize,
     checkpoints: BTreeMap<Height, BlockHash>,
     params: Params,
     store: S,
+    /// Called on every chain re-org.
+    on_reorg: Option<ReorgHook>,
+    /// Called for every block connected to the active chain.
+    on_connect: Option<BlockHook>,
+    /// Called for every block disconnected from the active chain.
+    on_disconnect: Option<BlockHook>,
+    /// Receivers of active chain changes.
+    subscribers: Subscribers,
+    /// Computes the difficulty target of new blocks.
+    difficulty: Arc<dyn DifficultyPolicy>,
+    /// Block up to which proof-of-work is assumed to be valid.
+    assume_valid: Option<BlockHash>,
+    /// First height of the active chain at which each median time past is reached, if
+    /// enabled.
+    time_index: Option<BTreeMap<BlockTime, Height>>,
+    /// How far ahead of the network-adjusted time a block timestamp may be.
+    max_future_time: Duration,
+    /// Whether block timestamps are checked against the clock.
+    clock_policy: ClockPolicy,
+    /// Minimum version of new blocks, if enforced.
+    min_version: Option<i32>,
+    /// Maximum number of blocks rolled back when switching to a fork, if limited.
+    max_reorg_depth: Option<Height>,
+    /// Minimum number of recent active chain blocks kept in memory, if limited. Older
+    /// blocks are only kept in the store, except for genesis and checkpoints.
+    window: Option<usize>,
+    /// Number of blocks following genesis that are only in the store. The tail of the
+    /// active chain starts at height `evicted + 1`.
+    evicted: Height,
+    /// Checkpoint blocks at or below the `evicted` height, which are kept in memory.
+    pinned: Arc<BTreeMap<Height, CachedBlock>>,
 }
 
 impl<S: Store<Header = BlockHeader>> BlockCache<S> {
     /// Create a new `BlockCache` from a `Store`, consensus parameters, and checkpoints.
+    ///
+    /// Returns [`Error::StoreCorruption`] if the stored headers don't form a chain, or if
+    /// the store is empty, and [`Error::GenesisMismatch`] if the stored genesis block isn't
+    /// the genesis block of the network given by the parameters.
     pub fn from(
         store: S,
         params: Params,
         checkpoints: &[(Height, BlockHash)],
+    ) -> Result<Self, Error> {
+        Self::with_store_and_replay_limit(store, params, checkpoints, None)
+    }
+
+    /// Create a new `BlockCache` from a `Store`, consensus parameters, and checkpoints,
+    /// keeping only the most recent `window` blocks of the active chain in memory, besides
+    /// genesis and checkpoints. With no window, this is the same as [`BlockCache::from`].
+    ///
+    /// Blocks below the window are neither hashed nor checked for linkage on startup,
+    /// which bounds the cost of replaying the store. As the chain grows, old blocks are
+    /// evicted from memory in batches, so that between `window` and twice as many blocks
+    /// are in memory. The window is never smaller than the difficulty adjustment interval,
+    /// since new blocks are validated against the blocks preceding them.
+    ///
+    /// Evicted blocks are still part of the active chain, but:
+    ///
+    /// * Accessors returning owned headers, eg. [`BlockTree::iter`] and
+    ///   [`BlockCache::get_block_by_height_or_load`], read them from the store, while
+    ///   accessors returning references, eg. [`BlockTree::get_block_by_height`] and
+    ///   [`BlockCache::ancestors`], don't return them. Code that is generic over
+    ///   [`BlockTree`] and looks up old blocks by height won't find them.
+    /// * Only their hashes and heights are kept in memory, so that they are still known to
+    ///   [`BlockTree::contains`] and [`BlockCache::height_of`]. The hash of each evicted
+    ///   block is the parent hash of the block following it, so they aren't hashed.
+    /// * Forks from less than a difficulty adjustment interval above them aren't accepted,
+    ///   and the chain can't be rolled back to them.
+    ///
+    /// Returns [`Error::StoreCorruption`] if the stored headers in the window don't form
+    /// a chain, or if the store is empty, and [`Error::GenesisMismatch`] if the stored
+    /// genesis block isn't the network's.
+    pub fn with_store_and_replay_limit(
+        store: S,
+        params: Params,
+        checkpoints: &[(Height, BlockHash)],
+        window: Option<usize>,
+    ) -> Result<Self, Error> {
+        let length = store.len()?;
+
+        check_genesis(&store, &params)?;
+
+        let mut cache = Self::genesis_only(store, params, checkpoints)?;
+
+        let window =
+            window.map(|w| usize::max(w, cache.params.difficulty_adjustment_interval() as usize));
+        let evicted = window.map_or(0, |w| length.saturating_sub(w + 1)) as Height;
+
+        if window.is_some() {
+            let resident = length - 1 - evicted as usize;
+
+            Arc::make_mut(&mut cache.chain).tail = Vec::with_capacity(resident);
+        }
+        cache.window = window;
+
+        let mut chainwork = cache.chain.head.chainwork;
+        let mut prev = cache.chain.head.header;
+
+        for result in cache.store.iter().skip(1) {
+            let (height, header) = result?;
+
+            if height <= evicted {
+                // Blocks below the window are trusted. Only checkpoints are hashed.
+                chainwork = chainwork + header.work();
+                prev = header;
+
+                Arc::make_mut(&mut cache.headers).insert(header.prev_blockhash, height - 1);
+
+                if cache.checkpoints.contains_key(&height) {
+                    let hash = header.block_hash();
+                    let seq = cache.next_seq();
+
+                    Arc::make_mut(&mut cache.pinned).insert(
+                        height,
+                        CachedBlock {
+                            height,
+                            hash,
+                            header,
+                            chainwork,
+                            seq,
+                        },
+                    );
+                    Arc::make_mut(&mut cache.headers).insert(hash, height);
+                }
+                continue;
+            }
+            let hash = header.block_hash();
+
+            if height == evicted + 1 && evicted > 0 {
+                // The first block in memory builds on an evicted block.
+                if header.prev_blockhash != prev.block_hash() {
+                    return Err(Error::StoreCorruption { height });
+                }
+                let seq = cache.next_seq();
+
+                cache.evicted = evicted;
+                let headers = Arc::make_mut(&mut cache.headers);
+
+                headers.insert(header.prev_blockhash, evicted);
+                headers.insert(hash, height);
+                Arc::make_mut(&mut cache.chain).push(CachedBlock {
+                    height,
+                    hash,
+                    header,
+                    chainwork: chainwork + header.work(),
+                    seq,
+                });
+                continue;
+            }
+            if header.prev_blockhash != cache.chain.last().hash {
+                return Err(Error::StoreCorruption { height });
+            }
+            cache.extend_chain(height, hash, header);
+        }
+
+        assert_eq!(length as Height, cache.height() + 1);
+        assert_eq!(length, cache.headers.len());
+
+        Ok(cache)
+    }
+
+    /// Create a new `BlockCache` from a `Store`, and extend its active chain with the given
+    /// headers, eg. from a headers file. The headers must form a chain that extends the
+    /// stored headers; for a new store, they start with the block following genesis.
+    ///
+    /// Unlike [`BlockTree::import_blocks`], there is no orphan or fork handling, and the
+    /// headers are stored in a single batch. If a header doesn't extend the chain or is
+    /// invalid, [`Error::BlockImportAborted`] is returned with its index, and none of the
+    /// headers are stored.
+    pub fn from_headers(
+        store: S,
+        params: Params,
+        checkpoints: &[(Height, BlockHash)],
+        headers: &[BlockHeader],
+        clock: &impl Clock,
+    ) -> Result<Self, Error> {
+        let mut cache = Self::from(store, params, checkpoints)?;
+
+        for (i, (header, pow)) in cache
+            .verify_pow(headers.iter().cloned())
+            .into_iter()
+            .enumerate()
+        {
+            let tip = *cache.chain.last();
+            let hash = header.block_hash();
+            let result = if header.prev_blockhash == tip.hash {
+                cache.validate(&tip, &header, pow, clock)
+            } else {
+                Err(Error::BlockMissing(header.prev_blockhash))
+            };
+
+            if let Err(err) = result {
+                return Err(Error::BlockImportAborted {
+                    error: err.into(),
+                    index: i,
+                    height: tip.height,
+                    hash,
+                    header: header.into(),
+                });
+            }
+            cache.extend_chain(tip.height + 1, hash, header);
+        }
+        cache.store.put(headers.iter().cloned())?;
+
+        Ok(cache)
+    }
+
+    /// Move the cache to a different store, eg. to migrate from an in-memory store to a
+    /// persistent one. The active chain is written to the new store, replacing any headers
+    /// it has after genesis, and the rest of the cache's state is kept as is.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::GenesisMismatch`] if the new store's genesis block isn't the cache's
+    /// genesis block. In that case, the new store isn't modified.
+    ///
+    pub fn replace_store<T: Store<Header = BlockHeader>>(
+        self,
+        mut store: T,
+    ) -> Result<BlockCache<T>, Error> {
+        let expected = self.chain.head.hash;
+        let found = store.genesis().block_hash();
+
+        if found != expected {
+            return Err(Error::GenesisMismatch { expected, found });
+        }
+        store.rollback(0)?;
+        store.put(self.iter().skip(1).map(|(_, header)| header))?;
+
+        Ok(BlockCache {
+            chain: self.chain,
+            headers: self.headers,
+            orphans: self.orphans,
+            orphans_order: self.orphans_order,
+            seq: self.seq,
+            max_orphans: self.max_orphans,
+            checkpoints: self.checkpoints,
+            params: self.params,
+            store,
+            on_reorg: self.on_reorg,
+            on_connect: self.on_connect,
+            on_disconnect: self.on_disconnect,
+            subscribers: self.subscribers,
+            difficulty: self.difficulty,
+            assume_valid: self.assume_valid,
+            time_index: self.time_index,
+            max_future_time: self.max_future_time,
+            clock_policy: self.clock_policy,
+            min_version: self.min_version,
+            max_reorg_depth: self.max_reorg_depth,
+            window: self.window,
+            evicted: self.evicted,
+            pinned: self.pinned,
+        })
+    }
+
+    /// Create a new `BlockCache` with only the store's genesis block on the active chain,
+    /// and capacity for all stored headers. The genesis block isn't checked against the
+    /// network's.
+    fn genesis_only(
+        store: S,
+        params: Params,
+        checkpoints: &[(Height, BlockHash)],
     ) -> Result<Self, Error> {
         let genesis = store.genesis();
         let length = store.len()?;
-        let orphans = HashMap::new();
+
+        // A store always holds at least the genesis block.
+        if length == 0 {
+            return Err(Error::StoreCorruption { height: 0 });
+        }
+        let orphans = BlockMap::default();
         let checkpoints = checkpoints.iter().cloned().collect();
 
         let chain = NonEmpty::from((
@@ -89,36 +498,127 @@ impl<S: Store<Header = BlockHeader>> BlockCache<S> {
                 height: 0,
                 hash: genesis.block_hash(),
                 header: genesis,
+                chainwork: genesis.work(),
+                seq: 0,
             },
             Vec::with_capacity(length - 1),
         ));
-        let mut headers = HashMap::with_capacity(length);
+        let mut headers = map::with_capacity(length);
         // Insert genesis in the headers map, but skip it during iteration.
         headers.insert(chain.head.hash, 0);
 
-        let mut cache = Self {
-            chain,
-            headers,
+        Ok(Self {
+            chain: Arc::new(chain),
+            headers: Arc::new(headers),
             orphans,
+            orphans_order: BTreeMap::new(),
+            seq: 1,
+            max_orphans: usize::MAX,
             params,
             checkpoints,
             store,
-        };
+            on_reorg: None,
+            on_connect: None,
+            on_disconnect: None,
+            subscribers: Subscribers::default(),
+            difficulty: Arc::new(difficulty::Bitcoin),
+            assume_valid: None,
+            time_index: None,
+            max_future_time: Duration::from_secs(time::MAX_FUTURE_BLOCK_TIME as u64),
+            clock_policy: ClockPolicy::Enforce,
+            min_version: None,
+            max_reorg_depth: None,
+            window: None,
+            evicted: 0,
+            pinned: Arc::new(BTreeMap::new()),
+        })
+    }
 
-        for result in cache.store.iter().skip(1) {
-            let (height, header) = result?;
-            let hash = header.block_hash();
+    /// Get the active chain block at the given height, if it is in memory.
+    fn block(&self, height: Height) -> Option<&CachedBlock> {
+        if height == 0 {
+            Some(&self.chain.head)
+        } else if height > self.evicted {
+            self.chain.tail.get((height - self.evicted - 1) as usize)
+        } else {
+            self.pinned.get(&height)
+        }
+    }
 
-            cache.extend_chain(height, hash, header);
+    /// Get an active chain block by hash, if it is in memory.
+    fn resident(&self, hash: &BlockHash) -> Option<(Height, &BlockHeader)> {
+        self.height_of(hash)
+            .and_then(|height| self.block(height))
+            .map(|blk| (blk.height, &blk.header))
+    }
+
+    /// Get the active chain header at the given height. Headers that aren't in memory are
+    /// read from the store.
+    fn header_at(&self, height: Height) -> Option<BlockHeader> {
+        if height > self.height() {
+            return None;
+        }
+        match self.block(height) {
+            Some(blk) => Some(blk.header),
+            None => self.store.get(height).ok(),
         }
+    }
 
-        assert_eq!(length, cache.chain.len());
-        assert_eq!(length, cache.headers.len());
+    /// Get the active chain block hash at the given height. Blocks that aren't in memory
+    /// are read from the store.
+    fn hash_at(&self, height: Height) -> Option<BlockHash> {
+        match self.block(height) {
+            Some(blk) => Some(blk.hash),
+            None => self.header_at(height).map(|h| h.block_hash()),
+        }
+    }
 
-        Ok(cache)
+    /// Get the lowest height a fork is allowed to start from. Forks from below the last
+    /// checkpoint are never accepted. Forks from evicted blocks, or blocks that don't have
+    /// enough ancestors in memory to validate the fork, aren't accepted either.
+    fn min_fork_height(&self) -> Height {
+        let last_checkpoint = self.last_checkpoint();
+
+        if self.evicted == 0 {
+            return last_checkpoint;
+        }
+        Height::max(
+            last_checkpoint,
+            self.evicted + self.params.difficulty_adjustment_interval(),
+        )
+    }
+
+    /// Get the lowest height the active chain can be rolled back to, ie. the lowest height
+    /// of the chain tail in memory.
+    fn min_rollback_height(&self) -> Height {
+        let last_checkpoint = self.last_checkpoint();
+
+        if self.evicted == 0 {
+            return last_checkpoint;
+        }
+        Height::max(last_checkpoint, self.evicted + 1)
+    }
+
+    /// Evict the oldest blocks from memory once there are more than twice the window,
+    /// keeping the checkpoint blocks. Blocks are evicted in batches, so that the cost of
+    /// shifting the chain is shared between the blocks added since the last eviction.
+    /// The hashes of evicted blocks stay indexed.
+    fn evict_blocks(&mut self) {
+        let window = match self.window {
+            Some(window) if self.chain.tail.len() > window * 2 => window,
+            _ => return,
+        };
+        let excess = self.chain.tail.len() - window;
+
+        for blk in Arc::make_mut(&mut self.chain).tail.drain(..excess) {
+            if self.checkpoints.contains_key(&blk.height) {
+                Arc::make_mut(&mut self.pinned).insert(blk.height, blk);
+            }
+        }
+        self.evicted += excess as Height;
     }
 
-    /// Iterate over a range of blocks.
+    /// Iterate over a range of blocks. Only blocks in memory are returned.
     ///
     /// # Errors
     ///
@@ -133,62 +633,1067 @@ impl<S: Store<Header = BlockHeader>> BlockCache<S> {
             "BlockCache::range: range start must not be greater than range end"
         );
 
-        self.chain
-            .iter()
-            .skip(range.start as usize)
-            .take((range.end - range.start) as usize)
+        let end = Height::min(range.end, self.height() + 1);
+
+        (range.start..end).filter_map(move |height| self.block(height))
     }
 
-    /// Get the median time past for the blocks leading up to the given height.
+    /// Iterate over a range of blocks, from the highest to the lowest. Unlike
+    /// `self.range(..).rev()`, this doesn't walk the chain up to the start of the range.
+    /// Only blocks in memory are returned.
     ///
     /// # Errors
     ///
-    /// Panics if height is `0`.
+    /// Panics if the range is negative, or extends past the tip.
     ///
-    pub fn median_time_past(&self, height: Height) -> BlockTime {
-        assert!(height != 0, "height must be > 0");
+    fn range_rev<'a>(
+        &'a self,
+        range: std::ops::Range<Height>,
+    ) -> impl DoubleEndedIterator<Item = &'a CachedBlock> + 'a {
+        assert!(
+            range.start <= range.end,
+            "BlockCache::range_rev: range start must not be greater than range end"
+        );
+        let genesis = Some(&self.chain.head).filter(|_| range.start == 0 && range.end > 0);
+        // The tail starts at height `evicted + 1`. Below it, only checkpoints are in memory.
+        let first = self.evicted + 1;
+        let start = Height::max(range.start, first);
+        let end = Height::max(range.end, start);
+        let tail = &self.chain.tail[(start - first) as usize..(end - first) as usize];
+        let pinned = self
+            .pinned
+            .range(range.start..Height::max(Height::min(range.end, first), range.start))
+            .map(|(_, blk)| blk);
+
+        tail.iter().rev().chain(pinned.rev()).chain(genesis)
+    }
+
+    /// Set the maximum number of orphan blocks kept in memory. When the limit is exceeded,
+    /// the least recently inserted orphans are evicted first. Orphans that are part of a
+    /// branch connecting to the active chain are never evicted.
+    ///
+    /// By default, the number of orphans is unbounded.
+    pub fn with_max_orphans(mut self, max_orphans: usize) -> Self {
+        self.max_orphans = max_orphans;
+        self.evict_orphans();
+        self
+    }
+
+    /// Set the policy used to compute the difficulty target of new blocks. By default, the
+    /// Bitcoin difficulty adjustment algorithm is used.
+    pub fn with_difficulty_policy(mut self, policy: impl DifficultyPolicy + 'static) -> Self {
+        self.difficulty = Arc::new(policy);
+        self
+    }
+
+    /// Assume that the proof-of-work of the given block and its ancestors is valid, like
+    /// Bitcoin Core's `-assumevalid` option. This trades a security assumption for speed:
+    /// the proof-of-work of these headers isn't checked, though their difficulty targets,
+    /// linkage, checkpoints and timestamps still are.
+    ///
+    /// Headers are only assumed valid if the block is on the active chain above them, or
+    /// if they are imported in the same batch as the block and lead up to it from the tip.
+    /// Otherwise, the setting has no effect.
+    pub fn with_assume_valid(mut self, hash: BlockHash) -> Self {
+        self.assume_valid = Some(hash);
+        self
+    }
+
+    /// Set how far ahead of the network-adjusted time a block timestamp may be. Defaults
+    /// to [`time::MAX_FUTURE_BLOCK_TIME`], which is what mainnet and testnet use; this is
+    /// mostly useful for regtest and other test setups.
+    pub fn with_max_future_time(mut self, max_future_time: Duration) -> Self {
+        self.max_future_time = max_future_time;
+        self
+    }
+
+    /// Set how block timestamps are checked against the clock. With [`ClockPolicy::Ignore`],
+    /// the clock passed to the import functions is never read, and the maximum future time
+    /// has no effect.
+    pub fn with_clock_policy(mut self, policy: ClockPolicy) -> Self {
+        self.clock_policy = policy;
+        self
+    }
+
+    /// Reject new blocks with a version below the given one, eg. to enforce the rules of a
+    /// soft fork that is active. Blocks already on the active chain, or in the store, are
+    /// not checked. By default, block versions are not checked.
+    pub fn with_min_version(mut self, version: i32) -> Self {
+        self.min_version = Some(version);
+        self
+    }
+
+    /// Set the maximum number of blocks that can be rolled back when switching to a fork.
+    /// Forks that would require a deeper re-org are ignored, even if they have more work.
+    /// By default, re-orgs are only limited by checkpoints.
+    pub fn with_max_reorg_depth(mut self, depth: Height) -> Self {
+        self.max_reorg_depth = Some(depth);
+        self
+    }
+
+    /// Keep an index of the active chain by median time past, so that
+    /// [`BlockCache::get_block_by_time`] doesn't have to compute the median time past of
+    /// the blocks it searches through. This costs memory proportional to the chain length.
+    pub fn with_time_index(mut self) -> Self {
+        let mut index = BTreeMap::new();
+
+        for height in self.min_time_height()..=self.height() {
+            index.entry(self.median_time_past(height)).or_insert(height);
+        }
+        self.time_index = Some(index);
+        self
+    }
+
+    /// Register a function to be called every time the active chain is re-organized.
+    /// The function is called once the new chain is stored, with the fork height,
+    /// the disconnected headers and the connected headers.
+    ///
+    /// Clones of the cache share the same function.
+    pub fn on_reorg(
+        mut self,
+        f: impl FnMut(Height, &[BlockHeader], &[BlockHeader]) + Send + 'static,
+    ) -> Self {
+        self.on_reorg = Some(ReorgHook(Arc::new(Mutex::new(Box::new(f)))));
+        self
+    }
 
+    /// Register a function to be called every time a block is connected to the active
+    /// chain, with the block height and header. Blocks are connected in ascending height
+    /// order, and each block is connected after its parent. The function is called once the
+    /// block is stored, so blocks that fail to be stored, eg. during a re-org, aren't
+    /// reported.
+    ///
+    /// The function isn't called for the blocks loaded from the store when the cache is
+    /// created. Together with [`BlockCache::on_disconnect`], this can drive a processor that
+    /// applies and undoes blocks as the active chain changes, eg. to track transactions.
+    ///
+    /// Clones of the cache share the same function.
+    pub fn on_connect(mut self, f: impl FnMut(Height, &BlockHeader) + Send + 'static) -> Self {
+        self.on_connect = Some(BlockHook::new(f));
+        self
+    }
+
+    /// Register a function to be called every time a block is disconnected from the active
+    /// chain, eg. on re-orgs and rollbacks, with the block height and header. Blocks are
+    /// disconnected in descending height order, starting from the tip, so that the
+    /// disconnections undo the connections reported by [`BlockCache::on_connect`]. The
+    /// function is called once the change is stored, and on re-orgs, after the function
+    /// registered with [`BlockCache::on_reorg`].
+    ///
+    /// Clones of the cache share the same function.
+    pub fn on_disconnect(mut self, f: impl FnMut(Height, &BlockHeader) + Send + 'static) -> Self {
+        self.on_disconnect = Some(BlockHook::new(f));
+        self
+    }
+
+    /// Subscribe to changes of the active chain. An event is sent on the returned channel
+    /// every time the active chain is extended or re-organized by an import, once the
+    /// change is stored.
+    ///
+    /// Clones of the cache share the same subscribers. Dropping the receiver
+    /// unsubscribes.
+    pub fn subscribe(&self) -> mpsc::Receiver<ChainEvent> {
+        let (tx, rx) = mpsc::channel();
+
+        self.subscribers.0.lock().unwrap().push(tx);
+        rx
+    }
+
+    /// Get owned copies of the active chain headers in the given height range, along with
+    /// their heights. The range is clamped to the active chain, and an empty or inverted
+    /// range returns no headers.
+    ///
+    /// This is useful for creating header snapshots that other nodes can bootstrap from.
+    pub fn export_headers(&self, range: std::ops::Range<Height>) -> Vec<(Height, BlockHeader)> {
+        let end = Height::min(range.end, self.height() + 1);
+
+        if range.start >= end {
+            return vec![];
+        }
+        (range.start..end)
+            .filter_map(|height| self.header_at(height).map(|header| (height, header)))
+            .collect()
+    }
+
+    /// Write the active chain headers in the given height range to `w`, in their 80-byte
+    /// consensus encoding, one after the other. The range is clamped to the active chain,
+    /// like with [`BlockCache::export_headers`]. Returns the number of headers written.
+    ///
+    /// The output can be read back with [`bitcoin::consensus::encode::Decodable`].
+    pub fn write_headers<W: io::Write>(
+        &self,
+        range: std::ops::Range<Height>,
+        w: &mut W,
+    ) -> io::Result<usize> {
+        let end = Height::min(range.end, self.height() + 1);
+        let mut count = 0;
+
+        for header in (range.start..end).filter_map(|height| self.header_at(height)) {
+            header.consensus_encode(&mut *w)?;
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Get up to `limit` active chain headers following the given block, in ascending height
+    /// order. Returns no headers if the block isn't on the active chain, eg. if it's unknown
+    /// or an orphan.
+    ///
+    /// This is useful for serving the headers a peer is missing, given its tip.
+    pub fn headers_since(&self, hash: &BlockHash, limit: usize) -> Vec<BlockHeader> {
+        match self.headers.get(hash) {
+            Some(height) => self.headers_at(height + 1, limit),
+            None => vec![],
+        }
+    }
+
+    /// Get the hashes of the active chain blocks in the given height range, in ascending
+    /// height order. The range is clamped to the active chain, so heights above the tip
+    /// are ignored, and an empty or inverted range returns no hashes.
+    ///
+    /// This is useful when only the hashes are needed, eg. for `inv` announcements.
+    pub fn hashes(&self, range: std::ops::Range<Height>) -> Vec<BlockHash> {
+        let end = Height::min(range.end, self.height() + 1);
+
+        (range.start..end)
+            .filter_map(|height| self.hash_at(height))
+            .collect()
+    }
+
+    /// Check that a transaction is included in the active chain block at the given height,
+    /// given a partial merkle tree, eg. from a `merkleblock` message. Returns `false` if
+    /// there is no block at this height, if the tree is invalid or doesn't reproduce the
+    /// block's merkle root, or if the transaction isn't one of the tree's matches.
+    pub fn verify_tx_proof(&self, height: Height, txid: &Txid, path: &PartialMerkleTree) -> bool {
+        let header = match self.header_at(height) {
+            Some(header) => header,
+            None => return false,
+        };
+        let mut matches = Vec::new();
+        let mut indexes = Vec::new();
+
+        match path.extract_matches(&mut matches, &mut indexes) {
+            Ok(root) => root == header.merkle_root && matches.contains(txid),
+            Err(_) => false,
+        }
+    }
+
+    /// Get locator hashes for the active chain between two heights, inclusive. Hashes start
+    /// at `high`, and step back with doubling gaps after the first few, down to `low`. The
+    /// range is clamped to the active chain, and an inverted range returns no hashes.
+    ///
+    /// Unlike [`BlockTree::locator_hashes`], this allows header download to be split into
+    /// independent ranges.
+    pub fn locator_between(&self, low: Height, high: Height) -> Vec<BlockHash> {
+        let high = Height::min(high, self.height());
+
+        if low > high {
+            return vec![];
+        }
+        block::locators_indexes(high - low)
+            .into_iter()
+            .filter_map(|offset| self.hash_at(low + offset))
+            .collect()
+    }
+
+    /// Verify that the active chain contains every configured checkpoint up to the tip.
+    /// Checkpoints above the tip are ignored. This is useful to assert the integrity of
+    /// the chain after a sync.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::InvalidBlockHash`] with the active chain's block hash at the first
+    /// checkpoint height that doesn't match.
+    ///
+    pub fn verify_checkpoints(&self) -> Result<(), Error> {
+        for (height, checkpoint) in self.checkpoints.range(..=self.height()) {
+            let hash = match self.block(*height) {
+                Some(blk) => blk.hash,
+                None => self.store.get(*height)?.block_hash(),
+            };
+
+            if &hash != checkpoint {
+                return Err(Error::InvalidBlockHash(hash, *height));
+            }
+        }
+        Ok(())
+    }
+
+    /// Check whether the checkpoint at the given height is satisfied by the active chain.
+    /// Returns `None` if there is no checkpoint at that height, and `Some(false)` if the
+    /// active chain has a different block at that height, or doesn't reach it yet.
+    pub fn checkpoint_satisfied(&self, height: Height) -> Option<bool> {
+        let checkpoint = self.checkpoints.get(&height)?;
+
+        Some(self.hash_at(height).as_ref() == Some(checkpoint))
+    }
+
+    /// Get the highest checkpoint at or below the given height, if any. Since forks from
+    /// below a checkpoint are never accepted, this is as far as the active chain at that
+    /// height can ever be rolled back to.
+    pub fn nearest_checkpoint_below(&self, height: Height) -> Option<(Height, BlockHash)> {
+        self.checkpoints
+            .range(..=height)
+            .next_back()
+            .map(|(height, hash)| (*height, *hash))
+    }
+
+    /// Check whether there is a checkpoint at the given height.
+    pub fn is_checkpoint_height(&self, height: Height) -> bool {
+        self.checkpoints.contains_key(&height)
+    }
+
+    /// Add a checkpoint at runtime. Once added, the checkpoint is enforced like the
+    /// checkpoints the cache was created with: no forks from the active chain prior to it
+    /// are accepted.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::InvalidBlockHeight`] if the checkpoint is below the last checkpoint,
+    /// and [`Error::InvalidBlockHash`] with the active chain's block hash if it conflicts
+    /// with the active chain.
+    ///
+    pub fn add_checkpoint(&mut self, height: Height, hash: BlockHash) -> Result<(), Error> {
+        if height < self.last_checkpoint() {
+            return Err(Error::InvalidBlockHeight(height));
+        }
+        if let Some(active) = self.hash_at(height) {
+            if active != hash {
+                return Err(Error::InvalidBlockHash(active, height));
+            }
+        }
+        self.checkpoints.insert(height, hash);
+
+        Ok(())
+    }
+
+    /// Replace all checkpoints, eg. with checkpoints loaded after the cache was created.
+    /// Unlike [`BlockCache::add_checkpoint`], the new checkpoints may be below the current
+    /// ones.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::InvalidBlockHash`] with the active chain's block hash at the lowest
+    /// checkpoint that conflicts with the active chain. The checkpoints are then left
+    /// unchanged.
+    ///
+    pub fn set_checkpoints(&mut self, checkpoints: &[(Height, BlockHash)]) -> Result<(), Error> {
+        let checkpoints = checkpoints.iter().cloned().collect::<BTreeMap<_, _>>();
+
+        for (height, hash) in checkpoints.iter() {
+            match self.hash_at(*height) {
+                Some(active) if active != *hash => {
+                    return Err(Error::InvalidBlockHash(active, *height));
+                }
+                Some(_) => {}
+                None => break,
+            }
+        }
+        self.checkpoints = checkpoints;
+
+        Ok(())
+    }
+
+    /// Get the difficulty target of the active chain block at the given height, or `None`
+    /// if there is no such block.
+    pub fn target_at(&self, height: Height) -> Option<Target> {
+        self.header_at(height).map(|h| h.target())
+    }
+
+    /// Get the difficulty of the active chain block at the given height, as displayed by
+    /// Bitcoin Core, or `None` if there is no such block. See [`block::difficulty`].
+    pub fn difficulty_at(&self, height: Height) -> Option<f64> {
+        self.header_at(height).map(|h| block::difficulty(h.bits))
+    }
+
+    /// Suggest a checkpoint for future startups: the active chain block with the given
+    /// number of confirmations on top of it, ie. at height `height() - confirmations`.
+    /// Returns `None` if the active chain isn't that long.
+    ///
+    /// The checkpoint isn't added to the cache. See [`BlockCache::add_checkpoint`].
+    pub fn suggest_checkpoint(&self, confirmations: Height) -> Option<(Height, BlockHash)> {
+        let height = self.height().checked_sub(confirmations)?;
+
+        self.hash_at(height).map(|hash| (height, hash))
+    }
+
+    /// Get the tips of the side chains connecting to the active chain, along with the height
+    /// each tip would be at if its branch was activated. Branches are not validated.
+    ///
+    /// Tips are ordered by height, highest first.
+    pub fn fork_tips(&self) -> Vec<(BlockHash, Height)> {
+        let parents = self
+            .orphans
+            .values()
+            .map(|orphan| orphan.prev_blockhash)
+            .collect::<HashSet<_>>();
+        let mut tips = self
+            .orphans
+            .keys()
+            .filter(|hash| !parents.contains(*hash))
+            .filter_map(|hash| self.fork(hash))
+            .map(|branch| {
+                (
+                    branch.tip,
+                    branch.fork_height + branch.headers.len() as Height,
+                )
+            })
+            .collect::<Vec<_>>();
+
+        tips.sort_by(|(a_hash, a), (b_hash, b)| b.cmp(a).then(a_hash.cmp(b_hash)));
+        tips
+    }
+
+    /// Write out any headers buffered by the store. See [`store::Buffered`].
+    ///
+    /// [`store::Buffered`]: crate::block::store::Buffered
+    pub fn flush(&mut self) -> Result<(), Error> {
+        self.store.flush().map_err(Error::from)
+    }
+
+    /// Check whether the given block hash is the active chain block at the given height.
+    /// Returns `false` if the height is beyond the tip.
+    pub fn is_at_height(&self, height: Height, hash: &BlockHash) -> bool {
+        self.hash_at(height).as_ref() == Some(hash)
+    }
+
+    /// Get the height of the given block on the active chain, without reading its header.
+    pub fn height_of(&self, hash: &BlockHash) -> Option<Height> {
+        self.headers.get(hash).copied()
+    }
+
+    /// Get an active chain block by hash. Unlike [`BlockTree::get_block`], blocks evicted
+    /// from memory are read from the store.
+    pub fn get_block_or_load(&self, hash: &BlockHash) -> Option<(Height, BlockHeader)> {
+        self.height_of(hash)
+            .and_then(|height| self.header_at(height).map(|header| (height, header)))
+    }
+
+    /// Get an active chain block by height. Unlike [`BlockTree::get_block_by_height`], blocks
+    /// evicted from memory are read from the store.
+    pub fn get_block_by_height_or_load(&self, height: Height) -> Option<BlockHeader> {
+        self.header_at(height)
+    }
+
+    /// Get the height, hash and header of the best block. Unlike [`BlockTree::tip`], the
+    /// header isn't copied, and unlike [`BlockTree::best_block`], the hash is included.
+    pub fn best_block(&self) -> (Height, BlockHash, &BlockHeader) {
+        let tip = self.chain.last();
+
+        (tip.height, tip.hash, &tip.header)
+    }
+
+    /// Check whether the given block hash is the tip of the active chain. This is a cheap way
+    /// of checking whether the tip changed since it was last seen.
+    pub fn tip_is(&self, hash: &BlockHash) -> bool {
+        self.chain.last().hash == *hash
+    }
+
+    /// Get the number of valid branches that fork off the active chain and could be switched
+    /// to. Each branch is validated, so this is about as costly as an import, but nothing
+    /// is kept in memory.
+    pub fn candidate_count(&self, clock: &impl Clock) -> usize {
+        self.candidates(clock).count()
+    }
+
+    /// Get the number of orphan blocks currently held in memory.
+    pub fn orphan_count(&self) -> usize {
+        self.orphans.len()
+    }
+
+    /// Estimate the memory used by the block cache, in bytes. This accounts for the active
+    /// chain, the header index, orphans, checkpoints and the time index, including unused
+    /// capacity, but not for the store.
+    ///
+    /// The estimate is approximate, since the allocation overhead of the collections isn't
+    /// known exactly.
+    pub fn memory_usage(&self) -> usize {
+        let chain = (self.chain.tail.capacity() + 1) * mem::size_of::<CachedBlock>();
+        let time_index = self.time_index.as_ref().map_or(0, btree_map_usage);
+
+        mem::size_of::<Self>()
+            + chain
+            + hash_map_usage(&self.headers)
+            + hash_map_usage(&self.orphans)
+            + btree_map_usage(&self.orphans_order)
+            + btree_map_usage(&self.checkpoints)
+            + btree_map_usage(&self.pinned)
+            + time_index
+    }
+
+    /// Get the number of headers in the store, including genesis.
+    pub fn store_len(&self) -> Result<usize, Error> {
+        self.store.len().map_err(Error::from)
+    }
+
+    /// Get information about every orphan block, in arrival order. This is mostly useful
+    /// for diagnosing sync issues, eg. telling apart orphans whose parents are unknown from
+    /// fork tips.
+    pub fn orphan_info(&self) -> Vec<OrphanInfo> {
+        let min_fork_height = self.min_fork_height();
+
+        self.orphans_order
+            .values()
+            .filter_map(|hash| self.orphans.get(hash).map(|orphan| (hash, orphan)))
+            .map(|(hash, orphan)| {
+                let fork_height = self.fork_height(hash);
+
+                OrphanInfo {
+                    hash: *hash,
+                    prev: orphan.prev_blockhash,
+                    fork_height,
+                    connects: matches!(fork_height, Some(h) if h >= min_fork_height),
+                }
+            })
+            .collect()
+    }
+
+    /// Get the parents of orphan blocks that are unknown, ie. neither on the active chain nor
+    /// orphans themselves, in the arrival order of the orphans. These are the blocks to fetch
+    /// to connect the orphans' branches.
+    pub fn missing_parents(&self) -> Vec<BlockHash> {
+        let mut seen = HashSet::new();
+
+        self.orphans_order
+            .values()
+            .filter_map(|hash| self.orphans.get(hash))
+            .map(|orphan| orphan.prev_blockhash)
+            .filter(|prev| !self.is_known(prev) && seen.insert(*prev))
+            .collect()
+    }
+
+    /// Remove the orphans that can never be part of the active chain, because their branch
+    /// forks off the active chain below the last checkpoint, or because it doesn't connect
+    /// to the active chain at all. Returns the number of orphans removed.
+    ///
+    /// Since all orphans on a branch fork off at the same height, the ancestors of a viable
+    /// branch tip are never removed.
+    pub fn prune_orphans(&mut self) -> usize {
+        let min_fork_height = self.min_fork_height();
+        let stale = self
+            .orphans
+            .keys()
+            .filter(|hash| match self.fork_height(hash) {
+                Some(height) => height < min_fork_height,
+                None => true,
+            })
+            .copied()
+            .collect::<Vec<_>>();
+
+        for hash in &stale {
+            self.remove_orphan(hash);
+        }
+        stale.len()
+    }
+
+    /// Get the total proof-of-work of the active chain, from genesis to the tip.
+    pub fn chain_work(&self) -> Work {
+        self.chain.last().chainwork
+    }
+
+    /// Check whether a branch made of the given headers would replace part of the active
+    /// chain, if it was imported. The headers must form a chain that connects to the active
+    /// chain; headers that are already on the active chain are skipped. Returns `false` if
+    /// the headers don't connect, or if they only extend the tip.
+    ///
+    /// The headers are not validated, and the block tree isn't modified.
+    pub fn would_reorg(&self, headers: &[BlockHeader]) -> bool {
+        let headers = match headers.iter().position(|h| !self.contains(&h.block_hash())) {
+            Some(ix) => &headers[ix..],
+            None => return false,
+        };
+        let fork = match self
+            .headers
+            .get(&headers[0].prev_blockhash)
+            .and_then(|height| self.block(*height))
+        {
+            Some(fork) if fork.height < self.height() && fork.height >= self.min_fork_height() => {
+                fork
+            }
+            _ => return false,
+        };
+        let connected = headers
+            .windows(2)
+            .all(|w| w[1].prev_blockhash == w[0].block_hash());
+
+        if !connected {
+            return false;
+        }
+        let tip = headers[headers.len() - 1].block_hash();
+        // The branch tip would be the latest block seen, unless we've seen it already.
+        let seq = self.orphans.get(&tip).map_or(self.seq, |o| o.seq);
+
+        self.is_better(fork.chainwork + Branch(headers).work(), &tip, seq)
+    }
+
+    /// Find where a chain of headers, eg. received from a peer, diverges from the active
+    /// chain. Returns the height of the last block both chains agree on, or `None` if they
+    /// don't agree on any block, eg. because the headers are from another network.
+    ///
+    /// The parent of the first header counts as a block both chains agree on, so that
+    /// headers extending the tip return the tip height. The headers are not validated,
+    /// and the block tree isn't modified.
+    pub fn fork_point(&self, headers: &[BlockHeader]) -> Option<Height> {
+        let first = headers.first()?;
+        let mut fork = self.headers.get(&first.prev_blockhash).copied();
+
+        for header in headers {
+            match self.headers.get(&header.block_hash()) {
+                Some(height) => fork = Some(*height),
+                None => break,
+            }
+        }
+        fork
+    }
+
+    /// Find the most recent common ancestor of two blocks. The blocks may be on the active
+    /// chain or on a fork. Returns `None` if either block is unknown, or if the two blocks
+    /// don't share an ancestor, eg. because one of them doesn't connect to the active chain.
+    pub fn common_ancestor(&self, a: &BlockHash, b: &BlockHash) -> Option<BlockHash> {
+        if !self.is_known(a) || !self.is_known(b) {
+            return None;
+        }
+
+        // Collect the fork blocks leading up to `a`.
+        let mut branch = HashSet::new();
+        let mut cursor = *a;
+
+        while let Some(orphan) = self.orphans.get(&cursor) {
+            branch.insert(cursor);
+            cursor = orphan.prev_blockhash;
+        }
+        let root = self.headers.get(&cursor).copied();
+
+        // Walk back from `b` until we find a block leading up to `a`.
+        let mut cursor = *b;
+
+        while let Some(orphan) = self.orphans.get(&cursor) {
+            if branch.contains(&cursor) {
+                return Some(cursor);
+            }
+            cursor = orphan.prev_blockhash;
+        }
+
+        // Both blocks lead back to the active chain: the common ancestor is the lower of
+        // the two fork points.
+        let height = Height::min(root?, *self.headers.get(&cursor)?);
+
+        self.hash_at(height)
+    }
+
+    /// Iterate over the ancestors of a block, starting with the block itself and ending with
+    /// genesis. The block may be on the active chain or on a fork.
+    ///
+    /// The iterator is empty if the block is unknown, or if it doesn't connect to the active
+    /// chain, since its height can't be determined. It ends early at blocks evicted from
+    /// memory, see [`BlockCache::with_store_and_replay_limit`].
+    pub fn ancestors<'a>(
+        &'a self,
+        hash: &BlockHash,
+    ) -> impl Iterator<Item = (Height, &'a BlockHeader)> + 'a {
+        let start = self.resident(hash).or_else(|| {
+            let orphan = self.orphans.get(hash)?;
+            let mut cursor = *hash;
+            let mut depth = 0;
+
+            while let Some(orphan) = self.orphans.get(&cursor) {
+                cursor = orphan.prev_blockhash;
+                depth += 1;
+            }
+            let root = self.headers.get(&cursor)?;
+
+            Some((root + depth, &orphan.header))
+        });
+
+        self.walk(start)
+    }
+
+    /// Iterate over the given block and its ancestors, given the block's height.
+    fn walk<'a>(
+        &'a self,
+        start: Option<(Height, &'a BlockHeader)>,
+    ) -> impl Iterator<Item = (Height, &'a BlockHeader)> + 'a {
+        std::iter::successors(start, move |(height, header)| {
+            let height = height.checked_sub(1)?;
+
+            self.resident(&header.prev_blockhash).or_else(|| {
+                self.orphans
+                    .get(&header.prev_blockhash)
+                    .map(|orphan| (height, &orphan.header))
+            })
+        })
+    }
+
+    /// Get the first block of the active chain from which all blocks have a timestamp at or
+    /// after the given time. If the time precedes genesis, genesis is returned. If the time
+    /// is later than the tip's timestamp, `None` is returned.
+    ///
+    /// Block timestamps are not monotonic, so we can't binary search on them directly.
+    /// Instead, we search on the median time past, which *is* monotonic, since every block's
+    /// timestamp must be greater than the median time past of the blocks preceding it.
+    /// We then walk back to include any preceding blocks that also satisfy the condition.
+    pub fn get_block_by_time(&self, time: BlockTime) -> Option<(Height, &BlockHeader)> {
+        if self.chain.last().time < time {
+            return None;
+        }
+
+        // Find the first height at which the median time past is at or after the given time.
+        // All blocks from there on have a timestamp that is later than the given time. If
+        // there is no such height, we end up one past the tip.
+        let min = self.min_time_height();
+        let low = if let Some(index) = &self.time_index {
+            index
+                .range(time..)
+                .next()
+                .map_or(self.height() + 1, |(_, height)| *height)
+        } else {
+            let mut low = min;
+            let mut high = self.height() + 1;
+
+            while low < high {
+                let mid = low + (high - low) / 2;
+
+                if self.median_time_past(mid) >= time {
+                    high = mid;
+                } else {
+                    low = mid + 1;
+                }
+            }
+            low
+        };
+
+        // The median time past of evicted blocks isn't known, so the block might be lower.
+        if self.evicted > 0 && low <= min {
+            return None;
+        }
+
+        // Include the preceding blocks that also have a timestamp at or after the given time.
+        // Since the median time past is below the given time at `low - 1`, this will never
+        // walk back more than `MEDIAN_TIME_SPAN` blocks.
+        let mut height = low;
+        while height > 0 && matches!(self.block(height - 1), Some(blk) if blk.time >= time) {
+            height -= 1;
+        }
+
+        self.block(height).map(|blk| (blk.height, &blk.header))
+    }
+
+    /// Get the median time past for the blocks leading up to the given height.
+    /// Since there are no blocks leading up to genesis, the genesis time is returned for
+    /// height `0`. Heights above the tip only count the blocks up to the tip, and for heights
+    /// more than [`time::MEDIAN_TIME_SPAN`] blocks above it, the tip time is returned.
+    pub fn median_time_past(&self, height: Height) -> BlockTime {
+        if height == 0 {
+            return self.chain.first().time;
+        }
         let mut times = [0; time::MEDIAN_TIME_SPAN as usize];
 
         let start = height.saturating_sub(time::MEDIAN_TIME_SPAN);
-        let end = height;
-
-        for (i, blk) in self.range(start..end).enumerate() {
-            times[i] = blk.time;
+        let end = Height::min(height, self.height() + 1);
+
+        let mut count = 0;
+        for h in start..end {
+            // Blocks evicted from memory are read from the store.
+            let time = match self.block(h) {
+                Some(blk) => Some(blk.time),
+                None => self.header_at(h).map(|header| header.time),
+            };
+            if let Some(time) = time {
+                times[count] = time;
+                count += 1;
+            }
+        }
+        // None of the blocks are on the active chain, eg. because the height is more than
+        // `MEDIAN_TIME_SPAN` blocks above the tip, or the store can't be read.
+        if count == 0 {
+            return self.block_at_or_before(height).time;
         }
 
         // Gracefully handle the case where `height` < `MEDIUM_TIME_SPAN`.
-        let available = &mut times[0..(end - start) as usize];
+        let available = &mut times[0..count];
 
         available.sort_unstable();
         available[available.len() / 2]
     }
 
-    /// Import a block into the tree. Performs header validation. This function may trigger
-    /// a chain re-org.
-    fn import_block(
+    /// Get the median time past of the tip, ie. the median timestamp of the last
+    /// [`time::MEDIAN_TIME_SPAN`] blocks up to and including the tip, or fewer if the chain
+    /// is shorter. This is the time that locktimes are compared against, as per BIP 113.
+    pub fn tip_median_time_past(&self) -> BlockTime {
+        // The median time past at a height covers the blocks preceding it.
+        self.median_time_past(self.height() + 1)
+    }
+
+    /// Get the number of seconds by which the time of the block at the given height lags
+    /// behind the clock. The result is negative if the block time is ahead of the clock.
+    /// Heights above the tip are treated as the tip height.
+    ///
+    /// This is useful to detect a stalled chain, by checking the drift of the tip.
+    pub fn time_drift(&self, height: Height, clock: &impl Clock) -> i64 {
+        clock.block_time() as i64 - self.block_at_or_before(height).time as i64
+    }
+
+    /// Get the header of the active chain at the given height, or the tip header if the
+    /// height is above the tip. If the block was evicted from memory, the closest block
+    /// below it that is in memory is returned, which may be genesis.
+    pub fn block_at_or_before(&self, height: Height) -> &BlockHeader {
+        let height = Height::min(height, self.height());
+
+        match self.block(height) {
+            Some(blk) => &blk.header,
+            None => self
+                .pinned
+                .range(..height)
+                .next_back()
+                .map_or(&self.chain.head.header, |(_, blk)| &blk.header),
+        }
+    }
+
+    /// Get the lowest height whose median time past can be computed from the blocks in
+    /// memory.
+    fn min_time_height(&self) -> Height {
+        if self.evicted == 0 {
+            return 0;
+        }
+        self.evicted + 1 + time::MEDIAN_TIME_SPAN
+    }
+
+    /// Get the difficulty target the next block on the active chain is required to have,
+    /// if it is found on schedule, one target spacing after the tip.
+    ///
+    /// On networks that allow minimum-difficulty blocks, a block found later may have the
+    /// minimum difficulty instead. Use [`BlockCache::next_target_at`] to get the target
+    /// required of a block with a given timestamp.
+    pub fn next_target(&self) -> Target {
+        let time = self.chain.last().header.time + self.params.pow_target_spacing as BlockTime;
+
+        self.next_target_at(time)
+    }
+
+    /// Get the difficulty target the next block on the active chain is required to have,
+    /// if it has the given timestamp.
+    ///
+    /// The timestamp only matters on networks that allow minimum-difficulty blocks, where a
+    /// block timestamped more than twice the target spacing after the tip may have the
+    /// minimum difficulty. Difficulty policies that look at other header fields are given
+    /// a header with the tip's version and bits.
+    pub fn next_target_at(&self, time: BlockTime) -> Target {
+        let tip = self.chain.last();
+        let header = BlockHeader {
+            version: tip.header.version,
+            prev_blockhash: tip.hash,
+            merkle_root: Default::default(),
+            time,
+            bits: tip.header.bits,
+            nonce: 0,
+        };
+        self.required_target(tip, &header)
+    }
+
+    /// Get the locator hashes for the active chain, starting at the given height, including
+    /// every block of the top `dense` heights. Below them, the hashes are spaced out as in
+    /// [`BlockTree::locator_hashes`].
+    ///
+    /// A dense locator lets a peer find a recent fork without further round-trips, eg.
+    /// after a re-org.
+    ///
+    /// *Panics* if the given starting height is out of bounds.
+    ///
+    pub fn locator_hashes_dense(&self, from: Height, dense: usize) -> Vec<BlockHash> {
+        let start = from.saturating_sub(dense as Height);
+        let indexes = (start + 1..=from)
+            .rev()
+            .chain(block::locators_indexes(start));
+
+        self.locator(from, indexes)
+    }
+
+    /// Check whether the active chain looks synced, ie. whether the tip's time is no more
+    /// than `tolerance` seconds behind the clock. A tip that is ahead of the clock counts
+    /// as synced.
+    ///
+    /// This is a heuristic: a chain that recently stopped receiving blocks, eg. because
+    /// all peers are stalled, will still be reported as synced until the tolerance runs out.
+    pub fn is_synced(&self, clock: &impl Clock, tolerance: BlockTime) -> bool {
+        self.time_drift(self.height(), clock) <= tolerance as i64
+    }
+
+    /// Roll the active chain back to the given height, discarding all blocks above it,
+    /// as well as any orphans descending from them. Returns the removed headers, in
+    /// ascending height order.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::InvalidBlockHeight`] if `height` is below the last checkpoint, or
+    /// if the block at `height` was evicted from memory.
+    ///
+    pub fn reset_to(&mut self, height: Height) -> Result<Vec<BlockHeader>, Error> {
+        if height < self.min_rollback_height() {
+            return Err(Error::InvalidBlockHeight(height));
+        }
+        if height >= self.height() {
+            return Ok(vec![]);
+        }
+        let stale = self.rollback(height)?;
+
+        self.notify_disconnected(&stale);
+
+        let mut removed = stale
+            .iter()
+            .map(|(_, h)| h.block_hash())
+            .collect::<HashSet<_>>();
+
+        // Remove the stale blocks that were re-inserted as orphans by the rollback,
+        // followed by any orphans building on top of them.
+        loop {
+            let descendants = self
+                .orphans
+                .iter()
+                .filter(|(hash, orphan)| {
+                    removed.contains(*hash) || removed.contains(&orphan.prev_blockhash)
+                })
+                .map(|(hash, _)| *hash)
+                .collect::<Vec<_>>();
+
+            if descendants.is_empty() {
+                break;
+            }
+            for hash in descendants {
+                self.remove_orphan(&hash);
+                removed.insert(hash);
+            }
+        }
+        Ok(stale.into_iter().map(|(_, h)| h).collect())
+    }
+
+    /// Reset the block cache to genesis, discarding all other blocks, including all orphans.
+    /// If there are checkpoints above genesis, the cache is reset to the last checkpoint
+    /// instead, since the chain can't be rolled back past it. Likewise, caches created with
+    /// a replay window are reset to their lowest block in memory.
+    pub fn reset(&mut self) -> Result<(), Error> {
+        self.reset_to(self.min_rollback_height())?;
+        self.orphans.clear();
+        self.orphans_order.clear();
+
+        Ok(())
+    }
+
+    /// Roll the active chain back to the given block, which becomes the new tip. Like
+    /// [`BlockCache::reset_to`], blocks above it are discarded, along with any orphans
+    /// descending from them. Returns the removed headers, in ascending height order.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::BlockMissing`] if the block isn't on the active chain, and
+    /// [`Error::InvalidBlockHeight`] if it is below the last checkpoint.
+    ///
+    pub fn rollback_to(&mut self, hash: &BlockHash) -> Result<Vec<BlockHeader>, Error> {
+        let height = *self.headers.get(hash).ok_or(Error::BlockMissing(*hash))?;
+
+        self.reset_to(height)
+    }
+
+    /// Import blocks into the block tree, like [`BlockTree::import_blocks`], but sort them
+    /// first, so that every header is imported after its parent, if the parent is part of
+    /// the batch. This avoids turning most of an unsorted batch into orphans, which are
+    /// costly to connect later on.
+    ///
+    /// Headers that don't build on another header of the batch are imported in the order
+    /// they are given, each followed by its descendants. The index of
+    /// [`Error::BlockImportAborted`] is the index of the failing header in the given batch.
+    pub fn import_blocks_sorted<I: Iterator<Item = BlockHeader>>(
+        &mut self,
+        chain: I,
+        clock: &impl Clock,
+    ) -> Result<ImportResult, Error> {
+        let (order, headers): (Vec<_>, Vec<_>) = sort_headers(chain.collect()).into_iter().unzip();
+
+        match self.import_blocks(headers.into_iter(), clock) {
+            Err(Error::BlockImportAborted {
+                error,
+                index,
+                height,
+                hash,
+                header,
+            }) => Err(Error::BlockImportAborted {
+                error,
+                index: order[index],
+                height,
+                hash,
+                header,
+            }),
+            result => result,
+        }
+    }
+
+    /// Import a single block into the tree. Performs header validation. This function may
+    /// trigger a chain re-org.
+    ///
+    /// Unlike [`BlockTree::extend_tip`], headers that don't extend the tip are handled like
+    /// in [`BlockTree::import_blocks`]: they may activate a fork, or are kept as orphans.
+    ///
+    /// Blocks that are already known are ignored, and [`ImportResult::TipUnchanged`] is
+    /// returned.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::BlockMissing`] with the parent hash if the header's parent is
+    /// unknown. The header is still kept as an orphan, so that it can be connected once
+    /// its parent arrives. Headers that extend the tip, and the proof-of-work of other
+    /// headers, are validated right away; on failure, the validation error is returned.
+    ///
+    pub fn import_block(
+        &mut self,
+        header: BlockHeader,
+        clock: &impl Clock,
+    ) -> Result<ImportResult, Error> {
+        self.import(header, None, clock)
+    }
+
+    /// Import a block into the tree, given the result of an earlier proof-of-work check of
+    /// the header against its own target, if any. See [`BlockCache::validate`].
+    fn import(
         &mut self,
         header: BlockHeader,
+        pow: Option<bool>,
         clock: &impl Clock,
     ) -> Result<ImportResult, Error> {
         let hash = header.block_hash();
         let tip = self.chain.last();
         let best = tip.hash;
 
+        // Blocks connected to and disconnected from the active chain during this import.
+        let mut connected = Vec::new();
+        let mut reverted = Vec::new();
+        // Orphans received before this import, that were connected to the active chain.
+        let mut reconnected = Vec::new();
+        // Height up to which the active chain is unchanged by this import.
+        let unchanged = tip.height;
+
+        // Block is already on the active chain, eg. because a peer re-sent our tip.
+        if self.headers.contains_key(&hash) {
+            return Ok(ImportResult::TipUnchanged);
+        }
+
         // Block extends the active chain.
         if header.prev_blockhash == best {
             let height = tip.height + 1;
 
-            self.validate(&tip, &header, clock)?;
-            self.extend_chain(height, hash, header);
+            self.validate(&tip, &header, pow, clock)?;
             self.store.put(std::iter::once(header))?;
-        } else if self.headers.contains_key(&hash) || self.orphans.contains_key(&hash) {
-            // FIXME: This shouldn't be an error.
-            return Err(Error::DuplicateBlock(hash));
+            self.extend_chain(height, hash, header);
+            self.notify_connected(iter::once((height, &header)));
+            self.subscribers.send(ChainEvent::TipExtended(height, hash));
+
+            connected.push((height, header));
+        } else if self.orphans.contains_key(&hash) {
+            // Block is already an orphan, eg. because a peer re-sent it. Since it was already
+            // considered for chain selection when it arrived, nothing can change.
+            return Ok(ImportResult::TipUnchanged);
         } else {
             if let Some(height) = self.headers.get(&header.prev_blockhash) {
                 // Don't accept any forks from the main chain, prior to the last checkpoint.
-                if *height < self.last_checkpoint() {
+                if *height < self.min_fork_height() {
                     return Err(Error::InvalidBlockHeight(*height + 1));
                 }
             }
@@ -197,33 +1702,17 @@ impl<S: Store<Header = BlockHeader>> BlockCache<S> {
             // is greater than the minimum allowed for this network.
             //
             // We do this because it's cheap to verify and prevents flooding attacks.
-            let target = header.target();
-            match header.validate_pow(&target) {
-                Ok(_) => {
-                    let limit = self.params.pow_limit;
-                    if target > limit {
-                        return Err(Error::InvalidBlockTarget(target, limit));
-                    }
-                }
-                Err(bitcoin::util::Error::BlockBadProofOfWork) => {
-                    return Err(Error::InvalidBlockPoW);
-                }
-                Err(bitcoin::util::Error::BlockBadTarget) => {
-                    // The only way to get a 'bad target' error is to pass a different target
-                    // than the one specified in the header.
-                    unreachable!();
-                }
-                Err(_) => {
-                    // We've handled all possible errors above.
-                    unreachable!();
-                }
-            }
-            self.orphans.insert(hash, header);
+            Self::check_pow(&header, &self.params)?;
+
+            let seq = self.next_seq();
+
+            self.insert_orphan(hash, header, seq, None);
+            self.evict_orphans();
         }
 
         // Activate the chain with the most work.
 
-        let candidates = self.chain_candidates(clock);
+        let (candidates, invalid) = self.chain_candidates(clock);
 
         // TODO: What are we trying to do here? We're saying that if there are no
         // forks, and this header has no parent, we return an error. But:
@@ -243,57 +1732,144 @@ impl<S: Store<Header = BlockHeader>> BlockCache<S> {
             return Err(Error::BlockMissing(header.prev_blockhash));
         }
 
-        // Stale blocks after potential re-org.
-        let mut stale = Vec::new();
+        // Orphans that can never be valid are dropped, so that they aren't validated again
+        // on every import.
+        if !invalid.is_empty() {
+            log::debug!(
+                "Dropping {} orphan(s) that failed validation after block {}",
+                invalid.len(),
+                hash
+            );
+            for hash in invalid.iter() {
+                self.remove_orphan(hash);
+            }
+        }
 
-        // TODO: Don't switch multiple times. Switch to the best branch in one go.
-        for branch in candidates.iter() {
-            let candidate_work = Branch(&branch.headers).work();
-            let main_work = Branch(self.chain_suffix(branch.fork_height)).work();
-
-            // TODO: Validate branch before switching to it.
-            if candidate_work > main_work {
-                stale = self.switch_to_fork(branch)?;
-            } else if self.params.network != Network::Bitcoin {
-                if candidate_work == main_work {
-                    // Nb. We intend here to compare the hashes as integers, and pick the lowest
-                    // hash as the winner. However, the `PartialEq` on `BlockHash` is implemented on
-                    // the underlying `[u8]` array, and does something different (lexographical
-                    // comparison). Since this code isn't run on Mainnet, it's okay, as it serves
-                    // its purpose of being determinstic when choosing the active chain.
-                    if branch.tip < self.chain.last().hash {
-                        stale = self.switch_to_fork(branch)?;
-                    }
+        // Order the candidates that have more work than the active chain, best first, so that
+        // we only switch once, to the best valid branch.
+        let mut candidates = candidates
+            .iter()
+            .map(|branch| {
+                let fork = self
+                    .block(branch.fork_height)
+                    .expect("the candidate must fork from a known block");
+
+                let tip = &self.orphans[&branch.tip];
+                // Branches that were rolled back from the active chain already know their work.
+                let work = tip
+                    .chainwork
+                    .unwrap_or_else(|| fork.chainwork + Branch(&branch.headers).work());
+
+                (work, tip.seq, branch)
+            })
+            .filter(|(work, seq, branch)| self.is_better(*work, &branch.tip, *seq))
+            .collect::<Vec<_>>();
+
+        candidates.sort_by(|(a_work, a_seq, a), (b_work, b_seq, b)| {
+            b_work
+                .cmp(a_work)
+                .then(a_seq.cmp(b_seq))
+                .then(cmp_hashes(&a.tip, &b.tip))
+        });
+
+        for (_, _, branch) in candidates {
+            let (stale, orphans) = match self.switch_to_fork(branch, clock) {
+                Ok(result) => result,
+                Err(err @ Error::Store(_)) => return Err(err),
+                Err(err @ Error::ReorgTooDeep { .. }) => {
+                    log::warn!("Ignoring fork with tip {}: {}", branch.tip, err);
+                    continue;
                 }
-            }
+                // The branch is not valid given the current state of the active chain.
+                // The active chain is left as it was, and we try the next best branch.
+                Err(_) => continue,
+            };
+
+            // Only blocks that were on the active chain prior to this import count as
+            // reverted. A block we connected earlier in this import and are now rolling
+            // back is simply forgotten.
+            reverted = stale.into_iter().filter(|(h, _)| *h <= unchanged).collect();
+
+            connected.retain(|(h, _)| *h <= branch.fork_height);
+            connected.extend((branch.fork_height + 1..).zip(branch.headers.iter().cloned()));
+            reconnected = orphans.into_iter().filter(|h| *h != hash).collect();
+
+            break;
         }
 
         let (hash, _) = self.tip();
         if hash != best {
-            // TODO: Test the reverted blocks.
-            Ok(ImportResult::TipChanged(
+            Ok(ImportResult::TipChanged {
                 header,
                 hash,
-                self.height(),
-                stale.into_iter().map(|h| h.block_hash()).collect(),
-            ))
+                height: self.height(),
+                reverted,
+                connected,
+                reconnected,
+                missing: vec![],
+            })
         } else {
             Ok(ImportResult::TipUnchanged)
         }
     }
 
-    /// Find all the potential forks off the main chain.
-    fn chain_candidates(&self, clock: &impl Clock) -> Vec<Candidate> {
-        let mut branches = Vec::new();
-
-        for tip in self.orphans.keys() {
-            if let Some(branch) = self.fork(tip) {
-                if self.validate_branch(&branch, clock).is_ok() {
-                    branches.push(branch);
+    /// Find all the potential forks off the main chain. Also returns the hashes of the
+    /// orphans that can never be part of a valid branch, because they, or one of their
+    /// ancestors, failed validation for a reason that can't change. See [`is_permanent`].
+    ///
+    /// Every orphan is considered as a potential branch tip, so that branches that were
+    /// previously disconnected are picked up as soon as a missing header fills the gap,
+    /// regardless of the order in which headers are received.
+    fn chain_candidates(&self, clock: &impl Clock) -> (Vec<Candidate>, HashSet<BlockHash>) {
+        let mut candidates = Vec::new();
+        let mut invalid = HashSet::new();
+
+        for (branch, result) in self.validated_branches(clock) {
+            match result {
+                Ok(()) => candidates.push(branch),
+                Err((ix, err)) if is_permanent(&err) => {
+                    // The hash of each header is the parent hash of the next one.
+                    let hashes = branch.headers[ix + 1..]
+                        .iter()
+                        .map(|h| h.prev_blockhash)
+                        .chain(iter::once(branch.tip));
+
+                    invalid.extend(hashes);
                 }
+                Err(_) => {}
             }
         }
-        branches
+        (candidates, invalid)
+    }
+
+    /// Iterate over the potential forks off the main chain. See
+    /// [`BlockCache::chain_candidates`].
+    fn candidates<'a, C: Clock>(&'a self, clock: &'a C) -> impl Iterator<Item = Candidate> + 'a {
+        self.validated_branches(clock)
+            .filter(|(_, result)| result.is_ok())
+            .map(|(branch, _)| branch)
+    }
+
+    /// Iterate over the branches forking off the main chain, along with the result of their
+    /// validation. See [`BlockCache::validate_branch`].
+    fn validated_branches<'a, C: Clock>(
+        &'a self,
+        clock: &'a C,
+    ) -> impl Iterator<Item = (Candidate, Result<(), (usize, Error)>)> + 'a {
+        let min_fork_height = self.min_fork_height();
+        let branches = self
+            .orphans
+            .keys()
+            .filter_map(|tip| self.fork(tip))
+            // Don't accept any forks from the main chain, prior to the last checkpoint.
+            .filter(|branch| branch.fork_height >= min_fork_height)
+            .collect::<Vec<_>>();
+        let pow = branches_pow(&branches);
+
+        branches.into_iter().zip(pow).map(move |(branch, pow)| {
+            let result = self.validate_branch(&branch, &pow, clock);
+            (branch, result)
+        })
     }
 
     /// Find a potential branch starting from the active chain and ending at the given tip.
@@ -314,69 +1890,133 @@ impl<S: Store<Header = BlockHeader>> BlockCache<S> {
             "BlockCache::fork: the provided tip must not be on the active chain"
         );
 
-        while let Some(header) = self.orphans.get(&cursor) {
-            cursor = header.prev_blockhash;
-            headers.push_front(*header);
+        while let Some(orphan) = self.orphans.get(&cursor) {
+            cursor = orphan.prev_blockhash;
+            headers.push_front(orphan.header);
         }
 
         if let Some(height) = self.headers.get(&cursor) {
             return Some(Candidate {
                 tip,
                 fork_height: *height,
-                fork_hash: cursor,
                 headers: headers.into(),
             });
         }
         None
     }
 
-    /// Validate a candidate branch. This function is useful for chain selection.
-    fn validate_branch(&self, candidate: &Candidate, clock: &impl Clock) -> Result<(), Error> {
-        let fork_header = self
-            .get_block_by_height(candidate.fork_height)
+    /// Validate a candidate branch, given the results of earlier proof-of-work checks of its
+    /// headers against their own targets, if any. This function is useful for chain
+    /// selection. On failure, returns the index of the first invalid header in the branch.
+    fn validate_branch(
+        &self,
+        candidate: &Candidate,
+        pow: &[Option<bool>],
+        clock: &impl Clock,
+    ) -> Result<(), (usize, Error)> {
+        let mut tip = *self
+            .block(candidate.fork_height)
             .expect("the given candidate must fork from a known block");
-        let mut tip = CachedBlock {
-            height: candidate.fork_height,
-            hash: candidate.fork_hash,
-            header: *fork_header,
-        };
 
-        for header in candidate.headers.iter() {
-            self.validate(&tip, header, clock)?;
+        for (i, header) in candidate.headers.iter().enumerate() {
+            self.validate(&tip, header, pow.get(i).copied().flatten(), clock)
+                .map_err(|err| (i, err))?;
 
             tip = CachedBlock {
                 height: tip.height + 1,
                 hash: header.block_hash(),
                 header: *header,
+                chainwork: tip.chainwork + header.work(),
+                seq: tip.seq,
             };
         }
         Ok(())
     }
 
+    /// Get the locator hashes for the given heights, in descending order, starting at `from`.
+    /// See [`BlockTree::locator_hashes`].
+    fn locator(&self, from: Height, indexes: impl IntoIterator<Item = Height>) -> Vec<BlockHash> {
+        assert!(from <= self.height());
+
+        locator(from, indexes, self.last_checkpoint(), |height| {
+            self.hash_at(height)
+        })
+    }
+
+    /// Get the median time past of the blocks up to and including `tip`, which may be on the
+    /// active chain or on a fork.
+    fn median_time_past_of(&self, tip: &CachedBlock) -> BlockTime {
+        if matches!(self.block(tip.height), Some(blk) if blk.hash == tip.hash) {
+            return self.median_time_past(tip.height + 1);
+        }
+        let mut times = self
+            .walk(Some((tip.height, &tip.header)))
+            .take(time::MEDIAN_TIME_SPAN as usize)
+            .map(|(_, header)| header.time)
+            .collect::<Vec<_>>();
+
+        times.sort_unstable();
+        times[times.len() / 2]
+    }
+
+    /// Get the difficulty target the given header, building on `tip`, is required to have.
+    fn required_target(&self, tip: &CachedBlock, header: &BlockHeader) -> Target {
+        // If the tip is on the active chain, its ancestors are read off the chain directly,
+        // instead of being looked up by hash. Since the chain is indexed by height, the
+        // first block of the tip's difficulty adjustment interval is looked up directly too,
+        // so that it doesn't have to be re-read on every retarget.
+        let (ancestors, start): (difficulty::Ancestors<'_>, _) = if matches!(self.block(tip.height), Some(blk) if blk.hash == tip.hash)
+        {
+            let interval = self.params.difficulty_adjustment_interval();
+            let start = self
+                .block(tip.height - tip.height % interval)
+                .map(|blk| (blk.height, &blk.header));
+            let ancestors = self
+                .range_rev(0..tip.height + 1)
+                .map(|blk| (blk.height, &blk.header));
+
+            (Box::new(ancestors), start)
+        } else {
+            (Box::new(self.walk(Some((tip.height, &tip.header)))), None)
+        };
+        let compact_target =
+            self.difficulty
+                .next_target_from(ancestors, start, header, &self.params);
+
+        BlockHeader::u256_from_compact_target(compact_target)
+    }
+
     /// Validate a block header as a potential new tip. This performs full header validation.
+    ///
+    /// If `pow` is set, it is the result of an earlier check of the header's proof-of-work
+    /// against its own target, and the proof-of-work isn't checked again.
     fn validate(
         &self,
         tip: &CachedBlock,
         header: &BlockHeader,
+        pow: Option<bool>,
         clock: &impl Clock,
     ) -> Result<(), Error> {
         assert_eq!(tip.hash, header.prev_blockhash);
 
-        let compact_target = if self.params.allow_min_difficulty_blocks
-            && (tip.height + 1) % self.params.difficulty_adjustment_interval() != 0
-        {
-            if header.time > tip.time + self.params.pow_target_spacing as BlockTime * 2 {
-                block::pow_limit_bits(&self.params.network)
-            } else {
-                self.next_min_difficulty_target(&self.params)
-            }
-        } else {
-            self.next_difficulty_target(tip.height, tip.time, tip.target(), &self.params)
+        let target = self.required_target(tip, header);
+
+        // Headers below the assume-valid block don't need their proof-of-work checked.
+        let assumed = matches! {
+            self.assume_valid.and_then(|hash| self.headers.get(&hash)),
+            Some(height) if tip.height < *height
         };
+        let pow = if assumed { Some(true) } else { pow };
 
-        let target = BlockHeader::u256_from_compact_target(compact_target);
+        let result = match pow {
+            Some(true) if header.target() == target => Ok(()),
+            Some(false) if header.target() == target => {
+                Err(bitcoin::util::Error::BlockBadProofOfWork)
+            }
+            _ => header.validate_pow(&target),
+        };
 
-        match header.validate_pow(&target) {
+        match result {
             Err(bitcoin::util::Error::BlockBadProofOfWork) => {
                 return Err(Error::InvalidBlockPoW);
             }
@@ -387,6 +2027,12 @@ impl<S: Store<Header = BlockHeader>> BlockCache<S> {
             Ok(_) => {}
         }
 
+        if let Some(min) = self.min_version {
+            if header.version < min {
+                return Err(Error::InvalidBlockVersion(header.version));
+            }
+        }
+
         // Validate against block checkpoints.
         let height = tip.height + 1;
 
@@ -400,93 +2046,561 @@ impl<S: Store<Header = BlockHeader>> BlockCache<S> {
 
         // A timestamp is accepted as valid if it is greater than the median timestamp of
         // the previous MEDIAN_TIME_SPAN blocks, and less than the network-adjusted
-        // time + MAX_FUTURE_BLOCK_TIME.
-        if header.time <= self.median_time_past(height) {
+        // time + the maximum future block time.
+        if header.time <= self.median_time_past_of(tip) {
             return Err(Error::InvalidBlockTime(header.time, Ordering::Less));
         }
-        if header.time > clock.block_time() + time::MAX_FUTURE_BLOCK_TIME {
+        if self.clock_policy == ClockPolicy::Enforce
+            && header.time as u64 > clock.block_time() as u64 + self.max_future_time.as_secs()
+        {
             return Err(Error::InvalidBlockTime(header.time, Ordering::Greater));
         }
 
         Ok(())
     }
 
+    /// Check whether a branch with the given total work, tip and tip sequence number should
+    /// replace the active chain.
+    fn is_better(&self, work: Work, tip: &BlockHash, seq: u64) -> bool {
+        let best = self.chain.last();
+
+        if work > best.chainwork {
+            true
+        } else if work == best.chainwork {
+            // On equal work, the branch whose tip was seen first wins. The tip hashes are
+            // only compared as a last resort, to keep chain selection deterministic.
+            seq.cmp(&best.seq).then(cmp_hashes(tip, &best.hash)) == Ordering::Less
+        } else {
+            false
+        }
+    }
+
     /// Get the height of the last checkpoint block.
     fn last_checkpoint(&self) -> Height {
-        let height = self.height();
+        self.nearest_checkpoint_below(self.height())
+            .map_or(0, |(height, _)| height)
+    }
 
-        self.checkpoints
-            .iter()
-            .rev()
-            .map(|(h, _)| *h)
-            .find(|h| *h <= height)
-            .unwrap_or(0)
+    /// Rollback active chain to the given height. Returns the list of rolled-back headers,
+    /// along with their heights.
+    fn rollback(&mut self, height: Height) -> Result<Vec<(Height, BlockHeader)>, Error> {
+        let mut stale = Vec::new();
+
+        // Genesis is the head of the chain, and is kept even when rolling back to height `0`.
+        let split = (height - self.evicted) as usize;
+
+        for block in Arc::make_mut(&mut self.chain).tail.split_off(split) {
+            stale.push((block.height, block.header));
+
+            Arc::make_mut(&mut self.headers).remove(&block.hash);
+            self.insert_orphan(block.hash, block.header, block.seq, Some(block.chainwork));
+        }
+        if let Some(index) = &mut self.time_index {
+            // Since the median time past is monotonic, the removed heights are the last
+            // entries of the index.
+            while let Some((time, h)) = index.iter().next_back().map(|(t, h)| (*t, *h)) {
+                if h <= height {
+                    break;
+                }
+                index.remove(&time);
+            }
+        }
+        self.store.rollback(height)?;
+
+        Ok(stale)
     }
 
-    /// Get the next minimum-difficulty target. Only valid in testnet and regtest networks.
-    fn next_min_difficulty_target(&self, params: &Params) -> Bits {
-        assert!(params.allow_min_difficulty_blocks);
+    /// Activate a fork candidate. Returns the list of rolled-back (stale) headers.
+    ///
+    /// The branch headers are validated against the active chain as they are applied,
+    /// since the chain may have changed since the candidate was found. If validation fails,
+    /// the active chain is restored and an error is returned. Forks from below the last
+    /// checkpoint, or deeper than the maximum re-org depth, are refused without touching
+    /// the active chain.
+    fn switch_to_fork(
+        &mut self,
+        branch: &Candidate,
+        clock: &impl Clock,
+    ) -> Result<(Vec<(Height, BlockHeader)>, Vec<BlockHash>), Error> {
+        let fork = self.block(branch.fork_height).map(|blk| blk.hash);
+
+        // The fork block may no longer be on the active chain, or the active chain may be
+        // shorter than the fork height, if another branch was activated since this candidate
+        // was found. Either way, the candidate is stale and can't be activated.
+        match branch.headers.first() {
+            Some(first) if fork == Some(first.prev_blockhash) => {}
+            Some(first) => return Err(Error::BlockMissing(first.prev_blockhash)),
+            None => return Err(Error::BlockMissing(branch.tip)),
+        }
+        // Candidates are already filtered by fork height, but we never want to roll back
+        // a checkpointed block, however the branch was found.
+        if branch.fork_height < self.min_fork_height() {
+            return Err(Error::InvalidBlockHeight(branch.fork_height + 1));
+        }
+        if let Some(limit) = self.max_reorg_depth {
+            let depth = self.height() - branch.fork_height;
+
+            if depth > limit {
+                return Err(Error::ReorgTooDeep { depth, limit });
+            }
+        }
+        let stale = self.rollback(branch.fork_height)?;
+        let mut connected = Vec::with_capacity(branch.headers.len());
+
+        for (i, header) in branch.headers.iter().enumerate() {
+            let tip = *self.chain.last();
+
+            if let Err(err) = self.validate(&tip, header, None, clock) {
+                self.restore_chain(branch.fork_height, &stale)?;
 
-        let pow_limit_bits = block::pow_limit_bits(&params.network);
+                return Err(err);
+            }
+            let hash = header.block_hash();
 
-        for (height, header) in self.iter().rev() {
-            if header.bits != pow_limit_bits
-                || height % self.params.difficulty_adjustment_interval() == 0
-            {
-                return header.bits;
+            if self.extend_chain(branch.fork_height + i as Height + 1, hash, *header) {
+                connected.push(hash);
             }
         }
-        pow_limit_bits
+        if let Err(err) = self.store.put(branch.headers.iter().cloned()) {
+            self.restore_chain(branch.fork_height, &stale)?;
+
+            return Err(err.into());
+        }
+
+        if let Some(ReorgHook(f)) = &self.on_reorg {
+            let disconnected = stale.iter().map(|(_, h)| *h).collect::<Vec<_>>();
+            let mut f = f.lock().unwrap();
+
+            f(branch.fork_height, &disconnected, &branch.headers);
+        }
+        self.notify_disconnected(&stale);
+        self.notify_connected(
+            branch
+                .headers
+                .iter()
+                .enumerate()
+                .map(|(i, header)| (branch.fork_height + i as Height + 1, header)),
+        );
+        self.subscribers.send(ChainEvent::Reorg {
+            from: branch.fork_height,
+            disconnected: stale
+                .iter()
+                .map(|(h, blk)| (*h, blk.block_hash()))
+                .collect(),
+            connected: self
+                .range(branch.fork_height + 1..self.height() + 1)
+                .map(|blk| (blk.height, blk.hash))
+                .collect(),
+        });
+        Ok((stale, connected))
     }
 
-    /// Rollback active chain to the given height. Returns the list of rolled-back headers.
-    fn rollback(&mut self, height: Height) -> Result<Vec<BlockHeader>, Error> {
-        let mut stale = Vec::new();
+    /// Restore the active chain after failing to switch to a fork at the given height, given
+    /// the blocks that were rolled back. The fork's blocks are put back in the orphan set.
+    ///
+    /// The stale blocks are stored before they are put back on the active chain, so that
+    /// if storing them fails, the active chain still matches the store.
+    fn restore_chain(
+        &mut self,
+        fork_height: Height,
+        stale: &[(Height, BlockHeader)],
+    ) -> Result<(), Error> {
+        self.rollback(fork_height)?;
+        self.store.put(stale.iter().map(|(_, h)| *h))?;
+
+        for (height, header) in stale {
+            self.extend_chain(*height, header.block_hash(), *header);
+        }
+        Ok(())
+    }
+
+    /// Get the next arrival sequence number.
+    fn next_seq(&mut self) -> u64 {
+        let seq = self.seq;
+        self.seq += 1;
+        seq
+    }
 
-        for block in self.chain.tail.drain(height as usize..) {
-            stale.push(block.header);
+    /// Insert a block into the orphan set, with the sequence number it arrived with, and
+    /// the cumulative work of its branch, if known.
+    fn insert_orphan(
+        &mut self,
+        hash: BlockHash,
+        header: BlockHeader,
+        seq: u64,
+        chainwork: Option<Work>,
+    ) {
+        self.orphans_order.insert(seq, hash);
 
-            self.headers.remove(&block.hash);
-            self.orphans.insert(block.hash, block.header);
+        if let Some(orphan) = self.orphans.insert(
+            hash,
+            Orphan {
+                header,
+                seq,
+                chainwork,
+            },
+        ) {
+            self.orphans_order.remove(&orphan.seq);
         }
-        self.store.rollback(height)?;
+    }
 
-        Ok(stale)
+    /// Remove a block from the orphan set, if present.
+    fn remove_orphan(&mut self, hash: &BlockHash) -> Option<BlockHeader> {
+        let orphan = self.orphans.remove(hash)?;
+        self.orphans_order.remove(&orphan.seq);
+
+        Some(orphan.header)
     }
 
-    /// Activate a fork candidate. Returns the list of rolled-back (stale) headers.
-    fn switch_to_fork(&mut self, branch: &Candidate) -> Result<Vec<BlockHeader>, Error> {
-        let stale = self.rollback(branch.fork_height)?;
+    /// Check whether an orphan is part of a branch connecting to the active chain. Branches
+    /// forking off blocks evicted from memory can't be switched to, so they don't count.
+    fn connects(&self, hash: &BlockHash) -> bool {
+        matches!(
+            self.fork_height(hash),
+            Some(height) if self.evicted == 0 || height > self.evicted
+        )
+    }
 
-        for (i, header) in branch.headers.iter().enumerate() {
-            self.extend_chain(
-                branch.fork_height + i as Height + 1,
-                header.block_hash(),
-                *header,
-            );
+    /// Get the height at which an orphan's branch forks off the active chain, if it connects
+    /// to it.
+    fn fork_height(&self, hash: &BlockHash) -> Option<Height> {
+        let mut cursor = *hash;
+
+        while let Some(orphan) = self.orphans.get(&cursor) {
+            cursor = orphan.prev_blockhash;
         }
-        self.store.put(branch.headers.iter().cloned())?;
+        self.headers.get(&cursor).copied()
+    }
 
-        Ok(stale)
+    /// Evict the least recently inserted orphans until we are within the configured limit.
+    /// Orphans that connect to the active chain are kept.
+    fn evict_orphans(&mut self) {
+        if self.orphans.len() <= self.max_orphans {
+            return;
+        }
+        let excess = self.orphans.len() - self.max_orphans;
+        let evicted = self
+            .orphans_order
+            .values()
+            .filter(|hash| !self.connects(hash))
+            .take(excess)
+            .copied()
+            .collect::<Vec<_>>();
+
+        for hash in evicted {
+            self.remove_orphan(&hash);
+        }
     }
 
-    /// Extend the active chain with a block.
-    fn extend_chain(&mut self, height: Height, hash: BlockHash, header: BlockHeader) {
-        assert_eq!(header.prev_blockhash, self.chain.last().hash);
+    /// Extend the active chain with a block. Orphans keep the sequence number they arrived
+    /// with, while new blocks are given the next one. Returns whether the block was an
+    /// orphan.
+    ///
+    /// The block must build on the tip. Callers check this before validating the block, so
+    /// it's only asserted in debug builds, to keep the store replay in [`BlockCache::from`]
+    /// cheap.
+    fn extend_chain(&mut self, height: Height, hash: BlockHash, header: BlockHeader) -> bool {
+        debug_assert_eq!(header.prev_blockhash, self.chain.last().hash);
+
+        let chainwork = self.chain.last().chainwork + header.work();
+        let seq = match self.orphans.get(&hash) {
+            Some(orphan) => orphan.seq,
+            None => self.next_seq(),
+        };
 
-        self.headers.insert(hash, height);
-        self.orphans.remove(&hash);
-        self.chain.push(CachedBlock {
+        if self.time_index.is_some() {
+            let time = self.median_time_past(height);
+
+            if let Some(index) = &mut self.time_index {
+                index.entry(time).or_insert(height);
+            }
+        }
+        Arc::make_mut(&mut self.headers).insert(hash, height);
+        let orphan = self.remove_orphan(&hash).is_some();
+        Arc::make_mut(&mut self.chain).push(CachedBlock {
             height,
             hash,
             header,
+            chainwork,
+            seq,
         });
+        self.evict_blocks();
+
+        orphan
+    }
+
+    /// Call the connect hook for the given blocks, which must be in ascending height order.
+    /// This is done once the blocks are stored, so that the hook never sees a block that
+    /// is disconnected again because of an error.
+    fn notify_connected<'a>(&self, blocks: impl IntoIterator<Item = (Height, &'a BlockHeader)>) {
+        if let Some(hook) = &self.on_connect {
+            for (height, header) in blocks {
+                hook.call(height, header);
+            }
+        }
+    }
+
+    /// Call the disconnect hook for the given blocks, from the highest to the lowest. Like
+    /// [`BlockCache::notify_connected`], this is done once the store is rolled back.
+    fn notify_disconnected(&self, blocks: &[(Height, BlockHeader)]) {
+        if let Some(hook) = &self.on_disconnect {
+            for (height, header) in blocks.iter().rev() {
+                hook.call(*height, header);
+            }
+        }
+    }
+
+    /// Pair each header with the result of its proof-of-work check, if it was done ahead of
+    /// import. This is only done for headers that extend the active chain. Headers up to and
+    /// including the assume-valid block are trusted, and with the `parallel` feature, the
+    /// others are checked in parallel.
+    fn verify_pow<I: Iterator<Item = BlockHeader>>(
+        &self,
+        chain: I,
+    ) -> Vec<(BlockHeader, Option<bool>)> {
+        let headers = chain.collect::<Vec<_>>();
+        let tip = self.chain.last().hash;
+
+        // Batches that don't start at the tip can't extend it, so don't spend any work on them.
+        if headers.first().map(|h| h.prev_blockhash) != Some(tip)
+            || (!cfg!(feature = "parallel") && self.assume_valid.is_none())
+        {
+            return headers.into_iter().map(|h| (h, None)).collect();
+        }
+        #[cfg(feature = "parallel")]
+        let (hashes, pow): (Vec<_>, Vec<_>) =
+            parallel::verify_pow(&headers.iter().copied().collect())
+                .into_iter()
+                .map(|(hash, valid)| (hash, Some(valid)))
+                .unzip();
+        #[cfg(not(feature = "parallel"))]
+        let (hashes, pow): (Vec<_>, Vec<_>) =
+            headers.iter().map(|h| (h.block_hash(), None)).unzip();
+
+        if !is_extension(tip, &headers, &hashes) {
+            return headers.into_iter().map(|h| (h, None)).collect();
+        }
+        let trusted = self
+            .assume_valid
+            .and_then(|hash| hashes.iter().position(|h| *h == hash))
+            .map_or(0, |ix| ix + 1);
+
+        headers
+            .into_iter()
+            .zip(pow)
+            .enumerate()
+            .map(|(i, (header, pow))| (header, if i < trusted { Some(true) } else { pow }))
+            .collect()
+    }
+}
+
+/// Check whether a header validation error is permanent, ie. whether the header would fail
+/// validation on the same branch no matter when it's validated. Only timestamps too far
+/// ahead of the clock can become valid, as time passes.
+fn is_permanent(err: &Error) -> bool {
+    !matches!(err, Error::InvalidBlockTime(_, Ordering::Greater))
+}
+
+/// Check the proof-of-work of each branch's headers against their own targets, ahead of
+/// branch validation. With the `parallel` feature, the headers of all branches are checked
+/// in parallel. Otherwise, they are left to be checked during validation.
+///
+/// The results are in the same order as the branches and their headers, so chain selection
+/// doesn't depend on how the work is spread across threads.
+fn branches_pow(branches: &[Candidate]) -> Vec<Vec<Option<bool>>> {
+    #[cfg(feature = "parallel")]
+    {
+        let headers = branches
+            .iter()
+            .flat_map(|branch| branch.headers.iter().cloned())
+            .collect();
+        let mut results = parallel::verify_pow(&headers)
+            .into_iter()
+            .map(|(_, valid)| Some(valid));
+
+        branches
+            .iter()
+            .map(|branch| results.by_ref().take(branch.headers.len()).collect())
+            .collect()
+    }
+    #[cfg(not(feature = "parallel"))]
+    branches
+        .iter()
+        .map(|branch| vec![None; branch.headers.len()])
+        .collect()
+}
+
+/// Check whether the headers, with the given hashes, form a chain that extends the given tip.
+fn is_extension(tip: BlockHash, headers: &[BlockHeader], hashes: &[BlockHash]) -> bool {
+    let mut prev = tip;
+
+    for (header, hash) in headers.iter().zip(hashes) {
+        if header.prev_blockhash != prev {
+            return false;
+        }
+        prev = *hash;
+    }
+    !headers.is_empty()
+}
+
+/// Changes to the active chain accumulated over a batch import. Blocks that are connected and
+/// disconnected again during the batch are dropped from all lists.
+#[derive(Debug, Default)]
+struct TipChanges {
+    reverted: Vec<(Height, BlockHeader)>,
+    connected: Vec<(Height, BlockHeader)>,
+    orphans: Vec<BlockHash>,
+}
+
+impl TipChanges {
+    /// Merge the changes of a single tip change.
+    fn merge(
+        &mut self,
+        reverted: Vec<(Height, BlockHeader)>,
+        connected: Vec<(Height, BlockHeader)>,
+        orphans: Vec<BlockHash>,
+    ) {
+        for (height, header) in reverted {
+            // Disconnected blocks are always at the top of the chain, so blocks the batch
+            // connected are at the end of the list.
+            if let Some(ix) = self.connected.iter().rposition(|(_, h)| *h == header) {
+                self.connected.remove(ix);
+                self.orphans.retain(|hash| *hash != header.block_hash());
+            } else {
+                self.reverted.push((height, header));
+            }
+        }
+        for (height, header) in connected {
+            // Blocks that were on the active chain before the batch may be reconnected.
+            if let Some(ix) = self.reverted.iter().position(|(_, h)| *h == header) {
+                self.reverted.remove(ix);
+            } else {
+                self.connected.push((height, header));
+            }
+        }
+        self.orphans.extend(orphans);
+    }
+
+    /// Get the stale blocks, the connected blocks, in ascending height order, and the
+    /// connected orphans.
+    fn finish(
+        mut self,
+    ) -> (
+        Vec<(Height, BlockHeader)>,
+        Vec<(Height, BlockHeader)>,
+        Vec<BlockHash>,
+    ) {
+        self.reverted.sort_by_key(|(height, _)| *height);
+
+        (self.reverted, self.connected, self.orphans)
+    }
+}
+
+/// Check that the store's genesis block is the genesis block of the network, to catch
+/// stores opened with the parameters of another network.
+fn check_genesis<S: Store<Header = BlockHeader>>(store: &S, params: &Params) -> Result<(), Error> {
+    let expected = constants::genesis_block(params.network).block_hash();
+    let found = store.genesis().block_hash();
+
+    if found != expected {
+        return Err(Error::GenesisMismatch { expected, found });
+    }
+    Ok(())
+}
+
+/// Get the locator hashes for the given heights, in descending order, starting at `from`,
+/// using `hash_at` to look up the active chain. Heights below the last checkpoint are
+/// skipped, and the last checkpoint is always included, if it's not above `from`.
+fn locator(
+    from: Height,
+    indexes: impl IntoIterator<Item = Height>,
+    last_checkpoint: Height,
+    hash_at: impl Fn(Height) -> Option<BlockHash>,
+) -> Vec<BlockHash> {
+    let mut hashes = Vec::new();
+
+    for height in indexes {
+        if height < last_checkpoint {
+            // Don't go past the latest checkpoint. We never want to accept a fork
+            // older than our last checkpoint.
+            break;
+        }
+        if let Some(hash) = hash_at(height) {
+            hashes.push(hash);
+        }
+    }
+
+    if from >= last_checkpoint {
+        let anchor = hash_at(last_checkpoint);
+
+        if hashes.last() != anchor.as_ref() {
+            hashes.extend(anchor);
+        }
     }
+    hashes
+}
+
+/// Sort headers so that every header comes after its parent, if its parent is one of the
+/// headers. Returns the headers along with their original index. Headers are otherwise kept
+/// in their original order, and duplicates are dropped.
+fn sort_headers(headers: Vec<BlockHeader>) -> Vec<(usize, BlockHeader)> {
+    let mut hashes = HashSet::with_capacity(headers.len());
+    let mut children = HashMap::<BlockHash, Vec<usize>>::new();
+    let mut roots = Vec::new();
+
+    let headers = headers
+        .into_iter()
+        .enumerate()
+        .filter_map(|(ix, header)| {
+            let hash = header.block_hash();
+            if hashes.insert(hash) {
+                Some((ix, hash, header))
+            } else {
+                None
+            }
+        })
+        .collect::<Vec<_>>();
+
+    for (i, (_, _, header)) in headers.iter().enumerate() {
+        if hashes.contains(&header.prev_blockhash) {
+            children.entry(header.prev_blockhash).or_default().push(i);
+        } else {
+            roots.push(i);
+        }
+    }
+
+    let mut sorted = Vec::with_capacity(headers.len());
+    let mut stack = roots;
+    stack.reverse();
+
+    while let Some(i) = stack.pop() {
+        let (ix, hash, header) = headers[i];
+
+        sorted.push((ix, header));
 
-    /// Get the blocks starting from the given height.
-    fn chain_suffix(&self, height: Height) -> &[CachedBlock] {
-        &self.chain.tail[height as usize..]
+        if let Some(children) = children.get(&hash) {
+            stack.extend(children.iter().rev());
+        }
     }
+    sorted
+}
+
+/// Compare two block hashes as 256-bit integers. Block hashes are stored in little-endian
+/// byte order, so the comparison starts from the last byte.
+fn cmp_hashes(a: &BlockHash, b: &BlockHash) -> Ordering {
+    a[..].iter().rev().cmp(b[..].iter().rev())
+}
+
+/// Estimate the memory used by a hash map. Hash maps allocate a slot for each entry they
+/// have capacity for, plus a control byte, and keep at least one eighth of the slots free.
+fn hash_map_usage<K, V, S>(map: &HashMap<K, V, S>) -> usize {
+    map.capacity() * 8 / 7 * (mem::size_of::<(K, V)>() + 1)
+}
+
+/// Estimate the memory used by a B-tree map. Nodes hold up to eleven entries, and are
+/// typically between half and fully occupied; we assume they are two thirds full.
+fn btree_map_usage<K, V>(map: &BTreeMap<K, V>) -> usize {
+    map.len() * mem::size_of::<(K, V)>() * 3 / 2
 }
 
 impl<S: Store<Header = BlockHeader>> BlockTree for BlockCache<S> {
@@ -496,17 +2610,83 @@ impl<S: Store<Header = BlockHeader>> BlockTree for BlockCache<S> {
         chain: I,
         context: &C,
     ) -> Result<ImportResult, Error> {
-        let mut result = None;
+        self.import_blocks_with(chain, context, |_| {})
+    }
 
-        for (i, header) in chain.enumerate() {
-            match self.import_block(header, context) {
-                Ok(r) => result = Some(r),
+    /// Import blocks into the block tree, calling `on_progress` with the new height every
+    /// time a block changes the active chain.
+    fn import_blocks_with<I, C, F>(
+        &mut self,
+        chain: I,
+        context: &C,
+        mut on_progress: F,
+    ) -> Result<ImportResult, Error>
+    where
+        I: Iterator<Item = BlockHeader>,
+        C: Clock,
+        F: FnMut(Height),
+    {
+        let mut best = None;
+        let mut changes = TipChanges::default();
+        let mut missing = Vec::new();
+
+        for (i, (header, pow)) in self.verify_pow(chain).into_iter().enumerate() {
+            match self.import(header, pow, context) {
+                Ok(ImportResult::TipChanged {
+                    header,
+                    hash,
+                    height,
+                    reverted,
+                    connected,
+                    reconnected: orphans,
+                    ..
+                }) => {
+                    on_progress(height);
+
+                    best = Some((header, hash, height));
+                    changes.merge(reverted, connected, orphans);
+                }
+                // Keep the tip changes so far, if any: the tip hasn't changed since.
+                Ok(ImportResult::TipUnchanged) | Ok(ImportResult::MissingParents(_)) => {}
                 Err(Error::DuplicateBlock(hash)) => log::trace!("Duplicate block {}", hash),
-                Err(Error::BlockMissing(hash)) => log::trace!("Missing block {}", hash),
-                Err(err) => return Err(Error::BlockImportAborted(err.into(), i, self.height())),
+                Err(Error::BlockMissing(hash)) => {
+                    log::trace!("Missing block {}", hash);
+
+                    if !missing.contains(&hash) {
+                        missing.push(hash);
+                    }
+                }
+                Err(err) => {
+                    return Err(Error::BlockImportAborted {
+                        error: err.into(),
+                        index: i,
+                        height: self.height(),
+                        hash: header.block_hash(),
+                        header: header.into(),
+                    })
+                }
             }
         }
-        Ok(result.unwrap_or(ImportResult::TipUnchanged))
+        // Parents that were imported later in the batch aren't missing anymore.
+        missing.retain(|hash| !self.is_known(hash));
+
+        match best {
+            Some((header, hash, height)) => {
+                let (reverted, connected, orphans) = changes.finish();
+
+                Ok(ImportResult::TipChanged {
+                    header,
+                    hash,
+                    height,
+                    reverted,
+                    connected,
+                    reconnected: orphans,
+                    missing,
+                })
+            }
+            None if missing.is_empty() => Ok(ImportResult::TipUnchanged),
+            None => Ok(ImportResult::MissingParents(missing)),
+        }
     }
 
     /// Extend the active chain.
@@ -521,27 +2701,60 @@ impl<S: Store<Header = BlockHeader>> BlockTree for BlockCache<S> {
         if header.prev_blockhash == tip.hash {
             let height = tip.height + 1;
 
-            self.validate(&tip, &header, clock)?;
-            self.extend_chain(height, hash, header);
+            self.validate(&tip, &header, None, clock)?;
             self.store.put(std::iter::once(header))?;
+            self.extend_chain(height, hash, header);
+            self.notify_connected(iter::once((height, &header)));
+            self.subscribers.send(ChainEvent::TipExtended(height, hash));
 
-            Ok(ImportResult::TipChanged(header, hash, height, vec![]))
+            Ok(ImportResult::TipChanged {
+                header,
+                hash,
+                height,
+                reverted: vec![],
+                connected: vec![(height, header)],
+                reconnected: vec![],
+                missing: vec![],
+            })
         } else {
             Ok(ImportResult::TipUnchanged)
         }
     }
 
-    /// Get a block by hash. Only searches the active chain.
+    /// Get a block by hash. Only searches the active chain. Blocks evicted from memory
+    /// aren't returned, see [`BlockCache::get_block_or_load`].
     fn get_block(&self, hash: &BlockHash) -> Option<(Height, &BlockHeader)> {
-        self.headers
-            .get(hash)
-            .and_then(|height| self.chain.get(*height as usize))
-            .map(|blk| (blk.height, &blk.header))
+        self.resident(hash)
     }
 
-    /// Get a block by height.
+    /// Get a block by height. Blocks evicted from memory aren't returned, see
+    /// [`BlockCache::get_block_by_height_or_load`].
     fn get_block_by_height(&self, height: Height) -> Option<&BlockHeader> {
-        self.chain.get(height as usize).map(|b| &b.header)
+        self.block(height).map(|blk| &blk.header)
+    }
+
+    /// Get up to `count` consecutive headers of the active chain, starting at the given height.
+    fn headers_at(&self, start: Height, count: usize) -> Vec<BlockHeader> {
+        let len = self.height() + 1;
+        let start = Height::min(start, len);
+        let end = Height::min(start.saturating_add(count as Height), len);
+
+        if start == end {
+            return vec![];
+        }
+        let mut headers = Vec::with_capacity((end - start) as usize);
+
+        // The genesis block and the evicted blocks aren't part of the tail.
+        let first = self.evicted + 1;
+
+        headers.extend((start..Height::min(end, first)).filter_map(|h| self.header_at(h)));
+        headers.extend(
+            self.chain.tail[(Height::max(start, first) - first) as usize
+                ..(Height::max(end, first) - first) as usize]
+                .iter()
+                .map(|blk| blk.header),
+        );
+        headers
     }
 
     /// Get the best block hash and header.
@@ -554,14 +2767,25 @@ impl<S: Store<Header = BlockHeader>> BlockTree for BlockCache<S> {
         &self.chain.first().header
     }
 
+    /// Get the genesis block hash, without hashing the genesis header.
+    fn genesis_hash(&self) -> BlockHash {
+        self.chain.first().hash
+    }
+
     /// Iterate over the longest chain, starting from genesis.
     fn iter<'a>(&'a self) -> Box<dyn DoubleEndedIterator<Item = (Height, BlockHeader)> + 'a> {
+        if self.evicted > 0 {
+            return Box::new(
+                (0..=self.height())
+                    .filter_map(move |h| self.header_at(h).map(|header| (h, header))),
+            );
+        }
         Box::new(Iter::new(&self.chain).map(|(i, h)| (i, h.header)))
     }
 
     /// Return the height of the longest chain.
     fn height(&self) -> Height {
-        self.chain.tail.len() as Height
+        self.evicted + self.chain.tail.len() as Height
     }
 
     /// Check whether this block hash is known.
@@ -574,6 +2798,22 @@ impl<S: Store<Header = BlockHeader>> BlockTree for BlockCache<S> {
         self.headers.contains_key(hash)
     }
 
+    /// Get the depth of a block in the active chain. Blocks evicted from memory have a
+    /// depth too.
+    fn depth(&self, hash: &BlockHash) -> Option<Height> {
+        self.height_of(hash)
+            .map(|height| self.height() - height + 1)
+    }
+
+    /// Find the highest block of the active chain that is in the given locator, including
+    /// blocks evicted from memory.
+    fn find_fork(&self, locator: &[BlockHash]) -> Option<(Height, BlockHash)> {
+        locator
+            .iter()
+            .filter_map(|hash| self.height_of(hash).map(|height| (height, *hash)))
+            .max_by_key(|(height, _)| *height)
+    }
+
     /// Return headers after the first known hash in the locators list, and until the stop hash
     /// is reached.
     ///
@@ -591,56 +2831,35 @@ impl<S: Store<Header = BlockHeader>> BlockTree for BlockCache<S> {
         max_headers: usize,
     ) -> Vec<BlockHeader> {
         if locators.is_empty() {
-            if let Some((_, header)) = self.get_block(&stop_hash) {
-                return vec![*header];
+            if let Some((_, header)) = self.get_block_or_load(&stop_hash) {
+                return vec![header];
             }
             return vec![];
         }
 
         // Start from the highest locator hash that is on our active chain.
         // We don't respond with anything if none of the locators were found.
-        let start = if let Some(hash) = locators.iter().find(|h| self.contains(h)) {
-            let (height, _) = self.get_block(hash).unwrap();
-            height
-        } else {
-            0
-        };
+        let start = locators.iter().find_map(|h| self.height_of(h)).unwrap_or(0);
 
         let start = start + 1;
-        let stop = self
-            .get_block(&stop_hash)
-            .map(|(h, _)| h)
-            .unwrap_or_else(|| self.height());
+        let stop = self.height_of(&stop_hash).unwrap_or_else(|| self.height());
         let stop = Height::min(start + max_headers as Height, stop + 1);
 
         if start > stop {
             return vec![];
         }
 
-        self.range(start..stop).map(|h| h.header).collect()
+        self.headers_at(start, (stop - start) as usize)
     }
 
     /// Get the locator hashes for the active chain, starting at the given height.
     ///
+    /// The locator always ends with the last checkpoint hash, or the genesis hash if there
+    /// are no checkpoints, so that peers on an unrelated chain can find a common point.
+    ///
     /// *Panics* if the given starting height is out of bounds.
     ///
     fn locator_hashes(&self, from: Height) -> Vec<BlockHash> {
-        let mut hashes = Vec::new();
-
-        assert!(from <= self.height());
-
-        let last_checkpoint = self.last_checkpoint();
-
-        for height in block::locators_indexes(from).into_iter() {
-            if height < last_checkpoint {
-                // Don't go past the latest checkpoint. We never want to accept a fork
-                // older than our last checkpoint.
-                break;
-            }
-            if let Some(blk) = self.chain.get(height as usize) {
-                hashes.push(blk.hash);
-            }
-        }
-        hashes
+        self.locator(from, block::locators_indexes(from))
     }
 }
@@ -0,0 +1,106 @@
+//! Hash maps keyed by block hash.
+//!
+//! *Used by the block cache to index the active chain and the orphans.*
+//!
+//! Block hashes are already uniformly distributed, and can't be chosen freely without
+//! doing the work, so they don't need to be hashed with a DoS-resistant hasher. With the
+//! `fast-hash` feature enabled, they are hashed with a simple multiplicative hasher
+//! instead of the standard SipHash.
+//!
+use std::collections::HashMap;
+
+use bitcoin::hash_types::BlockHash;
+
+/// Builds the hashers of block hash maps.
+#[cfg(feature = "fast-hash")]
+pub type BuildHasher = std::hash::BuildHasherDefault<FastHasher>;
+/// Builds the hashers of block hash maps.
+#[cfg(not(feature = "fast-hash"))]
+pub type BuildHasher = std::collections::hash_map::RandomState;
+
+/// A hash map keyed by block hash.
+pub type BlockMap<V> = HashMap<BlockHash, V, BuildHasher>;
+
+/// Create an empty block hash map with room for at least the given number of entries.
+pub fn with_capacity<V>(capacity: usize) -> BlockMap<V> {
+    BlockMap::with_capacity_and_hasher(capacity, BuildHasher::default())
+}
+
+/// A non-cryptographic hasher, based on the hasher used by the Rust compiler. Input is
+/// consumed eight bytes at a time, each word being mixed in with a rotation, an XOR and a
+/// multiplication.
+#[cfg(feature = "fast-hash")]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct FastHasher {
+    hash: u64,
+}
+
+#[cfg(feature = "fast-hash")]
+impl FastHasher {
+    /// Multiplier, from the fractional part of the golden ratio.
+    const SEED: u64 = 0x517c_c1b7_2722_0a95;
+
+    fn add(&mut self, word: u64) {
+        self.hash = (self.hash.rotate_left(5) ^ word).wrapping_mul(Self::SEED);
+    }
+}
+
+#[cfg(feature = "fast-hash")]
+impl std::hash::Hasher for FastHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        for chunk in bytes.chunks(8) {
+            let mut word = [0; 8];
+
+            word[..chunk.len()].copy_from_slice(chunk);
+            self.add(u64::from_le_bytes(word));
+        }
+    }
+
+    fn write_u64(&mut self, i: u64) {
+        self.add(i);
+    }
+
+    fn write_usize(&mut self, i: usize) {
+        self.add(i as u64);
+    }
+
+    fn finish(&self) -> u64 {
+        self.hash
+    }
+}
+
+#[cfg(all(test, feature = "fast-hash"))]
+mod test {
+    use std::collections::HashSet;
+    use std::hash::{Hash, Hasher};
+
+    use super::*;
+
+    #[test]
+    fn test_fast_hasher() {
+        let hash = |h: &BlockHash| {
+            let mut hasher = FastHasher::default();
+            h.hash(&mut hasher);
+            hasher.finish()
+        };
+        let hashes = nakamoto_test::BITCOIN_HEADERS
+            .iter()
+            .map(|h| h.block_hash())
+            .collect::<Vec<_>>();
+
+        assert_eq!(hash(&hashes[0]), hash(&hashes[0]));
+        assert_ne!(hash(&BlockHash::default()), hash(&hashes[0]));
+        assert_eq!(
+            hashes.iter().map(hash).collect::<HashSet<_>>().len(),
+            hashes.len(),
+            "Distinct block hashes have distinct hashes"
+        );
+
+        // Hash table slots are selected with the low bits of the hash.
+        let buckets = hashes
+            .iter()
+            .map(|h| hash(h) & 0xff)
+            .collect::<HashSet<_>>();
+        assert!(buckets.len() > 200, "Hashes are spread across buckets");
+    }
+}
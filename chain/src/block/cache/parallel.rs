@@ -0,0 +1,89 @@
+//! Parallel proof-of-work verification of header batches.
+//!
+//! *Used by the block cache during bulk import, with the `parallel` feature enabled.*
+//!
+//! There is no thread pool: threads are spawned for each batch that is large enough to be
+//! split, and they share the batch instead of copying it.
+//!
+use std::sync::Arc;
+use std::thread;
+
+use bitcoin::blockdata::block::BlockHeader;
+use bitcoin::hash_types::BlockHash;
+use bitcoin::util::uint::Uint256;
+
+/// Maximum number of threads used to verify a batch.
+pub const MAX_THREADS: usize = 4;
+/// Minimum number of headers verified by a single thread. Smaller batches are verified
+/// on fewer threads, since spawning threads isn't free.
+pub const MIN_HEADERS_PER_THREAD: usize = 512;
+
+/// Hash each header and verify its proof-of-work against its own target, spreading the
+/// work across threads. Returns each header's hash, and whether its proof-of-work is valid,
+/// in order.
+///
+/// The headers' targets are not checked against the expected difficulty; this is left to
+/// the caller.
+pub fn verify_pow(headers: &Arc<[BlockHeader]>) -> Vec<(BlockHash, bool)> {
+    let threads = (headers.len() / MIN_HEADERS_PER_THREAD).clamp(1, MAX_THREADS);
+
+    if threads == 1 {
+        return headers.iter().map(check).collect();
+    }
+    let chunk_size = headers.len() / threads + 1;
+    let handles = (0..headers.len())
+        .step_by(chunk_size)
+        .map(|start| {
+            let headers = headers.clone();
+            let end = (start + chunk_size).min(headers.len());
+
+            thread::spawn(move || headers[start..end].iter().map(check).collect::<Vec<_>>())
+        })
+        .collect::<Vec<_>>();
+
+    handles
+        .into_iter()
+        .flat_map(|h| h.join().expect("verification threads don't panic"))
+        .collect()
+}
+
+/// Hash a header, and check its proof-of-work against its own target.
+fn check(header: &BlockHeader) -> (BlockHash, bool) {
+    let hash = header.block_hash();
+    let mut bytes = [0; 32];
+
+    // Block hashes are little-endian.
+    bytes.copy_from_slice(&hash[..]);
+    bytes.reverse();
+
+    (hash, Uint256::from_be_bytes(bytes) <= header.target())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_verify_pow() {
+        let chain = &nakamoto_test::BITCOIN_HEADERS;
+        let mut headers = chain.tail.clone();
+
+        headers.extend(headers.clone());
+        headers.extend(headers.clone());
+        headers.extend(headers.clone());
+
+        assert!(headers.len() > MIN_HEADERS_PER_THREAD * 2);
+
+        let bad = headers.len() - 7;
+        headers[bad].nonce += 1;
+
+        let results = verify_pow(&headers.iter().copied().collect());
+
+        assert_eq!(results.len(), headers.len());
+        for (i, (header, (hash, ok))) in headers.iter().zip(results).enumerate() {
+            assert_eq!(header.block_hash(), hash);
+            assert_eq!(header.validate_pow(&header.target()).is_ok(), ok);
+            assert_eq!(ok, i != bad);
+        }
+    }
+}
@@ -0,0 +1,194 @@
+//! Block cache snapshots.
+//!
+//! *Saves the state of the block cache, so that it can be restored on restart without
+//! replaying the header store.*
+//!
+//! A snapshot holds the hash, cumulative work and sequence number of every block on the
+//! active chain, as well as the orphan blocks. The headers themselves are still read from
+//! the store when the snapshot is loaded, but they don't have to be hashed.
+//!
+use std::io::{self, Read, Write};
+use std::sync::Arc;
+
+use bitcoin::blockdata::block::BlockHeader;
+use bitcoin::consensus::encode::{self, Decodable, Encodable};
+use bitcoin::consensus::params::Params;
+use bitcoin::hash_types::BlockHash;
+
+use nonempty::NonEmpty;
+
+use nakamoto_common::block::store::Store;
+use nakamoto_common::block::tree::Error;
+use nakamoto_common::block::{Height, Work};
+
+use super::{BlockCache, CachedBlock};
+
+/// Snapshot format version. Snapshots with a different version are ignored.
+pub const VERSION: u8 = 1;
+
+/// A decoded snapshot.
+struct Snapshot {
+    /// Arrival sequence number of the next block seen.
+    seq: u64,
+    /// Hash, cumulative work and sequence number of each block of the active chain,
+    /// starting from genesis.
+    blocks: Vec<(BlockHash, Work, u64)>,
+    /// Orphan blocks, in arrival order.
+    orphans: Vec<(BlockHeader, u64)>,
+}
+
+impl Snapshot {
+    /// Decode a snapshot.
+    fn read<R: Read>(mut r: R) -> Result<Self, encode::Error> {
+        if u8::consensus_decode(&mut r)? != VERSION {
+            return Err(encode::Error::ParseFailed("unsupported snapshot version"));
+        }
+        let seq = u64::consensus_decode(&mut r)?;
+
+        let mut blocks = Vec::new();
+        for _ in 0..u64::consensus_decode(&mut r)? {
+            blocks.push((
+                BlockHash::consensus_decode(&mut r)?,
+                Work::consensus_decode(&mut r)?,
+                u64::consensus_decode(&mut r)?,
+            ));
+        }
+
+        let mut orphans = Vec::new();
+        for _ in 0..u64::consensus_decode(&mut r)? {
+            orphans.push((
+                BlockHeader::consensus_decode(&mut r)?,
+                u64::consensus_decode(&mut r)?,
+            ));
+        }
+
+        Ok(Self {
+            seq,
+            blocks,
+            orphans,
+        })
+    }
+
+    /// Pair the stored headers with the snapshot's blocks. Returns `None` if the snapshot
+    /// doesn't match the store.
+    ///
+    /// Besides the genesis and tip hashes, only the links between the headers and the
+    /// snapshot's hashes are checked, since this doesn't require hashing the headers.
+    fn chain<S: Store<Header = BlockHeader>>(
+        &self,
+        store: &S,
+    ) -> Result<Option<NonEmpty<CachedBlock>>, Error> {
+        if self.blocks.len() != store.len()? {
+            return Ok(None);
+        }
+        let mut chain = Vec::with_capacity(self.blocks.len());
+
+        for (result, (hash, chainwork, seq)) in store.iter().zip(self.blocks.iter()) {
+            let (height, header) = result?;
+
+            match chain.last() {
+                Some(CachedBlock { hash: prev, .. }) if *prev != header.prev_blockhash => {
+                    return Ok(None);
+                }
+                _ => {}
+            }
+            chain.push(CachedBlock {
+                height,
+                hash: *hash,
+                header,
+                chainwork: *chainwork,
+                seq: *seq,
+            });
+        }
+
+        match NonEmpty::from_vec(chain) {
+            Some(chain)
+                if chain.head.hash == chain.head.header.block_hash()
+                    && chain.last().hash == chain.last().header.block_hash() =>
+            {
+                Ok(Some(chain))
+            }
+            _ => Ok(None),
+        }
+    }
+}
+
+impl<S: Store<Header = BlockHeader>> BlockCache<S> {
+    /// Write a snapshot of the block cache, from which it can be restored with
+    /// [`BlockCache::load_snapshot`]. The snapshot only depends on the state of the cache,
+    /// and not on its settings.
+    ///
+    /// Caches that evicted blocks from memory can't be snapshotted, since the snapshot
+    /// covers the whole active chain.
+    pub fn save_snapshot<W: Write>(&self, mut w: W) -> Result<(), io::Error> {
+        if self.evicted > 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "can't snapshot a block cache with evicted blocks",
+            ));
+        }
+        VERSION.consensus_encode(&mut w)?;
+        self.seq.consensus_encode(&mut w)?;
+
+        (self.chain.len() as u64).consensus_encode(&mut w)?;
+        for blk in self.chain.iter() {
+            blk.hash.consensus_encode(&mut w)?;
+            blk.chainwork.consensus_encode(&mut w)?;
+            blk.seq.consensus_encode(&mut w)?;
+        }
+
+        let orphans = self
+            .orphans_order
+            .values()
+            .filter_map(|hash| self.orphans.get(hash))
+            .collect::<Vec<_>>();
+
+        (orphans.len() as u64).consensus_encode(&mut w)?;
+        for orphan in orphans {
+            orphan.header.consensus_encode(&mut w)?;
+            orphan.seq.consensus_encode(&mut w)?;
+        }
+        w.flush()
+    }
+
+    /// Restore a block cache from a store and a snapshot written by
+    /// [`BlockCache::save_snapshot`]. This is faster than [`BlockCache::from`], since the
+    /// stored headers aren't hashed and their work isn't recomputed.
+    ///
+    /// If the snapshot can't be read, was written by an incompatible version, or doesn't
+    /// match the store, eg. because headers were stored after it was written, the cache is
+    /// rebuilt from the store instead.
+    pub fn load_snapshot<R: Read>(
+        store: S,
+        params: Params,
+        checkpoints: &[(Height, BlockHash)],
+        r: R,
+    ) -> Result<Self, Error> {
+        super::check_genesis(&store, &params)?;
+
+        let snapshot = match Snapshot::read(r) {
+            Ok(snapshot) => snapshot,
+            Err(err) => {
+                log::warn!("Ignoring unreadable block cache snapshot: {}", err);
+                return Self::from(store, params, checkpoints);
+            }
+        };
+        let chain = match snapshot.chain(&store)? {
+            Some(chain) => chain,
+            None => {
+                log::warn!("Ignoring block cache snapshot that doesn't match the store");
+                return Self::from(store, params, checkpoints);
+            }
+        };
+        let mut cache = Self::genesis_only(store, params, checkpoints)?;
+
+        cache.headers = Arc::new(chain.iter().map(|blk| (blk.hash, blk.height)).collect());
+        cache.chain = Arc::new(chain);
+        cache.seq = snapshot.seq;
+
+        for (header, seq) in snapshot.orphans {
+            cache.insert_orphan(header.block_hash(), header, seq, None);
+        }
+        Ok(cache)
+    }
+}
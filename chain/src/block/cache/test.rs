@@ -1,8 +1,9 @@
-use super::BlockCache;
+use super::{BlockCache, Candidate, ChainEvent, ClockPolicy};
+use crate::block::difficulty::{self, DifficultyPolicy};
 
-use nakamoto_common::block::time::{AdjustedTime, Clock, LocalTime};
-use nakamoto_common::block::tree::{BlockTree, Error, ImportResult};
-use nakamoto_common::block::{BlockTime, Height, Target};
+use nakamoto_common::block::time::{self, AdjustedTime, Clock, LocalTime};
+use nakamoto_common::block::tree::{BlockTree, Branch, Error, ImportResult};
+use nakamoto_common::block::{Bits, BlockTime, Height, Target};
 
 use nakamoto_test::block;
 use nakamoto_test::block::cache::model;
@@ -13,6 +14,7 @@ use std::collections::{BTreeMap, VecDeque};
 use std::iter;
 use std::net;
 use std::sync::{Arc, RwLock};
+use std::time::Duration;
 
 use nonempty::NonEmpty;
 use quickcheck as qc;
@@ -475,6 +477,425 @@ fn prop_invalid_block_pow(import: BlockImport) -> bool {
     }
 }
 
+#[test]
+fn test_next_min_difficulty_target() {
+    let network = bitcoin::Network::Testnet;
+    let genesis = constants::genesis_block(network).header;
+    let params = Params::new(network);
+    let interval = params.difficulty_adjustment_interval();
+    let pow_limit_bits = block::pow_limit_bits(&network);
+
+    // Targets above the minimum difficulty.
+    let (a, b, c) = (0x1c7fffff, 0x1c3fffff, 0x1c1fffff);
+    // The rest are minimum-difficulty blocks, as happens when testnet blocks are more than
+    // 20 minutes apart. The first block of the third interval is one of them, so that the
+    // target of the block preceding it is out of reach.
+    let bits = |height: Height| match height {
+        h if h == interval => c,
+        h if h == interval * 2 => pow_limit_bits,
+        h if h < interval + 8 => a,
+        h if h == interval * 2 - 1 => b,
+        _ => pow_limit_bits,
+    };
+
+    let mut headers = vec![genesis];
+    for height in 1..interval * 2 + 64 {
+        let prev = headers.last().unwrap();
+        let header = BlockHeader {
+            prev_blockhash: prev.block_hash(),
+            time: prev.time + 60 * 20 + 1,
+            bits: bits(height),
+            nonce: height as u32,
+            ..genesis
+        };
+        headers.push(header);
+    }
+
+    // The target required of a block following the given height, when it isn't late, as
+    // computed by Bitcoin Core's `GetNextWorkRequired`: the target of the last block
+    // that isn't a minimum-difficulty block, or of the first block of the interval.
+    //
+    // Nb. The heights preceding a difficulty adjustment are skipped, since the next block
+    // is subject to the regular difficulty adjustment.
+    for (height, expected) in &[
+        (1, a),
+        (7, a),
+        (interval - 2, a),
+        (interval, c),
+        (interval + 7, a),
+        (interval + 8, a),
+        (interval + 9, a),
+        (interval * 2 - 2, a),
+        (interval * 2, pow_limit_bits),
+        (interval * 2 + 63, pow_limit_bits),
+    ] {
+        let chain = &headers[..=*height as usize];
+        let store = store::Memory::new(NonEmpty::from_slice(chain).unwrap());
+        let cache = BlockCache::from(store, params.clone(), &[]).unwrap();
+        let (hash, tip) = cache.tip();
+        let header = BlockHeader {
+            prev_blockhash: hash,
+            time: tip.time + 1,
+            ..tip
+        };
+
+        assert_eq!(
+            difficulty::Bitcoin.next_target(Box::new(cache.ancestors(&hash)), &header, &params),
+            *expected,
+            "height = {}",
+            height
+        );
+        assert_eq!(
+            cache.next_target(),
+            BlockHeader::u256_from_compact_target(*expected),
+            "height = {}",
+            height
+        );
+        // A late block may have the minimum difficulty.
+        assert_eq!(
+            cache.next_target_at(tip.time + params.pow_target_spacing as BlockTime * 2 + 1),
+            params.pow_limit,
+            "height = {}",
+            height
+        );
+    }
+}
+
+// Test our difficulty validation against the first blocks of the testnet3 chain.
+#[test]
+fn test_testnet_difficulty() {
+    use crate::tests;
+    use bitcoin::consensus::encode;
+
+    let network = bitcoin::Network::Testnet;
+    let genesis = constants::genesis_block(network).header;
+    let params = Params::new(network);
+    let ctx = AdjustedTime::<net::SocketAddr>::new(LOCAL_TIME);
+    let store = store::Memory::new(NonEmpty::new(genesis));
+    let mut cache = BlockCache::from(store, params.clone(), &[]).unwrap();
+
+    for (height, hash, hex) in tests::TESTNET_HEADERS.iter() {
+        let header: BlockHeader = encode::deserialize(&Vec::<u8>::from_hex(hex).unwrap()).unwrap();
+        let (tip, _) = cache.tip();
+
+        assert_eq!(header.block_hash(), BlockHash::from_hex(hash).unwrap());
+        assert_eq!(header.prev_blockhash, tip);
+        assert_eq!(
+            difficulty::Bitcoin.next_target(Box::new(cache.ancestors(&tip)), &header, &params),
+            header.bits,
+            "height = {}",
+            height
+        );
+        cache.import_block(header, &ctx).unwrap();
+        assert_eq!(cache.height(), *height);
+    }
+}
+
+#[test]
+fn test_verify_pow_skips_non_extensions() {
+    let network = bitcoin::Network::Regtest;
+    let genesis = constants::genesis_block(network).header;
+    let params = Params::new(network);
+    let g = &mut rand::thread_rng();
+
+    let mut a = vec![Tree::new(genesis)];
+    for _ in 0..8 {
+        let next = a.last().unwrap().next(g);
+        a.push(next);
+    }
+    let store = store::Memory::new(NonEmpty::new(genesis));
+    let cache = BlockCache::from(store, params, &[]).unwrap();
+
+    // A batch that extends the tip is checked ahead of import with the `parallel` feature.
+    let extension = cache.verify_pow(a[1..].iter().map(|t| t.block()));
+    assert_eq!(extension.len(), 8);
+    assert!(extension
+        .iter()
+        .all(|(_, pow)| pow.is_some() == cfg!(feature = "parallel")));
+
+    // A batch that doesn't start at the tip is never checked ahead of import.
+    let orphans = cache.verify_pow(a[2..].iter().map(|t| t.block()));
+    assert_eq!(orphans.len(), 7);
+    assert!(orphans.iter().all(|(_, pow)| pow.is_none()));
+}
+
+// Compares the time it takes to compare the work of fork branches with the active chain, using
+// the cumulative work of cached blocks, and summing the work of the active chain after the fork
+// point, as was done before cumulative work was cached. The fork branches are one-block orphans
+// off the last blocks of the active chain, and after each import, every branch is compared.
+//
+// Run with `cargo test --release -p nakamoto-chain bench_orphan_import -- --ignored --nocapture`.
+#[test]
+#[ignore]
+fn bench_orphan_import_work_comparison() {
+    use std::time::Instant;
+
+    let network = bitcoin::Network::Regtest;
+    let genesis = constants::genesis_block(network).header;
+    let params = Params::new(network);
+    let store = store::Memory::new(NonEmpty::new(genesis));
+    let ctx = AdjustedTime::<net::SocketAddr>::new(LOCAL_TIME);
+    let mut cache = BlockCache::from(store, params, &[]).unwrap();
+    let g = &mut rand::thread_rng();
+
+    let height: Height = 2100;
+    let orphans = 2000;
+    let depth = 50;
+
+    let a0 = Tree::new(genesis);
+    let mut a = vec![a0];
+    for _ in 0..height {
+        let next = a.last().unwrap().next(g);
+        a.push(next);
+    }
+    cache
+        .import_blocks(a[1..].iter().map(|t| t.block()), &ctx)
+        .unwrap();
+
+    let mut forks = Vec::with_capacity(orphans);
+    let mut import_elapsed = Duration::default();
+    let mut cached_elapsed = Duration::default();
+    let mut summed_elapsed = Duration::default();
+    let (mut cached, mut summed) = (Vec::new(), Vec::new());
+
+    for i in 0..orphans {
+        let fork_height = height - 1 - (i as Height % depth);
+        let blk = a[fork_height as usize].next(g);
+
+        let started = Instant::now();
+        cache.import_block(blk.block(), &ctx).unwrap();
+        import_elapsed += started.elapsed();
+
+        forks.push(fork_height);
+
+        let tip = cache.chain.last().chainwork;
+        let started = Instant::now();
+        cached = forks
+            .iter()
+            .map(|h| tip - cache.block(*h).unwrap().chainwork)
+            .collect::<Vec<_>>();
+        cached_elapsed += started.elapsed();
+
+        let started = Instant::now();
+        summed = forks
+            .iter()
+            .map(|h| Branch(&cache.chain.tail[*h as usize..]).work())
+            .collect::<Vec<_>>();
+        summed_elapsed += started.elapsed();
+    }
+    assert_eq!(cache.height(), height);
+    assert_eq!(cache.orphan_count(), orphans);
+    assert_eq!(cached, summed);
+
+    println!(
+        "{} orphans imported in {:?}; work comparisons: cached in {:?}, summed in {:?}",
+        orphans, import_elapsed, cached_elapsed, summed_elapsed
+    );
+}
+
+// Measures header import throughput over a chain that crosses several difficulty adjustment
+// boundaries, and compares the time it takes to import the retarget blocks with the time it
+// takes to import the other blocks. The first block of each interval is looked up by height,
+// so retarget blocks shouldn't be noticeably slower to import.
+//
+// The headers aren't mined: mainnet difficulty is too high for that, and on regtest, retargets
+// overflow. Their proof-of-work is assumed valid instead, but their targets are still checked.
+//
+// Run with `cargo test --release -p nakamoto-chain bench_retarget -- --ignored --nocapture`.
+#[test]
+#[ignore]
+fn bench_retarget_import() {
+    use std::time::Instant;
+
+    let network = bitcoin::Network::Bitcoin;
+    let genesis = constants::genesis_block(network).header;
+    let params = Params::new(network);
+    let ctx = AdjustedTime::<net::SocketAddr>::new(LOCAL_TIME);
+    let interval = params.difficulty_adjustment_interval();
+    let boundaries = 8;
+
+    // Blocks come every nine minutes, so the difficulty goes up on every boundary.
+    let mut headers = Vec::new();
+    let mut first = genesis;
+    let mut prev = genesis;
+    for height in 1..=interval * boundaries {
+        let bits = if height % interval == 0 {
+            block::retarget(&first, prev.time, prev.target(), &params)
+        } else {
+            prev.bits
+        };
+        let header = BlockHeader {
+            prev_blockhash: prev.block_hash(),
+            time: prev.time + 60 * 9,
+            nonce: height as u32,
+            bits,
+            ..prev
+        };
+        if height % interval == 0 {
+            first = header;
+        }
+        headers.push(header);
+        prev = header;
+    }
+    let store = store::Memory::new(NonEmpty::new(genesis));
+    let mut cache = BlockCache::from(store, params, &[])
+        .unwrap()
+        .with_assume_valid(prev.block_hash());
+
+    let mut retargets = (0, Duration::default());
+    let mut others = (0, Duration::default());
+    let started = Instant::now();
+    let mut last = started;
+
+    cache
+        .import_blocks_with(headers.iter().cloned(), &ctx, |height| {
+            let now = Instant::now();
+            let (count, elapsed) = if height % interval == 0 {
+                &mut retargets
+            } else {
+                &mut others
+            };
+            *count += 1;
+            *elapsed += now - last;
+            last = now;
+        })
+        .unwrap();
+    let elapsed = started.elapsed();
+
+    assert_eq!(cache.height(), interval * boundaries);
+    assert_eq!(retargets.0, boundaries);
+    assert!(prev.target() < genesis.target());
+
+    println!(
+        "{} headers across {} retargets imported in {:?} ({:.0} headers/s); \
+         {:?} per retarget block, {:?} per other block",
+        headers.len(),
+        boundaries,
+        elapsed,
+        headers.len() as f64 / elapsed.as_secs_f64(),
+        retargets.1 / retargets.0 as u32,
+        others.1 / others.0 as u32,
+    );
+}
+
+// Measures the throughput of the block cache operations that look up block hashes, with the
+// hasher selected by the `fast-hash` feature. Compare the output with and without the feature.
+//
+// Run with `cargo test --release -p nakamoto-chain bench_block_lookups -- --ignored --nocapture`,
+// then again with `--features fast-hash`.
+#[test]
+#[ignore]
+fn bench_block_lookups() {
+    use bitcoin_hashes::Hash;
+    use std::time::Instant;
+
+    let network = bitcoin::Network::Regtest;
+    let genesis = constants::genesis_block(network).header;
+    let params = Params::new(network);
+    let ctx = AdjustedTime::<net::SocketAddr>::new(LOCAL_TIME);
+    let g = &mut rand::thread_rng();
+    let height = 20_000;
+    let rounds = 50;
+
+    let mut headers = Vec::with_capacity(height);
+    let mut tip = Tree::new(genesis);
+    for _ in 0..height {
+        tip = tip.next(g);
+        headers.push(tip.block());
+    }
+    let hashes = headers.iter().map(|h| h.block_hash()).collect::<Vec<_>>();
+    let unknown = hashes
+        .iter()
+        .map(|h| BlockHash::hash(&h[..]))
+        .collect::<Vec<_>>();
+
+    let store = store::Memory::new(NonEmpty::new(genesis));
+    let mut cache = BlockCache::from(store, params, &[]).unwrap();
+
+    let started = Instant::now();
+    cache.import_blocks(headers.iter().cloned(), &ctx).unwrap();
+    let import_elapsed = started.elapsed();
+    assert_eq!(cache.height(), height as Height);
+
+    let started = Instant::now();
+    let mut known = 0;
+    for _ in 0..rounds {
+        known += hashes
+            .iter()
+            .chain(&unknown)
+            .filter(|h| cache.is_known(h))
+            .count();
+    }
+    let is_known_elapsed = started.elapsed();
+
+    let started = Instant::now();
+    let mut contained = 0;
+    for _ in 0..rounds {
+        contained += hashes
+            .iter()
+            .chain(&unknown)
+            .filter(|h| cache.contains(h))
+            .count();
+    }
+    let contains_elapsed = started.elapsed();
+
+    assert_eq!(known, height * rounds);
+    assert_eq!(contained, height * rounds);
+
+    println!(
+        "{}: {} headers imported in {:?}; {} lookups: is_known in {:?}, contains in {:?}",
+        if cfg!(feature = "fast-hash") {
+            "fast-hash"
+        } else {
+            "SipHash"
+        },
+        height,
+        import_elapsed,
+        height * rounds * 2,
+        is_known_elapsed,
+        contains_elapsed
+    );
+}
+
+#[test]
+fn test_cache_difficulty_policy() {
+    #[derive(Debug)]
+    struct Fixed(Bits);
+
+    impl DifficultyPolicy for Fixed {
+        fn next_target(
+            &self,
+            _ancestors: difficulty::Ancestors<'_>,
+            _header: &BlockHeader,
+            _params: &Params,
+        ) -> Bits {
+            self.0
+        }
+    }
+
+    let network = bitcoin::Network::Regtest;
+    let genesis = constants::genesis_block(network).header;
+    let params = Params::new(network);
+    let store = store::Memory::new(NonEmpty::new(genesis));
+    let ctx = AdjustedTime::<net::SocketAddr>::new(LOCAL_TIME);
+    let g = &mut rand::thread_rng();
+
+    let a0 = Tree::new(genesis);
+    let a1 = a0.next(g);
+    let bits = 0x1f00ffff;
+
+    let mut cache = BlockCache::from(store, params, &[])
+        .unwrap()
+        .with_difficulty_policy(Fixed(bits));
+
+    assert!(matches! {
+        cache.import_block(a1.block(), &ctx),
+        Err(Error::InvalidBlockTarget(_, target))
+            if target == BlockHeader::u256_from_compact_target(bits)
+    });
+    assert_eq!(cache.height(), 0);
+}
+
 // Test our difficulty validation against values from the bitcoin main chain.
 #[test]
 fn test_bitcoin_difficulty() {
@@ -512,463 +933,4935 @@ fn test_bitcoin_difficulty() {
     }
 }
 
-// Test that we're correctly loading headers from the header store.
+// Test a difficulty retarget end-to-end through header import, against values from the
+// bitcoin main chain. We don't have the real headers, so we rebuild the retarget window
+// from the timestamps of its first and last blocks, and check the bits of the retarget
+// block. Since the headers can't be mined at mainnet difficulty, their proof-of-work is
+// assumed valid.
 #[test]
-fn test_from_store() {
-    let genesis = constants::genesis_block(bitcoin::Network::Bitcoin).header;
-    let store = store::File::open(&*nakamoto_test::headers::PATH, genesis).unwrap();
-    let store_headers = store.iter().collect::<Result<Vec<_>, _>>().unwrap();
+fn test_bitcoin_retarget_import() {
+    use crate::tests;
 
     let network = bitcoin::Network::Bitcoin;
     let params = Params::new(network);
+    let interval = params.difficulty_adjustment_interval();
+
+    // The first difficulty change on the main chain, and the start of its window.
+    let (height, prev_time, prev_bits, time, bits) = tests::TARGETS
+        .iter()
+        .cloned()
+        .find(|(_, _, prev_bits, _, bits)| prev_bits != bits)
+        .unwrap();
+    let (_, _, _, start_time, start_bits) = tests::TARGETS
+        .iter()
+        .cloned()
+        .find(|(h, _, _, _, _)| *h == height - interval)
+        .unwrap();
+    assert_eq!(start_bits, prev_bits);
+
+    // Heights are relative to the start of the window, which is on an interval boundary.
+    let start = BlockHeader {
+        version: 1,
+        prev_blockhash: Default::default(),
+        merkle_root: Default::default(),
+        time: start_time,
+        bits: start_bits,
+        nonce: 0,
+    };
+    let mut headers = Vec::new();
+    let mut prev = start;
+
+    for i in 1..interval {
+        let header = BlockHeader {
+            prev_blockhash: prev.block_hash(),
+            time: start_time + ((prev_time - start_time) as u64 * i / (interval - 1)) as BlockTime,
+            ..prev
+        };
+        headers.push(header);
+        prev = header;
+    }
+    assert_eq!(prev.time, prev_time);
 
-    let cache = BlockCache::from(store, params, &[]).unwrap();
-    let cache_headers = cache.iter().collect::<Vec<_>>();
+    let retarget = BlockHeader {
+        prev_blockhash: prev.block_hash(),
+        time,
+        bits,
+        ..prev
+    };
+    let ctx = AdjustedTime::<net::SocketAddr>::new(LocalTime::from_block_time(time + 60));
+    // The window start isn't the network's genesis block, so it can't be loaded with
+    // `BlockCache::from`.
+    let store = store::Memory::new(NonEmpty::new(start));
+    let cache = BlockCache::genesis_only(store, params, &[]).unwrap();
+
+    // The retarget block's target is known ahead of its import.
+    let mut pending = cache.clone().with_assume_valid(prev.block_hash());
+    pending
+        .import_blocks(headers.iter().cloned(), &ctx)
+        .unwrap();
+    assert_eq!(pending.next_target_at(time), retarget.target());
+    assert_ne!(pending.next_target_at(time), prev.target());
+    assert_eq!(pending.next_target(), retarget.target());
+
+    // The genuine retarget block is accepted.
+    let mut valid = cache.clone().with_assume_valid(retarget.block_hash());
+    valid
+        .import_blocks(headers.iter().cloned().chain(iter::once(retarget)), &ctx)
+        .unwrap();
+    assert_eq!(valid.height(), interval as Height);
+    assert_eq!(valid.tip().0, retarget.block_hash());
+
+    // A retarget block with off-by-one bits is rejected.
+    for bits in &[bits - 1, bits + 1] {
+        let header = BlockHeader {
+            bits: *bits,
+            ..retarget
+        };
+        let err = cache
+            .clone()
+            .with_assume_valid(header.block_hash())
+            .import_blocks(headers.iter().cloned().chain(iter::once(header)), &ctx)
+            .unwrap_err();
+
+        assert!(
+            matches! {
+                err,
+                Error::BlockImportAborted { ref error, index, .. }
+                    if index == headers.len() && matches!(**error, Error::InvalidBlockTarget(..))
+            },
+            "{:?}",
+            err
+        );
+    }
+}
 
-    assert_eq!(store_headers.len(), cache_headers.len());
-    assert_eq!(
-        store_headers, cache_headers,
-        "all stored headers figure in the cache"
-    );
+/// A malformed store that reports being empty, even though it has a genesis block.
+#[derive(Debug)]
+struct EmptyStore(store::Memory<BlockHeader>);
 
-    // Make sure all cached headers are also in the `headers` map.
-    for (height, header) in store_headers.iter() {
-        let result = cache.headers.get(&header.block_hash());
-        assert_eq!(result, Some(height));
+impl Store for EmptyStore {
+    type Header = BlockHeader;
+
+    fn genesis(&self) -> BlockHeader {
+        self.0.genesis()
+    }
+
+    fn put<I: Iterator<Item = BlockHeader>>(&mut self, headers: I) -> Result<Height, store::Error> {
+        self.0.put(headers)
+    }
+
+    fn get(&self, height: Height) -> Result<BlockHeader, store::Error> {
+        self.0.get(height)
+    }
+
+    fn rollback(&mut self, height: Height) -> Result<(), store::Error> {
+        self.0.rollback(height)
+    }
+
+    fn sync(&mut self) -> Result<(), store::Error> {
+        self.0.sync()
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = Result<(Height, BlockHeader), store::Error>>> {
+        self.0.iter()
+    }
+
+    fn len(&self) -> Result<usize, store::Error> {
+        Ok(0)
+    }
+
+    fn height(&self) -> Result<Height, store::Error> {
+        self.0.height()
+    }
+
+    fn check(&self) -> Result<(), store::Error> {
+        self.0.check()
+    }
+
+    fn heal(&self) -> Result<(), store::Error> {
+        self.0.heal()
     }
 }
 
 #[test]
-fn test_median_time_past() {
-    let network = bitcoin::Network::Bitcoin;
+fn test_cache_replace_store() {
+    let network = bitcoin::Network::Regtest;
     let genesis = constants::genesis_block(network).header;
     let params = Params::new(network);
-    let store = store::File::open(&*nakamoto_test::headers::PATH, genesis).unwrap();
+    let store = store::Memory::new(NonEmpty::new(genesis));
+    let ctx = AdjustedTime::<net::SocketAddr>::new(LOCAL_TIME);
+    let mut cache = BlockCache::from(store, params.clone(), &[]).unwrap();
+    let g = &mut rand::thread_rng();
 
-    let cache = BlockCache::from(store, params, &[]).unwrap();
-    let headers = cache.iter().map(|(_, h)| h).collect::<Vec<_>>();
+    // a0 <- a1 <- a2 <- a3 * <- a4
+    //        \
+    //         <- b2
+    let a0 = Tree::new(genesis);
+    let a1 = a0.next(g);
+    let a3 = a1.next(g).next(g);
+    let a4 = a3.next(g);
+    let b2 = a1.next(g);
 
-    assert_eq!(cache.median_time_past(1), genesis.time);
-    assert_eq!(cache.median_time_past(2), headers[1].time);
-    assert_eq!(cache.median_time_past(3), headers[1].time);
-    assert_eq!(cache.median_time_past(4), headers[2].time);
-    assert_eq!(cache.median_time_past(11), headers[5].time);
-    assert_eq!(cache.median_time_past(13), headers[7].time);
+    cache.import_blocks(a0.branch([&a1, &a3]), &ctx).unwrap();
+    cache.import_block(b2.block(), &ctx).unwrap();
+
+    // A store for another network is refused.
+    let tmp = tempfile::tempdir().unwrap();
+    let other = constants::genesis_block(bitcoin::Network::Testnet).header;
+    let store = store::File::create(tmp.path().join("testnet.db"), other).unwrap();
+    assert!(matches! {
+        cache.clone().replace_store(store),
+        Err(Error::GenesisMismatch { expected, found })
+            if expected == genesis.block_hash() && found == other.block_hash()
+    });
+
+    // Headers already in the new store are replaced with the active chain.
+    let path = tmp.path().join("headers.db");
+    let mut store = store::File::create(&path, genesis).unwrap();
+    store.put(iter::once(b2.block())).unwrap();
+
+    let mut cache = cache.replace_store(store).unwrap();
+    assert_eq!(cache.store.height().unwrap(), 3);
+    assert_eq!(
+        cache.store.iter().map(|r| r.unwrap()).collect::<Vec<_>>(),
+        cache.iter().collect::<Vec<_>>()
+    );
+    assert_eq!(cache.orphan_count(), 1, "Orphans are kept");
+
+    cache.import_block(a4.block(), &ctx).unwrap();
+    assert_eq!(cache.tip().0, a4.hash);
+    drop(cache);
+
+    // The new store can be used to load the cache on restart.
+    let store = store::File::open(&path, genesis).unwrap();
+    let cache = BlockCache::from(store, params, &[]).unwrap();
+    assert_eq!(cache.tip().0, a4.hash);
+    assert_eq!(cache.height(), 4);
 }
 
 #[test]
-fn prop_cache_import_ordered() {
-    fn prop(input: arbitrary::OrderedHeaders) -> bool {
-        let arbitrary::OrderedHeaders { headers } = input;
-        let mut cache = model::Cache::new(headers.head);
-        let tip = *headers.last();
-        let clock = AdjustedTime::<net::SocketAddr>::new(LOCAL_TIME);
+fn test_from_empty_store() {
+    let network = bitcoin::Network::Regtest;
+    let genesis = constants::genesis_block(network).header;
+    let params = Params::new(network);
 
-        cache
-            .import_blocks(headers.tail.iter().cloned(), &clock)
-            .unwrap();
+    let store = EmptyStore(store::Memory::new(NonEmpty::new(genesis)));
+    assert!(matches!(
+        BlockCache::from(store, params.clone(), &[]),
+        Err(Error::StoreCorruption { height: 0 })
+    ));
 
-        cache.genesis() == &headers.head
-            && cache.tip() == (tip.block_hash(), tip)
-            && cache
-                .iter()
-                .all(|(i, h)| headers.get(i as usize) == Some(&h))
-    }
-    QuickCheck::with_gen(qc::StdGen::new(rand::thread_rng(), 16))
-        .quickcheck(prop as fn(arbitrary::OrderedHeaders) -> bool);
+    // A store with only the genesis block is the normal, empty case.
+    let cache = BlockCache::from(store::Memory::new(NonEmpty::new(genesis)), params, &[]).unwrap();
+    assert_eq!(cache.height(), 0);
+    assert_eq!(cache.tip().0, genesis.block_hash());
+    assert_eq!(cache.genesis_hash(), genesis.block_hash());
 }
 
-#[derive(Clone)]
-struct Tree {
-    headers: Arc<RwLock<BTreeMap<BlockHash, BlockHeader>>>,
-    genesis: BlockHeader,
-    hash: BlockHash,
-    time: BlockTime,
-}
+#[test]
+fn test_from_genesis_mismatch() {
+    let networks = [
+        bitcoin::Network::Bitcoin,
+        bitcoin::Network::Testnet,
+        bitcoin::Network::Signet,
+        bitcoin::Network::Regtest,
+    ];
 
-impl Tree {
-    fn new(genesis: BlockHeader) -> Self {
-        let headers = BTreeMap::new();
-        let hash = genesis.block_hash();
+    for store_network in networks.iter() {
+        let genesis = constants::genesis_block(*store_network).header;
 
-        Self {
-            headers: Arc::new(RwLock::new(headers)),
-            time: genesis.time,
-            genesis,
-            hash,
+        for network in networks.iter() {
+            let store = store::Memory::new(NonEmpty::new(genesis));
+            let result = BlockCache::from(store, Params::new(*network), &[]);
+
+            if network == store_network {
+                assert_eq!(result.unwrap().genesis_hash(), genesis.block_hash());
+            } else {
+                let expected = constants::genesis_block(*network).block_hash();
+
+                assert!(matches! {
+                    result,
+                    Err(Error::GenesisMismatch { expected: e, found })
+                        if e == expected && found == genesis.block_hash()
+                });
+            }
         }
     }
+}
 
-    fn next(&self, g: &mut impl Rng) -> Tree {
-        let nonce = g.gen::<u32>();
-        let mut header = BlockHeader {
-            version: 1,
-            prev_blockhash: self.hash,
-            merkle_root: Default::default(),
-            bits: BlockHeader::compact_target_from_u256(&TARGET),
-            time: self.time + TARGET_SPACING,
-            nonce,
-        };
-        block::solve(&mut header);
+/// A store that fails the given writes.
+#[derive(Debug)]
+struct FailingStore {
+    store: store::Memory<BlockHeader>,
+    /// Number of writes attempted.
+    writes: usize,
+    /// Writes that fail, by number.
+    fail: Vec<usize>,
+}
 
-        let hash = header.block_hash();
-        self.headers.write().unwrap().insert(hash, header);
+impl FailingStore {
+    /// Fail the next given number of writes.
+    fn fail_next(&mut self, count: usize) {
+        self.fail = (self.writes..self.writes + count).collect();
+    }
+}
 
-        Tree {
-            hash,
-            headers: self.headers.clone(),
-            time: header.time,
-            genesis: self.genesis,
-        }
+impl Store for FailingStore {
+    type Header = BlockHeader;
+
+    fn genesis(&self) -> BlockHeader {
+        self.store.genesis()
     }
 
-    fn next_invalid(&self, g: &mut impl Rng) -> Tree {
-        let nonce = g.gen::<u32>();
-        let mut header = BlockHeader {
-            version: 1,
-            prev_blockhash: self.hash,
-            merkle_root: Default::default(),
-            bits: BlockHeader::compact_target_from_u256(&TARGET),
-            time: self.time + TARGET_SPACING,
-            nonce,
-        };
-        let target = header.target();
-        while header.validate_pow(&target).is_ok() {
-            header.nonce += 1;
+    fn put<I: Iterator<Item = BlockHeader>>(&mut self, headers: I) -> Result<Height, store::Error> {
+        self.writes += 1;
+
+        if self.fail.contains(&(self.writes - 1)) {
+            return Err(store::Error::Io(std::io::Error::new(
+                std::io::ErrorKind::WriteZero,
+                "write failed",
+            )));
         }
+        self.store.put(headers)
+    }
 
-        let hash = header.block_hash();
-        self.headers.write().unwrap().insert(hash, header);
+    fn get(&self, height: Height) -> Result<BlockHeader, store::Error> {
+        self.store.get(height)
+    }
 
-        Tree {
-            hash,
-            headers: self.headers.clone(),
-            time: header.time,
-            genesis: self.genesis,
-        }
+    fn rollback(&mut self, height: Height) -> Result<(), store::Error> {
+        self.store.rollback(height)
     }
 
-    fn sample<G: Gen>(&self, g: &mut G) -> Tree {
-        let headers = self.headers.read().unwrap();
-        let ix = g.gen_range(0, headers.len());
-        let header = headers.values().nth(ix).unwrap();
+    fn sync(&mut self) -> Result<(), store::Error> {
+        self.store.sync()
+    }
 
-        Tree {
-            hash: header.block_hash(),
-            headers: self.headers.clone(),
-            time: header.time,
-            genesis: self.genesis,
-        }
+    fn iter(&self) -> Box<dyn Iterator<Item = Result<(Height, BlockHeader), store::Error>>> {
+        self.store.iter()
     }
 
-    fn headers(&self) -> Vec<BlockHeader> {
-        self.headers
-            .read()
-            .unwrap()
-            .values()
-            .cloned()
+    fn len(&self) -> Result<usize, store::Error> {
+        self.store.len()
+    }
+
+    fn height(&self) -> Result<Height, store::Error> {
+        self.store.height()
+    }
+
+    fn check(&self) -> Result<(), store::Error> {
+        self.store.check()
+    }
+
+    fn heal(&self) -> Result<(), store::Error> {
+        self.store.heal()
+    }
+}
+
+#[test]
+fn test_cache_store_write_failure() {
+    let network = bitcoin::Network::Regtest;
+    let genesis = constants::genesis_block(network).header;
+    let params = Params::new(network);
+    let store = FailingStore {
+        store: store::Memory::new(NonEmpty::new(genesis)),
+        writes: 0,
+        fail: vec![],
+    };
+    let ctx = AdjustedTime::<net::SocketAddr>::new(LOCAL_TIME);
+    let mut cache = BlockCache::from(store, params, &[]).unwrap();
+    let g = &mut rand::thread_rng();
+
+    // a0 <- a1 <- a2 <- (a3)
+    //        \
+    //         <- b2 <- b3 <- b4 * <- (b5)
+    //                          \
+    //                           <- c5 <- c6 <- c7
+    let a0 = Tree::new(genesis);
+    let a1 = a0.next(g);
+    let a2 = a1.next(g);
+    let a3 = a2.next(g);
+    let b2 = a1.next(g);
+    let b3 = b2.next(g);
+    let b4 = b3.next(g);
+    let b5 = b4.next(g);
+    let c5 = b4.next(g);
+    let c6 = c5.next(g);
+    let c7 = c6.next(g);
+
+    let is_store_error = |result: Result<ImportResult, Error>| {
+        matches!(
+            result,
+            Err(Error::BlockImportAborted { ref error, .. }) if matches!(**error, Error::Store(_))
+        )
+    };
+    let stored = |cache: &BlockCache<FailingStore>| {
+        cache
+            .store
+            .iter()
+            .map(|r| r.unwrap().1.block_hash())
             .collect::<Vec<_>>()
+    };
+
+    cache.import_blocks(a0.branch([&a1, &a2]), &ctx).unwrap();
+
+    // Extending the tip fails.
+    cache.store.fail_next(1);
+    assert!(is_store_error(
+        cache.import_blocks(iter::once(a3.block()), &ctx)
+    ));
+    assert_eq!(cache.tip().0, a2.hash);
+    assert!(!cache.contains(&a3.hash));
+    assert_eq!(stored(&cache), vec![a0.hash, a1.hash, a2.hash]);
+
+    // Switching to a fork fails when storing the fork, but not when restoring the active
+    // chain.
+    cache.store.fail_next(1);
+    let work = cache.chain_work();
+    let branch = a0.branch([&b2, &b4]).collect::<Vec<_>>();
+    assert!(is_store_error(
+        cache.import_blocks(branch.into_iter().rev(), &ctx)
+    ));
+    assert_eq!(cache.tip().0, a2.hash);
+    assert_eq!(cache.height(), 2);
+    assert_eq!(cache.chain_work(), work);
+    assert_eq!(cache.orphan_count(), 3);
+    assert_eq!(stored(&cache), vec![a0.hash, a1.hash, a2.hash]);
+    assert_eq!(
+        cache
+            .iter()
+            .map(|(_, h)| h.block_hash())
+            .collect::<Vec<_>>(),
+        stored(&cache)
+    );
+
+    // The fork is still known, and is switched to once the store works again.
+    cache.import_blocks(iter::once(b5.block()), &ctx).unwrap();
+    assert_eq!(cache.tip().0, b5.hash);
+    assert_eq!(
+        cache.orphan_count(),
+        1,
+        "a2 was rolled back into the orphan set"
+    );
+    assert_eq!(
+        stored(&cache),
+        vec![a0.hash, a1.hash, b2.hash, b3.hash, b4.hash, b5.hash]
+    );
+
+    // Switching to a fork fails, and so does restoring the active chain. The active chain
+    // is left at the fork block, which still matches the store.
+    cache.store.fail_next(2);
+    assert!(is_store_error(
+        cache.import_blocks(vec![c6.block(), c5.block()].into_iter(), &ctx)
+    ));
+    assert_eq!(cache.tip().0, b4.hash);
+    assert_eq!(cache.orphan_count(), 4, "The orphans are a2, b5, c5 and c6");
+    assert_eq!(
+        stored(&cache),
+        vec![a0.hash, a1.hash, b2.hash, b3.hash, b4.hash]
+    );
+    assert_eq!(
+        cache
+            .iter()
+            .map(|(_, h)| h.block_hash())
+            .collect::<Vec<_>>(),
+        stored(&cache)
+    );
+
+    cache.import_blocks(iter::once(c7.block()), &ctx).unwrap();
+    assert_eq!(cache.tip().0, c7.hash);
+    assert_eq!(cache.store.height().unwrap(), 7);
+}
+
+// Test that we're correctly loading headers from the header store.
+#[test]
+fn test_from_store() {
+    let genesis = constants::genesis_block(bitcoin::Network::Bitcoin).header;
+    let store = store::File::open(&*nakamoto_test::headers::PATH, genesis).unwrap();
+    let store_headers = store.iter().collect::<Result<Vec<_>, _>>().unwrap();
+
+    let network = bitcoin::Network::Bitcoin;
+    let params = Params::new(network);
+
+    let cache = BlockCache::from(store, params, &[]).unwrap();
+    let cache_headers = cache.iter().collect::<Vec<_>>();
+
+    assert_eq!(store_headers.len(), cache_headers.len());
+    assert_eq!(
+        store_headers, cache_headers,
+        "all stored headers figure in the cache"
+    );
+
+    // Make sure all cached headers are also in the `headers` map.
+    for (height, header) in store_headers.iter() {
+        let result = cache.headers.get(&header.block_hash());
+        assert_eq!(result, Some(height));
     }
+}
 
-    fn block(&self) -> BlockHeader {
-        if self.hash == self.genesis.block_hash() {
-            return self.genesis;
-        }
-        self.headers
-            .read()
-            .unwrap()
-            .get(&self.hash)
-            .cloned()
-            .unwrap()
+#[test]
+fn test_cache_replay_limit() {
+    let network = bitcoin::Network::Regtest;
+    let genesis = constants::genesis_block(network).header;
+    let mut params = Params::new(network);
+    let ctx = AdjustedTime::<net::SocketAddr>::new(LOCAL_TIME);
+    let g = &mut rand::thread_rng();
+
+    // Use a short difficulty adjustment interval, so that a small window can be used.
+    params.pow_target_timespan = params.pow_target_spacing * 16;
+    assert_eq!(params.difficulty_adjustment_interval(), 16);
+
+    let mut a = vec![Tree::new(genesis)];
+    for _ in 0..100 {
+        let next = a.last().unwrap().next(g);
+        a.push(next);
     }
+    let store = store::Memory::new(NonEmpty::new(genesis));
+    let mut full = BlockCache::from(store, params.clone(), &[(10, a[10].hash)]).unwrap();
+    full.import_blocks(a[0].branch([&a[1], &a[100]]), &ctx)
+        .unwrap();
+
+    let mut cache = BlockCache::with_store_and_replay_limit(
+        full.store.clone(),
+        params.clone(),
+        &[(10, a[10].hash)],
+        Some(32),
+    )
+    .unwrap();
+
+    assert_eq!(cache.tip(), full.tip());
+    assert_eq!(cache.height(), 100);
+    assert_eq!(cache.chain_work(), full.chain_work());
+    assert_eq!(cache.evicted, 68);
+    assert_eq!(cache.chain.tail.len(), 32);
+    assert!(cache.memory_usage() < full.memory_usage());
+
+    // Evicted blocks are read from the store, if possible.
+    assert_eq!(
+        cache.iter().collect::<Vec<_>>(),
+        full.iter().collect::<Vec<_>>()
+    );
+    assert_eq!(cache.hashes(0..101), full.hashes(0..101));
+    assert_eq!(cache.locator_hashes(100), full.locator_hashes(100));
+    assert_eq!(
+        cache.headers_since(&a[10].hash, 5),
+        full.headers_since(&a[10].hash, 5)
+    );
+    assert!(cache.is_at_height(50, &a[50].hash));
+    assert_eq!(cache.get_block_by_height_or_load(50), Some(a[50].block()));
+    assert_eq!(
+        cache.get_block_or_load(&a[50].hash),
+        Some((50, a[50].block()))
+    );
+    assert_eq!(cache.get_block_by_height(90), full.get_block_by_height(90));
+    assert_eq!(cache.depth(&a[50].hash), full.depth(&a[50].hash));
+    assert_eq!(
+        cache.find_fork(&[a[50].hash, a[10].hash]),
+        Some((50, a[50].hash))
+    );
 
-    fn branch(&self, range: [&Tree; 2]) -> impl Iterator<Item = BlockHeader> {
-        let headers = self.headers.read().unwrap();
-        let mut blocks = VecDeque::new();
+    // Accessors returning references only return blocks in memory.
+    assert_eq!(cache.get_block_by_height(50), None);
+    assert_eq!(cache.get_block(&a[50].hash), None);
+    assert_eq!(cache.height_of(&a[1].hash), Some(1));
+    assert_eq!(cache.height_of(&a[68].hash), Some(68));
+    assert!(cache.contains(&a[50].hash));
+    assert!(cache.is_known(&a[50].hash));
+    assert_eq!(
+        cache.headers_at(60, 20),
+        a[60..80].iter().map(|t| t.block()).collect::<Vec<_>>()
+    );
+    assert_eq!(
+        cache.locate_headers(&[a[50].hash, a[10].hash], a[55].hash, 100),
+        full.locate_headers(&[a[50].hash, a[10].hash], a[55].hash, 100)
+    );
+    assert_eq!(
+        cache.locate_headers(&[a[50].hash], a[55].hash, 100).len(),
+        5,
+        "Headers are served from the first known locator"
+    );
+    cache.verify_checkpoints().unwrap();
+
+    // Genesis and checkpoints are kept in memory.
+    assert_eq!(cache.get_block_by_height(0), Some(&genesis));
+    assert_eq!(cache.get_block_by_height(10), full.get_block_by_height(10));
+    assert!(cache.contains(&a[10].hash));
+    assert_eq!(cache.block_at_or_before(50), &a[10].block());
+
+    // Times that might be matched by evicted blocks aren't looked up.
+    assert_eq!(cache.get_block_by_time(a[5].time), None);
+    assert_eq!(
+        cache.get_block_by_time(a[95].time),
+        full.get_block_by_time(a[95].time)
+    );
+
+    // The chain can't be rolled back to evicted blocks.
+    assert!(matches!(
+        cache.reset_to(60),
+        Err(Error::InvalidBlockHeight(60))
+    ));
+
+    // Forks from evicted blocks, or from less than an adjustment interval above them, are
+    // rejected.
+    let b51 = a[50].next(g);
+    assert!(matches!(
+        cache.import_block(b51.block(), &ctx),
+        Err(Error::InvalidBlockHeight(51))
+    ));
+    let b84 = a[83].next(g);
+    assert!(matches!(
+        cache.import_block(b84.block(), &ctx),
+        Err(Error::InvalidBlockHeight(84))
+    ));
+
+    // Other forks are accepted.
+    //
+    // a0 <- ... <- a90 <- ... <- a100 *
+    //                 \
+    //                  <- b91 <- ... <- b110
+    let mut b = vec![a[90].next(g)];
+    for _ in 91..110 {
+        let next = b.last().unwrap().next(g);
+        b.push(next);
+    }
+    let fork = b.iter().map(|t| t.block()).collect::<Vec<_>>();
+
+    cache.import_blocks(fork.iter().cloned(), &ctx).unwrap();
+    full.import_blocks(fork.iter().cloned(), &ctx).unwrap();
+
+    assert_eq!(cache.tip(), full.tip());
+    assert_eq!(cache.height(), 110);
+    assert_eq!(cache.chain_work(), full.chain_work());
+
+    // Old blocks are evicted as the chain grows.
+    let mut c = vec![b.last().unwrap().next(g)];
+    for _ in 0..40 {
+        let next = c.last().unwrap().next(g);
+        c.push(next);
+    }
+    cache
+        .import_blocks(c.iter().map(|t| t.block()), &ctx)
+        .unwrap();
+    full.import_blocks(c.iter().map(|t| t.block()), &ctx)
+        .unwrap();
+
+    assert_eq!(cache.tip(), full.tip());
+    assert!(cache.chain.tail.len() >= 32 && cache.chain.tail.len() <= 64);
+    assert_eq!(
+        cache.evicted + cache.chain.tail.len() as Height,
+        cache.height()
+    );
+    assert_eq!(
+        cache.iter().collect::<Vec<_>>(),
+        full.iter().collect::<Vec<_>>()
+    );
+    assert_eq!(cache.headers.len(), cache.height() as usize + 1);
+    assert_eq!(cache.headers, full.headers);
+
+    // Without a window, nothing is evicted.
+    let cache =
+        BlockCache::with_store_and_replay_limit(full.store.clone(), params, &[], None).unwrap();
+    assert_eq!(cache.evicted, 0);
+    assert_eq!(cache.headers, full.headers);
+}
+
+#[test]
+fn test_cache_from_corrupt_store() {
+    let network = bitcoin::Network::Bitcoin;
+    let params = Params::new(network);
+    let mut chain = (*nakamoto_test::BITCOIN_HEADERS).clone();
+
+    chain.tail[41].prev_blockhash = chain.tail[39].block_hash();
+
+    let store = store::Memory::new(chain);
+    let err = BlockCache::from(store, params, &[]).unwrap_err();
+
+    assert!(matches!(err, Error::StoreCorruption { height: 42 }));
+}
+
+#[test]
+fn test_median_time_past() {
+    let network = bitcoin::Network::Bitcoin;
+    let genesis = constants::genesis_block(network).header;
+    let params = Params::new(network);
+    let store = store::File::open(&*nakamoto_test::headers::PATH, genesis).unwrap();
+
+    let cache = BlockCache::from(store, params, &[]).unwrap();
+    let headers = cache.iter().map(|(_, h)| h).collect::<Vec<_>>();
+
+    assert_eq!(cache.median_time_past(0), genesis.time);
+    assert_eq!(cache.median_time_past(1), genesis.time);
+    assert_eq!(cache.median_time_past(2), headers[1].time);
+    assert_eq!(cache.median_time_past(3), headers[1].time);
+    assert_eq!(cache.median_time_past(4), headers[2].time);
+    assert_eq!(cache.median_time_past(11), headers[5].time);
+    assert_eq!(cache.median_time_past(13), headers[7].time);
+}
+
+#[test]
+fn test_median_time_past_unavailable() {
+    let network = bitcoin::Network::Regtest;
+    let genesis = constants::genesis_block(network).header;
+    let mut params = Params::new(network);
+    let ctx = AdjustedTime::<net::SocketAddr>::new(LOCAL_TIME);
+    let g = &mut rand::thread_rng();
+
+    params.pow_target_timespan = params.pow_target_spacing * 16;
+
+    let mut a = vec![Tree::new(genesis)];
+    for _ in 0..100 {
+        let next = a.last().unwrap().next(g);
+        a.push(next);
+    }
+    let store = store::Memory::new(NonEmpty::new(genesis));
+    let mut full = BlockCache::from(store, params.clone(), &[]).unwrap();
+    full.import_blocks(a[0].branch([&a[1], &a[100]]), &ctx)
+        .unwrap();
+
+    // Heights above the tip.
+    assert_eq!(full.median_time_past(101), full.tip_median_time_past());
+    assert_eq!(full.median_time_past(105), a[97].time);
+    assert_eq!(full.median_time_past(200), a[100].time);
+    assert_eq!(full.median_time_past(Height::MAX), a[100].time);
+
+    // Spans that were evicted from memory are read from the store.
+    let cache =
+        BlockCache::with_store_and_replay_limit(full.store.clone(), params, &[], Some(32)).unwrap();
+    assert_eq!(cache.evicted, 68);
+
+    for height in [1, 11, 50, 68, 69, 75, 101, 200] {
+        assert_eq!(
+            cache.median_time_past(height),
+            full.median_time_past(height),
+            "height = {}",
+            height
+        );
+    }
+}
+
+#[test]
+fn test_tip_median_time_past() {
+    let network = bitcoin::Network::Bitcoin;
+    let genesis = constants::genesis_block(network).header;
+    let params = Params::new(network);
+    let chain = &nakamoto_test::BITCOIN_HEADERS;
+    let cache =
+        BlockCache::from(store::Memory::new((*chain).clone()), params.clone(), &[]).unwrap();
+
+    let mut times = chain.tail[chain.tail.len() - time::MEDIAN_TIME_SPAN as usize..]
+        .iter()
+        .map(|h| h.time)
+        .collect::<Vec<_>>();
+    times.sort_unstable();
+
+    assert_eq!(cache.tip_median_time_past(), times[times.len() / 2]);
+
+    let cache = BlockCache::from(store::Memory::new(NonEmpty::new(genesis)), params, &[]).unwrap();
+    assert_eq!(cache.tip_median_time_past(), genesis.time);
+}
+
+#[test]
+fn test_time_drift() {
+    let network = bitcoin::Network::Bitcoin;
+    let genesis = constants::genesis_block(network).header;
+    let params = Params::new(network);
+    let store = store::Memory::new((*nakamoto_test::BITCOIN_HEADERS).clone());
+    let cache = BlockCache::from(store, params, &[]).unwrap();
+    let (_, tip) = cache.tip();
+
+    let clock = AdjustedTime::<net::SocketAddr>::new(LocalTime::from_block_time(tip.time + 60));
+
+    assert_eq!(cache.time_drift(cache.height(), &clock), 60);
+    assert_eq!(cache.time_drift(cache.height() + 1, &clock), 60);
+    assert_eq!(
+        cache.time_drift(0, &clock),
+        tip.time as i64 + 60 - genesis.time as i64
+    );
+
+    let clock = AdjustedTime::<net::SocketAddr>::new(LocalTime::from_block_time(tip.time - 60));
+
+    assert_eq!(
+        cache.time_drift(cache.height(), &clock),
+        -60,
+        "The drift is negative when the block is ahead of the clock"
+    );
+}
+
+#[test]
+fn test_block_at_or_before() {
+    let network = bitcoin::Network::Bitcoin;
+    let genesis = constants::genesis_block(network).header;
+    let params = Params::new(network);
+    let chain = &nakamoto_test::BITCOIN_HEADERS;
+    let store = store::Memory::new((*chain).clone());
+    let cache = BlockCache::from(store, params.clone(), &[]).unwrap();
+    let (_, tip) = cache.tip();
+
+    assert_eq!(cache.block_at_or_before(0), &genesis);
+    assert_eq!(cache.block_at_or_before(42), chain.get(42).unwrap());
+    assert_eq!(cache.block_at_or_before(cache.height()), &tip);
+    assert_eq!(cache.block_at_or_before(cache.height() + 1), &tip);
+    assert_eq!(cache.block_at_or_before(Height::MAX), &tip);
+
+    let cache = BlockCache::from(store::Memory::new(NonEmpty::new(genesis)), params, &[]).unwrap();
+    assert_eq!(cache.block_at_or_before(Height::MAX), &genesis);
+}
+
+#[test]
+fn test_target_at() {
+    let network = bitcoin::Network::Bitcoin;
+    let params = Params::new(network);
+    let chain = &nakamoto_test::BITCOIN_HEADERS;
+    let store = store::Memory::new((*chain).clone());
+    let cache = BlockCache::from(store, params.clone(), &[]).unwrap();
+
+    assert_eq!(cache.target_at(0), Some(params.pow_limit));
+    assert_eq!(cache.target_at(42), Some(chain.get(42).unwrap().target()));
+    assert_eq!(cache.target_at(cache.height() + 1), None);
+
+    // The first blocks of mainnet all have the minimum difficulty.
+    assert_eq!(cache.difficulty_at(0), Some(1.));
+    assert_eq!(cache.difficulty_at(cache.height()), Some(1.));
+    assert_eq!(cache.difficulty_at(cache.height() + 1), None);
+}
+
+#[test]
+fn test_is_synced() {
+    let network = bitcoin::Network::Bitcoin;
+    let params = Params::new(network);
+    let store = store::Memory::new((*nakamoto_test::BITCOIN_HEADERS).clone());
+    let cache = BlockCache::from(store, params, &[]).unwrap();
+    let (_, tip) = cache.tip();
+
+    let clock = AdjustedTime::<net::SocketAddr>::new(LocalTime::from_block_time(tip.time + 60));
+    assert!(cache.is_synced(&clock, 60));
+    assert!(!cache.is_synced(&clock, 59));
+
+    let clock = AdjustedTime::<net::SocketAddr>::new(LocalTime::from_block_time(tip.time - 60));
+    assert!(
+        cache.is_synced(&clock, 0),
+        "A tip ahead of the clock is synced"
+    );
+}
+
+#[test]
+fn test_get_block_by_time() {
+    let network = bitcoin::Network::Bitcoin;
+    let genesis = constants::genesis_block(network).header;
+    let params = Params::new(network);
+    let store = store::File::open(&*nakamoto_test::headers::PATH, genesis).unwrap();
+
+    let cache = BlockCache::from(store, params, &[]).unwrap();
+    let headers = cache.iter().map(|(_, h)| h).collect::<Vec<_>>();
+    let tip = headers.last().unwrap();
+
+    assert_eq!(
+        cache.get_block_by_time(genesis.time - 1),
+        Some((0, &genesis)),
+        "Times before genesis return the genesis block"
+    );
+    assert_eq!(cache.get_block_by_time(genesis.time), Some((0, &genesis)));
+    assert_eq!(
+        cache.get_block_by_time(tip.time),
+        Some((cache.height(), tip))
+    );
+    assert_eq!(
+        cache.get_block_by_time(tip.time + 1),
+        None,
+        "Times after the tip return nothing"
+    );
+
+    // Check against a linear scan.
+    for header in headers.iter().step_by(7) {
+        for time in &[header.time - 1, header.time, header.time + 1] {
+            let expected = headers
+                .iter()
+                .rposition(|h| h.time < *time)
+                .map_or(0, |i| i + 1);
+            let expected = headers.get(expected).map(|h| (expected as Height, h));
+
+            assert_eq!(cache.get_block_by_time(*time), expected);
+        }
+    }
+}
+
+#[test]
+fn test_get_block_by_time_index() {
+    let network = bitcoin::Network::Bitcoin;
+    let genesis = constants::genesis_block(network).header;
+    let params = Params::new(network);
+    let ctx = AdjustedTime::<net::SocketAddr>::new(LOCAL_TIME);
+    let chain = &nakamoto_test::BITCOIN_HEADERS;
+    let height = chain.len() as Height - 1;
+
+    let store = store::Memory::new(NonEmpty::new(genesis));
+    let mut indexed = BlockCache::from(store, params.clone(), &[])
+        .unwrap()
+        .with_time_index();
+    let plain = BlockCache::from(store::Memory::new((*chain).clone()), params, &[]).unwrap();
+
+    indexed.import_blocks(chain.iter().cloned(), &ctx).unwrap();
+
+    let check = |indexed: &BlockCache<_>| {
+        for header in chain.iter() {
+            for time in &[header.time - 1, header.time, header.time + 1] {
+                let expected = plain
+                    .get_block_by_time(*time)
+                    .filter(|(h, _)| *h <= indexed.height());
+
+                assert_eq!(indexed.get_block_by_time(*time), expected);
+            }
+        }
+    };
+    check(&indexed);
+
+    // The index is updated on rollback.
+    indexed.reset_to(height - 20).unwrap();
+    check(&indexed);
+
+    indexed
+        .import_blocks(chain.iter().skip(height as usize - 19).cloned(), &ctx)
+        .unwrap();
+    assert_eq!(indexed.height(), height);
+    check(&indexed);
+
+    // An index built from an existing chain is the same.
+    let rebuilt = indexed.clone().with_time_index();
+    assert_eq!(rebuilt.time_index, indexed.time_index);
+}
+
+#[test]
+fn prop_cache_import_ordered() {
+    fn prop(input: arbitrary::OrderedHeaders) -> bool {
+        let arbitrary::OrderedHeaders { headers } = input;
+        let mut cache = model::Cache::new(headers.head);
+        let tip = *headers.last();
+        let clock = AdjustedTime::<net::SocketAddr>::new(LOCAL_TIME);
+
+        cache
+            .import_blocks(headers.tail.iter().cloned(), &clock)
+            .unwrap();
+
+        cache.genesis() == &headers.head
+            && cache.tip() == (tip.block_hash(), tip)
+            && cache
+                .iter()
+                .all(|(i, h)| headers.get(i as usize) == Some(&h))
+    }
+    QuickCheck::with_gen(qc::StdGen::new(rand::thread_rng(), 16))
+        .quickcheck(prop as fn(arbitrary::OrderedHeaders) -> bool);
+}
+
+#[derive(Clone)]
+struct Tree {
+    headers: Arc<RwLock<BTreeMap<BlockHash, BlockHeader>>>,
+    genesis: BlockHeader,
+    hash: BlockHash,
+    time: BlockTime,
+}
+
+impl Tree {
+    fn new(genesis: BlockHeader) -> Self {
+        let headers = BTreeMap::new();
+        let hash = genesis.block_hash();
+
+        Self {
+            headers: Arc::new(RwLock::new(headers)),
+            time: genesis.time,
+            genesis,
+            hash,
+        }
+    }
+
+    fn next(&self, g: &mut impl Rng) -> Tree {
+        self.next_at(self.time + TARGET_SPACING, g)
+    }
+
+    fn next_at(&self, time: BlockTime, g: &mut impl Rng) -> Tree {
+        let nonce = g.gen::<u32>();
+        let mut header = BlockHeader {
+            version: 1,
+            prev_blockhash: self.hash,
+            merkle_root: Default::default(),
+            bits: BlockHeader::compact_target_from_u256(&TARGET),
+            time,
+            nonce,
+        };
+        block::solve(&mut header);
+
+        let hash = header.block_hash();
+        self.headers.write().unwrap().insert(hash, header);
+
+        Tree {
+            hash,
+            headers: self.headers.clone(),
+            time: header.time,
+            genesis: self.genesis,
+        }
+    }
+
+    fn next_invalid(&self, g: &mut impl Rng) -> Tree {
+        let nonce = g.gen::<u32>();
+        let mut header = BlockHeader {
+            version: 1,
+            prev_blockhash: self.hash,
+            merkle_root: Default::default(),
+            bits: BlockHeader::compact_target_from_u256(&TARGET),
+            time: self.time + TARGET_SPACING,
+            nonce,
+        };
+        let target = header.target();
+        while header.validate_pow(&target).is_ok() {
+            header.nonce += 1;
+        }
+
+        let hash = header.block_hash();
+        self.headers.write().unwrap().insert(hash, header);
+
+        Tree {
+            hash,
+            headers: self.headers.clone(),
+            time: header.time,
+            genesis: self.genesis,
+        }
+    }
+
+    fn sample<G: Gen>(&self, g: &mut G) -> Tree {
+        let headers = self.headers.read().unwrap();
+        let ix = g.gen_range(0, headers.len());
+        let header = headers.values().nth(ix).unwrap();
+
+        Tree {
+            hash: header.block_hash(),
+            headers: self.headers.clone(),
+            time: header.time,
+            genesis: self.genesis,
+        }
+    }
+
+    fn headers(&self) -> Vec<BlockHeader> {
+        self.headers
+            .read()
+            .unwrap()
+            .values()
+            .cloned()
+            .collect::<Vec<_>>()
+    }
+
+    fn block(&self) -> BlockHeader {
+        if self.hash == self.genesis.block_hash() {
+            return self.genesis;
+        }
+        self.headers
+            .read()
+            .unwrap()
+            .get(&self.hash)
+            .cloned()
+            .unwrap()
+    }
+
+    fn branch(&self, range: [&Tree; 2]) -> impl Iterator<Item = BlockHeader> {
+        let headers = self.headers.read().unwrap();
+        let mut blocks = VecDeque::new();
+
+        let [from, to] = range;
+        let mut tip = &to.hash;
+
+        while let Some(h) = headers.get(tip) {
+            blocks.push_front(*h);
+            if tip == &from.hash {
+                break;
+            }
+            tip = &h.prev_blockhash;
+        }
+
+        blocks.into_iter()
+    }
+}
+
+impl Arbitrary for Tree {
+    fn arbitrary<G: Gen>(g: &mut G) -> Tree {
+        let network = bitcoin::Network::Regtest;
+        let genesis = constants::genesis_block(network).header;
+        let height = g.gen_range(1, g.size() / 5 + 1);
+        let forks = g.gen_range(0, g.size() / 10);
+
+        let mut tree = Tree::new(genesis);
+
+        // Generate trunk.
+        for _ in 0..height {
+            tree = tree.next(g);
+        }
+        // Generate forks.
+        for _ in 0..forks {
+            let mut fork = tree.sample(g);
+            let height = g.gen_range(1, g.size() / 5 + 1);
+
+            for _ in 0..height {
+                fork = fork.next(g);
+            }
+        }
+
+        tree
+    }
+
+    fn shrink(&self) -> Box<dyn Iterator<Item = Self>> {
+        let headers = self.headers.read().unwrap().clone();
+        let mut shrunk = Vec::new();
+
+        if headers.len() <= 1 {
+            return Box::new(std::iter::empty());
+        }
+
+        {
+            let mut headers = headers.clone();
+            if let Some(tip) = headers.remove(&self.hash) {
+                if let Some(prev) = headers.get(&tip.prev_blockhash) {
+                    shrunk.push(Self {
+                        time: prev.time,
+                        hash: tip.prev_blockhash,
+                        headers: Arc::new(RwLock::new(headers)),
+                        genesis: self.genesis,
+                    });
+                }
+            }
+        }
+
+        for i in 0..headers.len() {
+            if let Some(h) = headers.keys().nth(i) {
+                if h != &self.hash {
+                    let mut headers = headers.clone();
+                    headers.remove(h);
+
+                    shrunk.push(Self {
+                        time: self.time,
+                        hash: self.hash,
+                        headers: Arc::new(RwLock::new(headers)),
+                        genesis: self.genesis,
+                    });
+                }
+            }
+        }
+
+        Box::new(shrunk.into_iter())
+    }
+}
+
+impl std::fmt::Debug for Tree {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        writeln!(fmt)?;
+        writeln!(fmt, "hash: {:#?}", &self.hash)?;
+        writeln!(fmt, "genesis: {:#?}", &self.genesis)?;
+        writeln!(fmt, "time: {:#?}", &self.time)?;
+        writeln!(fmt, "headers: {:#?}", &self.headers())?;
+
+        Ok(())
+    }
+}
+
+#[ignore]
+#[quickcheck]
+fn prop_cache_import_tree(tree: Tree) -> bool {
+    let headers = tree.headers();
+
+    let network = bitcoin::Network::Regtest;
+    let genesis = constants::genesis_block(network).header;
+    let params = Params::new(network);
+    let store = store::Memory::new(NonEmpty::new(genesis));
+
+    let ctx = AdjustedTime::<net::SocketAddr>::new(LOCAL_TIME);
+    let mut real = BlockCache::from(store, params, &[]).unwrap();
+    let mut model = model::Cache::new(genesis);
+
+    real.import_blocks(headers.iter().cloned(), &ctx).unwrap();
+    model.import_blocks(headers.iter().cloned(), &ctx).unwrap();
+
+    real.tip() == model.tip()
+}
+
+#[test]
+fn test_cache_import_back_and_forth() {
+    let network = bitcoin::Network::Regtest;
+    let genesis = constants::genesis_block(network).header;
+    let params = Params::new(network);
+    let store = store::Memory::new(NonEmpty::new(genesis));
+    let ctx = AdjustedTime::<net::SocketAddr>::new(LOCAL_TIME);
+    let mut cache = BlockCache::from(store, params, &[]).unwrap();
+
+    let g = &mut rand::thread_rng();
+
+    let a0 = Tree::new(genesis);
+
+    // a0 <- a1 <- a2 *
+    let a1 = a0.next(g);
+    let a2 = a1.next(g);
+
+    cache.import_blocks(a0.branch([&a1, &a2]), &ctx).unwrap();
+    assert_eq!(cache.tip().0, a2.hash);
+
+    // a0 <- a1 <- a2
+    //           \
+    //            <- b2 <- b3 *
+    let b2 = a1.next(g);
+    let b3 = b2.next(g);
+
+    cache.import_blocks(a0.branch([&b2, &b3]), &ctx).unwrap();
+    assert_eq!(cache.tip().0, b3.hash);
+
+    // a0 <- a1 <- a2 <- a3 <- a4 *
+    //           \
+    //            <- b2 <- b3
+    let a3 = a2.next(g);
+    let a4 = a3.next(g);
+
+    cache.import_blocks(a0.branch([&a3, &a4]), &ctx).unwrap();
+    assert_eq!(cache.tip().0, a4.hash);
+
+    // a0 <- a1 <- a2 <- a3 <- a4
+    //           \
+    //            <- b2 <- b3 <- b4 <- b5 *
+    let b4 = b3.next(g);
+    let b5 = b4.next(g);
+
+    cache.import_blocks(a1.branch([&b4, &b5]), &ctx).unwrap();
+    assert_eq!(cache.tip().0, b5.hash);
+}
+
+#[test]
+fn test_cmp_hashes() {
+    use std::cmp::Ordering;
+
+    let low =
+        BlockHash::from_hex("00000000000000000000000000000000000000000000000000000000000000ff")
+            .unwrap();
+    let high =
+        BlockHash::from_hex("ff00000000000000000000000000000000000000000000000000000000000000")
+            .unwrap();
+
+    assert!(
+        low > high,
+        "Hashes are compared lexicographically on their bytes"
+    );
+    assert_eq!(super::cmp_hashes(&low, &high), Ordering::Less);
+    assert_eq!(super::cmp_hashes(&high, &low), Ordering::Greater);
+    assert_eq!(super::cmp_hashes(&low, &low), Ordering::Equal);
+}
+
+#[test]
+fn test_cache_import_equal_difficulty_blocks() {
+    let mut headers = vec![
+        BlockHeader {
+            version: 1,
+            prev_blockhash: BlockHash::from_hex(
+                "0f9188f13cb7b2c71f2a335e3a4fc328bf5beb436012afca590b1a11466e2206",
+            )
+            .unwrap(),
+            merkle_root: Default::default(),
+            time: 1296688662,
+            bits: 545259519,
+            nonce: 3705677718,
+        },
+        BlockHeader {
+            version: 1,
+            prev_blockhash: BlockHash::from_hex(
+                "40e6856aba3aa0bab2ba97b5612dc22a485c3a583dc98a9f1cd1706dd858f623",
+            )
+            .unwrap(),
+            merkle_root: Default::default(),
+            time: 1296688722,
+            bits: 545259519,
+            nonce: 3581550584,
+        },
+        BlockHeader {
+            version: 1,
+            prev_blockhash: BlockHash::from_hex(
+                "40e6856aba3aa0bab2ba97b5612dc22a485c3a583dc98a9f1cd1706dd858f623",
+            )
+            .unwrap(),
+            merkle_root: Default::default(),
+            time: 1296688722,
+            bits: 545259519,
+            nonce: 3850925874,
+        },
+    ];
+    let network = bitcoin::Network::Regtest;
+    let genesis = constants::genesis_block(network).header;
+    let params = Params::new(network);
+    let store = store::Memory::new(NonEmpty::new(genesis));
+    let ctx = AdjustedTime::<net::SocketAddr>::new(LOCAL_TIME);
+
+    let mut real = BlockCache::from(store.clone(), params.clone(), &[]).unwrap();
+    let mut model = model::Cache::new(genesis);
+
+    model.import_blocks(headers.iter().cloned(), &ctx).unwrap();
+    real.import_blocks(headers.iter().cloned(), &ctx).unwrap();
+
+    assert_eq!(real.tip(), model.tip());
+
+    let expected =
+        BlockHash::from_hex("6941cfa462f8a3c486c9da15e249ea6fdedd0a77fcc339d095f1641685db71f8")
+            .unwrap();
+
+    assert_eq!(real.tip().0, expected, "The first block seen wins");
+    assert_eq!(model.tip().0, expected);
+
+    // Swap the import order. The other block is now seen first.
+
+    headers.swap(1, 2);
+
+    let mut real = BlockCache::from(store, params, &[]).unwrap();
+    let mut model = model::Cache::new(genesis);
+
+    model.import_blocks(headers.iter().cloned(), &ctx).unwrap();
+    real.import_blocks(headers.iter().cloned(), &ctx).unwrap();
+
+    let expected =
+        BlockHash::from_hex("79cdea612df7f65b541da8ff45913f472eb0bf9376e1b9e3cd2c6ce78f261954")
+            .unwrap();
+
+    assert_eq!(
+        real.tip().0,
+        expected,
+        "The first block seen wins over the lowest hash"
+    );
+    assert_eq!(model.tip().0, expected);
+}
+
+#[test]
+fn test_cache_import_equal_work_first_seen() {
+    let network = bitcoin::Network::Regtest;
+    let genesis = constants::genesis_block(network).header;
+    let params = Params::new(network);
+    let store = store::Memory::new(NonEmpty::new(genesis));
+    let ctx = AdjustedTime::<net::SocketAddr>::new(LOCAL_TIME);
+    let g = &mut rand::thread_rng();
+
+    // a0 <- a1 <- a2 <- a3
+    //        \
+    //         <- b2 <- b3
+    //        \
+    //         <- c2 <- c3
+    let a0 = Tree::new(genesis);
+    let a1 = a0.next(g);
+    let a3 = a1.next(g).next(g);
+    let b2 = a1.next(g);
+    let b3 = b2.next(g);
+    let c2 = a1.next(g);
+    let c3 = c2.next(g);
+
+    for (first, second) in &[(&a3, &b3), (&b3, &a3)] {
+        let mut cache = BlockCache::from(store.clone(), params.clone(), &[]).unwrap();
+
+        cache.import_blocks(a0.branch([&a1, first]), &ctx).unwrap();
+        cache.import_blocks(a0.branch([&a1, second]), &ctx).unwrap();
+
+        assert_eq!(
+            cache.tip().0,
+            first.hash,
+            "The first branch seen stays active"
+        );
+    }
+
+    // The branch whose tip was seen first wins, even if it was connected last.
+    for (first, second) in &[(&b3, &c3), (&c3, &b3)] {
+        let mut cache = BlockCache::from(store.clone(), params.clone(), &[]).unwrap();
+
+        cache.import_block(a1.block(), &ctx).unwrap();
+        cache
+            .import_blocks(
+                vec![first.block(), second.block(), b2.block(), c2.block()].into_iter(),
+                &ctx,
+            )
+            .unwrap();
+
+        assert_eq!(cache.tip().0, first.hash);
+    }
+}
+
+#[test]
+fn test_cache_import_longer_chain_with_less_difficulty() {
+    // TODO
+}
+
+#[test]
+fn test_cache_import_with_checkpoints() {
+    let network = bitcoin::Network::Regtest;
+    let genesis = constants::genesis_block(network).header;
+    let params = Params::new(network);
+    let store = store::Memory::new(NonEmpty::new(genesis));
+    let ctx = AdjustedTime::<net::SocketAddr>::new(LOCAL_TIME);
+    let g = &mut rand::thread_rng();
+
+    let tree = Tree::new(genesis);
+
+    // a0 <- a1 <- a2 <- a3 *
+    let a1 = tree.next(g);
+    let a2 = a1.next(g);
+    let a3 = a2.next(g);
+
+    let mut cache = BlockCache::from(store.clone(), params.clone(), &[]).unwrap();
+    cache.import_blocks(tree.branch([&a1, &a3]), &ctx).unwrap();
+
+    let mut cache =
+        BlockCache::from(store.clone(), params.clone(), &[(32, Default::default())]).unwrap();
+    cache
+        .import_blocks(tree.branch([&a1, &a3]), &ctx)
+        .expect("A checkpoint in the future cannot cause any error");
+
+    let mut cache =
+        BlockCache::from(store.clone(), params.clone(), &[(1, Default::default())]).unwrap();
+    assert!(
+        matches! {
+            cache.import_block(a1.block(), &ctx),
+            Err(Error::InvalidBlockHash(hash, 1)) if hash == a1.hash
+        },
+        "An incorrect checkpoint at height 1 causes an error"
+    );
+
+    let mut cache = BlockCache::from(store, params, &[(1, a1.hash), (2, a2.hash)]).unwrap();
+    cache
+        .import_blocks(tree.branch([&a1, &a2]), &ctx)
+        .expect("Correct checkpoints cause no error");
+}
+
+#[test]
+fn test_cache_verify_checkpoints() {
+    let network = bitcoin::Network::Bitcoin;
+    let genesis = constants::genesis_block(network).header;
+    let params = Params::new(network);
+    let chain = &nakamoto_test::BITCOIN_HEADERS;
+    let height = chain.len() as Height - 1;
+    let store = store::Memory::new((*chain).clone());
+    let hash = |h: Height| chain.get(h as usize).unwrap().block_hash();
+
+    let cache = BlockCache::from(store.clone(), params.clone(), &[]).unwrap();
+    cache
+        .verify_checkpoints()
+        .expect("No checkpoints cause no error");
+
+    let checkpoints = &[
+        (0, genesis.block_hash()),
+        (12, hash(12)),
+        (height, hash(height)),
+    ];
+    let cache = BlockCache::from(store.clone(), params.clone(), checkpoints).unwrap();
+    cache
+        .verify_checkpoints()
+        .expect("Correct checkpoints cause no error");
+
+    let checkpoints = &[(12, hash(12)), (height + 1, Default::default())];
+    let cache = BlockCache::from(store.clone(), params.clone(), checkpoints).unwrap();
+    cache
+        .verify_checkpoints()
+        .expect("Checkpoints above the tip are ignored");
+
+    // The chain was loaded from the store, and was therefore not checked against the
+    // checkpoints.
+    let checkpoints = &[(12, hash(12)), (24, hash(25)), (36, hash(37))];
+    let cache = BlockCache::from(store, params, checkpoints).unwrap();
+    assert!(
+        matches! {
+            cache.verify_checkpoints(),
+            Err(Error::InvalidBlockHash(h, 24)) if h == hash(24)
+        },
+        "The first incorrect checkpoint causes an error"
+    );
+}
+
+#[test]
+fn test_cache_checkpoint_satisfied() {
+    let network = bitcoin::Network::Bitcoin;
+    let params = Params::new(network);
+    let chain = &nakamoto_test::BITCOIN_HEADERS;
+    let height = chain.len() as Height - 1;
+    let store = store::Memory::new((*chain).clone());
+    let hash = |h: Height| chain.get(h as usize).unwrap().block_hash();
+
+    // Checkpoints aren't checked when the chain is loaded from the store.
+    let checkpoints = &[
+        (12, hash(12)),
+        (24, hash(25)),
+        (height, hash(height)),
+        (height + 1, Default::default()),
+    ];
+    let cache = BlockCache::from(store, params, checkpoints).unwrap();
+
+    assert_eq!(cache.checkpoint_satisfied(12), Some(true));
+    assert_eq!(cache.checkpoint_satisfied(24), Some(false));
+    assert_eq!(cache.checkpoint_satisfied(height), Some(true));
+    assert_eq!(
+        cache.checkpoint_satisfied(height + 1),
+        Some(false),
+        "The active chain doesn't reach the checkpoint"
+    );
+    assert_eq!(cache.checkpoint_satisfied(13), None);
+}
+
+#[test]
+fn test_cache_nearest_checkpoint_below() {
+    let network = bitcoin::Network::Bitcoin;
+    let params = Params::new(network);
+    let chain = &nakamoto_test::BITCOIN_HEADERS;
+    let height = chain.len() as Height - 1;
+    let store = store::Memory::new((*chain).clone());
+    let hash = |h: Height| chain.get(h as usize).unwrap().block_hash();
+
+    let cache = BlockCache::from(store.clone(), params.clone(), &[]).unwrap();
+    assert_eq!(cache.nearest_checkpoint_below(height), None);
+    assert!(!cache.is_checkpoint_height(0));
+
+    let checkpoints = &[
+        (12, hash(12)),
+        (24, hash(24)),
+        (height + 1, Default::default()),
+    ];
+    let cache = BlockCache::from(store, params, checkpoints).unwrap();
+
+    assert_eq!(cache.nearest_checkpoint_below(11), None);
+    assert_eq!(cache.nearest_checkpoint_below(12), Some((12, hash(12))));
+    assert_eq!(cache.nearest_checkpoint_below(23), Some((12, hash(12))));
+    assert_eq!(cache.nearest_checkpoint_below(height), Some((24, hash(24))));
+    assert_eq!(
+        cache.nearest_checkpoint_below(Height::MAX),
+        Some((height + 1, Default::default())),
+        "Checkpoints above the tip are included"
+    );
+    assert_eq!(cache.last_checkpoint(), 24);
+
+    assert!(cache.is_checkpoint_height(12));
+    assert!(cache.is_checkpoint_height(height + 1));
+    assert!(!cache.is_checkpoint_height(13));
+}
+
+#[test]
+fn test_cache_add_checkpoint() {
+    let network = bitcoin::Network::Regtest;
+    let genesis = constants::genesis_block(network).header;
+    let params = Params::new(network);
+    let store = store::Memory::new(NonEmpty::new(genesis));
+    let ctx = AdjustedTime::<net::SocketAddr>::new(LOCAL_TIME);
+    let mut cache = BlockCache::from(store, params, &[]).unwrap();
+    let g = &mut rand::thread_rng();
+
+    // a0 <- a1 <- a2 <- a3 <- a4 *
+    //        \           \
+    //         <- b2        <- c4 <- c5 <- c6
+    let a0 = Tree::new(genesis);
+    let a1 = a0.next(g);
+    let a2 = a1.next(g);
+    let a3 = a2.next(g);
+    let a4 = a3.next(g);
+    let b2 = a1.next(g);
+    let c4 = a3.next(g);
+    let c5 = c4.next(g);
+    let c6 = c5.next(g);
+
+    cache.import_blocks(a0.branch([&a1, &a4]), &ctx).unwrap();
+
+    assert!(matches! {
+        cache.add_checkpoint(2, b2.hash),
+        Err(Error::InvalidBlockHash(hash, 2)) if hash == a2.hash
+    });
+    cache.add_checkpoint(2, a2.hash).unwrap();
+    assert!(
+        matches! {
+            cache.import_block(b2.block(), &ctx),
+            Err(Error::InvalidBlockHeight(2))
+        },
+        "Can't fork passed the new checkpoint"
+    );
+    assert!(
+        matches! {
+            cache.add_checkpoint(1, a1.hash),
+            Err(Error::InvalidBlockHeight(1))
+        },
+        "Can't add a checkpoint below the last checkpoint"
+    );
+
+    // A checkpoint above the tip is enforced once the chain reaches it.
+    cache.add_checkpoint(6, Default::default()).unwrap();
+    assert!(matches! {
+        cache.import_blocks(a0.branch([&c4, &c6]), &ctx),
+        Err(Error::BlockImportAborted { error, index: 2, height: 5, hash, header })
+            if matches!(*error, Error::InvalidBlockHash(_, 6))
+                && hash == c6.hash
+                && *header == c6.block()
+    });
+    assert_eq!(cache.tip().0, c5.hash);
+    assert!(!cache.contains(&c6.hash));
+}
+
+#[test]
+fn test_cache_set_checkpoints() {
+    let network = bitcoin::Network::Bitcoin;
+    let params = Params::new(network);
+    let chain = &nakamoto_test::BITCOIN_HEADERS;
+    let height = chain.len() as Height - 1;
+    let store = store::Memory::new((*chain).clone());
+    let hash = |h: Height| chain.get(h as usize).unwrap().block_hash();
+    let mut cache = BlockCache::from(store, params, &[(500, hash(500))]).unwrap();
+
+    assert!(matches! {
+        cache.set_checkpoints(&[(12, hash(12)), (900, hash(901)), (24, hash(25))]),
+        Err(Error::InvalidBlockHash(h, 24)) if h == hash(24)
+    });
+    assert!(
+        cache.is_checkpoint_height(500) && !cache.is_checkpoint_height(12),
+        "The checkpoints are unchanged"
+    );
+
+    cache
+        .set_checkpoints(&[(12, hash(12)), (height + 1, Default::default())])
+        .unwrap();
+    assert!(!cache.is_checkpoint_height(500));
+    assert_eq!(cache.nearest_checkpoint_below(height), Some((12, hash(12))));
+    assert!(cache.is_checkpoint_height(height + 1));
+}
+
+#[test]
+fn test_cache_suggest_checkpoint() {
+    let network = bitcoin::Network::Regtest;
+    let genesis = constants::genesis_block(network).header;
+    let params = Params::new(network);
+    let store = store::Memory::new(NonEmpty::new(genesis));
+    let ctx = AdjustedTime::<net::SocketAddr>::new(LOCAL_TIME);
+    let mut cache = BlockCache::from(store, params, &[]).unwrap();
+    let g = &mut rand::thread_rng();
+
+    let a0 = Tree::new(genesis);
+    let a1 = a0.next(g);
+    let a2 = a1.next(g);
+    let a4 = a2.next(g).next(g);
+
+    assert_eq!(cache.suggest_checkpoint(0), Some((0, a0.hash)));
+    assert_eq!(cache.suggest_checkpoint(1), None);
+
+    cache.import_blocks(a0.branch([&a1, &a4]), &ctx).unwrap();
+
+    assert_eq!(cache.suggest_checkpoint(0), Some((4, a4.hash)));
+    assert_eq!(cache.suggest_checkpoint(2), Some((2, a2.hash)));
+    assert_eq!(cache.suggest_checkpoint(4), Some((0, a0.hash)));
+    assert_eq!(cache.suggest_checkpoint(5), None);
+
+    // The suggested checkpoint can be added as is.
+    let (height, hash) = cache.suggest_checkpoint(2).unwrap();
+    cache.add_checkpoint(height, hash).unwrap();
+    assert_eq!(cache.last_checkpoint(), 2);
+}
+
+#[test]
+fn test_cache_import_invalid_fork() {
+    let network = bitcoin::Network::Regtest;
+    let genesis = constants::genesis_block(network).header;
+    let params = Params::new(network);
+    let store = store::Memory::new(NonEmpty::new(genesis));
+    let ctx = AdjustedTime::<net::SocketAddr>::new(LOCAL_TIME);
+    let mut cache = BlockCache::from(store, params, &[]).unwrap();
+    let g = &mut rand::thread_rng();
+
+    let a0 = Tree::new(genesis);
+
+    // a0 <- a1 <- a2 <- a3 *
+    let a1 = a0.next(g);
+    let a2 = a1.next(g);
+    let a3 = a2.next(g);
+
+    cache.import_blocks(a0.branch([&a1, &a3]), &ctx).unwrap();
+    assert_eq!(cache.tip().0, a3.hash);
+
+    //            <- c2 <- c3 <- c4 <- (c5)
+    //           /
+    // a0 <- a1 <- a2 <- a3 *
+    //
+    let c2 = a1.next(g);
+    let c3 = c2.next(g);
+    let c4 = c3.next(g);
+    let c5 = c4.next_invalid(g);
+
+    cache
+        .import_blocks(a0.branch([&c3, &c5]), &ctx)
+        .unwrap_err();
+    assert_eq!(cache.tip().0, a3.hash);
+
+    cache.import_block(c2.block(), &ctx).unwrap();
+    assert_eq!(
+        cache.tip().0,
+        c4.hash,
+        "Switch to sub-fork, since the last block is invalid"
+    );
+
+    //            <- c2 <- c3 <- c4 *
+    //           /
+    // a0 <- a1 <- a2 <- a3
+    //                \
+    //                 <- (b3) <- b4 <- b5 <- b6
+    let b3 = a2.next_invalid(g);
+    let b4 = b3.next(g);
+    let b5 = b4.next(g);
+    let b6 = b5.next(g);
+
+    cache.import_blocks(a0.branch([&b4, &b6]), &ctx).unwrap();
+    cache.import_block(b3.block(), &ctx).unwrap_err();
+    assert_eq!(cache.tip().0, c4.hash, "Don't switch to invalid fork");
+}
+
+#[test]
+fn test_cache_import_fork_with_checkpoints() {
+    let network = bitcoin::Network::Regtest;
+    let genesis = constants::genesis_block(network).header;
+    let params = Params::new(network);
+    let store = store::Memory::new(NonEmpty::new(genesis));
+    let ctx = AdjustedTime::<net::SocketAddr>::new(LOCAL_TIME);
+    let g = &mut rand::thread_rng();
+
+    let a0 = Tree::new(genesis);
+
+    // a0 <- a1 <- [a2] <- a3 *
+    let a1 = a0.next(g);
+    let a2 = a1.next(g);
+    let a3 = a2.next(g);
+
+    // Prevent block `a2` from being reverted.
+    let checkpoints = &[(0, genesis.block_hash()), (2, a2.hash)];
+    let mut cache = BlockCache::from(store, params, checkpoints).unwrap();
+
+    cache.import_blocks(a0.branch([&a1, &a3]), &ctx).unwrap();
+    assert_eq!(cache.tip().0, a3.hash, "{:#?}", cache);
+
+    //            <- c2
+    //           /
+    // a0 <- a1 <- [a2] <- a3 *
+    //    \
+    //     <- b1
+    let b1 = a0.next(g);
+    let c2 = a1.next(g);
+
+    assert!(
+        matches! {
+            cache.import_block(b1.block(), &ctx),
+            Err(Error::InvalidBlockHeight(1))
+        },
+        "Can't fork passed the last checkpoint"
+    );
+    assert!(
+        matches! {
+            cache.import_block(c2.block(), &ctx),
+            Err(Error::InvalidBlockHeight(2))
+        },
+        "Can't fork passed the last checkpoint"
+    );
+
+    //            <- c2 <- c3 <- c4
+    //           /
+    // a0 <- a1 <- [a2] <- a3 *
+    let c3 = c2.next(g);
+    let c4 = c3.next(g);
+
+    // Import more blocks starting from before the last checkpoint.
+    cache.import_blocks(a0.branch([&c3, &c4]), &ctx).unwrap();
+    assert_eq!(
+        cache.tip().0,
+        a3.hash,
+        "We can import a longer chain, but it won't overtake the main chain"
+    );
+
+    // a0 <- a1 <- [a2] <- a3
+    //                  \
+    //                   <- b3 <- b4 *
+    let b3 = a2.next(g);
+    let b4 = b3.next(g);
+
+    cache.import_blocks(a0.branch([&b3, &b4]), &ctx).unwrap();
+    assert_eq!(
+        cache.tip().0,
+        b4.hash,
+        "Forking after the last checkpoint is fine"
+    );
+}
+
+#[test]
+fn test_cache_reorg_checkpointed_block() {
+    let network = bitcoin::Network::Regtest;
+    let genesis = constants::genesis_block(network).header;
+    let params = Params::new(network);
+    let ctx = AdjustedTime::<net::SocketAddr>::new(LOCAL_TIME);
+    let g = &mut rand::thread_rng();
+
+    // a0 <- a1 <- [a2] <- a3 *
+    //        \
+    //         <- b2 <- b3 <- b4 <- b5
+    let a0 = Tree::new(genesis);
+    let a1 = a0.next(g);
+    let a2 = a1.next(g);
+    let a3 = a2.next(g);
+    let b2 = a1.next(g);
+    let b3 = b2.next(g);
+    let b4 = b3.next(g);
+    let b5 = b4.next(g);
+
+    let checkpoints = &[(2, a2.hash)];
+    let fork = vec![b2.block(), b3.block(), b4.block(), b5.block()];
+
+    // No matter the order in which the longer fork is received, the checkpointed block
+    // is never re-orged away.
+    for headers in &[
+        fork.clone(),
+        fork.iter().rev().cloned().collect::<Vec<_>>(),
+        fork[1..].iter().chain(&fork[..1]).cloned().collect(),
+    ] {
+        let store = store::Memory::new(NonEmpty::new(genesis));
+        let mut cache = BlockCache::from(store, params.clone(), checkpoints).unwrap();
+        cache.import_blocks(a0.branch([&a1, &a3]), &ctx).unwrap();
+
+        for header in headers {
+            cache.import_block(*header, &ctx).ok();
+        }
+        assert_eq!(cache.tip().0, a3.hash);
+        assert!(!cache.is_known(&b2.hash));
+    }
+
+    // A checkpoint above the tip can't be bypassed by a fork either.
+    //
+    // a0 <- a1 <- a2 * ... [c3]
+    //        \
+    //         <- b2 <- b3 <- b4 <- b5
+    let store = store::Memory::new(NonEmpty::new(genesis));
+    let mut cache = BlockCache::from(store, params, &[(3, a3.hash)]).unwrap();
+    cache.import_blocks(a0.branch([&a1, &a2]), &ctx).unwrap();
+
+    for header in fork {
+        cache.import_block(header, &ctx).ok();
+    }
+    assert_eq!(cache.tip().0, a2.hash);
+}
+
+#[test]
+fn test_cache_import_fork_with_future_checkpoint() {
+    let network = bitcoin::Network::Regtest;
+    let genesis = constants::genesis_block(network).header;
+    let params = Params::new(network);
+    let store = store::Memory::new(NonEmpty::new(genesis));
+    let ctx = AdjustedTime::<net::SocketAddr>::new(LOCAL_TIME);
+    let g = &mut rand::thread_rng();
+
+    let a0 = Tree::new(genesis);
+
+    // a0 <- a1 <- a2 * ... [c4]
+    let a1 = a0.next(g);
+    let a2 = a1.next(g);
+
+    // Create a checkpoint block [c4] in the future, at height 4.
+    let checkpoints = &[(0, genesis.block_hash()), (4, BlockHash::default())];
+    let mut cache = BlockCache::from(store, params, checkpoints).unwrap();
+
+    cache.import_blocks(a0.branch([&a1, &a2]), &ctx).unwrap();
+    assert_eq!(cache.tip().0, a2.hash, "{:#?}", cache);
+
+    //            <- b2
+    //           /          ... [c4]
+    // a0 <- a1 <- a2 *
+    //
+    let b2 = a1.next(g);
+
+    // We can do this because we don't know yet whether a2 or b2 is the correct branch.
+    cache
+        .import_block(b2.block(), &ctx)
+        .expect("we can import a fork before the next checkpoint");
+}
+
+#[test]
+#[allow(unused_variables)]
+fn test_cache_import_duplicate() {
+    let network = bitcoin::Network::Regtest;
+    let genesis = constants::genesis_block(network).header;
+    let params = Params::new(network);
+    let store = store::Memory::new(NonEmpty::new(genesis));
+    let ctx = AdjustedTime::<net::SocketAddr>::new(LOCAL_TIME);
+    let mut cache = BlockCache::from(store, params, &[]).unwrap();
+    let g = &mut rand::thread_rng();
+
+    let tree = Tree::new(genesis);
+    let a0 = tree;
+
+    // a0 <- a1 <- a2 <- a3 *
+    let a1 = a0.next(g);
+    let a2 = a1.next(g);
+    let a3 = a2.next(g);
+
+    assert!(matches! {
+        cache.import_block(a1.block(), &ctx), Ok(_)
+    });
+    assert!(matches! {
+        cache.import_block(a1.block(), &ctx),
+        Ok(ImportResult::TipUnchanged)
+    });
+
+    assert!(matches! {
+        cache.import_block(a2.block(), &ctx), Ok(_)
+    });
+    assert!(matches! {
+        cache.import_block(a2.block(), &ctx), Ok(ImportResult::TipUnchanged)
+    });
+    assert!(matches! {
+        cache.import_block(a1.block(), &ctx), Ok(ImportResult::TipUnchanged)
+    });
+
+    // a0 <- a1 <- a2 <- a3 *
+    //           \
+    //            <- b3
+    let b3 = a1.next(g);
+
+    assert!(matches! {
+        cache.import_block(b3.block(), &ctx), Ok(_)
+    });
+    assert!(matches! {
+        cache.import_block(b3.block(), &ctx),
+        Ok(ImportResult::TipUnchanged)
+    });
+    assert!(matches! {
+        cache.import_block(a0.block(), &ctx),
+        Ok(ImportResult::TipUnchanged)
+    });
+
+    // Re-sent blocks after a new tip don't hide the tip change.
+    assert!(matches! {
+        cache.import_blocks(vec![a3.block(), a2.block(), b3.block()].into_iter(), &ctx),
+        Ok(ImportResult::TipChanged { hash, height: 3, .. }) if hash == a3.hash
+    });
+}
+
+#[test]
+fn test_cache_import_orphan_twice() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// The default policy, counting the targets computed, ie. the headers validated.
+    #[derive(Debug)]
+    struct Counting(Arc<AtomicUsize>);
+
+    impl DifficultyPolicy for Counting {
+        fn next_target(
+            &self,
+            ancestors: difficulty::Ancestors<'_>,
+            header: &BlockHeader,
+            params: &Params,
+        ) -> Bits {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            difficulty::Bitcoin.next_target(ancestors, header, params)
+        }
+    }
+
+    let network = bitcoin::Network::Regtest;
+    let genesis = constants::genesis_block(network).header;
+    let params = Params::new(network);
+    let store = store::Memory::new(NonEmpty::new(genesis));
+    let ctx = AdjustedTime::<net::SocketAddr>::new(LOCAL_TIME);
+    let validated = Arc::new(AtomicUsize::new(0));
+    let mut cache = BlockCache::from(store, params, &[])
+        .unwrap()
+        .with_difficulty_policy(Counting(validated.clone()));
+    let g = &mut rand::thread_rng();
+
+    // a0 <- a1 <- a2 <- a3 *
+    //        \
+    //         <- b2 <- b3
+    let a0 = Tree::new(genesis);
+    let a1 = a0.next(g);
+    let a3 = a1.next(g).next(g);
+    let b2 = a1.next(g);
+    let b3 = b2.next(g);
+
+    cache.import_blocks(a0.branch([&a1, &a3]), &ctx).unwrap();
+    cache.import_block(b2.block(), &ctx).unwrap();
+    cache.import_block(b3.block(), &ctx).unwrap();
+
+    let orphans = cache.orphan_info();
+    let seq = cache.seq;
+
+    // Re-sending an orphan doesn't look for fork candidates, which would validate the
+    // branch headers again.
+    let count = validated.load(Ordering::SeqCst);
+    for _ in 0..2 {
+        assert!(matches! {
+            cache.import_block(b3.block(), &ctx),
+            Ok(ImportResult::TipUnchanged)
+        });
+    }
+    assert!(matches! {
+        cache.import_blocks(vec![b2.block(), b3.block()].into_iter(), &ctx),
+        Ok(ImportResult::TipUnchanged)
+    });
+    assert_eq!(validated.load(Ordering::SeqCst), count);
+
+    assert_eq!(cache.orphan_info(), orphans);
+    assert_eq!(cache.seq, seq, "The orphans aren't re-inserted");
+    assert_eq!(cache.tip().0, a3.hash);
+}
+
+#[test]
+fn test_cache_import_reverted_connected() {
+    let network = bitcoin::Network::Regtest;
+    let genesis = constants::genesis_block(network).header;
+    let params = Params::new(network);
+    let store = store::Memory::new(NonEmpty::new(genesis));
+    let ctx = AdjustedTime::<net::SocketAddr>::new(LOCAL_TIME);
+    let mut cache = BlockCache::from(store, params, &[]).unwrap();
+    let g = &mut rand::thread_rng();
+
+    let a0 = Tree::new(genesis);
+
+    // a0 <- a1 <- a2 <- a3 *
+    let a1 = a0.next(g);
+    let a2 = a1.next(g);
+    let a3 = a2.next(g);
+
+    assert_eq!(
+        cache.import_block(a1.block(), &ctx).unwrap(),
+        ImportResult::TipChanged {
+            header: a1.block(),
+            hash: a1.hash,
+            height: 1,
+            reverted: vec![],
+            connected: vec![(1, a1.block())],
+            reconnected: vec![],
+            missing: vec![]
+        }
+    );
+    cache.import_blocks(a0.branch([&a2, &a3]), &ctx).unwrap();
+    assert_eq!(cache.tip().0, a3.hash);
+
+    // a0 <- a1 <- a2 <- a3
+    //           \
+    //            <- b2 <- b3 <- b4 *
+    let b2 = a1.next(g);
+    let b3 = b2.next(g);
+    let b4 = b3.next(g);
+
+    // Import the branch in reverse, so that it is only connected by the last header. The
+    // other headers are reported as reconnected orphans.
+    cache.import_block(b4.block(), &ctx).unwrap_err();
+    cache.import_block(b3.block(), &ctx).unwrap_err();
+
+    assert_eq!(
+        cache.import_block(b2.block(), &ctx).unwrap(),
+        ImportResult::TipChanged {
+            header: b2.block(),
+            hash: b4.hash,
+            height: 4,
+            reverted: vec![(2, a2.block()), (3, a3.block())],
+            connected: vec![(2, b2.block()), (3, b3.block()), (4, b4.block())],
+            reconnected: vec![b3.hash, b4.hash],
+            missing: vec![]
+        }
+    );
+
+    // a0 <- a1 <- a2 <- a3
+    //          |\
+    //          | <- b2 <- b3 <- b4
+    //           \
+    //            <- x2 <- c3 <- c4 <- c5 <- c6 *
+    //                  \
+    //                   <- d3 <- d4 <- d5
+    //
+    // Both the `c` and `d` branches are connected by `x2`. Depending on the order in which
+    // the candidates are evaluated, we may switch twice, but the result should be the same.
+    let x2 = a1.next(g);
+    let c3 = x2.next(g);
+    let c4 = c3.next(g);
+    let c5 = c4.next(g);
+    let c6 = c5.next(g);
+    let d3 = x2.next(g);
+    let d4 = d3.next(g);
+    let d5 = d4.next(g);
+
+    cache.import_blocks(x2.branch([&c3, &c6]), &ctx).unwrap();
+    cache.import_blocks(x2.branch([&d3, &d5]), &ctx).unwrap();
+    assert_eq!(cache.tip().0, b4.hash);
+
+    assert_eq!(
+        cache.import_block(x2.block(), &ctx).unwrap(),
+        ImportResult::TipChanged {
+            header: x2.block(),
+            hash: c6.hash,
+            height: 6,
+            reverted: vec![(2, b2.block()), (3, b3.block()), (4, b4.block())],
+            connected: vec![
+                (2, x2.block()),
+                (3, c3.block()),
+                (4, c4.block()),
+                (5, c5.block()),
+                (6, c6.block())
+            ],
+            reconnected: vec![c3.hash, c4.hash, c5.hash, c6.hash],
+            missing: vec![]
+        }
+    );
+}
+
+#[test]
+fn test_cache_chain_work() {
+    let network = bitcoin::Network::Regtest;
+    let genesis = constants::genesis_block(network).header;
+    let params = Params::new(network);
+    let store = store::Memory::new(NonEmpty::new(genesis));
+    let ctx = AdjustedTime::<net::SocketAddr>::new(LOCAL_TIME);
+    let mut cache = BlockCache::from(store, params, &[]).unwrap();
+    let g = &mut rand::thread_rng();
+
+    assert_eq!(cache.chain_work(), genesis.work());
+
+    // a0 <- a1 <- a2 <- a3 *
+    let a0 = Tree::new(genesis);
+    let a1 = a0.next(g);
+    let a2 = a1.next(g);
+    let a3 = a2.next(g);
+
+    cache.import_blocks(a0.branch([&a1, &a3]), &ctx).unwrap();
+    assert_eq!(cache.tip().0, a3.hash);
+    assert_eq!(
+        cache.chain_work(),
+        Branch(&cache.chain().collect::<Vec<_>>()).work()
+    );
+
+    // a0 <- a1 <- a2 <- a3
+    //           \
+    //            <- b2 <- b3 <- b4 <- b5 *
+    let b2 = a1.next(g);
+    let b3 = b2.next(g);
+    let b4 = b3.next(g);
+    let b5 = b4.next(g);
+
+    cache.import_blocks(a0.branch([&b2, &b5]), &ctx).unwrap();
+    assert_eq!(cache.tip().0, b5.hash);
+    assert_eq!(
+        cache.chain_work(),
+        Branch(&cache.chain().collect::<Vec<_>>()).work(),
+        "The stale work was subtracted and the new branch work was added"
+    );
+
+    // Each block carries the cumulative work of the chain leading up to it.
+    let headers = cache.chain().collect::<Vec<_>>();
+    for blk in cache.chain.iter() {
+        assert_eq!(
+            blk.chainwork,
+            Branch(&headers[..=blk.height as usize]).work()
+        );
+    }
+}
+
+#[test]
+fn test_cache_max_orphans() {
+    let network = bitcoin::Network::Regtest;
+    let genesis = constants::genesis_block(network).header;
+    let params = Params::new(network);
+    let store = store::Memory::new(NonEmpty::new(genesis));
+    let ctx = AdjustedTime::<net::SocketAddr>::new(LOCAL_TIME);
+    let mut cache = BlockCache::from(store, params, &[])
+        .unwrap()
+        .with_max_orphans(2);
+    let g = &mut rand::thread_rng();
+
+    // a0 <- a1 <- a2 <- a3 *
+    let a0 = Tree::new(genesis);
+    let a1 = a0.next(g);
+    let a2 = a1.next(g);
+    let a3 = a2.next(g);
+
+    cache.import_blocks(a0.branch([&a1, &a3]), &ctx).unwrap();
+    assert_eq!(cache.orphan_count(), 0);
+
+    // a0 <- a1 <- a2 <- a3 *
+    //           \
+    //            <- b2    (c2) <- c3 <- c4 <- c5
+    let b2 = a1.next(g);
+    let c2 = a1.next(g);
+    let c3 = c2.next(g);
+    let c4 = c3.next(g);
+    let c5 = c4.next(g);
+
+    cache.import_block(b2.block(), &ctx).unwrap();
+    assert_eq!(cache.orphan_count(), 1);
+
+    cache.import_blocks(a0.branch([&c3, &c5]), &ctx).unwrap();
+    assert_eq!(cache.orphan_count(), 2);
+
+    assert!(
+        cache.is_known(&b2.hash),
+        "Orphans connecting to the active chain are never evicted"
+    );
+    assert!(!cache.is_known(&c3.hash));
+    assert!(!cache.is_known(&c4.hash));
+    assert!(cache.is_known(&c5.hash));
+}
+
+#[test]
+fn test_cache_common_ancestor() {
+    let network = bitcoin::Network::Regtest;
+    let genesis = constants::genesis_block(network).header;
+    let params = Params::new(network);
+    let store = store::Memory::new(NonEmpty::new(genesis));
+    let ctx = AdjustedTime::<net::SocketAddr>::new(LOCAL_TIME);
+    let mut cache = BlockCache::from(store, params, &[]).unwrap();
+    let g = &mut rand::thread_rng();
+
+    // a0 <- a1 <- a2 <- a3 <- a4 *
+    //           \
+    //            <- b2 <- b3    (c2) <- c3 <- c4
+    let a0 = Tree::new(genesis);
+    let a1 = a0.next(g);
+    let a2 = a1.next(g);
+    let a3 = a2.next(g);
+    let a4 = a3.next(g);
+    let b2 = a1.next(g);
+    let b3 = b2.next(g);
+    let c2 = a1.next(g);
+    let c3 = c2.next(g);
+    let c4 = c3.next(g);
+
+    cache.import_blocks(a0.branch([&a1, &a4]), &ctx).unwrap();
+    cache.import_blocks(a0.branch([&b2, &b3]), &ctx).unwrap();
+    cache.import_blocks(a0.branch([&c3, &c4]), &ctx).unwrap();
+    assert_eq!(cache.tip().0, a4.hash);
+
+    assert_eq!(cache.common_ancestor(&a4.hash, &b3.hash), Some(a1.hash));
+    assert_eq!(cache.common_ancestor(&b3.hash, &a4.hash), Some(a1.hash));
+    assert_eq!(cache.common_ancestor(&b3.hash, &b2.hash), Some(b2.hash));
+    assert_eq!(cache.common_ancestor(&a2.hash, &a4.hash), Some(a2.hash));
+    assert_eq!(cache.common_ancestor(&a4.hash, &a4.hash), Some(a4.hash));
+    assert_eq!(cache.common_ancestor(&a0.hash, &b3.hash), Some(a0.hash));
+    assert_eq!(cache.common_ancestor(&c4.hash, &c3.hash), Some(c3.hash));
+
+    assert_eq!(
+        cache.common_ancestor(&c4.hash, &a4.hash),
+        None,
+        "Disconnected trees don't share an ancestor"
+    );
+    assert_eq!(
+        cache.common_ancestor(&c2.hash, &a4.hash),
+        None,
+        "Unknown blocks don't have an ancestor"
+    );
+}
+
+#[test]
+fn test_cache_ancestors() {
+    let network = bitcoin::Network::Regtest;
+    let genesis = constants::genesis_block(network).header;
+    let params = Params::new(network);
+    let store = store::Memory::new(NonEmpty::new(genesis));
+    let ctx = AdjustedTime::<net::SocketAddr>::new(LOCAL_TIME);
+    let mut cache = BlockCache::from(store, params, &[]).unwrap();
+    let g = &mut rand::thread_rng();
+
+    // a0 <- a1 <- a2 <- a3 <- a4 *
+    //           \
+    //            <- b2 <- b3    (c2) <- c3
+    let a0 = Tree::new(genesis);
+    let a1 = a0.next(g);
+    let a2 = a1.next(g);
+    let a3 = a2.next(g);
+    let a4 = a3.next(g);
+    let b2 = a1.next(g);
+    let b3 = b2.next(g);
+    let c2 = a1.next(g);
+    let c3 = c2.next(g);
+
+    cache.import_blocks(a0.branch([&a1, &a4]), &ctx).unwrap();
+    cache.import_blocks(a0.branch([&b2, &b3]), &ctx).unwrap();
+    cache.import_block(c3.block(), &ctx).ok();
+
+    let ancestors = |hash| {
+        cache
+            .ancestors(hash)
+            .map(|(height, header)| (height, header.block_hash()))
+            .collect::<Vec<_>>()
+    };
+
+    assert_eq!(
+        ancestors(&a3.hash),
+        vec![(3, a3.hash), (2, a2.hash), (1, a1.hash), (0, a0.hash)]
+    );
+    assert_eq!(
+        ancestors(&b3.hash),
+        vec![(3, b3.hash), (2, b2.hash), (1, a1.hash), (0, a0.hash)]
+    );
+    assert_eq!(ancestors(&a0.hash), vec![(0, a0.hash)]);
+    assert_eq!(
+        ancestors(&c2.hash),
+        vec![],
+        "Unknown blocks have no ancestors"
+    );
+    assert_eq!(
+        ancestors(&c3.hash),
+        vec![],
+        "Disconnected blocks have no ancestors"
+    );
+
+    assert_eq!(
+        cache
+            .ancestors(&a4.hash)
+            .find(|(_, h)| h.block_hash() == a2.hash)
+            .map(|(height, _)| height),
+        Some(2)
+    );
+}
+
+#[test]
+fn test_cache_import_blocks_with() {
+    let network = bitcoin::Network::Regtest;
+    let genesis = constants::genesis_block(network).header;
+    let params = Params::new(network);
+    let store = store::Memory::new(NonEmpty::new(genesis));
+    let ctx = AdjustedTime::<net::SocketAddr>::new(LOCAL_TIME);
+    let mut cache = BlockCache::from(store, params, &[]).unwrap();
+    let g = &mut rand::thread_rng();
+
+    // a0 <- a1 <- a2 <- a3 <- a4 <- a5 <- a6
+    let a0 = Tree::new(genesis);
+    let a1 = a0.next(g);
+    let a4 = a1.next(g).next(g).next(g);
+    let a6 = a4.next(g).next(g);
+
+    let mut progress = Vec::new();
+    cache
+        .import_blocks_with(a0.branch([&a1, &a4]), &ctx, |height| progress.push(height))
+        .unwrap();
+    assert_eq!(progress, vec![1, 2, 3, 4]);
+
+    // Duplicates and blocks that don't change the active chain are not reported.
+    let mut progress = Vec::new();
+    cache
+        .import_blocks_with(a0.branch([&a1, &a6]), &ctx, |height| progress.push(height))
+        .unwrap();
+    assert_eq!(progress, vec![5, 6]);
+
+    // Errors are the same as with `import_blocks`.
+    let a7 = a6.next_invalid(g);
+    let a8 = a7.next(g);
+    let mut progress = Vec::new();
+    let err = cache
+        .import_blocks_with(a0.branch([&a7, &a8]), &ctx, |height| progress.push(height))
+        .unwrap_err();
+
+    assert!(matches!(
+        err,
+        Error::BlockImportAborted {
+            index: 0,
+            height: 6,
+            ..
+        }
+    ));
+    assert!(progress.is_empty());
+}
+
+#[test]
+fn test_cache_import_reverse() {
+    let network = bitcoin::Network::Regtest;
+    let genesis = constants::genesis_block(network).header;
+    let params = Params::new(network);
+    let store = store::Memory::new(NonEmpty::new(genesis));
+    let ctx = AdjustedTime::<net::SocketAddr>::new(LOCAL_TIME);
+    let mut cache = BlockCache::from(store, params, &[]).unwrap();
+    let g = &mut rand::thread_rng();
+
+    // a0 <- a1 <- ... <- a8
+    let a0 = Tree::new(genesis);
+    let mut chain = vec![a0.next(g)];
+    for _ in 0..7 {
+        chain.push(chain.last().unwrap().next(g));
+    }
+    let headers = a0.branch([&chain[0], &chain[7]]).collect::<Vec<_>>();
+
+    // Import everything but the first header, in reverse order.
+    for header in headers.iter().skip(1).rev() {
+        cache.import_block(*header, &ctx).unwrap_err();
+        assert_eq!(cache.tip().0, a0.hash);
+    }
+
+    // Once the first header arrives, the whole chain is connected.
+    cache.import_block(headers[0], &ctx).unwrap();
+    assert_eq!(cache.tip().0, chain[7].hash);
+    assert_eq!(cache.orphan_count(), 0);
+
+    // a8 <- b9 <- b10 <- b11 <- b12 *
+    let b9 = chain[7].next(g);
+    let b10 = b9.next(g);
+    let b11 = b10.next(g);
+    let b12 = b11.next(g);
+
+    // Leave a gap in the middle, and fill it last.
+    cache.import_block(b9.block(), &ctx).unwrap();
+    cache.import_block(b12.block(), &ctx).unwrap_err();
+    cache.import_block(b11.block(), &ctx).unwrap_err();
+    assert_eq!(cache.tip().0, b9.hash);
+
+    cache.import_block(b10.block(), &ctx).unwrap();
+    assert_eq!(cache.tip().0, b12.hash);
+    assert_eq!(cache.height(), 12);
+}
+
+#[test]
+fn test_cache_reset_to() {
+    let network = bitcoin::Network::Regtest;
+    let genesis = constants::genesis_block(network).header;
+    let params = Params::new(network);
+    let store = store::Memory::new(NonEmpty::new(genesis));
+    let ctx = AdjustedTime::<net::SocketAddr>::new(LOCAL_TIME);
+    let g = &mut rand::thread_rng();
+
+    // a0 <- a1 <- a2 <- a3 <- a4 *
+    //             \
+    //              <- b3 <- b4 <- b5 <- b6 <- b7
+    let a0 = Tree::new(genesis);
+    let a1 = a0.next(g);
+    let a2 = a1.next(g);
+    let a3 = a2.next(g);
+    let a4 = a3.next(g);
+    let b3 = a2.next(g);
+    let b4 = b3.next(g);
+    let b5 = b4.next(g);
+    let b6 = b5.next(g);
+    let b7 = b6.next(g);
+
+    let checkpoints = &[(1, a1.hash)];
+    let mut cache = BlockCache::from(store, params, checkpoints).unwrap();
+
+    cache.import_blocks(a0.branch([&a1, &a4]), &ctx).unwrap();
+    // Import a fork off the part of the chain we're removing, with `b4` missing.
+    cache.import_block(b3.block(), &ctx).unwrap();
+    cache.import_blocks(a0.branch([&b5, &b7]), &ctx).unwrap();
+    assert_eq!(cache.tip().0, a4.hash);
+    assert_eq!(cache.orphan_count(), 4);
+
+    assert!(
+        matches! {
+            cache.reset_to(0),
+            Err(Error::InvalidBlockHeight(0))
+        },
+        "Can't roll back passed the last checkpoint"
+    );
+    assert_eq!(cache.tip().0, a4.hash);
+    assert!(cache.reset_to(4).unwrap().is_empty());
+    assert!(cache.reset_to(5).unwrap().is_empty());
+
+    let removed = cache.reset_to(1).unwrap();
+    assert_eq!(removed, vec![a2.block(), a3.block(), a4.block()]);
+    assert_eq!(cache.tip().0, a1.hash);
+    assert_eq!(cache.height(), 1);
+    assert!(!cache.is_known(&a2.hash));
+    assert!(!cache.is_known(&b3.hash));
+    // Since `b4` is missing, `b5` to `b7` aren't known to descend from the removed blocks.
+    assert_eq!(cache.orphan_count(), 3);
+    assert!(cache.is_known(&b7.hash));
+    assert_eq!(cache.store.len().unwrap(), 2);
+
+    // The chain can be re-synced from the reset height.
+    cache.import_blocks(a0.branch([&a2, &a4]), &ctx).unwrap();
+    assert_eq!(cache.tip().0, a4.hash);
+}
+
+#[test]
+fn test_cache_switch_to_stale_fork() {
+    let network = bitcoin::Network::Regtest;
+    let genesis = constants::genesis_block(network).header;
+    let params = Params::new(network);
+    let store = store::Memory::new(NonEmpty::new(genesis));
+    let ctx = AdjustedTime::<net::SocketAddr>::new(LOCAL_TIME);
+    let mut cache = BlockCache::from(store, params, &[]).unwrap();
+    let g = &mut rand::thread_rng();
+
+    // a0 <- a1 <- a2 * ... [a3] <- b4 <- b5
+    //  \
+    //   <- [c1] <- c2 <- c3
+    let a0 = Tree::new(genesis);
+    let a1 = a0.next(g);
+    let a2 = a1.next(g);
+    let a3 = a2.next(g);
+    let b4 = a3.next(g);
+    let b5 = b4.next(g);
+    let c1 = a0.next(g);
+    let c2 = c1.next(g);
+    let c3 = c2.next(g);
+
+    cache.import_blocks(a0.branch([&a1, &a2]), &ctx).unwrap();
+
+    // Candidates recorded against a different state of the active chain, eg. before
+    // another branch was activated.
+    let stale = [
+        // Fork height above the tip.
+        Candidate {
+            tip: b5.hash,
+            headers: vec![b4.block(), b5.block()],
+            fork_height: 3,
+        },
+        // Fork block no longer on the active chain.
+        Candidate {
+            tip: c3.hash,
+            headers: vec![c2.block(), c3.block()],
+            fork_height: 1,
+        },
+        // No headers to activate.
+        Candidate {
+            tip: b5.hash,
+            headers: vec![],
+            fork_height: 5,
+        },
+    ];
+    for branch in stale.iter() {
+        assert!(matches!(
+            cache.switch_to_fork(branch, &ctx),
+            Err(Error::BlockMissing(_))
+        ));
+        assert_eq!(cache.tip().0, a2.hash);
+        assert_eq!(cache.height(), 2);
+        assert_eq!(cache.store.len().unwrap(), 3);
+    }
+}
+
+#[test]
+fn test_cache_reorg_back() {
+    let network = bitcoin::Network::Regtest;
+    let genesis = constants::genesis_block(network).header;
+    let params = Params::new(network);
+    let ctx = AdjustedTime::<net::SocketAddr>::new(LOCAL_TIME);
+    let g = &mut rand::thread_rng();
+
+    // a0 <- a1 <- a2 <- a3 <- a4 <- a5
+    //        \
+    //         <- b2 <- b3 <- b4
+    let a0 = Tree::new(genesis);
+    let a1 = a0.next(g);
+    let a2 = a1.next(g);
+    let a3 = a2.next(g);
+    let a4 = a3.next(g);
+    let a5 = a4.next(g);
+    let b2 = a1.next(g);
+    let b3 = b2.next(g);
+    let b4 = b3.next(g);
+
+    let store = store::Memory::new(NonEmpty::new(genesis));
+    let mut cache = BlockCache::from(store, params.clone(), &[]).unwrap();
+
+    // A -> B.
+    cache.import_blocks(a0.branch([&a1, &a3]), &ctx).unwrap();
+    let work = cache.chain_work();
+    cache.import_blocks(a0.branch([&b2, &b4]), &ctx).unwrap();
+    assert_eq!(cache.tip().0, b4.hash);
+
+    // The work of the rolled back branch is kept.
+    assert_eq!(cache.orphans[&a3.hash].chainwork, Some(work));
+    assert!(cache.orphans[&a2.hash].chainwork.is_some());
+
+    // B -> A.
+    let work = cache.chain_work();
+    cache.import_blocks(a0.branch([&a4, &a5]), &ctx).unwrap();
+    assert_eq!(cache.tip().0, a5.hash);
+    assert_eq!(cache.orphans[&b4.hash].chainwork, Some(work));
+
+    // The state is the same as if we had never re-orged.
+    let store = store::Memory::new(NonEmpty::new(genesis));
+    let mut expected = BlockCache::from(store, params, &[]).unwrap();
+    expected.import_blocks(a0.branch([&a1, &a5]), &ctx).unwrap();
+    expected.import_blocks(a0.branch([&b2, &b4]), &ctx).unwrap();
+
+    assert_eq!(cache.tip(), expected.tip());
+    assert_eq!(cache.chain_work(), expected.chain_work());
+    assert_eq!(cache.headers, expected.headers);
+    assert_eq!(
+        cache.iter().collect::<Vec<_>>(),
+        expected.iter().collect::<Vec<_>>()
+    );
+    assert_eq!(
+        cache
+            .chain
+            .iter()
+            .map(|blk| blk.chainwork)
+            .collect::<Vec<_>>(),
+        expected
+            .chain
+            .iter()
+            .map(|blk| blk.chainwork)
+            .collect::<Vec<_>>()
+    );
+    assert_eq!(cache.orphan_count(), expected.orphan_count());
+    assert_eq!(cache.store.len().unwrap(), expected.store.len().unwrap());
+}
+
+#[test]
+fn test_cache_reset() {
+    let network = bitcoin::Network::Regtest;
+    let genesis = constants::genesis_block(network).header;
+    let params = Params::new(network);
+    let ctx = AdjustedTime::<net::SocketAddr>::new(LOCAL_TIME);
+    let g = &mut rand::thread_rng();
+
+    // a0 <- a1 <- a2 <- a3 *
+    //        \
+    //         <- b2 <- [b3] <- b4
+    let a0 = Tree::new(genesis);
+    let a1 = a0.next(g);
+    let a2 = a1.next(g);
+    let a3 = a2.next(g);
+    let b2 = a1.next(g);
+    let b3 = b2.next(g);
+    let b4 = b3.next(g);
+
+    let mut cache = BlockCache::from(
+        store::Memory::new(NonEmpty::new(genesis)),
+        params.clone(),
+        &[],
+    )
+    .unwrap();
+    cache.import_blocks(a0.branch([&a1, &a3]), &ctx).unwrap();
+    cache.import_blocks(iter::once(b4.block()), &ctx).unwrap();
+    assert_eq!(cache.orphan_count(), 1);
+
+    cache.reset().unwrap();
+    assert_eq!(cache.tip().0, genesis.block_hash());
+    assert_eq!(cache.height(), 0);
+    assert_eq!(cache.orphan_count(), 0);
+    assert!(!cache.is_known(&a1.hash));
+    assert_eq!(cache.store.len().unwrap(), 1);
+
+    // The cache can be reused.
+    cache.import_blocks(a0.branch([&a1, &a3]), &ctx).unwrap();
+    assert_eq!(cache.tip().0, a3.hash);
+
+    // With a checkpoint, the cache is reset to it.
+    let checkpoints = &[(1, a1.hash)];
+    let mut cache = BlockCache::from(
+        store::Memory::new(NonEmpty::new(genesis)),
+        params,
+        checkpoints,
+    )
+    .unwrap();
+    cache.import_blocks(a0.branch([&a1, &a3]), &ctx).unwrap();
+    cache.import_block(b2.block(), &ctx).unwrap();
+    cache.import_blocks(iter::once(b4.block()), &ctx).unwrap();
+    assert_eq!(cache.orphan_count(), 2);
+
+    cache.reset().unwrap();
+    assert_eq!(cache.tip().0, a1.hash);
+    assert_eq!(cache.orphan_count(), 0);
+    assert_eq!(cache.store.len().unwrap(), 2);
+}
+
+#[test]
+fn test_cache_rollback_to() {
+    let network = bitcoin::Network::Regtest;
+    let genesis = constants::genesis_block(network).header;
+    let params = Params::new(network);
+    let store = store::Memory::new(NonEmpty::new(genesis));
+    let ctx = AdjustedTime::<net::SocketAddr>::new(LOCAL_TIME);
+    let g = &mut rand::thread_rng();
+
+    // a0 <- [a1] <- a2 <- a3 <- a4 *
+    //               \
+    //                <- b3
+    let a0 = Tree::new(genesis);
+    let a1 = a0.next(g);
+    let a2 = a1.next(g);
+    let a4 = a2.next(g).next(g);
+    let b3 = a2.next(g);
+    let unknown = b3.next(g);
+
+    let mut cache = BlockCache::from(store, params, &[(1, a1.hash)]).unwrap();
+
+    cache.import_blocks(a0.branch([&a1, &a4]), &ctx).unwrap();
+    cache.import_block(b3.block(), &ctx).unwrap();
+
+    assert!(matches! {
+        cache.rollback_to(&b3.hash),
+        Err(Error::BlockMissing(hash)) if hash == b3.hash
+    });
+    assert!(matches! {
+        cache.rollback_to(&unknown.hash),
+        Err(Error::BlockMissing(_))
+    });
+    assert!(
+        matches! {
+            cache.rollback_to(&a0.hash),
+            Err(Error::InvalidBlockHeight(0))
+        },
+        "Can't roll back past the last checkpoint"
+    );
+    assert!(cache.rollback_to(&a4.hash).unwrap().is_empty());
+    assert_eq!(cache.tip().0, a4.hash);
+
+    let removed = cache.rollback_to(&a2.hash).unwrap();
+    assert_eq!(removed, a0.branch([&a2, &a4]).skip(1).collect::<Vec<_>>());
+    assert_eq!(cache.tip().0, a2.hash);
+    assert_eq!(cache.store.height().unwrap(), 2);
+    assert!(cache.is_known(&b3.hash), "Forks off the new tip are kept");
+
+    cache.rollback_to(&a1.hash).unwrap();
+    assert_eq!(cache.tip().0, a1.hash);
+    assert!(!cache.is_known(&b3.hash));
+}
+
+#[test]
+fn test_cache_switch_to_fork_invalid() {
+    let network = bitcoin::Network::Regtest;
+    let genesis = constants::genesis_block(network).header;
+    let params = Params::new(network);
+    let store = store::Memory::new(NonEmpty::new(genesis));
+    let ctx = AdjustedTime::<net::SocketAddr>::new(LOCAL_TIME);
+    let mut cache = BlockCache::from(store, params, &[]).unwrap();
+    let g = &mut rand::thread_rng();
+
+    // a0 <- a1 <- a2 <- a3 <- a4 *
+    //        \
+    //         <- b2 <- b3 <- b4
+    let a0 = Tree::new(genesis);
+    let a1 = a0.next(g);
+    let a2 = a1.next(g);
+    let a3 = a2.next(g);
+    let a4 = a3.next(g);
+
+    // The median time past at the height of `b4` is `a2`'s time on the active chain,
+    // but `b3`'s time on the `b` branch, which is greater than `b4`'s.
+    let b2 = a1.next_at(a1.time + 1000, g);
+    let b3 = b2.next_at(b2.time + 1, g);
+    let b4 = b3.next_at(a2.time + 1, g);
+
+    cache.import_blocks(a0.branch([&a1, &a4]), &ctx).unwrap();
+    cache.import_block(b2.block(), &ctx).unwrap();
+    cache.import_block(b3.block(), &ctx).unwrap();
+
+    // The branch is invalid. It isn't a candidate, but switching to it directly fails
+    // too, once the active chain is rolled back.
+    let seq = cache.next_seq();
+    cache.insert_orphan(b4.hash, b4.block(), seq, None);
+    let candidate = cache.fork(&b4.hash).unwrap();
+    assert!(matches! {
+        cache.validate_branch(&candidate, &[], &ctx),
+        Err((2, Error::InvalidBlockTime(_, std::cmp::Ordering::Less)))
+    });
+    assert!(matches! {
+        cache.switch_to_fork(&candidate, &ctx),
+        Err(Error::InvalidBlockTime(_, std::cmp::Ordering::Less))
+    });
+
+    // The active chain is left intact.
+    assert_eq!(cache.tip().0, a4.hash);
+    assert_eq!(cache.height(), 4);
+    assert_eq!(cache.store.len().unwrap(), 5);
+    assert_eq!(
+        cache.iter().map(|(_, h)| h).collect::<Vec<_>>(),
+        vec![a0.block(), a1.block(), a2.block(), a3.block(), a4.block()]
+    );
+    assert_eq!(cache.orphan_count(), 3);
+
+    // Importing the branch normally doesn't activate it either.
+    cache.remove_orphan(&b4.hash);
+    assert!(matches! {
+        cache.import_block(b4.block(), &ctx),
+        Ok(ImportResult::TipUnchanged)
+    });
+    assert_eq!(cache.tip().0, a4.hash);
+    assert_eq!(cache.store.len().unwrap(), 5);
+}
+
+#[test]
+fn test_cache_switch_to_best_branch() {
+    let network = bitcoin::Network::Regtest;
+    let genesis = constants::genesis_block(network).header;
+    let params = Params::new(network);
+    let store = store::Memory::new(NonEmpty::new(genesis));
+    let ctx = AdjustedTime::<net::SocketAddr>::new(LOCAL_TIME);
+    let reorgs = Arc::new(RwLock::new(0));
+    let mut cache = BlockCache::from(store, params, &[]).unwrap().on_reorg({
+        let reorgs = reorgs.clone();
+        move |_, _, _| *reorgs.write().unwrap() += 1
+    });
+    let g = &mut rand::thread_rng();
+
+    // a0 <- a1 <- a2 *
+    //        \
+    //         <- x2 <- b3
+    //             \
+    //              <- c3 <- c4
+    let a0 = Tree::new(genesis);
+    let a1 = a0.next(g);
+    let a2 = a1.next(g);
+    let x2 = a1.next(g);
+    let b3 = x2.next(g);
+    let c3 = x2.next(g);
+    let c4 = c3.next(g);
+
+    cache.import_blocks(a0.branch([&a1, &a2]), &ctx).unwrap();
+    cache
+        .import_blocks(vec![b3.block(), c3.block(), c4.block()].into_iter(), &ctx)
+        .unwrap();
+    assert_eq!(cache.tip().0, a2.hash);
+
+    // Both `b3` and `c4` have more work than the active chain once `x2` is imported.
+    // Switching to `b3` first would roll back `a2`, and then `x2` and `b3`, causing two
+    // re-orgs instead of one.
+
+    match cache.import_block(x2.block(), &ctx) {
+        Ok(ImportResult::TipChanged {
+            hash,
+            height,
+            reverted,
+            connected,
+            ..
+        }) => {
+            assert_eq!(hash, c4.hash);
+            assert_eq!(height, 4);
+            assert_eq!(reverted, vec![(2, a2.block())]);
+            assert_eq!(
+                connected,
+                vec![(2, x2.block()), (3, c3.block()), (4, c4.block())]
+            );
+        }
+        result => panic!("unexpected import result: {:?}", result),
+    }
+    assert_eq!(*reorgs.read().unwrap(), 1);
+    assert_eq!(cache.tip().0, c4.hash);
+    assert_eq!(cache.store.len().unwrap(), 5);
+}
+
+#[test]
+fn test_cache_import_block() {
+    let network = bitcoin::Network::Regtest;
+    let genesis = constants::genesis_block(network).header;
+    let params = Params::new(network);
+    let store = store::Memory::new(NonEmpty::new(genesis));
+    let ctx = AdjustedTime::<net::SocketAddr>::new(LOCAL_TIME);
+    let mut cache = BlockCache::from(store, params, &[]).unwrap();
+    let g = &mut rand::thread_rng();
+
+    // a0 <- a1 *
+    //  \
+    //   <- (b1) <- b2
+    let a0 = Tree::new(genesis);
+    let a1 = a0.next(g);
+    let b1 = a0.next(g);
+    let b2 = b1.next(g);
+
+    cache.import_block(a1.block(), &ctx).unwrap();
+
+    // Headers that don't extend the tip are ignored by `extend_tip`.
+    assert_eq!(
+        cache.extend_tip(b2.block(), &ctx).unwrap(),
+        ImportResult::TipUnchanged
+    );
+    assert!(!cache.is_known(&b2.hash));
+
+    assert!(matches! {
+        cache.import_block(b2.block(), &ctx),
+        Err(Error::BlockMissing(hash)) if hash == b1.hash
+    });
+    assert!(cache.is_known(&b2.hash), "The orphan is kept");
+
+    assert!(matches! {
+        cache.import_block(b1.block(), &ctx),
+        Ok(ImportResult::TipChanged { hash, height: 2, .. }) if hash == b2.hash
+    });
+    assert_eq!(
+        cache.import_block(b1.block(), &ctx).unwrap(),
+        ImportResult::TipUnchanged
+    );
+}
+
+#[test]
+fn test_cache_connected_range() {
+    let network = bitcoin::Network::Regtest;
+    let genesis = constants::genesis_block(network).header;
+    let params = Params::new(network);
+    let store = store::Memory::new(NonEmpty::new(genesis));
+    let ctx = AdjustedTime::<net::SocketAddr>::new(LOCAL_TIME);
+    let mut cache = BlockCache::from(store, params, &[]).unwrap();
+    let g = &mut rand::thread_rng();
+
+    // a0 <- a1 <- a2 <- a3
+    //        \
+    //         <- (b2) <- b3 <- b4 *
+    let a0 = Tree::new(genesis);
+    let a1 = a0.next(g);
+    let a2 = a1.next(g);
+    let a3 = a2.next(g);
+    let b2 = a1.next(g);
+    let b3 = b2.next(g);
+    let b4 = b3.next(g);
+
+    let result = cache.import_block(a1.block(), &ctx).unwrap();
+    assert_eq!(result.connected_range(), Some(1..2));
+
+    let result = cache.extend_tip(a2.block(), &ctx).unwrap();
+    assert_eq!(result.connected_range(), Some(2..3));
+
+    cache.import_block(a3.block(), &ctx).unwrap();
+    cache.import_blocks(a0.branch([&b3, &b4]), &ctx).unwrap();
+
+    let result = cache.import_block(b2.block(), &ctx).unwrap();
+    assert_eq!(cache.tip().0, b4.hash);
+    assert_eq!(result.connected_range(), Some(2..5));
+
+    let result = cache.import_block(a2.block(), &ctx).unwrap();
+    assert_eq!(result, ImportResult::TipUnchanged);
+    assert_eq!(result.connected_range(), None);
+}
+
+#[test]
+fn test_cache_on_reorg() {
+    let network = bitcoin::Network::Regtest;
+    let genesis = constants::genesis_block(network).header;
+    let params = Params::new(network);
+    let store = store::Memory::new(NonEmpty::new(genesis));
+    let ctx = AdjustedTime::<net::SocketAddr>::new(LOCAL_TIME);
+    let events = Arc::new(RwLock::new(Vec::new()));
+    let mut cache = BlockCache::from(store, params, &[]).unwrap().on_reorg({
+        let events = events.clone();
+        move |height, disconnected, connected| {
+            events
+                .write()
+                .unwrap()
+                .push((height, disconnected.to_vec(), connected.to_vec()));
+        }
+    });
+    let g = &mut rand::thread_rng();
+
+    // a0 <- a1 <- a2 <- a3
+    //        \
+    //         <- b2 <- b3 <- b4 *
+    let a0 = Tree::new(genesis);
+    let a1 = a0.next(g);
+    let a2 = a1.next(g);
+    let a3 = a2.next(g);
+    let b2 = a1.next(g);
+    let b3 = b2.next(g);
+    let b4 = b3.next(g);
+
+    cache.import_blocks(a0.branch([&a1, &a3]), &ctx).unwrap();
+    assert!(
+        events.read().unwrap().is_empty(),
+        "Extending the active chain doesn't trigger a re-org"
+    );
+
+    // Import the branch in reverse order, so that it is only activated once complete.
+    // Otherwise, `b3` could win the tie-break against `a3`.
+    let branch = a0.branch([&b2, &b4]).collect::<Vec<_>>();
+    cache.import_blocks(branch.into_iter().rev(), &ctx).unwrap();
+    assert_eq!(cache.tip().0, b4.hash);
+    assert_eq!(
+        events.read().unwrap().as_slice(),
+        &[(
+            1,
+            vec![a2.block(), a3.block()],
+            vec![b2.block(), b3.block(), b4.block()]
+        )]
+    );
+}
+
+#[test]
+fn test_cache_on_connect() {
+    let network = bitcoin::Network::Regtest;
+    let genesis = constants::genesis_block(network).header;
+    let params = Params::new(network);
+    let ctx = AdjustedTime::<net::SocketAddr>::new(LOCAL_TIME);
+    let g = &mut rand::thread_rng();
+
+    // a0 <- a1 <- a2 <- a3
+    //        \
+    //         <- b2 <- b3 <- b4 *
+    let a0 = Tree::new(genesis);
+    let a1 = a0.next(g);
+    let a2 = a1.next(g);
+    let a3 = a2.next(g);
+    let b2 = a1.next(g);
+    let b3 = b2.next(g);
+    let b4 = b3.next(g);
+
+    // Blocks connected, and not disconnected since.
+    let ledger = Arc::new(RwLock::new(Vec::<(Height, BlockHash)>::new()));
+    let store = store::Memory::new(NonEmpty::from((genesis, vec![a1.block()])));
+    let mut cache = BlockCache::from(store, params, &[])
+        .unwrap()
+        .on_connect({
+            let ledger = ledger.clone();
+            move |height, header| ledger.write().unwrap().push((height, header.block_hash()))
+        })
+        .on_disconnect({
+            let ledger = ledger.clone();
+            move |height, header| {
+                assert_eq!(
+                    ledger.write().unwrap().pop(),
+                    Some((height, header.block_hash())),
+                    "Blocks are disconnected in the reverse order they were connected"
+                );
+            }
+        });
+    let active = |cache: &BlockCache<_>| {
+        cache
+            .range(2..cache.height() + 1)
+            .map(|blk| (blk.height, blk.hash))
+            .collect::<Vec<_>>()
+    };
+    assert!(
+        ledger.read().unwrap().is_empty(),
+        "Stored blocks aren't connected"
+    );
+
+    cache.import_blocks(a0.branch([&a2, &a3]), &ctx).unwrap();
+    assert_eq!(*ledger.read().unwrap(), active(&cache));
+
+    let branch = a0.branch([&b2, &b4]).collect::<Vec<_>>();
+    cache.import_blocks(branch.into_iter().rev(), &ctx).unwrap();
+    assert_eq!(cache.tip().0, b4.hash);
+    assert_eq!(*ledger.read().unwrap(), active(&cache));
+
+    cache.rollback_to(&b2.hash).unwrap();
+    assert_eq!(*ledger.read().unwrap(), vec![(2, b2.hash)]);
+}
+
+#[test]
+fn test_cache_on_connect_store_failure() {
+    let network = bitcoin::Network::Regtest;
+    let genesis = constants::genesis_block(network).header;
+    let params = Params::new(network);
+    let ctx = AdjustedTime::<net::SocketAddr>::new(LOCAL_TIME);
+    let g = &mut rand::thread_rng();
+
+    // a0 <- a1 <- a2 <- (a3)
+    //        \
+    //         <- b2 <- b3 <- b4 <- b5 *
+    let a0 = Tree::new(genesis);
+    let a1 = a0.next(g);
+    let a2 = a1.next(g);
+    let a3 = a2.next(g);
+    let b2 = a1.next(g);
+    let b3 = b2.next(g);
+    let b4 = b3.next(g);
+    let b5 = b4.next(g);
+
+    // Hook calls, in order: reorgs are `(0, fork height)`, connections `(1, height)` and
+    // disconnections `(-1, height)`.
+    let calls = Arc::new(RwLock::new(Vec::<(i8, Height)>::new()));
+    let store = FailingStore {
+        store: store::Memory::new(NonEmpty::new(genesis)),
+        writes: 0,
+        fail: vec![],
+    };
+    let mut cache = BlockCache::from(store, params, &[])
+        .unwrap()
+        .on_reorg({
+            let calls = calls.clone();
+            move |height, _, _| calls.write().unwrap().push((0, height))
+        })
+        .on_connect({
+            let calls = calls.clone();
+            move |height, _| calls.write().unwrap().push((1, height))
+        })
+        .on_disconnect({
+            let calls = calls.clone();
+            move |height, _| calls.write().unwrap().push((-1, height))
+        });
+    let calls = move || calls.write().unwrap().drain(..).collect::<Vec<_>>();
+
+    cache.import_blocks(a0.branch([&a1, &a2]), &ctx).unwrap();
+    assert_eq!(calls(), vec![(1, 1), (1, 2)]);
+
+    // Blocks that can't be stored aren't connected.
+    cache.store.fail_next(1);
+    cache
+        .import_blocks(iter::once(a3.block()), &ctx)
+        .unwrap_err();
+    assert_eq!(calls(), vec![]);
+
+    // Neither are the blocks of a fork that can't be stored, and the active chain isn't
+    // disconnected.
+    cache.store.fail_next(1);
+    let branch = a0.branch([&b2, &b4]).collect::<Vec<_>>();
+    cache
+        .import_blocks(branch.into_iter().rev(), &ctx)
+        .unwrap_err();
+    assert_eq!(cache.tip().0, a2.hash);
+    assert_eq!(calls(), vec![]);
+
+    // Once the fork is stored, the active chain is disconnected, and the fork connected.
+    cache.import_blocks(iter::once(b5.block()), &ctx).unwrap();
+    assert_eq!(cache.tip().0, b5.hash);
+    assert_eq!(
+        calls(),
+        vec![(0, 1), (-1, 2), (1, 2), (1, 3), (1, 4), (1, 5)]
+    );
+
+    // Rolling back disconnects blocks once the store is rolled back.
+    cache.rollback_to(&b3.hash).unwrap();
+    assert_eq!(calls(), vec![(-1, 5), (-1, 4)]);
+}
+
+#[test]
+fn test_cache_subscribe() {
+    let network = bitcoin::Network::Regtest;
+    let genesis = constants::genesis_block(network).header;
+    let params = Params::new(network);
+    let store = store::Memory::new(NonEmpty::new(genesis));
+    let ctx = AdjustedTime::<net::SocketAddr>::new(LOCAL_TIME);
+    let mut cache = BlockCache::from(store, params, &[]).unwrap();
+    let g = &mut rand::thread_rng();
+
+    // a0 <- a1 <- a2 <- a3
+    //        \
+    //         <- b2 <- b3 <- b4 *
+    let a0 = Tree::new(genesis);
+    let a1 = a0.next(g);
+    let a2 = a1.next(g);
+    let a3 = a2.next(g);
+    let b2 = a1.next(g);
+    let b3 = b2.next(g);
+    let b4 = b3.next(g);
+
+    let events = cache.subscribe();
+    let dropped = cache.subscribe();
+    drop(dropped);
+
+    cache.import_blocks(a0.branch([&a1, &a3]), &ctx).unwrap();
+    assert_eq!(
+        events.try_iter().collect::<Vec<_>>(),
+        vec![
+            ChainEvent::TipExtended(1, a1.hash),
+            ChainEvent::TipExtended(2, a2.hash),
+            ChainEvent::TipExtended(3, a3.hash),
+        ]
+    );
+    assert_eq!(cache.subscribers.0.lock().unwrap().len(), 1);
+
+    // Clones share subscribers.
+    let clone = cache.clone();
+    let other = clone.subscribe();
+
+    let branch = a0.branch([&b2, &b4]).collect::<Vec<_>>();
+    cache.import_blocks(branch.into_iter().rev(), &ctx).unwrap();
+    assert_eq!(cache.tip().0, b4.hash);
+
+    let reorg = ChainEvent::Reorg {
+        from: 1,
+        disconnected: vec![(2, a2.hash), (3, a3.hash)],
+        connected: vec![(2, b2.hash), (3, b3.hash), (4, b4.hash)],
+    };
+    assert_eq!(events.try_iter().collect::<Vec<_>>(), vec![reorg.clone()]);
+    assert_eq!(other.try_iter().collect::<Vec<_>>(), vec![reorg]);
+
+    // Rejected blocks send no events.
+    cache
+        .import_block(b4.next_invalid(g).block(), &ctx)
+        .unwrap_err();
+    assert!(events.try_recv().is_err());
+}
+
+#[test]
+#[allow(clippy::reversed_empty_ranges)]
+fn test_cache_export_headers() {
+    let network = bitcoin::Network::Bitcoin;
+    let genesis = constants::genesis_block(network).header;
+    let params = Params::new(network);
+    let store = store::Memory::new(NonEmpty::new(genesis));
+    let ctx = AdjustedTime::<net::SocketAddr>::new(LOCAL_TIME);
+    let mut cache = BlockCache::from(store, params, &[]).unwrap();
+    let chain = &nakamoto_test::BITCOIN_HEADERS;
+    let height = chain.len() as Height - 1;
+
+    cache.import_blocks(chain.iter().cloned(), &ctx).unwrap();
+
+    assert!(cache.export_headers(0..0).is_empty());
+    assert!(cache.export_headers(7..7).is_empty());
+    assert!(cache.export_headers(9..3).is_empty());
+    assert!(cache.export_headers(height + 1..height + 9).is_empty());
+
+    assert_eq!(cache.export_headers(0..1), vec![(0, genesis)]);
+    assert_eq!(
+        cache.export_headers(3..6),
+        (3..6)
+            .map(|h| (h, *chain.get(h as usize).unwrap()))
+            .collect::<Vec<_>>()
+    );
+    assert_eq!(
+        cache.export_headers(height..Height::MAX),
+        vec![(height, *chain.last())],
+        "Over-wide ranges are clamped to the tip"
+    );
+    assert_eq!(
+        cache.export_headers(0..height * 2),
+        cache.iter().collect::<Vec<_>>()
+    );
+}
+
+#[test]
+#[allow(clippy::reversed_empty_ranges)]
+fn test_cache_write_headers() {
+    use bitcoin::consensus::encode::{self, Decodable};
+
+    let network = bitcoin::Network::Bitcoin;
+    let params = Params::new(network);
+    let chain = &nakamoto_test::BITCOIN_HEADERS;
+    let height = chain.len() as Height - 1;
+    let store = store::Memory::new((*chain).clone());
+    let cache = BlockCache::from(store, params, &[]).unwrap();
+
+    let mut buf = Vec::new();
+    assert_eq!(cache.write_headers(3..8, &mut buf).unwrap(), 5);
+    assert_eq!(
+        buf,
+        chain.tail[2..7]
+            .iter()
+            .flat_map(encode::serialize)
+            .collect::<Vec<_>>()
+    );
+
+    let mut buf = Vec::new();
+    assert_eq!(cache.write_headers(8..3, &mut buf).unwrap(), 0);
+    assert_eq!(
+        cache
+            .write_headers(height + 1..height + 2, &mut buf)
+            .unwrap(),
+        0
+    );
+    assert!(buf.is_empty());
+
+    // The whole chain is written out, and can be read back.
+    let mut buf = Vec::new();
+    assert_eq!(
+        cache.write_headers(0..height * 2, &mut buf).unwrap(),
+        chain.len()
+    );
+    assert_eq!(buf.len(), chain.len() * 80);
+
+    let mut r = buf.as_slice();
+    let headers = (0..chain.len())
+        .map(|_| BlockHeader::consensus_decode(&mut r).unwrap())
+        .collect::<Vec<_>>();
+    assert!(r.is_empty());
+    assert_eq!(headers, chain.iter().cloned().collect::<Vec<_>>());
+}
+
+#[test]
+fn test_cache_fork_tips() {
+    let network = bitcoin::Network::Regtest;
+    let genesis = constants::genesis_block(network).header;
+    let params = Params::new(network);
+    let store = store::Memory::new(NonEmpty::new(genesis));
+    let ctx = AdjustedTime::<net::SocketAddr>::new(LOCAL_TIME);
+    let mut cache = BlockCache::from(store, params, &[]).unwrap();
+    let g = &mut rand::thread_rng();
+
+    // a0 <- a1 <- a2 <- a3 <- a4 <- a5 *
+    //        \     \
+    //         \     <- b3 <- b4
+    //          \
+    //           <- c2 <- c3
+    //                \
+    //                 <- d3 <- (d4) <- d5
+    let a0 = Tree::new(genesis);
+    let a1 = a0.next(g);
+    let a2 = a1.next(g);
+    let a5 = a2.next(g).next(g).next(g);
+    let b3 = a2.next(g);
+    let b4 = b3.next(g);
+    let c2 = a1.next(g);
+    let c3 = c2.next(g);
+    let d3 = c2.next(g);
+    let d4 = d3.next(g);
+    let d5 = d4.next(g);
+
+    assert!(cache.fork_tips().is_empty());
+
+    cache.import_blocks(a0.branch([&a1, &a5]), &ctx).unwrap();
+    cache.import_blocks(a0.branch([&b3, &b4]), &ctx).unwrap();
+    cache.import_blocks(a0.branch([&c2, &c3]), &ctx).unwrap();
+    cache.import_block(d3.block(), &ctx).unwrap();
+    cache.import_blocks(iter::once(d5.block()), &ctx).unwrap();
+
+    assert_eq!(
+        cache.fork_tips(),
+        {
+            let mut tips = vec![(b4.hash, 4), (c3.hash, 3), (d3.hash, 3)];
+            tips.sort_by(|(a_hash, a), (b_hash, b)| b.cmp(a).then(a_hash.cmp(b_hash)));
+            tips
+        },
+        "The disconnected `d5` is not a fork tip"
+    );
+    assert_eq!(cache.tip().0, a5.hash);
+}
+
+#[test]
+fn test_cache_prune_orphans() {
+    let network = bitcoin::Network::Regtest;
+    let genesis = constants::genesis_block(network).header;
+    let params = Params::new(network);
+    let store = store::Memory::new(NonEmpty::new(genesis));
+    let ctx = AdjustedTime::<net::SocketAddr>::new(LOCAL_TIME);
+    let g = &mut rand::thread_rng();
+
+    // a0 <- a1 <- a2 <- [a3] <- a4 <- a5 <- a6 *
+    //        \             \
+    //         <- b2          <- c4 <- c5
+    //
+    //                                   (d6) <- d7
+    let a0 = Tree::new(genesis);
+    let a1 = a0.next(g);
+    let a2 = a1.next(g);
+    let a3 = a2.next(g);
+    let a6 = a3.next(g).next(g).next(g);
+    let b2 = a1.next(g);
+    let c4 = a3.next(g);
+    let c5 = c4.next(g);
+    let d7 = a6.next(g).next(g);
+
+    let mut cache = BlockCache::from(store, params, &[(3, a3.hash)]).unwrap();
+
+    cache.import_blocks(a0.branch([&a1, &a2]), &ctx).unwrap();
+    cache.import_block(b2.block(), &ctx).unwrap();
+    cache.import_blocks(a0.branch([&a3, &a6]), &ctx).unwrap();
+    cache.import_blocks(a0.branch([&c4, &c5]), &ctx).unwrap();
+    cache.import_blocks(iter::once(d7.block()), &ctx).unwrap();
+
+    assert_eq!(cache.tip().0, a6.hash);
+    assert_eq!(cache.orphan_count(), 4);
+
+    assert_eq!(cache.prune_orphans(), 2, "`b2` and `d7` are removed");
+    assert!(cache.orphans.contains_key(&c4.hash));
+    assert!(cache.orphans.contains_key(&c5.hash));
+
+    assert_eq!(cache.prune_orphans(), 0);
+    assert_eq!(cache.tip().0, a6.hash);
+}
+
+#[test]
+fn test_cache_assume_valid() {
+    let network = bitcoin::Network::Regtest;
+    let genesis = constants::genesis_block(network).header;
+    let params = Params::new(network);
+    let store = store::Memory::new(NonEmpty::new(genesis));
+    let ctx = AdjustedTime::<net::SocketAddr>::new(LOCAL_TIME);
+    let g = &mut rand::thread_rng();
+
+    // a0 <- a1 <- (a2) <- a3 <- a4
+    //
+    // Where `a2` has an invalid proof-of-work.
+    let a0 = Tree::new(genesis);
+    let a1 = a0.next(g);
+    let a2 = a1.next_invalid(g);
+    let a3 = a2.next(g);
+    let a4 = a3.next(g);
+
+    let cache = BlockCache::from(store, params, &[]).unwrap();
+
+    let err = cache
+        .clone()
+        .import_blocks(a0.branch([&a1, &a4]), &ctx)
+        .unwrap_err();
+    assert!(matches!(err, Error::BlockImportAborted { index: 1, .. }));
+
+    let mut trusting = cache.clone().with_assume_valid(a3.hash);
+    trusting.import_blocks(a0.branch([&a1, &a4]), &ctx).unwrap();
+    assert_eq!(trusting.tip().0, a4.hash, "Headers up to `a3` are trusted");
+
+    let err = cache
+        .clone()
+        .with_assume_valid(a1.hash)
+        .import_blocks(a0.branch([&a1, &a4]), &ctx)
+        .unwrap_err();
+    assert!(
+        matches!(err, Error::BlockImportAborted { index: 1, .. }),
+        "Headers after the assume-valid block are checked"
+    );
+
+    let mut trusting = cache.with_assume_valid(a3.hash);
+    let err = trusting
+        .import_blocks(a0.branch([&a1, &a2]), &ctx)
+        .unwrap_err();
+    assert!(
+        matches!(err, Error::BlockImportAborted { index: 1, .. }),
+        "Headers are checked if the assume-valid block isn't in the batch"
+    );
+}
+
+#[test]
+fn test_is_extension() {
+    let chain = &nakamoto_test::BITCOIN_HEADERS;
+    let tip = chain.head.block_hash();
+    let headers = &chain.tail;
+    let hashes = headers.iter().map(|h| h.block_hash()).collect::<Vec<_>>();
+
+    assert!(super::is_extension(tip, headers, &hashes));
+    assert!(super::is_extension(tip, &headers[..1], &hashes[..1]));
+    assert!(!super::is_extension(tip, &[], &[]));
+    assert!(!super::is_extension(tip, &headers[1..], &hashes[1..]));
+    assert!(!super::is_extension(
+        tip,
+        &[headers[0], headers[2], headers[1]],
+        &[hashes[0], hashes[2], hashes[1]]
+    ));
+}
+
+#[test]
+fn test_cache_would_reorg() {
+    let network = bitcoin::Network::Regtest;
+    let genesis = constants::genesis_block(network).header;
+    let params = Params::new(network);
+    let store = store::Memory::new(NonEmpty::new(genesis));
+    let ctx = AdjustedTime::<net::SocketAddr>::new(LOCAL_TIME);
+    let mut cache = BlockCache::from(store, params, &[]).unwrap();
+    let g = &mut rand::thread_rng();
+
+    // a0 <- a1 <- a2 <- a3 <- a4 <- a5 <- (a6) *
+    //                  //               <- (b3) <- (b4) <- (b5) <- (b6)
+    let a0 = Tree::new(genesis);
+    let a1 = a0.next(g);
+    let a2 = a1.next(g);
+    let a5 = a2.next(g).next(g).next(g);
+    let a6 = a5.next(g);
+    let b3 = a2.next(g);
+    let b4 = b3.next(g);
+    let b6 = b4.next(g).next(g);
+
+    cache.import_blocks(a0.branch([&a1, &a5]), &ctx).unwrap();
+
+    let branch = |tip: &Tree| a0.branch([&b3, tip]).collect::<Vec<_>>();
+
+    assert!(!cache.would_reorg(&[]));
+    assert!(!cache.would_reorg(&branch(&b4)), "The branch has less work");
+    assert!(cache.would_reorg(&branch(&b6)), "The branch has more work");
+    assert!(
+        cache.would_reorg(&a0.branch([&a1, &b6]).collect::<Vec<_>>()),
+        "Headers on the active chain are skipped"
+    );
+    assert!(
+        !cache.would_reorg(&[a6.block()]),
+        "Extending the tip isn't a re-org"
+    );
+    assert!(
+        !cache.would_reorg(&branch(&b6)[1..]),
+        "The headers must connect to the active chain"
+    );
+    assert!(
+        !cache.would_reorg(&[b3.block(), b6.block()]),
+        "The headers must form a chain"
+    );
+
+    assert_eq!(cache.orphan_count(), 0, "Nothing was imported");
+    assert_eq!(cache.tip().0, a5.hash);
+    assert_eq!(cache.store.height().unwrap(), 5);
+}
+
+#[test]
+fn test_cache_fork_point() {
+    let network = bitcoin::Network::Regtest;
+    let genesis = constants::genesis_block(network).header;
+    let params = Params::new(network);
+    let store = store::Memory::new(NonEmpty::new(genesis));
+    let ctx = AdjustedTime::<net::SocketAddr>::new(LOCAL_TIME);
+    let mut cache = BlockCache::from(store, params, &[]).unwrap();
+    let g = &mut rand::thread_rng();
+
+    // a0 <- a1 <- a2 <- a3 * <- (a4) <- (a5)
+    //              \
+    //               <- (b3) <- (b4)
+    let a0 = Tree::new(genesis);
+    let a1 = a0.next(g);
+    let a2 = a1.next(g);
+    let a3 = a2.next(g);
+    let a5 = a3.next(g).next(g);
+    let b3 = a2.next(g);
+    let b4 = b3.next(g);
+
+    cache.import_blocks(a0.branch([&a1, &a3]), &ctx).unwrap();
+
+    let headers = |from: &Tree, to: &Tree| a0.branch([from, to]).collect::<Vec<_>>();
+
+    assert_eq!(cache.fork_point(&[]), None);
+    assert_eq!(cache.fork_point(&[genesis]), Some(0));
+    assert_eq!(cache.fork_point(&headers(&a1, &a3)), Some(3));
+    assert_eq!(cache.fork_point(&headers(&a1, &a5)), Some(3));
+    assert_eq!(cache.fork_point(&headers(&a1, &b4)), Some(2));
+    assert_eq!(
+        cache.fork_point(&headers(&b3, &b4)),
+        Some(2),
+        "The parent of the first header is a common block"
+    );
+    assert_eq!(cache.fork_point(&[b4.block()]), None);
+
+    // A chain from another network doesn't even agree on genesis.
+    let testnet = constants::genesis_block(bitcoin::Network::Testnet).header;
+    let t1 = Tree::new(testnet).next(g);
+    assert_eq!(cache.fork_point(&[testnet, t1.block()]), None);
+
+    assert_eq!(cache.orphan_count(), 0, "Nothing was imported");
+    assert_eq!(cache.tip().0, a3.hash);
+}
+
+#[test]
+fn test_cache_buffered_store() {
+    let network = bitcoin::Network::Regtest;
+    let genesis = constants::genesis_block(network).header;
+    let params = Params::new(network);
+    let store = store::Buffered::new(store::Memory::new(NonEmpty::new(genesis)))
+        .unwrap()
+        .with_capacity(4);
+    let ctx = AdjustedTime::<net::SocketAddr>::new(LOCAL_TIME);
+    let mut cache = BlockCache::from(store, params, &[]).unwrap();
+    let g = &mut rand::thread_rng();
+
+    // a0 <- a1 <- a2 <- a3 <- a4 <- a5 <- a6
+    //                    \
+    //                     <- b4 <- b5 <- b6 <- b7 *
+    let a0 = Tree::new(genesis);
+    let a1 = a0.next(g);
+    let a3 = a1.next(g).next(g);
+    let a6 = a3.next(g).next(g).next(g);
+    let b4 = a3.next(g);
+    let b7 = b4.next(g).next(g).next(g);
+
+    for header in a0.branch([&a1, &a6]) {
+        cache.import_block(header, &ctx).unwrap();
+    }
+    assert_eq!(cache.store.buffered(), 2);
+    assert_eq!(cache.store.inner().height().unwrap(), 4);
+
+    // Re-org past the last write to the underlying store.
+    let branch = a0.branch([&b4, &b7]).collect::<Vec<_>>();
+    cache.import_blocks(branch.into_iter().rev(), &ctx).unwrap();
+    assert_eq!(cache.tip().0, b7.hash);
+    assert_eq!(cache.store.height().unwrap(), 7);
+
+    cache.flush().unwrap();
+    assert_eq!(cache.store.buffered(), 0);
+    assert_eq!(
+        cache
+            .store
+            .inner()
+            .iter()
+            .map(|r| r.unwrap())
+            .collect::<Vec<_>>(),
+        cache.iter().collect::<Vec<_>>()
+    );
+}
+
+#[test]
+#[allow(unused_variables)]
+fn test_cache_import_unordered() {
+    let network = bitcoin::Network::Regtest;
+    let genesis = constants::genesis_block(network).header;
+    let params = Params::new(network);
+    let store = store::Memory::new(NonEmpty::new(genesis));
+    let ctx = AdjustedTime::<net::SocketAddr>::new(LOCAL_TIME);
+    let mut cache = BlockCache::from(store, params, &[]).unwrap();
+    let mut model = model::Cache::new(genesis);
+
+    let g = &mut rand::thread_rng();
+
+    let a0 = Tree::new(genesis);
+
+    // a0 <- a1 <- a2 <- a3 *
+    let a1 = a0.next(g);
+    let a2 = a1.next(g);
+    let a3 = a2.next(g);
+
+    cache.import_blocks(a0.branch([&a1, &a3]), &ctx).unwrap();
+    assert_eq!(cache.tip().0, a3.hash, "{:#?}", cache);
+
+    // a0 <- a1 <- a2 <- a3
+    //                 \
+    //                  <- b3 <- b4 *
+    let b3 = a2.next(g);
+    let b4 = b3.next(g);
+
+    cache.import_blocks(a0.branch([&b3, &b4]), &ctx).unwrap();
+    assert_eq!(cache.tip().0, b4.hash, "{:#?}", cache);
+
+    //            <- c2 <- c3 <- c4 <- c5 *
+    //           /
+    // a0 <- a1 <- a2 <- a3
+    //                 \
+    //                  <- b3 <- b4
+    let c2 = a1.next(g);
+    let c3 = c2.next(g);
+    let c4 = c3.next(g);
+    let c5 = c4.next(g);
+
+    cache.import_blocks(a0.branch([&c2, &c5]), &ctx).unwrap();
+    assert_eq!(cache.tip().0, c5.hash, "{:#?}", cache);
+
+    //                                <- d5 <- d6 *
+    //                               /
+    //            <- c2 <- c3 <- c4 <- c5
+    //           /
+    // a0 <- a1 <- a2 <- a3
+    //                 \
+    //                  <- b3 <- b4
+    let d5 = c4.next(g);
+    let d6 = d5.next(g);
+
+    let mut headers = a0.headers();
+    let mut rng = rand::thread_rng();
+    let len = headers.len();
+
+    // Expected longest chain.
+    let expected = vec![
+        a0.hash, a1.hash, c2.hash, c3.hash, c4.hash, d5.hash, d6.hash,
+    ];
+
+    for _ in 0..len * len {
+        use rand::seq::SliceRandom;
+
+        headers.shuffle(&mut rng);
+
+        cache.import_blocks(headers.iter().cloned(), &ctx).unwrap();
+        assert_eq!(cache.tip().0, d6.hash);
+
+        model.import_blocks(headers.iter().cloned(), &ctx).unwrap();
+        assert_eq!(model.tip().0, d6.hash);
+
+        let actual = cache.chain().map(|h| h.block_hash()).collect::<Vec<_>>();
+        assert_eq!(actual, expected);
+
+        let actual = model.chain().map(|h| h.block_hash()).collect::<Vec<_>>();
+        assert_eq!(actual, expected);
+    }
+}
+
+#[test]
+fn test_cache_import_blocks_sorted() {
+    use rand::seq::SliceRandom;
+
+    let network = bitcoin::Network::Regtest;
+    let genesis = constants::genesis_block(network).header;
+    let params = Params::new(network);
+    let store = store::Memory::new(NonEmpty::new(genesis));
+    let ctx = AdjustedTime::<net::SocketAddr>::new(LOCAL_TIME);
+    let g = &mut rand::thread_rng();
+
+    // a0 <- a1 <- ... <- a5 <- ... <- a16 *
+    //                     \
+    //                      <- b6 <- b7 <- b8
+    //
+    // (x1) <- x2
+    let a0 = Tree::new(genesis);
+    let mut a = vec![a0.clone()];
+    for _ in 0..16 {
+        a.push(a.last().unwrap().next(g));
+    }
+    let b8 = a[5].next(g).next(g).next(g);
+    let x2 = a0.next(g).next(g);
+
+    let mut headers = a0
+        .branch([&a[1], &a[16]])
+        .chain(a0.branch([&a[6], &b8]).skip(5))
+        .chain(iter::once(x2.block()))
+        .collect::<Vec<_>>();
+    assert_eq!(headers.len(), 20);
+
+    headers.shuffle(g);
+    headers.push(headers[3]);
+
+    let sorted = super::sort_headers(headers.clone());
+    assert_eq!(sorted.len(), 20, "Duplicates are dropped");
+
+    for (i, (ix, header)) in sorted.iter().enumerate() {
+        assert_eq!(headers[*ix], *header);
+        assert!(
+            sorted[i..]
+                .iter()
+                .all(|(_, h)| h.block_hash() != header.prev_blockhash),
+            "Parents come first"
+        );
+    }
+
+    let mut cache = BlockCache::from(store, params, &[]).unwrap();
+    cache
+        .import_blocks_sorted(headers.iter().cloned(), &ctx)
+        .unwrap();
+
+    assert_eq!(cache.tip().0, a[16].hash);
+    assert_eq!(cache.orphan_count(), 4);
+    assert_eq!(cache.missing_parents(), vec![x2.block().prev_blockhash]);
+
+    // Errors point to the failing header of the given batch.
+    let c1 = a[16].next_at(genesis.time, g);
+    let c2 = c1.next(g);
+    let headers = vec![x2.block(), c2.block(), c1.block()];
+
+    assert!(matches! {
+        cache.import_blocks_sorted(headers.into_iter(), &ctx),
+        Err(Error::BlockImportAborted { index: 2, .. })
+    });
+}
+
+#[test]
+fn test_cache_depth() {
+    let network = bitcoin::Network::Regtest;
+    let genesis = constants::genesis_block(network).header;
+    let params = Params::new(network);
+    let store = store::Memory::new(NonEmpty::new(genesis));
+    let ctx = AdjustedTime::<net::SocketAddr>::new(LOCAL_TIME);
+    let mut cache = BlockCache::from(store, params, &[]).unwrap();
+    let g = &mut rand::thread_rng();
+
+    // a0 <- a1 <- a2 <- a3 *
+    //        \
+    //         <- b2
+    let a0 = Tree::new(genesis);
+    let a1 = a0.next(g);
+    let a3 = a1.next(g).next(g);
+    let b2 = a1.next(g);
+    let unknown = b2.next(g);
+
+    cache.import_blocks(a0.branch([&a1, &a3]), &ctx).unwrap();
+    cache.import_block(b2.block(), &ctx).unwrap();
+
+    assert_eq!(cache.depth(&a3.hash), Some(1));
+    assert_eq!(cache.depth(&a1.hash), Some(3));
+    assert_eq!(cache.depth(&a0.hash), Some(cache.height() + 1));
+    assert_eq!(cache.depth(&b2.hash), None, "Orphans have no depth");
+    assert_eq!(cache.depth(&unknown.hash), None);
+}
+
+#[test]
+fn test_cache_find_fork() {
+    let network = bitcoin::Network::Bitcoin;
+    let genesis = constants::genesis_block(network).header;
+    let params = Params::new(network);
+    let store = store::Memory::new(NonEmpty::new(genesis));
+    let ctx = AdjustedTime::<net::SocketAddr>::new(LOCAL_TIME);
+    let mut cache = BlockCache::from(store, params, &[]).unwrap();
+    let chain = &nakamoto_test::BITCOIN_HEADERS;
+
+    cache.import_blocks(chain.iter().cloned(), &ctx).unwrap();
+
+    let unknown =
+        BlockHash::from_hex("0f9188f13cb7b2c71f2a345e3a4fc328bf5bbb436012afca590b1a11466e2206")
+            .unwrap();
+    let hash = |height: usize| chain.get(height).unwrap().block_hash();
+
+    assert_eq!(cache.find_fork(&[]), None);
+    assert_eq!(cache.find_fork(&[unknown]), None);
+    assert_eq!(
+        cache.find_fork(&[unknown, hash(45), hash(12), genesis.block_hash()]),
+        Some((45, hash(45)))
+    );
+    assert_eq!(
+        cache.find_fork(&[hash(12), unknown, hash(45)]),
+        Some((45, hash(45))),
+        "The highest block is returned, regardless of locator order"
+    );
+    assert_eq!(
+        cache.find_fork(&cache.locator_hashes(cache.height())),
+        Some((cache.height(), cache.tip().0))
+    );
+}
+
+#[test]
+#[allow(clippy::identity_op)]
+fn test_cache_locate_headers() {
+    let network = bitcoin::Network::Bitcoin;
+    let genesis = constants::genesis_block(network).header;
+    let params = Params::new(network);
+    let store = store::Memory::new(NonEmpty::new(genesis));
+    let ctx = AdjustedTime::<net::SocketAddr>::new(LOCAL_TIME);
+    let mut cache = BlockCache::from(store, params, &[]).unwrap();
+    let chain = &nakamoto_test::BITCOIN_HEADERS;
+    let height = chain.len() - 1;
+
+    assert!(chain.len() > 99);
+
+    cache.import_blocks(chain.iter().cloned(), &ctx).unwrap();
+
+    assert!(cache
+        .locate_headers(&[], BlockHash::default(), 1)
+        .is_empty());
+
+    assert_eq!(
+        cache.locate_headers(&[], chain.last().block_hash(), 1),
+        vec![*chain.last()],
+        "Passing just a stop hash requests the block"
+    );
+
+    let unknown =
+        BlockHash::from_hex("0f9188f13cb7b2c71f2a345e3a4fc328bf5bbb436012afca590b1a11466e2206")
+            .unwrap();
+
+    assert_eq!(
+        cache.locate_headers(&[unknown, unknown], BlockHash::default(), 4),
+        chain.iter().skip(1).take(4).cloned().collect::<Vec<_>>(),
+        "When the locators are unknown, starts from genesis"
+    );
+
+    let known1 = chain.get(12).unwrap();
+    let known2 = chain.get(45).unwrap();
+
+    assert_eq!(
+        cache.locate_headers(
+            &[unknown, known1.block_hash(), known2.block_hash()],
+            BlockHash::default(),
+            9
+        ),
+        chain
+            .iter()
+            .skip(12 + 1)
+            .take(9)
+            .cloned()
+            .collect::<Vec<_>>(),
+        "The headers start after the first known hash"
+    );
+
+    assert_eq!(
+        cache.locate_headers(&[chain.last().block_hash()], BlockHash::default(), 9),
+        vec![],
+        "Nothing is returned if we're starting from the tip"
+    );
+
+    assert_eq!(
+        cache.locate_headers(
+            &[chain.get(height - 3).unwrap().block_hash()],
+            BlockHash::default(),
+            9
+        ),
+        vec![
+            *chain.get(height - 2).unwrap(),
+            *chain.get(height - 1).unwrap(),
+            *chain.get(height - 0).unwrap()
+        ],
+        "If the max amount is more than we have, return up to the tip"
+    );
+
+    assert_eq!(
+        cache.locate_headers(
+            &[chain.get(5).unwrap().block_hash()],
+            chain.get(8).unwrap().block_hash(),
+            9
+        ),
+        vec![
+            *chain.get(6).unwrap(),
+            *chain.get(7).unwrap(),
+            *chain.get(8).unwrap()
+        ],
+        "Stops at the stop hash"
+    );
+
+    assert_eq!(
+        cache.locate_headers(
+            &[chain.get(5).unwrap().block_hash()],
+            chain.get(8).unwrap().block_hash(),
+            2
+        ),
+        vec![*chain.get(6).unwrap(), *chain.get(7).unwrap(),],
+        "Stops at the max"
+    );
+
+    assert_eq!(
+        cache.locate_headers(&[unknown], chain.get(3).unwrap().block_hash(), 9),
+        vec![
+            *chain.get(1).unwrap(),
+            *chain.get(2).unwrap(),
+            *chain.get(3).unwrap()
+        ],
+        "If the stop hash is known, but the locators unknown, start from genesis"
+    );
+
+    assert_eq!(
+        cache.locate_headers(
+            &[chain.get(43).unwrap().block_hash()],
+            chain.get(3).unwrap().block_hash(),
+            9
+        ),
+        vec![],
+        "If the stop height is earlier than the start height, we don't expect anything"
+    );
+
+    assert_eq!(
+        cache.locate_headers(
+            &[chain.get(4).unwrap().block_hash()],
+            chain.get(4).unwrap().block_hash(),
+            9
+        ),
+        vec![],
+        "If the stop height is equal to the start height, we don't expect anything"
+    );
+}
+
+#[test]
+fn test_cache_headers_at() {
+    let network = bitcoin::Network::Bitcoin;
+    let params = Params::new(network);
+    let chain = &nakamoto_test::BITCOIN_HEADERS;
+    let height = chain.len() as Height - 1;
+    let store = store::Memory::new((*chain).clone());
+    let cache = BlockCache::from(store, params, &[]).unwrap();
+    let mut model = HeightCache::new(*chain.first());
+
+    for (height, header) in chain.tail.iter().enumerate() {
+        model.import(height as Height + 1, *header);
+    }
+
+    for (start, count) in &[
+        (0, 0),
+        (0, 1),
+        (0, 2),
+        (1, 1),
+        (7, 16),
+        (height, 1),
+        (height, 2),
+        (height - 3, usize::MAX),
+        (height + 1, 1),
+        (height + 1, usize::MAX),
+    ] {
+        let expected = chain
+            .iter()
+            .skip(*start as usize)
+            .take(*count)
+            .cloned()
+            .collect::<Vec<_>>();
+
+        assert_eq!(cache.headers_at(*start, *count), expected);
+        // The default implementation.
+        assert_eq!(model.headers_at(*start, *count), expected);
+    }
+    assert!(cache.headers_at(Height::MAX, usize::MAX).is_empty());
+}
+
+#[test]
+fn test_cache_is_at_height() {
+    let network = bitcoin::Network::Bitcoin;
+    let params = Params::new(network);
+    let chain = &nakamoto_test::BITCOIN_HEADERS;
+    let height = chain.len() as Height - 1;
+    let store = store::Memory::new((*chain).clone());
+    let cache = BlockCache::from(store, params, &[]).unwrap();
+    let hash = |h: Height| chain.get(h as usize).unwrap().block_hash();
+
+    assert!(cache.is_at_height(0, &hash(0)));
+    assert!(cache.is_at_height(12, &hash(12)));
+    assert!(cache.is_at_height(height, &hash(height)));
+
+    assert!(!cache.is_at_height(0, &hash(1)));
+    assert!(!cache.is_at_height(12, &hash(11)));
+    assert!(!cache.is_at_height(height + 1, &hash(height)));
+    assert!(!cache.is_at_height(Height::MAX, &hash(height)));
+}
+
+#[test]
+fn test_cache_height_of() {
+    let network = bitcoin::Network::Bitcoin;
+    let params = Params::new(network);
+    let chain = &nakamoto_test::BITCOIN_HEADERS;
+    let height = chain.len() as Height - 1;
+    let store = store::Memory::new((*chain).clone());
+    let cache = BlockCache::from(store, params, &[]).unwrap();
+    let hash = |h: Height| chain.get(h as usize).unwrap().block_hash();
+
+    assert_eq!(cache.height_of(&hash(0)), Some(0));
+    assert_eq!(cache.height_of(&hash(12)), Some(12));
+    assert_eq!(cache.height_of(&hash(height)), Some(height));
+    assert_eq!(cache.height_of(&BlockHash::default()), None);
+}
+
+#[test]
+fn test_cache_tip_is() {
+    let network = bitcoin::Network::Regtest;
+    let genesis = constants::genesis_block(network).header;
+    let params = Params::new(network);
+    let store = store::Memory::new(NonEmpty::new(genesis));
+    let ctx = AdjustedTime::<net::SocketAddr>::new(LOCAL_TIME);
+    let mut cache = BlockCache::from(store, params, &[]).unwrap();
+    let g = &mut rand::thread_rng();
+
+    let a0 = Tree::new(genesis);
+    let a1 = a0.next(g);
+
+    assert!(cache.tip_is(&a0.hash));
+
+    cache.import_block(a1.block(), &ctx).unwrap();
+    assert!(cache.tip_is(&a1.hash));
+    assert!(!cache.tip_is(&a0.hash));
+}
+
+#[test]
+fn test_cache_locator_hashes() {
+    let network = bitcoin::Network::Bitcoin;
+    let genesis = constants::genesis_block(network).header;
+    let params = Params::new(network);
+    let ctx = AdjustedTime::<net::SocketAddr>::new(LOCAL_TIME);
+    let chain = &nakamoto_test::BITCOIN_HEADERS;
+    let height = chain.len() as Height - 1;
+
+    // Without checkpoints, the locator ends with genesis.
+    {
+        let store = store::Memory::new(NonEmpty::new(genesis));
+        let mut cache = BlockCache::from(store, params.clone(), &[]).unwrap();
+
+        cache.import_blocks(chain.iter().cloned(), &ctx).unwrap();
+
+        let locators = cache.locator_hashes(height);
+        assert_eq!(locators.first(), Some(&cache.tip().0));
+        assert_eq!(locators.last(), Some(&genesis.block_hash()));
+    }
+
+    // With a checkpoint that isn't part of the regular locator indexes, the locator
+    // ends with the checkpoint.
+    {
+        let checkpoint = 37;
+        let checkpoint_hash = chain.get(checkpoint).unwrap().block_hash();
+        let store = store::Memory::new(NonEmpty::new(genesis));
+        let mut cache =
+            BlockCache::from(store, params, &[(checkpoint as Height, checkpoint_hash)]).unwrap();
+
+        assert!(!block::locators_indexes(height).contains(&(checkpoint as Height)));
+        cache.import_blocks(chain.iter().cloned(), &ctx).unwrap();
+
+        let locators = cache.locator_hashes(height);
+        assert_eq!(locators.first(), Some(&cache.tip().0));
+        assert_eq!(locators.last(), Some(&checkpoint_hash));
+        assert_eq!(
+            locators.iter().filter(|h| **h == checkpoint_hash).count(),
+            1,
+            "The checkpoint is not repeated"
+        );
+        assert!(!locators.contains(&genesis.block_hash()));
+    }
+}
+
+#[test]
+fn test_cache_snapshot_view() {
+    let network = bitcoin::Network::Bitcoin;
+    let genesis = constants::genesis_block(network).header;
+    let params = Params::new(network);
+    let ctx = AdjustedTime::<net::SocketAddr>::new(LOCAL_TIME);
+    let chain = &nakamoto_test::BITCOIN_HEADERS;
+    let height = chain.len() as Height - 1;
+    let checkpoint = (37, chain.get(37).unwrap().block_hash());
+    let store = store::Memory::new(NonEmpty::new(genesis));
+    let mut cache = BlockCache::from(store, params, &[checkpoint]).unwrap();
+
+    cache
+        .import_blocks(chain.iter().take(500).cloned(), &ctx)
+        .unwrap();
+
+    let view = cache.snapshot();
+    let (tip, _) = cache.tip();
+    let locators = cache.locator_hashes(499);
+    let reader = {
+        let view = view.clone();
+        std::thread::spawn(move || (view.tip().0, view.locator_hashes(499)))
+    };
+    // The active chain is shared with the view until the next write.
+    assert_eq!(Arc::strong_count(&cache.chain), 2);
+    assert_eq!(Arc::strong_count(&cache.headers), 2);
+
+    cache
+        .import_blocks(chain.iter().skip(500).cloned(), &ctx)
+        .unwrap();
+    assert_eq!(Arc::strong_count(&cache.chain), 1);
+    assert_eq!(Arc::strong_count(&cache.headers), 1);
+
+    assert_eq!(reader.join().unwrap(), (tip, locators));
+    assert_eq!(cache.height(), height);
+    assert_eq!(
+        view.height(),
+        499,
+        "The view isn't affected by later imports"
+    );
+    assert_eq!(view.genesis(), &genesis);
+    assert_eq!(view.get_block(&tip), Some((499, chain.get(499).unwrap())));
+    assert_eq!(view.get_block_by_height(500), None);
+    assert_eq!(
+        view.height_of(&chain.get(12).unwrap().block_hash()),
+        Some(12)
+    );
+    assert!(!view.contains(&chain.get(500).unwrap().block_hash()));
+    assert_eq!(view.headers_at(498, 4), chain.tail[497..499].to_vec());
+    assert_eq!(view.checkpoints().len(), 1);
+}
+
+#[test]
+fn test_cache_locator_hashes_dense() {
+    let network = bitcoin::Network::Bitcoin;
+    let params = Params::new(network);
+    let chain = &nakamoto_test::BITCOIN_HEADERS;
+    let height = chain.len() as Height - 1;
+    let store = store::Memory::new((*chain).clone());
+    let cache = BlockCache::from(store, params, &[]).unwrap();
+
+    let hash = |height: Height| chain.get(height as usize).unwrap().block_hash();
+    let dense = 32;
+    let locators = cache.locator_hashes_dense(height, dense);
+
+    assert_eq!(
+        locators[..dense],
+        (height - dense as Height + 1..=height)
+            .rev()
+            .map(hash)
+            .collect::<Vec<_>>()[..]
+    );
+    assert_eq!(
+        locators[dense..],
+        block::locators_indexes(height - dense as Height)
+            .into_iter()
+            .map(hash)
+            .collect::<Vec<_>>()[..]
+    );
+    assert_eq!(
+        cache.locator_hashes_dense(height, 0),
+        cache.locator_hashes(height)
+    );
+    assert_eq!(
+        cache.locator_hashes_dense(height, usize::MAX),
+        (0..=height).rev().map(hash).collect::<Vec<_>>(),
+        "The whole chain can be included"
+    );
+}
+
+#[test]
+fn test_cache_locator_between() {
+    let network = bitcoin::Network::Bitcoin;
+    let genesis = constants::genesis_block(network).header;
+    let params = Params::new(network);
+    let ctx = AdjustedTime::<net::SocketAddr>::new(LOCAL_TIME);
+    let chain = &nakamoto_test::BITCOIN_HEADERS;
+    let height = chain.len() as Height - 1;
+    let store = store::Memory::new(NonEmpty::new(genesis));
+    let mut cache = BlockCache::from(store, params, &[]).unwrap();
+
+    cache.import_blocks(chain.iter().cloned(), &ctx).unwrap();
+
+    let hash = |height: Height| chain.get(height as usize).unwrap().block_hash();
+
+    assert_eq!(
+        cache.locator_between(0, height),
+        cache.locator_hashes(height),
+        "Without checkpoints, the full range is the same as the regular locator"
+    );
+    assert_eq!(
+        cache.locator_between(40, 60),
+        vec![60, 59, 58, 57, 56, 55, 54, 53, 52, 50, 46, 40]
+            .into_iter()
+            .map(hash)
+            .collect::<Vec<_>>()
+    );
+    assert_eq!(cache.locator_between(7, 7), vec![hash(7)]);
+    assert_eq!(
+        cache.locator_between(height - 1, height + 100),
+        vec![hash(height), hash(height - 1)],
+        "The range is clamped to the active chain"
+    );
+    assert!(cache.locator_between(8, 7).is_empty());
+    assert!(cache.locator_between(height + 1, height + 2).is_empty());
+}
+
+#[test]
+fn test_cache_max_future_time() {
+    let network = bitcoin::Network::Regtest;
+    let genesis = constants::genesis_block(network).header;
+    let params = Params::new(network);
+    let store = store::Memory::new(NonEmpty::new(genesis));
+    let ctx = AdjustedTime::<net::SocketAddr>::new(LOCAL_TIME);
+    let g = &mut rand::thread_rng();
+
+    // A block four hours ahead of the network-adjusted time.
+    let a0 = Tree::new(genesis);
+    let a1 = a0.next_at(ctx.block_time() + 4 * 60 * 60, g);
+
+    let cache = BlockCache::from(store, params, &[]).unwrap();
+
+    assert!(matches! {
+        cache.clone().import_block(a1.block(), &ctx),
+        Err(Error::InvalidBlockTime(_, std::cmp::Ordering::Greater))
+    });
+
+    let mut cache = cache.with_max_future_time(Duration::from_secs(5 * 60 * 60));
+    cache.import_block(a1.block(), &ctx).unwrap();
+    assert_eq!(cache.tip().0, a1.hash);
+}
+
+#[test]
+fn test_cache_candidates_pow() {
+    let network = bitcoin::Network::Regtest;
+    let genesis = constants::genesis_block(network).header;
+    let params = Params::new(network);
+    let store = store::Memory::new(NonEmpty::new(genesis));
+    let ctx = AdjustedTime::<net::SocketAddr>::new(LOCAL_TIME);
+    let mut cache = BlockCache::from(store, params, &[]).unwrap();
+    let g = &mut rand::thread_rng();
+
+    let a0 = Tree::new(genesis);
+    let a1 = a0.next(g);
+    let a3 = a1.next(g).next(g);
+
+    cache.import_blocks(a0.branch([&a1, &a3]), &ctx).unwrap();
+
+    // Several long branches, forking off genesis and off the tip. Every block of a branch
+    // is the tip of a candidate, so there are enough headers to check to spread the work
+    // across threads.
+    let mut tips = Vec::new();
+    for fork in [&a0, &a3].iter() {
+        for _ in 0..2 {
+            let mut tip = (*fork).clone();
+
+            for i in 0..32 {
+                // One branch is invalid half way through.
+                tip = if tips.len() == 1 && i == 16 {
+                    tip.next_invalid(g)
+                } else {
+                    tip.next(g)
+                };
+                let seq = cache.next_seq();
+                cache.insert_orphan(tip.hash, tip.block(), seq, None);
+            }
+            tips.push(tip);
+        }
+    }
+
+    // The candidates are the same as the ones found by validating each branch sequentially,
+    // and in the same order.
+    let sequential = cache
+        .orphans
+        .keys()
+        .filter_map(|tip| cache.fork(tip))
+        .filter(|branch| cache.validate_branch(branch, &[], &ctx).is_ok())
+        .map(|branch| branch.tip)
+        .collect::<Vec<_>>();
+    let candidates = cache
+        .chain_candidates(&ctx)
+        .0
+        .into_iter()
+        .map(|branch| branch.tip)
+        .collect::<Vec<_>>();
+
+    assert_eq!(candidates, sequential);
+    assert_eq!(candidates.len(), 4 * 32 - 16);
+    assert!(!candidates.contains(&tips[1].hash));
+}
+
+#[test]
+fn test_cache_clock_policy() {
+    let network = bitcoin::Network::Regtest;
+    let genesis = constants::genesis_block(network).header;
+    let params = Params::new(network);
+    let store = store::Memory::new(NonEmpty::new(genesis));
+    let g = &mut rand::thread_rng();
+
+    // A clock frozen at the genesis time, and blocks a year later.
+    let ctx = AdjustedTime::<net::SocketAddr>::new(LocalTime::from_block_time(genesis.time));
+    let a0 = Tree::new(genesis);
+    let a1 = a0.next_at(genesis.time + 365 * 24 * 60 * 60, g);
+    let a2 = a1.next(g);
+    let x3 = a2.next_at(genesis.time, g);
+
+    let cache = BlockCache::from(store, params, &[]).unwrap();
+
+    assert!(matches! {
+        cache.clone().import_blocks(a0.branch([&a1, &a2]), &ctx),
+        Err(Error::BlockImportAborted { error, .. })
+            if matches!(*error, Error::InvalidBlockTime(_, std::cmp::Ordering::Greater))
+    });
+
+    let mut cache = cache.with_clock_policy(ClockPolicy::Ignore);
+    cache.import_blocks(a0.branch([&a1, &a2]), &ctx).unwrap();
+    assert_eq!(cache.tip().0, a2.hash);
+
+    assert!(
+        matches! {
+            cache.import_block(x3.block(), &ctx),
+            Err(Error::InvalidBlockTime(_, std::cmp::Ordering::Less))
+        },
+        "The median time past is still enforced"
+    );
+}
+
+#[test]
+fn test_cache_range_rev() {
+    let network = bitcoin::Network::Regtest;
+    let genesis = constants::genesis_block(network).header;
+    let params = Params::new(network);
+    let store = store::Memory::new(NonEmpty::new(genesis));
+    let ctx = AdjustedTime::<net::SocketAddr>::new(LOCAL_TIME);
+    let g = &mut rand::thread_rng();
+
+    let a0 = Tree::new(genesis);
+    let a1 = a0.next(g);
+    let a5 = a1.next(g).next(g).next(g).next(g);
+
+    let mut cache = BlockCache::from(store, params, &[]).unwrap();
+    cache.import_blocks(a0.branch([&a1, &a5]), &ctx).unwrap();
+    assert_eq!(cache.height(), 5);
+
+    for (start, end) in &[(0, 6), (0, 1), (1, 6), (2, 4), (3, 3), (0, 0)] {
+        let expected = cache
+            .range(*start..*end)
+            .map(|blk| blk.height)
+            .collect::<Vec<_>>();
+        let mut actual = cache
+            .range_rev(*start..*end)
+            .map(|blk| blk.height)
+            .collect::<Vec<_>>();
+        actual.reverse();
+
+        assert_eq!(actual, expected, "{}..{}", start, end);
+    }
+}
+
+#[test]
+fn test_cache_best_block() {
+    let network = bitcoin::Network::Regtest;
+    let genesis = constants::genesis_block(network).header;
+    let params = Params::new(network);
+    let store = store::Memory::new(NonEmpty::new(genesis));
+    let ctx = AdjustedTime::<net::SocketAddr>::new(LOCAL_TIME);
+    let mut cache = BlockCache::from(store, params, &[]).unwrap();
+    let mut model = model::Cache::new(genesis);
+    let g = &mut rand::thread_rng();
+
+    assert_eq!(cache.best_block(), (0, genesis.block_hash(), &genesis));
+
+    let a0 = Tree::new(genesis);
+    let a1 = a0.next(g);
+    let a3 = a1.next(g).next(g);
+
+    cache.import_blocks(a0.branch([&a1, &a3]), &ctx).unwrap();
+    model.import_blocks(a0.branch([&a1, &a3]), &ctx).unwrap();
+
+    assert_eq!(cache.best_block(), (3, a3.hash, &a3.block()));
+    assert_eq!(BlockTree::best_block(&cache), model.best_block());
+}
+
+#[test]
+fn test_cache_memory_usage() {
+    let network = bitcoin::Network::Bitcoin;
+    let genesis = constants::genesis_block(network).header;
+    let params = Params::new(network);
+    let ctx = AdjustedTime::<net::SocketAddr>::new(LOCAL_TIME);
+    let chain = &nakamoto_test::BITCOIN_HEADERS;
+
+    let mut cache =
+        BlockCache::from(store::Memory::new(NonEmpty::new(genesis)), params, &[]).unwrap();
+    let empty = cache.memory_usage();
+
+    assert_eq!(cache.store_len().unwrap(), 1);
+
+    cache
+        .import_blocks(chain.tail.iter().cloned(), &ctx)
+        .unwrap();
+    let usage = cache.memory_usage();
+
+    assert_eq!(cache.store_len().unwrap(), chain.len());
+    assert!(
+        usage > empty + chain.tail.len() * std::mem::size_of::<BlockHeader>(),
+        "Every header is accounted for"
+    );
+
+    let cache = cache.with_time_index();
+    assert!(cache.memory_usage() > usage);
+}
+
+#[test]
+fn test_cache_reorg_from_genesis() {
+    let network = bitcoin::Network::Regtest;
+    let genesis = constants::genesis_block(network).header;
+    let params = Params::new(network);
+    let store = store::Memory::new(NonEmpty::new(genesis));
+    let ctx = AdjustedTime::<net::SocketAddr>::new(LOCAL_TIME);
+    let mut cache = BlockCache::from(store, params, &[]).unwrap();
+    let g = &mut rand::thread_rng();
 
-        let [from, to] = range;
-        let mut tip = &to.hash;
+    // a0 <- a1 <- a2 <- a3
+    //   \
+    //    <- b1 <- b2
+    let a0 = Tree::new(genesis);
+    let a1 = a0.next(g);
+    let a2 = a1.next(g);
+    let a3 = a2.next(g);
+    let b1 = a0.next(g);
+    let b2 = b1.next(g);
 
-        while let Some(h) = headers.get(tip) {
-            blocks.push_front(*h);
-            if tip == &from.hash {
-                break;
-            }
-            tip = &h.prev_blockhash;
+    cache.import_block(a1.block(), &ctx).unwrap();
+
+    // Both chains fork off genesis. Since `b1` has the same work as `a1`, it is only
+    // connected once `b2` is imported.
+    assert_eq!(
+        cache.import_blocks(a0.branch([&b1, &b2]), &ctx).unwrap(),
+        ImportResult::TipChanged {
+            header: b2.block(),
+            hash: b2.hash,
+            height: 2,
+            reverted: vec![(1, a1.block())],
+            connected: vec![(1, b1.block()), (2, b2.block())],
+            reconnected: vec![b1.hash],
+            missing: vec![]
         }
+    );
+    assert_eq!(cache.genesis(), &genesis);
+    assert_eq!(cache.store.len().unwrap(), 3);
+    assert_eq!(
+        cache.iter().map(|(_, h)| h).collect::<Vec<_>>(),
+        vec![genesis, b1.block(), b2.block()]
+    );
 
-        blocks.into_iter()
-    }
+    // The stale block is kept, and allows us to switch back.
+    cache.import_blocks(a1.branch([&a2, &a3]), &ctx).unwrap();
+    assert_eq!(cache.tip().0, a3.hash);
+    assert_eq!(cache.genesis(), &genesis);
+    assert_eq!(cache.store.get(0).unwrap(), genesis);
+    assert_eq!(
+        cache.iter().map(|(_, h)| h).collect::<Vec<_>>(),
+        vec![genesis, a1.block(), a2.block(), a3.block()]
+    );
 }
 
-impl Arbitrary for Tree {
-    fn arbitrary<G: Gen>(g: &mut G) -> Tree {
-        let network = bitcoin::Network::Regtest;
-        let genesis = constants::genesis_block(network).header;
-        let height = g.gen_range(1, g.size() / 5 + 1);
-        let forks = g.gen_range(0, g.size() / 10);
+#[test]
+fn test_cache_import_blocks_tip_changes() {
+    let network = bitcoin::Network::Regtest;
+    let genesis = constants::genesis_block(network).header;
+    let params = Params::new(network);
+    let store = store::Memory::new(NonEmpty::new(genesis));
+    let ctx = AdjustedTime::<net::SocketAddr>::new(LOCAL_TIME);
+    let mut cache = BlockCache::from(store, params, &[]).unwrap();
+    let g = &mut rand::thread_rng();
 
-        let mut tree = Tree::new(genesis);
+    // a0 <- a1 <- a2 <- a3 <- a4
+    //         \
+    //          <- b2 <- b3 <- b4 <- b5
+    let a0 = Tree::new(genesis);
+    let a1 = a0.next(g);
+    let a2 = a1.next(g);
+    let a3 = a2.next(g);
+    let a4 = a3.next(g);
+    let b2 = a1.next(g);
+    let b3 = b2.next(g);
+    let b4 = b3.next(g);
+    let b5 = b4.next(g);
 
-        // Generate trunk.
-        for _ in 0..height {
-            tree = tree.next(g);
+    // Every header of the batch is reported, not only the last one.
+    assert_eq!(
+        cache.import_blocks(a0.branch([&a1, &a3]), &ctx).unwrap(),
+        ImportResult::TipChanged {
+            header: a3.block(),
+            hash: a3.hash,
+            height: 3,
+            reverted: vec![],
+            connected: vec![(1, a1.block()), (2, a2.block()), (3, a3.block())],
+            reconnected: vec![],
+            missing: vec![]
         }
-        // Generate forks.
-        for _ in 0..forks {
-            let mut fork = tree.sample(g);
-            let height = g.gen_range(1, g.size() / 5 + 1);
+    );
 
-            for _ in 0..height {
-                fork = fork.next(g);
-            }
+    // `a4` is connected and then rolled back within the same batch, so it isn't reported.
+    let batch = vec![a4.block(), b2.block(), b3.block(), b4.block(), b5.block()];
+    assert_eq!(
+        cache.import_blocks(batch.into_iter(), &ctx).unwrap(),
+        ImportResult::TipChanged {
+            header: b5.block(),
+            hash: b5.hash,
+            height: 5,
+            reverted: vec![(2, a2.block()), (3, a3.block())],
+            connected: vec![
+                (2, b2.block()),
+                (3, b3.block()),
+                (4, b4.block()),
+                (5, b5.block())
+            ],
+            reconnected: vec![b2.hash, b3.hash, b4.hash],
+            missing: vec![]
         }
+    );
+    assert_eq!(cache.tip().0, b5.hash);
+}
 
-        tree
-    }
-
-    fn shrink(&self) -> Box<dyn Iterator<Item = Self>> {
-        let headers = self.headers.read().unwrap().clone();
-        let mut shrunk = Vec::new();
+#[test]
+fn test_check_pow() {
+    type Cache = BlockCache<store::Memory<BlockHeader>>;
 
-        if headers.len() <= 1 {
-            return Box::new(std::iter::empty());
-        }
+    let network = bitcoin::Network::Regtest;
+    let genesis = constants::genesis_block(network).header;
+    let params = Params::new(network);
+    let g = &mut rand::thread_rng();
 
-        {
-            let mut headers = headers.clone();
-            if let Some(tip) = headers.remove(&self.hash) {
-                if let Some(prev) = headers.get(&tip.prev_blockhash) {
-                    shrunk.push(Self {
-                        time: prev.time,
-                        hash: tip.prev_blockhash,
-                        headers: Arc::new(RwLock::new(headers)),
-                        genesis: self.genesis,
-                    });
-                }
-            }
-        }
+    let a1 = Tree::new(genesis).next(g);
+    let invalid = Tree::new(genesis).next_invalid(g);
 
-        for i in 0..headers.len() {
-            if let Some(h) = headers.keys().nth(i) {
-                if h != &self.hash {
-                    let mut headers = headers.clone();
-                    headers.remove(h);
+    Cache::check_pow(&a1.block(), &params).unwrap();
+    assert!(matches!(
+        Cache::check_pow(&invalid.block(), &params),
+        Err(Error::InvalidBlockPoW)
+    ));
 
-                    shrunk.push(Self {
-                        time: self.time,
-                        hash: self.hash,
-                        headers: Arc::new(RwLock::new(headers)),
-                        genesis: self.genesis,
-                    });
-                }
-            }
-        }
+    // A target above the proof-of-work limit is easily met, but invalid.
+    let easy = BlockHeader {
+        bits: 0x2100ffff,
+        ..a1.block()
+    };
+    assert!(matches!(
+        Cache::check_pow(&easy, &params),
+        Err(Error::InvalidBlockTarget(_, limit)) if limit == params.pow_limit
+    ));
+}
 
-        Box::new(shrunk.into_iter())
+/// Create a header on top of the given block, that meets its own target, but has a target
+/// above the proof-of-work limit of every network.
+fn above_pow_limit(prev: &Tree, g: &mut impl Rng) -> BlockHeader {
+    let mut header = BlockHeader {
+        bits: 0x2100ffff,
+        nonce: g.gen(),
+        ..prev.next(g).block()
+    };
+    while header.validate_pow(&header.target()).is_err() {
+        header.nonce += 1;
     }
+    header
 }
 
-impl std::fmt::Debug for Tree {
-    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
-        writeln!(fmt)?;
-        writeln!(fmt, "hash: {:#?}", &self.hash)?;
-        writeln!(fmt, "genesis: {:#?}", &self.genesis)?;
-        writeln!(fmt, "time: {:#?}", &self.time)?;
-        writeln!(fmt, "headers: {:#?}", &self.headers())?;
+/// Assert that a rejected header wasn't stored anywhere in the cache.
+fn assert_rejected(cache: &BlockCache<store::Memory<BlockHeader>>, header: &BlockHeader) {
+    let hash = header.block_hash();
 
-        Ok(())
-    }
+    assert!(!cache.orphans.contains_key(&hash));
+    assert!(!cache.orphans_order.values().any(|h| *h == hash));
+    assert!(!cache.is_known(&hash));
+    assert!(!cache.contains(&hash));
 }
 
-#[ignore]
-#[quickcheck]
-fn prop_cache_import_tree(tree: Tree) -> bool {
-    let headers = tree.headers();
-
+#[test]
+fn test_cache_invalid_pow_orphans_not_stored() {
     let network = bitcoin::Network::Regtest;
     let genesis = constants::genesis_block(network).header;
     let params = Params::new(network);
     let store = store::Memory::new(NonEmpty::new(genesis));
-
     let ctx = AdjustedTime::<net::SocketAddr>::new(LOCAL_TIME);
-    let mut real = BlockCache::from(store, params, &[]).unwrap();
-    let mut model = model::Cache::new(genesis);
+    let mut cache = BlockCache::from(store, params, &[]).unwrap();
+    let g = &mut rand::thread_rng();
 
-    real.import_blocks(headers.iter().cloned(), &ctx).unwrap();
-    model.import_blocks(headers.iter().cloned(), &ctx).unwrap();
+    // a0 <- a1 <- a2 *
+    //  \             \
+    //   <- b1 <- .    <- [a3] <- .
+    //   \     \
+    //    <- .   <- .
+    let a0 = Tree::new(genesis);
+    let a1 = a0.next(g);
+    let a2 = a1.next(g);
+    let a3 = a2.next(g);
+    let b1 = a0.next(g);
 
-    real.tip() == model.tip()
+    cache.import_blocks(a0.branch([&a1, &a2]), &ctx).unwrap();
+    cache.import_block(b1.block(), &ctx).unwrap();
+    assert_eq!(cache.orphan_count(), 1);
+
+    // Invalid blocks forking off the active chain, building on an orphan, and with an
+    // unknown parent.
+    for parent in &[&a0, &b1, &a3] {
+        let invalid = parent.next_invalid(g).block();
+
+        assert!(matches!(
+            cache.import_block(invalid, &ctx),
+            Err(Error::InvalidBlockPoW)
+        ));
+        assert_rejected(&cache, &invalid);
+
+        let err = cache.import_blocks(iter::once(invalid), &ctx).unwrap_err();
+        assert!(matches!(
+            err,
+            Error::BlockImportAborted { ref error, .. } if matches!(**error, Error::InvalidBlockPoW)
+        ));
+        assert_rejected(&cache, &invalid);
+    }
+    assert_eq!(cache.orphan_count(), 1);
+    assert_eq!(cache.tip().0, a2.hash);
 }
 
 #[test]
-fn test_cache_import_back_and_forth() {
+fn test_cache_invalid_target_orphans_not_stored() {
     let network = bitcoin::Network::Regtest;
     let genesis = constants::genesis_block(network).header;
     let params = Params::new(network);
     let store = store::Memory::new(NonEmpty::new(genesis));
     let ctx = AdjustedTime::<net::SocketAddr>::new(LOCAL_TIME);
     let mut cache = BlockCache::from(store, params, &[]).unwrap();
-
     let g = &mut rand::thread_rng();
 
     let a0 = Tree::new(genesis);
-
-    // a0 <- a1 <- a2 *
     let a1 = a0.next(g);
     let a2 = a1.next(g);
+    let a3 = a2.next(g);
+    let b1 = a0.next(g);
 
     cache.import_blocks(a0.branch([&a1, &a2]), &ctx).unwrap();
-    assert_eq!(cache.tip().0, a2.hash);
+    cache.import_block(b1.block(), &ctx).unwrap();
+
+    for parent in &[&a0, &b1, &a3] {
+        let easy = above_pow_limit(parent, g);
+
+        assert!(matches!(
+            cache.import_block(easy, &ctx),
+            Err(Error::InvalidBlockTarget(..))
+        ));
+        assert_rejected(&cache, &easy);
+
+        let err = cache.import_blocks(iter::once(easy), &ctx).unwrap_err();
+        assert!(matches!(
+            err,
+            Error::BlockImportAborted { ref error, .. }
+                if matches!(**error, Error::InvalidBlockTarget(..))
+        ));
+        assert_rejected(&cache, &easy);
+    }
+    assert_eq!(cache.orphan_count(), 1);
 
-    // a0 <- a1 <- a2
-    //           \
-    //            <- b2 <- b3 *
-    let b2 = a1.next(g);
-    let b3 = b2.next(g);
+    // Rejected headers don't become known after their parent is imported either.
+    let easy = above_pow_limit(&a3, g);
+    cache.import_block(easy, &ctx).unwrap_err();
+    cache.import_block(a3.block(), &ctx).unwrap();
 
-    cache.import_blocks(a0.branch([&b2, &b3]), &ctx).unwrap();
-    assert_eq!(cache.tip().0, b3.hash);
+    assert_eq!(cache.tip().0, a3.hash);
+    assert_rejected(&cache, &easy);
+}
+
+#[test]
+fn test_cache_import_resume() {
+    let network = bitcoin::Network::Regtest;
+    let genesis = constants::genesis_block(network).header;
+    let params = Params::new(network);
+    let store = store::Memory::new(NonEmpty::new(genesis));
+    let ctx = AdjustedTime::<net::SocketAddr>::new(LOCAL_TIME);
+    let mut cache = BlockCache::from(store, params, &[]).unwrap();
+    let g = &mut rand::thread_rng();
 
     // a0 <- a1 <- a2 <- a3 <- a4 *
-    //           \
-    //            <- b2 <- b3
+    //  \
+    //   <- x1
+    let a0 = Tree::new(genesis);
+    let a1 = a0.next(g);
+    let a2 = a1.next(g);
     let a3 = a2.next(g);
     let a4 = a3.next(g);
+    let x1 = a0.next_invalid(g);
 
-    cache.import_blocks(a0.branch([&a3, &a4]), &ctx).unwrap();
-    assert_eq!(cache.tip().0, a4.hash);
+    let headers = [a1.block(), a2.block(), x1.block(), a3.block(), a4.block()];
 
-    // a0 <- a1 <- a2 <- a3 <- a4
-    //           \
-    //            <- b2 <- b3 <- b4 <- b5 *
+    let index = match cache.import_blocks(headers.iter().cloned(), &ctx) {
+        Err(Error::BlockImportAborted {
+            index,
+            height,
+            hash,
+            ..
+        }) => {
+            assert_eq!(hash, x1.hash);
+            assert_eq!(height, 2);
+            index
+        }
+        result => panic!("unexpected import result: {:?}", result),
+    };
+    assert_eq!(index, 2, "Two headers were processed before the abort");
+
+    // The headers before the offending one are imported and stored.
+    assert_eq!(cache.tip().0, a2.hash);
+    assert_eq!(cache.store.height().unwrap(), 2);
+    assert_eq!(cache.orphan_count(), 0);
+    assert!(!cache.is_known(&x1.hash));
+
+    // Resume with the headers following the offending one.
+    match cache.import_blocks(headers[index + 1..].iter().cloned(), &ctx) {
+        Ok(ImportResult::TipChanged {
+            hash,
+            height,
+            reverted,
+            ..
+        }) => {
+            assert_eq!(hash, a4.hash);
+            assert_eq!(height, 4);
+            assert!(reverted.is_empty());
+        }
+        result => panic!("unexpected import result: {:?}", result),
+    }
+    assert_eq!(cache.store.height().unwrap(), 4);
+    assert_eq!(
+        cache
+            .iter()
+            .map(|(_, h)| h.block_hash())
+            .collect::<Vec<_>>(),
+        vec![a0.hash, a1.hash, a2.hash, a3.hash, a4.hash]
+    );
+}
+
+#[test]
+fn test_cache_import_missing_parents() {
+    let network = bitcoin::Network::Regtest;
+    let genesis = constants::genesis_block(network).header;
+    let params = Params::new(network);
+    let store = store::Memory::new(NonEmpty::new(genesis));
+    let ctx = AdjustedTime::<net::SocketAddr>::new(LOCAL_TIME);
+    let mut cache = BlockCache::from(store, params, &[]).unwrap();
+    let g = &mut rand::thread_rng();
+
+    // a0 <- a1 <- a2 <- a3
+    //   \
+    //    <- b1 <- b2 <- b3
+    let a0 = Tree::new(genesis);
+    let a1 = a0.next(g);
+    let a2 = a1.next(g);
+    let a3 = a2.next(g);
+    let b1 = a0.next(g);
+    let b2 = b1.next(g);
+    let b3 = b2.next(g);
+    let c1 = a0.next(g);
+    let c2 = c1.next(g);
+
+    // Missing parents are reported across the batch, except for `a2`, which arrives later
+    // in the batch.
+    assert_eq!(
+        cache
+            .import_blocks(vec![a3.block(), b3.block(), a2.block()].into_iter(), &ctx)
+            .unwrap(),
+        ImportResult::MissingParents(vec![b2.hash, a1.hash])
+    );
+    assert_eq!(cache.tip().0, genesis.block_hash());
+
+    // Once the gap is filled, the tip changes. Missing parents are still reported.
+    let result = cache
+        .import_blocks(vec![a1.block(), c2.block()].into_iter(), &ctx)
+        .unwrap();
+    assert!(matches!(
+        result,
+        ImportResult::TipChanged { hash, height: 3, .. } if hash == a3.hash
+    ));
+    assert_eq!(result.missing_parents(), &[c1.hash]);
+    assert_eq!(
+        cache.import_blocks(iter::once(b1.block()), &ctx).unwrap(),
+        ImportResult::TipUnchanged
+    );
+}
+
+#[test]
+fn test_cache_snapshot() {
+    let network = bitcoin::Network::Regtest;
+    let genesis = constants::genesis_block(network).header;
+    let params = Params::new(network);
+    let store = store::Memory::new(NonEmpty::new(genesis));
+    let ctx = AdjustedTime::<net::SocketAddr>::new(LOCAL_TIME);
+    let mut cache = BlockCache::from(store, params.clone(), &[]).unwrap();
+    let g = &mut rand::thread_rng();
+
+    // a0 <- a1 <- a2 <- a3 *
+    //        \
+    //         <- b2 <- (b3) <- b4
+    let a0 = Tree::new(genesis);
+    let a1 = a0.next(g);
+    let a3 = a1.next(g).next(g);
+    let b2 = a1.next(g);
+    let b3 = b2.next(g);
     let b4 = b3.next(g);
-    let b5 = b4.next(g);
 
-    cache.import_blocks(a1.branch([&b4, &b5]), &ctx).unwrap();
-    assert_eq!(cache.tip().0, b5.hash);
+    cache.import_blocks(a0.branch([&a1, &a3]), &ctx).unwrap();
+    cache.import_block(b2.block(), &ctx).unwrap();
+    cache.import_blocks(iter::once(b4.block()), &ctx).unwrap();
+
+    let mut snapshot = Vec::new();
+    cache.save_snapshot(&mut snapshot).unwrap();
+
+    let restored =
+        BlockCache::load_snapshot(cache.store.clone(), params.clone(), &[], &snapshot[..]).unwrap();
+    let rebuilt = BlockCache::from(cache.store.clone(), params.clone(), &[]).unwrap();
+
+    assert_eq!(
+        restored.iter().collect::<Vec<_>>(),
+        cache.iter().collect::<Vec<_>>()
+    );
+    assert_eq!(restored.headers, rebuilt.headers);
+    assert_eq!(restored.chain_work(), cache.chain_work());
+    assert_eq!(restored.orphan_count(), 2);
+    assert_eq!(restored.seq, cache.seq);
+
+    // Saving the restored cache gives the same snapshot.
+    let mut again = Vec::new();
+    restored.save_snapshot(&mut again).unwrap();
+    assert_eq!(again, snapshot);
+
+    // The restored cache behaves like the original one.
+    let mut restored = restored;
+    restored.import_block(b3.block(), &ctx).unwrap();
+    assert_eq!(restored.tip().0, b4.hash);
+
+    // Snapshots with another version are ignored.
+    let mut unsupported = snapshot.clone();
+    unsupported[0] += 1;
+    let cache =
+        BlockCache::load_snapshot(cache.store.clone(), params.clone(), &[], &unsupported[..])
+            .unwrap();
+    assert_eq!(cache.headers, rebuilt.headers);
+    assert_eq!(cache.orphan_count(), 0);
+
+    // Snapshots that don't match the store are ignored.
+    let cache =
+        BlockCache::load_snapshot(restored.store.clone(), params, &[], &snapshot[..]).unwrap();
+    assert_eq!(cache.tip().0, b4.hash);
+    assert_eq!(cache.height(), 4);
 }
 
 #[test]
-fn test_cache_import_equal_difficulty_blocks() {
-    let mut headers = vec![
-        BlockHeader {
-            version: 1,
-            prev_blockhash: BlockHash::from_hex(
-                "0f9188f13cb7b2c71f2a335e3a4fc328bf5beb436012afca590b1a11466e2206",
-            )
-            .unwrap(),
-            merkle_root: Default::default(),
-            time: 1296688662,
-            bits: 545259519,
-            nonce: 3705677718,
-        },
-        BlockHeader {
-            version: 1,
-            prev_blockhash: BlockHash::from_hex(
-                "40e6856aba3aa0bab2ba97b5612dc22a485c3a583dc98a9f1cd1706dd858f623",
-            )
-            .unwrap(),
-            merkle_root: Default::default(),
-            time: 1296688722,
-            bits: 545259519,
-            nonce: 3581550584,
-        },
-        BlockHeader {
-            version: 1,
-            prev_blockhash: BlockHash::from_hex(
-                "40e6856aba3aa0bab2ba97b5612dc22a485c3a583dc98a9f1cd1706dd858f623",
-            )
-            .unwrap(),
-            merkle_root: Default::default(),
-            time: 1296688722,
-            bits: 545259519,
-            nonce: 3850925874,
-        },
-    ];
+fn test_cache_candidate_count() {
     let network = bitcoin::Network::Regtest;
     let genesis = constants::genesis_block(network).header;
     let params = Params::new(network);
     let store = store::Memory::new(NonEmpty::new(genesis));
     let ctx = AdjustedTime::<net::SocketAddr>::new(LOCAL_TIME);
+    let mut cache = BlockCache::from(store, params, &[]).unwrap();
+    let g = &mut rand::thread_rng();
 
-    let mut real = BlockCache::from(store.clone(), params.clone(), &[]).unwrap();
-    let mut model = model::Cache::new(genesis);
+    // a0 <- a1 <- a2 <- a3 *
+    //        \
+    //         <- b2 <- b3
+    //
+    //               (c3) <- c4
+    let a0 = Tree::new(genesis);
+    let a1 = a0.next(g);
+    let a3 = a1.next(g).next(g);
+    let b2 = a1.next(g);
+    let b3 = b2.next(g);
+    let c4 = a3.next(g).next(g);
 
-    model.import_blocks(headers.iter().cloned(), &ctx).unwrap();
-    real.import_blocks(headers.iter().cloned(), &ctx).unwrap();
+    cache.import_blocks(a0.branch([&a1, &a3]), &ctx).unwrap();
+    assert_eq!(cache.candidate_count(&ctx), 0);
 
-    assert_eq!(real.tip(), model.tip());
+    cache.import_blocks(a1.branch([&b2, &b3]), &ctx).unwrap();
+    cache.import_blocks(iter::once(c4.block()), &ctx).unwrap();
 
-    let expected =
-        BlockHash::from_hex("79cdea612df7f65b541da8ff45913f472eb0bf9376e1b9e3cd2c6ce78f261954")
-            .unwrap();
+    // Every block of the `b` branch is the tip of a candidate, while `c4` doesn't connect.
+    assert_eq!(cache.orphan_count(), 3);
+    assert_eq!(cache.candidate_count(&ctx), 2);
+    assert_eq!(
+        cache.candidate_count(&ctx),
+        cache.chain_candidates(&ctx).0.len()
+    );
+}
 
-    assert_eq!(real.tip().0, expected);
-    assert_eq!(model.tip().0, expected);
+#[test]
+fn test_cache_min_version() {
+    let network = bitcoin::Network::Regtest;
+    let genesis = constants::genesis_block(network).header;
+    let params = Params::new(network);
+    let store = store::Memory::new(NonEmpty::new(genesis));
+    let ctx = AdjustedTime::<net::SocketAddr>::new(LOCAL_TIME);
+    let g = &mut rand::thread_rng();
 
-    // Swap the import order. Tip should be stable.
+    // a0 <- a1 <- a2 *
+    //   \
+    //    <- b1 <- b2 <- b3
+    let a0 = Tree::new(genesis);
+    let a1 = a0.next(g);
+    let a2 = a1.next(g);
+    let b1 = a0.next(g);
+    let b3 = b1.next(g).next(g);
+    assert_eq!(a1.block().version, 1);
 
-    headers.swap(1, 2);
+    let cache = BlockCache::from(store, params, &[]).unwrap();
 
-    let mut real = BlockCache::from(store, params, &[]).unwrap();
-    let mut model = model::Cache::new(genesis);
+    let mut forked = cache.clone();
+    forked.import_blocks(a0.branch([&a1, &a2]), &ctx).unwrap();
 
-    model.import_blocks(headers.iter().cloned(), &ctx).unwrap();
-    real.import_blocks(headers.iter().cloned(), &ctx).unwrap();
+    let mut unchecked = forked.clone();
+    unchecked
+        .import_blocks(a0.branch([&b1, &b3]), &ctx)
+        .unwrap();
+    assert_eq!(unchecked.tip().0, b3.hash);
 
-    assert_eq!(real.tip().0, expected);
-    assert_eq!(model.tip().0, expected);
+    let mut cache = cache.with_min_version(2);
+    assert!(matches!(
+        cache.import_block(a1.block(), &ctx),
+        Err(Error::InvalidBlockVersion(1))
+    ));
+
+    let mut header = BlockHeader {
+        version: 2,
+        ..a1.block()
+    };
+    block::solve(&mut header);
+    cache.import_block(header, &ctx).unwrap();
+    assert_eq!(cache.tip().0, header.block_hash());
+
+    // Forks are held to the same standard.
+    let mut cache = forked.with_min_version(2);
+    cache.import_blocks(a0.branch([&b1, &b3]), &ctx).unwrap();
+    assert_eq!(cache.tip().0, a2.hash);
 }
 
 #[test]
-fn test_cache_import_longer_chain_with_less_difficulty() {
-    // TODO
+fn test_cache_hashes() {
+    let network = bitcoin::Network::Bitcoin;
+    let params = Params::new(network);
+    let chain = &nakamoto_test::BITCOIN_HEADERS;
+    let height = chain.len() as Height - 1;
+    let store = store::Memory::new((*chain).clone());
+    let cache = BlockCache::from(store, params, &[]).unwrap();
+
+    let hash = |height: Height| chain.get(height as usize).unwrap().block_hash();
+
+    assert_eq!(cache.hashes(0..3), vec![hash(0), hash(1), hash(2)]);
+    assert_eq!(
+        cache.hashes(10..20),
+        cache
+            .range(10..20)
+            .map(|blk| blk.header.block_hash())
+            .collect::<Vec<_>>()
+    );
+    assert_eq!(
+        cache.hashes(height - 1..height + 10),
+        vec![hash(height - 1), hash(height)],
+        "The range is clamped to the tip"
+    );
+    assert!(cache.hashes(height + 1..height + 10).is_empty());
+    assert!(cache.hashes(5..5).is_empty());
+    #[allow(clippy::reversed_empty_ranges)]
+    let inverted = 5..2;
+    assert!(cache.hashes(inverted).is_empty());
 }
 
 #[test]
-fn test_cache_import_with_checkpoints() {
+fn test_cache_headers_since() {
     let network = bitcoin::Network::Regtest;
     let genesis = constants::genesis_block(network).header;
     let params = Params::new(network);
     let store = store::Memory::new(NonEmpty::new(genesis));
     let ctx = AdjustedTime::<net::SocketAddr>::new(LOCAL_TIME);
+    let mut cache = BlockCache::from(store, params, &[]).unwrap();
     let g = &mut rand::thread_rng();
 
-    let tree = Tree::new(genesis);
-
-    // a0 <- a1 <- a2 <- a3 *
-    let a1 = tree.next(g);
+    // a0 <- a1 <- a2 <- a3 <- a4 *
+    //        \
+    //         <- b2
+    let a0 = Tree::new(genesis);
+    let a1 = a0.next(g);
     let a2 = a1.next(g);
     let a3 = a2.next(g);
+    let a4 = a3.next(g);
+    let b2 = a1.next(g);
 
-    let mut cache = BlockCache::from(store.clone(), params.clone(), &[]).unwrap();
-    cache.import_blocks(tree.branch([&a1, &a3]), &ctx).unwrap();
-
-    let mut cache =
-        BlockCache::from(store.clone(), params.clone(), &[(32, Default::default())]).unwrap();
-    cache
-        .import_blocks(tree.branch([&a1, &a3]), &ctx)
-        .expect("A checkpoint in the future cannot cause any error");
+    cache.import_blocks(a0.branch([&a1, &a4]), &ctx).unwrap();
+    cache.import_block(b2.block(), &ctx).unwrap();
 
-    let mut cache =
-        BlockCache::from(store.clone(), params.clone(), &[(1, Default::default())]).unwrap();
-    assert!(
-        matches! {
-            cache.import_block(a1.block(), &ctx),
-            Err(Error::InvalidBlockHash(hash, 1)) if hash == a1.hash
-        },
-        "An incorrect checkpoint at height 1 causes an error"
+    assert_eq!(
+        cache.headers_since(&a0.hash, usize::MAX),
+        vec![a1.block(), a2.block(), a3.block(), a4.block()]
     );
-
-    let mut cache = BlockCache::from(store, params, &[(1, a1.hash), (2, a2.hash)]).unwrap();
-    cache
-        .import_blocks(tree.branch([&a1, &a2]), &ctx)
-        .expect("Correct checkpoints cause no error");
+    assert_eq!(
+        cache.headers_since(&a1.hash, 2),
+        vec![a2.block(), a3.block()]
+    );
+    assert_eq!(cache.headers_since(&a3.hash, 0), vec![]);
+    assert_eq!(
+        cache.headers_since(&a4.hash, 10),
+        vec![],
+        "Nothing follows the tip"
+    );
+    assert_eq!(
+        cache.headers_since(&b2.hash, 10),
+        vec![],
+        "Orphans are ignored"
+    );
+    assert_eq!(cache.headers_since(&b2.next(g).hash, 10), vec![]);
 }
 
 #[test]
-fn test_cache_import_invalid_fork() {
+fn test_cache_orphan_info() {
+    use super::OrphanInfo;
+
     let network = bitcoin::Network::Regtest;
     let genesis = constants::genesis_block(network).header;
     let params = Params::new(network);
@@ -977,128 +5870,95 @@ fn test_cache_import_invalid_fork() {
     let mut cache = BlockCache::from(store, params, &[]).unwrap();
     let g = &mut rand::thread_rng();
 
-    let a0 = Tree::new(genesis);
-
     // a0 <- a1 <- a2 <- a3 *
+    //        \
+    //         <- b2
+    //
+    //                (c3) <- c4
+    let a0 = Tree::new(genesis);
     let a1 = a0.next(g);
     let a2 = a1.next(g);
     let a3 = a2.next(g);
-
-    cache.import_blocks(a0.branch([&a1, &a3]), &ctx).unwrap();
-    assert_eq!(cache.tip().0, a3.hash);
-
-    //            <- c2 <- c3 <- c4 <- (c5)
-    //           /
-    // a0 <- a1 <- a2 <- a3 *
-    //
-    let c2 = a1.next(g);
-    let c3 = c2.next(g);
+    let b2 = a1.next(g);
+    let c3 = a2.next(g);
     let c4 = c3.next(g);
-    let c5 = c4.next_invalid(g);
 
-    cache
-        .import_blocks(a0.branch([&c3, &c5]), &ctx)
-        .unwrap_err();
-    assert_eq!(cache.tip().0, a3.hash);
+    cache.import_blocks(a0.branch([&a1, &a3]), &ctx).unwrap();
+    cache.import_blocks(iter::once(b2.block()), &ctx).unwrap();
+    cache.import_blocks(iter::once(c4.block()), &ctx).unwrap();
 
-    cache.import_block(c2.block(), &ctx).unwrap();
     assert_eq!(
-        cache.tip().0,
-        c4.hash,
-        "Switch to sub-fork, since the last block is invalid"
+        cache.orphan_info(),
+        vec![
+            OrphanInfo {
+                hash: b2.hash,
+                prev: a1.hash,
+                fork_height: Some(1),
+                connects: true,
+            },
+            OrphanInfo {
+                hash: c4.hash,
+                prev: c3.hash,
+                fork_height: None,
+                connects: false,
+            },
+        ]
     );
 
-    //            <- c2 <- c3 <- c4 *
-    //           /
-    // a0 <- a1 <- a2 <- a3
-    //                \
-    //                 <- (b3) <- b4 <- b5 <- b6
-    let b3 = a2.next_invalid(g);
-    let b4 = b3.next(g);
-    let b5 = b4.next(g);
-    let b6 = b5.next(g);
-
-    cache.import_blocks(a0.branch([&b4, &b6]), &ctx).unwrap();
-    cache.import_block(b3.block(), &ctx).unwrap_err();
-    assert_eq!(cache.tip().0, c4.hash, "Don't switch to invalid fork");
+    // Forks below the last checkpoint are not viable.
+    cache.add_checkpoint(2, a2.hash).unwrap();
+    assert!(!cache.orphan_info()[0].connects);
+    assert_eq!(cache.orphan_info()[0].fork_height, Some(1));
 }
 
 #[test]
-fn test_cache_import_fork_with_checkpoints() {
+fn test_cache_missing_parents() {
     let network = bitcoin::Network::Regtest;
     let genesis = constants::genesis_block(network).header;
     let params = Params::new(network);
     let store = store::Memory::new(NonEmpty::new(genesis));
     let ctx = AdjustedTime::<net::SocketAddr>::new(LOCAL_TIME);
+    let mut cache = BlockCache::from(store, params, &[]).unwrap();
     let g = &mut rand::thread_rng();
 
+    // a0 <- a1 <- a2 *
+    //  \
+    //   <- b1 <- (b2) <- b3 <- b4
+    //   \                \
+    //    <- (c1) <- c2     <- b4'
     let a0 = Tree::new(genesis);
-
-    // a0 <- a1 <- [a2] <- a3 *
     let a1 = a0.next(g);
     let a2 = a1.next(g);
-    let a3 = a2.next(g);
-
-    // Prevent block `a2` from being reverted.
-    let checkpoints = &[(0, genesis.block_hash()), (2, a2.hash)];
-    let mut cache = BlockCache::from(store, params, checkpoints).unwrap();
-
-    cache.import_blocks(a0.branch([&a1, &a3]), &ctx).unwrap();
-    assert_eq!(cache.tip().0, a3.hash, "{:#?}", cache);
-
-    //            <- c2
-    //           /
-    // a0 <- a1 <- [a2] <- a3 *
-    //    \
-    //     <- b1
     let b1 = a0.next(g);
-    let c2 = a1.next(g);
-
-    assert!(
-        matches! {
-            cache.import_block(b1.block(), &ctx),
-            Err(Error::InvalidBlockHeight(1))
-        },
-        "Can't fork passed the last checkpoint"
-    );
-    assert!(
-        matches! {
-            cache.import_block(c2.block(), &ctx),
-            Err(Error::InvalidBlockHeight(2))
-        },
-        "Can't fork passed the last checkpoint"
-    );
+    let b2 = b1.next(g);
+    let b3 = b2.next(g);
+    let b4 = b3.next(g);
+    let b4_ = b3.next(g);
+    let c1 = a0.next(g);
+    let c2 = c1.next(g);
 
-    //            <- c2 <- c3 <- c4
-    //           /
-    // a0 <- a1 <- [a2] <- a3 *
-    let c3 = c2.next(g);
-    let c4 = c3.next(g);
+    cache.import_blocks(a0.branch([&a1, &a2]), &ctx).unwrap();
+    assert!(cache.missing_parents().is_empty());
 
-    // Import more blocks starting from before the last checkpoint.
-    cache.import_blocks(a0.branch([&c3, &c4]), &ctx).unwrap();
+    cache
+        .import_blocks(
+            vec![b1.block(), b3.block(), c2.block(), b4.block(), b4_.block()].into_iter(),
+            &ctx,
+        )
+        .unwrap();
+    assert_eq!(cache.orphan_count(), 5);
     assert_eq!(
-        cache.tip().0,
-        a3.hash,
-        "We can import a longer chain, but it won't overtake the main chain"
+        cache.missing_parents(),
+        vec![b2.hash, c1.hash],
+        "Missing parents are listed once, in the arrival order of their children"
     );
 
-    // a0 <- a1 <- [a2] <- a3
-    //                  \
-    //                   <- b3 <- b4 *
-    let b3 = a2.next(g);
-    let b4 = b3.next(g);
-
-    cache.import_blocks(a0.branch([&b3, &b4]), &ctx).unwrap();
-    assert_eq!(
-        cache.tip().0,
-        b4.hash,
-        "Forking after the last checkpoint is fine"
-    );
+    cache.import_blocks(iter::once(b2.block()), &ctx).unwrap();
+    assert_eq!(cache.missing_parents(), vec![c1.hash]);
 }
 
 #[test]
-fn test_cache_import_fork_with_future_checkpoint() {
+fn test_cache_invalid_orphans_dropped() {
     let network = bitcoin::Network::Regtest;
     let genesis = constants::genesis_block(network).header;
     let params = Params::new(network);
@@ -1106,291 +5966,216 @@ fn test_cache_import_fork_with_future_checkpoint() {
     let ctx = AdjustedTime::<net::SocketAddr>::new(LOCAL_TIME);
     let g = &mut rand::thread_rng();
 
+    // a0 <- a1 <- a2 *
+    //  \
+    //   <- (b1) <- b2 <- b3 <- b4 <- b5 <- b6
+    //                                 \
+    //                                  <- b6'
     let a0 = Tree::new(genesis);
-
-    // a0 <- a1 <- a2 * ... [c4]
     let a1 = a0.next(g);
     let a2 = a1.next(g);
-
-    // Create a checkpoint block [c4] in the future, at height 4.
-    let checkpoints = &[(0, genesis.block_hash()), (4, BlockHash::default())];
+    let b1 = a0.next(g);
+    let b2 = b1.next(g);
+    let b3 = b2.next(g);
+    let b4 = b3.next(g);
+    let b5 = b4.next(g);
+    let b6 = b5.next(g);
+    let b6_ = b5.next(g);
+
+    // The checkpoint rules out `b5` and its descendants.
+    let checkpoints = &[(5, Default::default())];
     let mut cache = BlockCache::from(store, params, checkpoints).unwrap();
 
     cache.import_blocks(a0.branch([&a1, &a2]), &ctx).unwrap();
-    assert_eq!(cache.tip().0, a2.hash, "{:#?}", cache);
-
-    //            <- b2
-    //           /          ... [c4]
-    // a0 <- a1 <- a2 *
-    //
-    let b2 = a1.next(g);
-
-    // We can do this because we don't know yet whether a2 or b2 is the correct branch.
     cache
-        .import_block(b2.block(), &ctx)
-        .expect("we can import a fork before the next checkpoint");
+        .import_blocks(a0.branch([&b2, &b6]).chain(iter::once(b6_.block())), &ctx)
+        .unwrap();
+    assert_eq!(cache.orphan_count(), 6);
+
+    cache.import_blocks(iter::once(b1.block()), &ctx).unwrap();
+    assert_eq!(cache.tip().0, b4.hash);
+    assert_eq!(
+        cache.orphan_count(),
+        2,
+        "Only the blocks of the stale chain are left, and the invalid blocks are dropped"
+    );
+    assert!(cache.orphans.contains_key(&a1.hash));
+    assert!(cache.orphans.contains_key(&a2.hash));
 }
 
 #[test]
-#[allow(unused_variables)]
-fn test_cache_import_duplicate() {
+fn test_cache_future_orphans_kept() {
     let network = bitcoin::Network::Regtest;
     let genesis = constants::genesis_block(network).header;
     let params = Params::new(network);
     let store = store::Memory::new(NonEmpty::new(genesis));
-    let ctx = AdjustedTime::<net::SocketAddr>::new(LOCAL_TIME);
-    let mut cache = BlockCache::from(store, params, &[]).unwrap();
     let g = &mut rand::thread_rng();
 
-    let tree = Tree::new(genesis);
-    let a0 = tree;
-
-    // a0 <- a1 <- a2 <- a3 *
+    // a0 <- a1 <- a2 *
+    //  \
+    //   <- (b1) <- b2 <- b3
+    let a0 = Tree::new(genesis);
     let a1 = a0.next(g);
     let a2 = a1.next(g);
-    let a3 = a2.next(g);
+    let b1 = a0.next(g);
+    let b2 = b1.next(g);
+    let b3 = b2.next_at(LOCAL_TIME.block_time() + 24 * 60 * 60, g);
 
-    assert!(matches! {
-        cache.import_block(a1.block(), &ctx), Ok(_)
-    });
-    assert!(matches! {
-        cache.import_block(a1.block(), &ctx),
-        Err(Error::DuplicateBlock(h)) if h == a1.hash
-    });
+    let mut ctx = AdjustedTime::<net::SocketAddr>::new(LOCAL_TIME);
+    let mut cache = BlockCache::from(store, params, &[]).unwrap();
 
-    assert!(matches! {
-        cache.import_block(a2.block(), &ctx), Ok(_)
-    });
-    assert!(matches! {
-        cache.import_block(a2.block(), &ctx), Err(Error::DuplicateBlock(_))
-    });
+    cache.import_blocks(a0.branch([&a1, &a2]), &ctx).unwrap();
+    cache.import_blocks(a0.branch([&b2, &b3]), &ctx).unwrap();
 
-    // a0 <- a1 <- a2 <- a3 *
-    //           \
-    //            <- b3
-    let b3 = a1.next(g);
+    // `b3` is too far in the future, but `b1` and `b2` don't outweigh the active chain.
+    cache.import_blocks(iter::once(b1.block()), &ctx).unwrap();
+    assert_eq!(cache.tip().0, a2.hash);
+    assert_eq!(cache.orphan_count(), 3, "No blocks are dropped");
 
-    assert!(matches! {
-        cache.import_block(b3.block(), &ctx), Ok(_)
-    });
-    assert!(matches! {
-        cache.import_block(b3.block(), &ctx),
-        Err(Error::DuplicateBlock(h)) if h == b3.hash
-    });
-    assert!(matches! {
-        cache.import_block(a0.block(), &ctx),
-        Err(Error::DuplicateBlock(h)) if h == a0.hash
-    });
+    // Once the clock catches up, `b3` is valid.
+    ctx.set_local_time(LocalTime::from_block_time(b3.time));
+    let c1 = a0.next(g);
+    cache.import_blocks(iter::once(c1.block()), &ctx).unwrap();
+    assert_eq!(cache.tip().0, b3.hash);
 }
 
 #[test]
-#[allow(unused_variables)]
-fn test_cache_import_unordered() {
+fn test_cache_max_reorg_depth() {
     let network = bitcoin::Network::Regtest;
     let genesis = constants::genesis_block(network).header;
     let params = Params::new(network);
     let store = store::Memory::new(NonEmpty::new(genesis));
     let ctx = AdjustedTime::<net::SocketAddr>::new(LOCAL_TIME);
-    let mut cache = BlockCache::from(store, params, &[]).unwrap();
-    let mut model = model::Cache::new(genesis);
-
     let g = &mut rand::thread_rng();
 
+    // a0 <- a1 <- a2 <- a3 <- a4 *
+    //        \           \
+    //         \           <- c4 <- c5
+    //          <- b2 <- b3 <- b4 <- b5 <- b6
     let a0 = Tree::new(genesis);
-
-    // a0 <- a1 <- a2 <- a3 *
     let a1 = a0.next(g);
-    let a2 = a1.next(g);
-    let a3 = a2.next(g);
-
-    cache.import_blocks(a0.branch([&a1, &a3]), &ctx).unwrap();
-    assert_eq!(cache.tip().0, a3.hash, "{:#?}", cache);
-
-    // a0 <- a1 <- a2 <- a3
-    //                 \
-    //                  <- b3 <- b4 *
-    let b3 = a2.next(g);
-    let b4 = b3.next(g);
-
-    cache.import_blocks(a0.branch([&b3, &b4]), &ctx).unwrap();
-    assert_eq!(cache.tip().0, b4.hash, "{:#?}", cache);
-
-    //            <- c2 <- c3 <- c4 <- c5 *
-    //           /
-    // a0 <- a1 <- a2 <- a3
-    //                 \
-    //                  <- b3 <- b4
-    let c2 = a1.next(g);
-    let c3 = c2.next(g);
-    let c4 = c3.next(g);
+    let a3 = a1.next(g).next(g);
+    let a4 = a3.next(g);
+    let b2 = a1.next(g);
+    let b6 = b2.next(g).next(g).next(g).next(g);
+    let c4 = a3.next(g);
     let c5 = c4.next(g);
 
-    cache.import_blocks(a0.branch([&c2, &c5]), &ctx).unwrap();
-    assert_eq!(cache.tip().0, c5.hash, "{:#?}", cache);
-
-    //                                <- d5 <- d6 *
-    //                               /
-    //            <- c2 <- c3 <- c4 <- c5
-    //           /
-    // a0 <- a1 <- a2 <- a3
-    //                 \
-    //                  <- b3 <- b4
-    let d5 = c4.next(g);
-    let d6 = d5.next(g);
-
-    let mut headers = a0.headers();
-    let mut rng = rand::thread_rng();
-    let len = headers.len();
-
-    // Expected longest chain.
-    let expected = vec![
-        a0.hash, a1.hash, c2.hash, c3.hash, c4.hash, d5.hash, d6.hash,
-    ];
-
-    for _ in 0..len * len {
-        use rand::seq::SliceRandom;
-
-        headers.shuffle(&mut rng);
-
-        cache.import_blocks(headers.iter().cloned(), &ctx).unwrap();
-        assert_eq!(cache.tip().0, d6.hash);
-
-        model.import_blocks(headers.iter().cloned(), &ctx).unwrap();
-        assert_eq!(model.tip().0, d6.hash);
+    let mut cache = BlockCache::from(store, params, &[])
+        .unwrap()
+        .with_max_reorg_depth(2);
+    cache.import_blocks(a0.branch([&a1, &a4]), &ctx).unwrap();
 
-        let actual = cache.chain().map(|h| h.block_hash()).collect::<Vec<_>>();
-        assert_eq!(actual, expected);
+    // Switching to the `b` branch would roll back three blocks.
+    let mut b = cache.clone();
+    b.import_blocks(a1.branch([&b2, &b6]), &ctx).unwrap();
+    assert_eq!(b.tip().0, a4.hash);
 
-        let actual = model.chain().map(|h| h.block_hash()).collect::<Vec<_>>();
-        assert_eq!(actual, expected);
-    }
+    let candidate = b.fork(&b6.hash).unwrap();
+    assert!(matches!(
+        b.switch_to_fork(&candidate, &ctx),
+        Err(Error::ReorgTooDeep { depth: 3, limit: 2 })
+    ));
+    assert_eq!(b.tip().0, a4.hash);
+
+    // Shallower re-orgs are allowed.
+    cache.import_blocks(a3.branch([&c4, &c5]), &ctx).unwrap();
+    assert_eq!(cache.tip().0, c5.hash);
+
+    // Without a limit, the `b` branch wins.
+    let mut unlimited = BlockCache::from(b.store.clone(), b.params.clone(), &[]).unwrap();
+    unlimited
+        .import_blocks(a1.branch([&b2, &b6]), &ctx)
+        .unwrap();
+    assert_eq!(unlimited.tip().0, b6.hash);
 }
 
 #[test]
-#[allow(clippy::identity_op)]
-fn test_cache_locate_headers() {
-    let network = bitcoin::Network::Bitcoin;
+fn test_cache_verify_tx_proof() {
+    use bitcoin::hash_types::Txid;
+    use bitcoin::util::merkleblock::PartialMerkleTree;
+    use bitcoin_hashes::Hash;
+
+    let network = bitcoin::Network::Regtest;
     let genesis = constants::genesis_block(network).header;
     let params = Params::new(network);
     let store = store::Memory::new(NonEmpty::new(genesis));
     let ctx = AdjustedTime::<net::SocketAddr>::new(LOCAL_TIME);
     let mut cache = BlockCache::from(store, params, &[]).unwrap();
-    let chain = &nakamoto_test::BITCOIN_HEADERS;
-    let height = chain.len() - 1;
+    let g = &mut rand::thread_rng();
 
-    assert!(chain.len() > 99);
+    let txids = (0..7u8).map(|i| Txid::hash(&[i])).collect::<Vec<_>>();
+    let matches = txids
+        .iter()
+        .map(|txid| txid == &txids[3])
+        .collect::<Vec<_>>();
+    let proof = PartialMerkleTree::from_txids(&txids, &matches);
+    let root = proof.extract_matches(&mut vec![], &mut vec![]).unwrap();
 
-    cache.import_blocks(chain.iter().cloned(), &ctx).unwrap();
+    let a0 = Tree::new(genesis);
+    let mut a1 = BlockHeader {
+        merkle_root: root,
+        ..a0.next(g).block()
+    };
+    block::solve(&mut a1);
 
-    assert!(cache
-        .locate_headers(&[], BlockHash::default(), 1)
-        .is_empty());
+    cache.import_block(a1, &ctx).unwrap();
 
-    assert_eq!(
-        cache.locate_headers(&[], chain.last().block_hash(), 1),
-        vec![*chain.last()],
-        "Passing just a stop hash requests the block"
+    assert!(cache.verify_tx_proof(1, &txids[3], &proof));
+    assert!(
+        !cache.verify_tx_proof(1, &txids[4], &proof),
+        "The transaction must be matched"
     );
-
-    let unknown =
-        BlockHash::from_hex("0f9188f13cb7b2c71f2a345e3a4fc328bf5bbb436012afca590b1a11466e2206")
-            .unwrap();
-
-    assert_eq!(
-        cache.locate_headers(&[unknown, unknown], BlockHash::default(), 4),
-        chain.iter().skip(1).take(4).cloned().collect::<Vec<_>>(),
-        "When the locators are unknown, starts from genesis"
+    assert!(
+        !cache.verify_tx_proof(0, &txids[3], &proof),
+        "The merkle root must match"
     );
+    assert!(!cache.verify_tx_proof(2, &txids[3], &proof));
+    assert!(!cache.verify_tx_proof(Height::MAX, &txids[3], &proof));
 
-    let known1 = chain.get(12).unwrap();
-    let known2 = chain.get(45).unwrap();
-
-    assert_eq!(
-        cache.locate_headers(
-            &[unknown, known1.block_hash(), known2.block_hash()],
-            BlockHash::default(),
-            9
-        ),
-        chain
-            .iter()
-            .skip(12 + 1)
-            .take(9)
-            .cloned()
-            .collect::<Vec<_>>(),
-        "The headers start after the first known hash"
-    );
+    let other = PartialMerkleTree::from_txids(&[txids[3], txids[4]], &[true, false]);
+    assert!(!cache.verify_tx_proof(1, &txids[3], &other));
+}
 
-    assert_eq!(
-        cache.locate_headers(&[chain.last().block_hash()], BlockHash::default(), 9),
-        vec![],
-        "Nothing is returned if we're starting from the tip"
-    );
+#[test]
+fn test_cache_from_headers() {
+    let network = bitcoin::Network::Bitcoin;
+    let genesis = constants::genesis_block(network).header;
+    let params = Params::new(network);
+    let ctx = AdjustedTime::<net::SocketAddr>::new(LOCAL_TIME);
+    let chain = &nakamoto_test::BITCOIN_HEADERS;
+    let store = store::Memory::new(NonEmpty::new(genesis));
 
-    assert_eq!(
-        cache.locate_headers(
-            &[chain.get(height - 3).unwrap().block_hash()],
-            BlockHash::default(),
-            9
-        ),
-        vec![
-            *chain.get(height - 2).unwrap(),
-            *chain.get(height - 1).unwrap(),
-            *chain.get(height - 0).unwrap()
-        ],
-        "If the max amount is more than we have, return up to the tip"
-    );
+    let cache =
+        BlockCache::from_headers(store.clone(), params.clone(), &[], &chain.tail, &ctx).unwrap();
+    let expected =
+        BlockCache::from(store::Memory::new((*chain).clone()), params.clone(), &[]).unwrap();
 
-    assert_eq!(
-        cache.locate_headers(
-            &[chain.get(5).unwrap().block_hash()],
-            chain.get(8).unwrap().block_hash(),
-            9
-        ),
-        vec![
-            *chain.get(6).unwrap(),
-            *chain.get(7).unwrap(),
-            *chain.get(8).unwrap()
-        ],
-        "Stops at the stop hash"
-    );
+    assert_eq!(cache.tip(), expected.tip());
+    assert_eq!(cache.headers, expected.headers);
+    assert_eq!(cache.chain_work(), expected.chain_work());
+    assert_eq!(cache.store.len().unwrap(), chain.len());
 
-    assert_eq!(
-        cache.locate_headers(
-            &[chain.get(5).unwrap().block_hash()],
-            chain.get(8).unwrap().block_hash(),
-            2
-        ),
-        vec![*chain.get(6).unwrap(), *chain.get(7).unwrap(),],
-        "Stops at the max"
-    );
+    // An invalid header.
+    let mut headers = chain.tail.clone();
+    headers[99].nonce += 1;
 
-    assert_eq!(
-        cache.locate_headers(&[unknown], chain.get(3).unwrap().block_hash(), 9),
-        vec![
-            *chain.get(1).unwrap(),
-            *chain.get(2).unwrap(),
-            *chain.get(3).unwrap()
-        ],
-        "If the stop hash is known, but the locators unknown, start from genesis"
-    );
+    let err =
+        BlockCache::from_headers(store.clone(), params.clone(), &[], &headers, &ctx).unwrap_err();
+    assert!(matches!(
+        err,
+        Error::BlockImportAborted { ref error, index: 99, height: 99, .. }
+            if matches!(**error, Error::InvalidBlockPoW)
+    ));
 
-    assert_eq!(
-        cache.locate_headers(
-            &[chain.get(43).unwrap().block_hash()],
-            chain.get(3).unwrap().block_hash(),
-            9
-        ),
-        vec![],
-        "If the stop height is earlier than the start height, we don't expect anything"
-    );
+    // A gap in the headers.
+    let mut headers = chain.tail.clone();
+    headers.remove(42);
 
-    assert_eq!(
-        cache.locate_headers(
-            &[chain.get(4).unwrap().block_hash()],
-            chain.get(4).unwrap().block_hash(),
-            9
-        ),
-        vec![],
-        "If the stop height is equal to the start height, we don't expect anything"
-    );
+    let err = BlockCache::from_headers(store, params, &[], &headers, &ctx).unwrap_err();
+    assert!(matches!(
+        err,
+        Error::BlockImportAborted { ref error, index: 42, .. }
+            if matches!(**error, Error::BlockMissing(hash) if hash == chain.tail[42].block_hash())
+    ));
 }
@@ -0,0 +1,167 @@
+//! Read-only views of the active chain.
+//!
+//! *Lets reader threads query the chain while the block cache keeps importing headers.*
+//!
+//! A view shares the block cache's in-memory active chain, as it was when the view was
+//! taken. Views are immutable, so they can be shared between threads without locking.
+//! Taking and cloning a view is cheap, but the first write to the block cache after a view
+//! is taken copies the in-memory active chain and its index, so views are best taken once
+//! per batch of changes.
+//!
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use bitcoin::blockdata::block::BlockHeader;
+use bitcoin::consensus::params::Params;
+use bitcoin::hash_types::BlockHash;
+
+use nonempty::NonEmpty;
+
+use nakamoto_common::block::store::Store;
+use nakamoto_common::block::{self, Height};
+
+use super::map::BlockMap;
+use super::{BlockCache, CachedBlock};
+
+/// A read-only view of the active chain, as it was when the view was taken. See
+/// [`BlockCache::snapshot`].
+///
+/// Blocks evicted from the cache's memory aren't part of the view, since they are only
+/// available from the store.
+#[derive(Debug, Clone)]
+pub struct ChainView {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    chain: Arc<NonEmpty<CachedBlock>>,
+    headers: Arc<BlockMap<Height>>,
+    checkpoints: BTreeMap<Height, BlockHash>,
+    params: Params,
+    evicted: Height,
+    pinned: Arc<BTreeMap<Height, CachedBlock>>,
+}
+
+impl ChainView {
+    /// Get the height of the best block.
+    pub fn height(&self) -> Height {
+        self.inner.evicted + self.inner.chain.tail.len() as Height
+    }
+
+    /// Get the hash and header of the best block.
+    pub fn tip(&self) -> (BlockHash, BlockHeader) {
+        let tip = self.inner.chain.last();
+
+        (tip.hash, tip.header)
+    }
+
+    /// Get the genesis block header.
+    pub fn genesis(&self) -> &BlockHeader {
+        &self.inner.chain.first().header
+    }
+
+    /// Get an active chain block by hash.
+    pub fn get_block(&self, hash: &BlockHash) -> Option<(Height, &BlockHeader)> {
+        self.height_of(hash)
+            .and_then(|height| self.block(height))
+            .map(|blk| (blk.height, &blk.header))
+    }
+
+    /// Get an active chain block by height.
+    pub fn get_block_by_height(&self, height: Height) -> Option<&BlockHeader> {
+        self.block(height).map(|blk| &blk.header)
+    }
+
+    /// Get the height of the given active chain block.
+    pub fn height_of(&self, hash: &BlockHash) -> Option<Height> {
+        self.inner
+            .headers
+            .get(hash)
+            .copied()
+            .filter(|height| self.block(*height).is_some())
+    }
+
+    /// Check whether this block hash is part of the active chain.
+    pub fn contains(&self, hash: &BlockHash) -> bool {
+        self.height_of(hash).is_some()
+    }
+
+    /// Get up to `count` consecutive headers of the active chain, starting at the given
+    /// height.
+    pub fn headers_at(&self, start: Height, count: usize) -> Vec<BlockHeader> {
+        (start..)
+            .take(count)
+            .map(|height| self.get_block_by_height(height))
+            .take_while(Option::is_some)
+            .flatten()
+            .copied()
+            .collect()
+    }
+
+    /// Get the locator hashes starting from the given height and going backwards, like
+    /// [`BlockCache`] does. Evicted blocks are skipped.
+    pub fn locator_hashes(&self, from: Height) -> Vec<BlockHash> {
+        assert!(from <= self.height());
+
+        let last_checkpoint = self
+            .inner
+            .checkpoints
+            .range(..=self.height())
+            .next_back()
+            .map_or(0, |(height, _)| *height);
+
+        super::locator(
+            from,
+            block::locators_indexes(from),
+            last_checkpoint,
+            |height| self.block(height).map(|blk| blk.hash),
+        )
+    }
+
+    /// Get the checkpoints of the chain.
+    pub fn checkpoints(&self) -> &BTreeMap<Height, BlockHash> {
+        &self.inner.checkpoints
+    }
+
+    /// Get the consensus parameters of the chain.
+    pub fn params(&self) -> &Params {
+        &self.inner.params
+    }
+
+    /// Get the active chain block at the given height, if it's in the view.
+    fn block(&self, height: Height) -> Option<&CachedBlock> {
+        let inner = &self.inner;
+
+        if height == 0 {
+            Some(&inner.chain.head)
+        } else if height > inner.evicted {
+            inner.chain.tail.get((height - inner.evicted - 1) as usize)
+        } else {
+            inner.pinned.get(&height)
+        }
+    }
+}
+
+impl<S: Store<Header = BlockHeader>> BlockCache<S> {
+    /// Take a read-only view of the active chain, which can be shared with reader threads.
+    /// The view isn't affected by later changes to the cache.
+    ///
+    /// The view shares the in-memory active chain with the cache, so taking it only copies
+    /// the checkpoints and consensus parameters. Instead, the next change to the active
+    /// chain copies it, along with its index, which takes time linear in the number of
+    /// blocks. No further copies are made until another view is taken. This is unrelated
+    /// to [`BlockCache::save_snapshot`], which writes the cache state out.
+    pub fn snapshot(&self) -> ChainView {
+        ChainView {
+            inner: Arc::new(Inner {
+                chain: self.chain.clone(),
+                headers: self.headers.clone(),
+                checkpoints: self.checkpoints.clone(),
+                params: self.params.clone(),
+                evicted: self.evicted,
+                pinned: self.pinned.clone(),
+            }),
+        }
+    }
+}
@@ -0,0 +1,113 @@
+//! Difficulty adjustment policies.
+//!
+//! *Determines the difficulty target a block header is required to have.*
+//!
+use std::fmt;
+use std::iter;
+
+use bitcoin::blockdata::block::BlockHeader;
+use bitcoin::consensus::params::Params;
+
+use nakamoto_common::block::{self, Bits, BlockTime, Height};
+
+/// A block and its ancestors, most recent first, down to genesis.
+pub type Ancestors<'a> = Box<dyn Iterator<Item = (Height, &'a BlockHeader)> + 'a>;
+
+/// Computes the difficulty target of new blocks.
+pub trait DifficultyPolicy: fmt::Debug + Send + Sync {
+    /// Get the compact difficulty target the given header is required to have. The header
+    /// builds on the first block yielded by `ancestors`.
+    fn next_target(&self, ancestors: Ancestors<'_>, header: &BlockHeader, params: &Params) -> Bits;
+
+    /// Like [`DifficultyPolicy::next_target`], but also given the first block of the
+    /// difficulty adjustment interval the header's parent is in, with its height, if it's
+    /// known without walking `ancestors`. By default, it isn't used.
+    fn next_target_from(
+        &self,
+        ancestors: Ancestors<'_>,
+        _start: Option<(Height, &BlockHeader)>,
+        header: &BlockHeader,
+        params: &Params,
+    ) -> Bits {
+        self.next_target(ancestors, header, params)
+    }
+}
+
+/// The Bitcoin difficulty adjustment algorithm, including the minimum-difficulty rule for
+/// networks that allow it. This is the default policy.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Bitcoin;
+
+impl DifficultyPolicy for Bitcoin {
+    fn next_target(&self, ancestors: Ancestors<'_>, header: &BlockHeader, params: &Params) -> Bits {
+        self.next_target_from(ancestors, None, header, params)
+    }
+
+    fn next_target_from(
+        &self,
+        mut ancestors: Ancestors<'_>,
+        start: Option<(Height, &BlockHeader)>,
+        header: &BlockHeader,
+        params: &Params,
+    ) -> Bits {
+        let (height, tip) = ancestors.next().expect("the tip is always present");
+        let interval = params.difficulty_adjustment_interval();
+
+        // Only adjust on set intervals. Since the height is 0-indexed, we add `1` to check
+        // it against the interval.
+        if (height + 1) % interval != 0 {
+            if params.allow_min_difficulty_blocks {
+                return min_difficulty_target(height, tip, ancestors, header, params);
+            }
+            return BlockHeader::compact_target_from_u256(&tip.target());
+        }
+
+        // Unless it was given, the first block of the interval is found by walking back
+        // from the tip.
+        let first_height = height.saturating_sub(interval - 1);
+        let first = match start {
+            Some((h, first)) if h == first_height => first,
+            _ => iter::once((height, tip))
+                .chain(ancestors)
+                .find(|(h, _)| *h == first_height)
+                .map(|(_, blk)| blk)
+                .expect("the first block of the interval is an ancestor of the tip"),
+        };
+
+        block::retarget(first, tip.time, tip.target(), params)
+    }
+}
+
+/// Get the next minimum-difficulty target. Only valid in testnet and regtest networks.
+///
+/// If the header is more than twice the target spacing after the tip, it may have the
+/// minimum difficulty. Otherwise, it must have the target of the last block that isn't a
+/// minimum-difficulty block, or the target of the first block of the current difficulty
+/// adjustment interval. We never walk further back than that, no matter how many
+/// minimum-difficulty blocks are chained.
+fn min_difficulty_target<'a>(
+    height: Height,
+    tip: &'a BlockHeader,
+    ancestors: Ancestors<'a>,
+    header: &BlockHeader,
+    params: &Params,
+) -> Bits {
+    let pow_limit_bits = block::pow_limit_bits(&params.network);
+
+    if header.time > tip.time + params.pow_target_spacing as BlockTime * 2 {
+        return pow_limit_bits;
+    }
+
+    let interval = params.difficulty_adjustment_interval();
+    let start = height - height % interval;
+
+    for (height, header) in iter::once((height, tip))
+        .chain(ancestors)
+        .take_while(|(height, _)| *height >= start)
+    {
+        if header.bits != pow_limit_bits || height % interval == 0 {
+            return header.bits;
+        }
+    }
+    pow_limit_bits
+}
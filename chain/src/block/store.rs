@@ -2,8 +2,10 @@
 
 pub use nakamoto_common::block::store::*;
 
+pub mod buffered;
 pub mod io;
 pub mod memory;
 
+pub use buffered::Buffered;
 pub use io::File;
 pub use memory::Memory;
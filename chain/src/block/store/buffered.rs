@@ -0,0 +1,416 @@
+//! Buffered storage backend for blocks.
+//!
+//! Wraps another store and buffers header writes in memory, so that they can be written out
+//! in batches, instead of one at a time.
+use std::time::{Duration, Instant};
+
+use nakamoto_common::block::store::{Error, Store};
+use nakamoto_common::block::Height;
+
+/// Default maximum number of headers buffered before they are written out.
+pub const DEFAULT_CAPACITY: usize = 2000;
+
+/// A block store that buffers writes to an underlying store.
+///
+/// Buffered headers are written out when the buffer is full, when the oldest buffered header
+/// is older than the maximum delay, on [`Store::flush`], and when the store is dropped.
+/// Since the buffered headers always come after the headers in the underlying store, the
+/// underlying store always contains a prefix of the chain, even if the buffer is lost.
+#[derive(Debug)]
+pub struct Buffered<S: Store>
+where
+    S::Header: Clone,
+{
+    store: S,
+    buffer: Vec<S::Header>,
+    /// Height of the underlying store.
+    height: Height,
+    /// Maximum number of buffered headers.
+    capacity: usize,
+    /// Maximum time headers are kept in the buffer.
+    max_delay: Option<Duration>,
+    /// When the buffer last went from empty to non-empty.
+    since: Option<Instant>,
+}
+
+impl<S: Store> Buffered<S>
+where
+    S::Header: Clone,
+{
+    /// Create a new buffered store, wrapping the given store.
+    pub fn new(store: S) -> Result<Self, Error> {
+        let height = store.height()?;
+
+        Ok(Self {
+            store,
+            buffer: Vec::new(),
+            height,
+            capacity: DEFAULT_CAPACITY,
+            max_delay: None,
+            since: None,
+        })
+    }
+
+    /// Set the maximum number of headers buffered before they are written out.
+    /// A capacity of zero disables buffering.
+    pub fn with_capacity(mut self, capacity: usize) -> Self {
+        self.capacity = capacity;
+        self
+    }
+
+    /// Set the maximum time headers are kept in the buffer before they are written out.
+    /// The delay is only checked when new headers are stored.
+    pub fn with_max_delay(mut self, delay: Duration) -> Self {
+        self.max_delay = Some(delay);
+        self
+    }
+
+    /// Get the number of buffered headers.
+    pub fn buffered(&self) -> usize {
+        self.buffer.len()
+    }
+
+    /// Get a reference to the underlying store.
+    pub fn inner(&self) -> &S {
+        &self.store
+    }
+
+    /// Write out buffered headers to the underlying store. If the write fails, the headers
+    /// are kept in the buffer, so that they can be written out later.
+    fn write_buffer(&mut self) -> Result<(), Error> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        self.height = self.store.put(self.buffer.iter().cloned())?;
+        self.buffer.clear();
+        self.since = None;
+
+        Ok(())
+    }
+
+    /// Check whether the buffer should be written out.
+    fn is_due(&self) -> bool {
+        if self.buffer.len() >= self.capacity {
+            return true;
+        }
+        match (self.max_delay, self.since) {
+            (Some(delay), Some(since)) => since.elapsed() >= delay,
+            _ => false,
+        }
+    }
+}
+
+impl<S: Store> Drop for Buffered<S>
+where
+    S::Header: Clone,
+{
+    fn drop(&mut self) {
+        if let Err(err) = self.write_buffer() {
+            log::error!("Failed to flush buffered headers: {}", err);
+        }
+    }
+}
+
+impl<S: Store> Store for Buffered<S>
+where
+    S::Header: 'static + Copy,
+{
+    type Header = S::Header;
+
+    /// Get the genesis block.
+    fn genesis(&self) -> Self::Header {
+        self.store.genesis()
+    }
+
+    /// Append a batch of consecutive block headers to the end of the chain. The headers
+    /// may not be written out to the underlying store until later. If writing out the
+    /// buffer fails, the batch is discarded, and the previously buffered headers are kept.
+    fn put<I: Iterator<Item = Self::Header>>(&mut self, headers: I) -> Result<Height, Error> {
+        let len = self.buffer.len();
+        let since = self.since;
+
+        self.buffer.extend(headers);
+
+        if self.buffer.len() > len && len == 0 {
+            self.since = Some(Instant::now());
+        }
+        if self.is_due() {
+            if let Err(err) = self.flush() {
+                self.buffer.truncate(len);
+                self.since = since;
+
+                return Err(err);
+            }
+        }
+        self.height()
+    }
+
+    /// Get the block at the given height.
+    fn get(&self, height: Height) -> Result<Self::Header, Error> {
+        if height <= self.height {
+            return self.store.get(height);
+        }
+        match self.buffer.get((height - self.height - 1) as usize) {
+            Some(header) => Ok(*header),
+            None => Err(Error::Io(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "unexpected end of file",
+            ))),
+        }
+    }
+
+    /// Rollback the chain to the given height. Buffered headers above the height are discarded
+    /// without being written out.
+    fn rollback(&mut self, height: Height) -> Result<(), Error> {
+        if height >= self.height {
+            self.buffer.truncate((height - self.height) as usize);
+        } else {
+            self.buffer.clear();
+            self.store.rollback(height)?;
+            self.height = height;
+        }
+        if self.buffer.is_empty() {
+            self.since = None;
+        }
+        Ok(())
+    }
+
+    /// Write out buffered headers to the underlying store.
+    fn flush(&mut self) -> Result<(), Error> {
+        self.write_buffer()
+    }
+
+    /// Write out buffered headers, and synchronize the changes to disk.
+    fn sync(&mut self) -> Result<(), Error> {
+        self.flush()?;
+        self.store.sync()
+    }
+
+    /// Iterate over all headers in the store.
+    fn iter(&self) -> Box<dyn Iterator<Item = Result<(Height, Self::Header), Error>>> {
+        let height = self.height;
+        let buffered = self
+            .buffer
+            .clone()
+            .into_iter()
+            .enumerate()
+            .map(move |(i, h)| Ok((height + i as Height + 1, h)));
+
+        Box::new(self.store.iter().chain(buffered))
+    }
+
+    /// Return the number of headers in the store.
+    fn len(&self) -> Result<usize, Error> {
+        Ok(self.height as usize + 1 + self.buffer.len())
+    }
+
+    /// Return the store block height.
+    fn height(&self) -> Result<Height, Error> {
+        Ok(self.height + self.buffer.len() as Height)
+    }
+
+    /// Check the store integrity.
+    fn check(&self) -> Result<(), Error> {
+        self.store.check()
+    }
+
+    /// Heal data corruption.
+    fn heal(&self) -> Result<(), Error> {
+        self.store.heal()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::iter;
+
+    use nonempty::NonEmpty;
+
+    use super::{Buffered, Error, Height, Store};
+    use crate::block::store::Memory;
+    use crate::block::BlockHeader;
+
+    /// A store whose writes fail while `fail` is set.
+    #[derive(Debug)]
+    struct FailingStore {
+        store: Memory<BlockHeader>,
+        fail: bool,
+    }
+
+    impl Store for FailingStore {
+        type Header = BlockHeader;
+
+        fn genesis(&self) -> BlockHeader {
+            self.store.genesis()
+        }
+
+        fn put<I: Iterator<Item = BlockHeader>>(&mut self, headers: I) -> Result<Height, Error> {
+            if self.fail {
+                return Err(Error::Io(std::io::Error::new(
+                    std::io::ErrorKind::WriteZero,
+                    "write failed",
+                )));
+            }
+            self.store.put(headers)
+        }
+
+        fn get(&self, height: Height) -> Result<BlockHeader, Error> {
+            self.store.get(height)
+        }
+
+        fn rollback(&mut self, height: Height) -> Result<(), Error> {
+            self.store.rollback(height)
+        }
+
+        fn sync(&mut self) -> Result<(), Error> {
+            self.store.sync()
+        }
+
+        fn iter(&self) -> Box<dyn Iterator<Item = Result<(Height, BlockHeader), Error>>> {
+            self.store.iter()
+        }
+
+        fn len(&self) -> Result<usize, Error> {
+            self.store.len()
+        }
+
+        fn height(&self) -> Result<Height, Error> {
+            self.store.height()
+        }
+
+        fn check(&self) -> Result<(), Error> {
+            self.store.check()
+        }
+
+        fn heal(&self) -> Result<(), Error> {
+            self.store.heal()
+        }
+    }
+
+    fn headers(count: u32) -> Vec<BlockHeader> {
+        (1..=count)
+            .map(|nonce| BlockHeader {
+                version: 1,
+                prev_blockhash: Default::default(),
+                merkle_root: Default::default(),
+                bits: 0x2ffffff,
+                time: 1842918273,
+                nonce,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_buffered_put_flush() {
+        let genesis = BlockHeader {
+            nonce: 0,
+            ..headers(1)[0]
+        };
+        let headers = headers(8);
+        let mut store = Buffered::new(Memory::new(NonEmpty::new(genesis)))
+            .unwrap()
+            .with_capacity(3);
+
+        assert_eq!(store.put(headers[..2].iter().cloned()).unwrap(), 2);
+        assert_eq!(store.buffered(), 2);
+        assert_eq!(
+            store.inner().len().unwrap(),
+            1,
+            "Nothing is written out yet"
+        );
+        assert_eq!(store.len().unwrap(), 3);
+        assert_eq!(store.get(2).unwrap(), headers[1]);
+        assert!(store.get(3).is_err());
+
+        assert_eq!(store.put(iter::once(headers[2])).unwrap(), 3);
+        assert_eq!(store.buffered(), 0, "The buffer is full and written out");
+        assert_eq!(store.inner().len().unwrap(), 4);
+
+        store.put(headers[3..5].iter().cloned()).unwrap();
+        assert_eq!(
+            store.iter().map(|r| r.unwrap()).collect::<Vec<_>>(),
+            iter::once(genesis)
+                .chain(headers[..5].iter().cloned())
+                .enumerate()
+                .map(|(i, h)| (i as Height, h))
+                .collect::<Vec<_>>()
+        );
+
+        store.flush().unwrap();
+        assert_eq!(store.buffered(), 0);
+        assert_eq!(store.inner().len().unwrap(), 6);
+    }
+
+    #[test]
+    fn test_buffered_rollback() {
+        let genesis = BlockHeader {
+            nonce: 0,
+            ..headers(1)[0]
+        };
+        let headers = headers(8);
+        let mut store = Buffered::new(Memory::new(NonEmpty::new(genesis)))
+            .unwrap()
+            .with_capacity(4);
+
+        store.put(headers[..4].iter().cloned()).unwrap();
+        store.put(headers[4..7].iter().cloned()).unwrap();
+        assert_eq!(store.inner().height().unwrap(), 4);
+        assert_eq!(store.height().unwrap(), 7);
+
+        // Rolling back within the buffer doesn't touch the underlying store.
+        store.rollback(5).unwrap();
+        assert_eq!(store.buffered(), 1);
+        assert_eq!(store.height().unwrap(), 5);
+        assert_eq!(store.inner().height().unwrap(), 4);
+        assert_eq!(store.get(5).unwrap(), headers[4]);
+
+        // Rolling back past the buffer discards it, and rolls back the underlying store.
+        store.rollback(2).unwrap();
+        assert_eq!(store.buffered(), 0);
+        assert_eq!(store.height().unwrap(), 2);
+        assert_eq!(store.inner().height().unwrap(), 2);
+
+        store.put(iter::once(headers[7])).unwrap();
+        assert_eq!(store.get(3).unwrap(), headers[7]);
+
+        store.sync().unwrap();
+        assert_eq!(store.inner().height().unwrap(), 3);
+        assert_eq!(store.inner().get(3).unwrap(), headers[7]);
+    }
+
+    #[test]
+    fn test_buffered_write_failure() {
+        let genesis = BlockHeader {
+            nonce: 0,
+            ..headers(1)[0]
+        };
+        let headers = headers(8);
+        let inner = FailingStore {
+            store: Memory::new(NonEmpty::new(genesis)),
+            fail: true,
+        };
+        let mut store = Buffered::new(inner).unwrap().with_capacity(4);
+
+        store.put(headers[..2].iter().cloned()).unwrap();
+        assert!(store.flush().is_err());
+        assert_eq!(store.buffered(), 2, "Headers are kept when the write fails");
+        assert_eq!(store.height().unwrap(), 2);
+
+        // The batch that fills the buffer is discarded if it can't be written out.
+        assert!(store.put(headers[2..4].iter().cloned()).is_err());
+        assert_eq!(store.buffered(), 2);
+        assert_eq!(store.height().unwrap(), 2);
+        assert_eq!(store.inner().height().unwrap(), 0);
+
+        store.store.fail = false;
+        store.put(headers[2..4].iter().cloned()).unwrap();
+        assert_eq!(store.buffered(), 0);
+        assert_eq!(store.inner().height().unwrap(), 4);
+        assert_eq!(
+            store.iter().map(|r| r.unwrap().1).collect::<Vec<_>>(),
+            iter::once(genesis)
+                .chain(headers[..4].iter().cloned())
+                .collect::<Vec<_>>()
+        );
+    }
+}
@@ -196,3 +196,27 @@ pub(crate) const TARGETS: &[(Height, BlockTime, Bits, BlockTime, Bits)] = &[
     (379008, 1444908588, 0x18121472, 1444908751, 0x18120f14),
     (381024, 1446091729, 0x18120f14, 1446092706, 0x1811a954),
 ];
+
+/// The first block headers of the testnet3 chain after genesis, serialized.
+///
+///   (height, hash, header)
+///
+/// Block 1 links the genesis block to block 2, which with block 3 is part of the BIP 158
+/// test vectors.
+pub(crate) const TESTNET_HEADERS: &[(Height, &str, &str)] = &[
+    (
+        1,
+        "00000000b873e79784647a6c82962c70d228557d24a747ea4d1b8bbe878e1206",
+        "0100000043497fd7f826957108f4a30fd9cec3aeba79972084e90ead01ea330900000000bac8b0fa927c0ac8234287e33c5f74d38d354820e24756ad709d7038fc5f31f020e7494dffff001d03e4b672",
+    ),
+    (
+        2,
+        "000000006c02c8ea6e4ff69651f7fcde348fb9d557a06e6957b65552002a7820",
+        "0100000006128e87be8b1b4dea47a7247d5528d2702c96826c7a648497e773b800000000e241352e3bec0a95a6217e10c3abb54adfa05abb12c126695595580fb92e222032e7494dffff001d00d23534",
+    ),
+    (
+        3,
+        "000000008b896e272758da5297bcd98fdc6d97c9b765ecec401e286dc1fdbe10",
+        "0100000020782a005255b657696ea057d5b98f34defcf75196f64f6eeac8026c0000000041ba5afc532aae03151b8aa87b65e1594f97504a768e010c98c0add79216247186e7494dffff001d058dc2b6",
+    ),
+];
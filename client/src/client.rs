@@ -632,7 +632,7 @@ where
                 &events,
                 |e| match e {
                     Event::SyncManager(syncmgr::Event::HeadersImported(
-                        ImportResult::TipChanged(_, hash, height, _),
+                        ImportResult::TipChanged { hash, height, .. },
                     )) if height == h => Some(hash),
 
                     _ => None,
@@ -27,7 +27,7 @@ pub type Height = u64;
 pub type BlockTime = u32;
 
 /// Get the locator indexes starting from a given height, and going backwards, exponentially
-/// backing off.
+/// backing off. The indexes are strictly decreasing, and always end with genesis.
 ///
 /// ```
 /// use nakamoto_common::block;
@@ -45,7 +45,7 @@ pub fn locators_indexes(mut from: Height) -> Vec<Height> {
     while from > 0 {
         // For the first 8 blocks, don't skip any heights.
         if indexes.len() >= 8 {
-            step *= 2;
+            step = Height::saturating_mul(step, 2);
         }
         indexes.push(from as Height);
         from = from.saturating_sub(step);
@@ -64,3 +64,85 @@ pub fn pow_limit_bits(network: &bitcoin::Network) -> Bits {
         bitcoin::Network::Signet => 0x1e0377ae,
     }
 }
+
+/// Get the difficulty of a compact target, as displayed by Bitcoin Core, ie. as a multiple
+/// of the minimum mainnet difficulty.
+///
+/// ```
+/// use nakamoto_common::block;
+///
+/// assert_eq!(block::difficulty(0x1d00ffff), 1.);
+/// assert_eq!(block::difficulty(0x1b0404cb), 16307.420938523983);
+/// assert!(block::difficulty(0x207fffff) < 1.);
+/// ```
+pub fn difficulty(bits: Bits) -> f64 {
+    let mut shift = (bits >> 24) & 0xff;
+    let mut difficulty = 0xffff as f64 / (bits & 0x00ffffff) as f64;
+
+    while shift < 29 {
+        difficulty *= 256.;
+        shift += 1;
+    }
+    while shift > 29 {
+        difficulty /= 256.;
+        shift -= 1;
+    }
+    difficulty
+}
+
+/// Compute the difficulty target following a difficulty adjustment, given the first block of
+/// the adjustment interval, and the time and target of the last block of the interval.
+pub fn retarget(
+    first: &BlockHeader,
+    last_time: BlockTime,
+    last_target: Target,
+    params: &bitcoin::consensus::params::Params,
+) -> Bits {
+    if params.no_pow_retargeting {
+        return first.bits;
+    }
+
+    let actual_timespan = last_time - first.time;
+    let mut adjusted_timespan = actual_timespan;
+
+    if actual_timespan < params.pow_target_timespan as BlockTime / 4 {
+        adjusted_timespan = params.pow_target_timespan as BlockTime / 4;
+    } else if actual_timespan > params.pow_target_timespan as BlockTime * 4 {
+        adjusted_timespan = params.pow_target_timespan as BlockTime * 4;
+    }
+
+    let mut target = last_target;
+
+    target = target.mul_u32(adjusted_timespan);
+    target = target / Target::from_u64(params.pow_target_timespan).unwrap();
+
+    // Ensure a difficulty floor.
+    if target > params.pow_limit {
+        target = params.pow_limit;
+    }
+
+    BlockHeader::compact_target_from_u256(&target)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_locators_indexes_high() {
+        for from in [
+            Height::MAX,
+            Height::MAX - 1,
+            1 << 63,
+            (1 << 63) - 1,
+            1 << 32,
+        ] {
+            let indexes = locators_indexes(from);
+
+            assert_eq!(indexes.first(), Some(&from));
+            assert_eq!(indexes.last(), Some(&0));
+            assert!(indexes.windows(2).all(|w| w[0] > w[1]), "{:?}", indexes);
+            assert!(indexes.len() <= 8 + Height::BITS as usize + 1);
+        }
+    }
+}
@@ -66,6 +66,11 @@ pub trait Store {
     fn get(&self, height: Height) -> Result<Self::Header, Error>;
     /// Rollback the chain to the given height.
     fn rollback(&mut self, height: Height) -> Result<(), Error>;
+    /// Write out any buffered headers. Stores that don't buffer writes don't need to
+    /// implement this.
+    fn flush(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
     /// Synchronize the changes to disk.
     fn sync(&mut self) -> Result<(), Error>;
     /// Iterate over all headers in the store.
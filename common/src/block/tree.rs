@@ -1,5 +1,7 @@
 //! Types and functions relating to block trees.
 #![warn(missing_docs)]
+use std::ops;
+
 use bitcoin::blockdata::block::BlockHeader;
 use bitcoin::consensus::params::Params;
 use bitcoin::hash_types::BlockHash;
@@ -33,6 +35,10 @@ pub enum Error {
     #[error("block timestamp {0} is invalid")]
     InvalidBlockTime(BlockTime, std::cmp::Ordering),
 
+    /// The block version is below the required minimum.
+    #[error("block version {0} is below the minimum")]
+    InvalidBlockVersion(i32),
+
     /// The block is already known.
     #[error("duplicate block {0}")]
     DuplicateBlock(BlockHash),
@@ -42,12 +48,49 @@ pub enum Error {
     BlockMissing(BlockHash),
 
     /// A block import was aborted. FIXME: Move this error out of here.
-    #[error("block import aborted at height {2}: {0} ({1} block(s) imported)")]
-    BlockImportAborted(Box<Self>, usize, Height),
+    #[error("block import aborted at height {height} on block {hash}: {error} ({index} block(s) imported)")]
+    BlockImportAborted {
+        /// The error that caused the import to abort.
+        error: Box<Self>,
+        /// Index of the offending header in the imported headers. This is also the number of
+        /// headers that were processed before the import was aborted.
+        index: usize,
+        /// Height of the active chain when the import was aborted.
+        height: Height,
+        /// Hash of the offending header.
+        hash: BlockHash,
+        /// The offending header.
+        header: Box<BlockHeader>,
+    },
+
+    /// Switching to a fork would roll back more blocks than allowed.
+    #[error("re-org of depth {depth} exceeds the limit of {limit} block(s)")]
+    ReorgTooDeep {
+        /// Number of blocks that would be rolled back.
+        depth: Height,
+        /// Maximum number of blocks that can be rolled back.
+        limit: Height,
+    },
 
     /// A storage error occured.
     #[error("storage error: {0}")]
     Store(#[from] store::Error),
+
+    /// The stored headers don't form a chain.
+    #[error("block store is corrupt at height {height}")]
+    StoreCorruption {
+        /// Height of the first header that doesn't connect to the previous one.
+        height: Height,
+    },
+
+    /// The stored genesis block isn't the genesis block of the network.
+    #[error("genesis block mismatch: expected {expected}, found {found}")]
+    GenesisMismatch {
+        /// Hash of the network's genesis block.
+        expected: BlockHash,
+        /// Hash of the stored genesis block.
+        found: BlockHash,
+    },
 }
 
 /// A generic block header.
@@ -68,21 +111,87 @@ pub enum ImportResult {
     /// A new tip was found. This can happen in either of two scenarios:
     ///
     /// 1. The imported block(s) extended the active chain, or
-    /// 2. The imported block(s) caused a chain re-org. In that case, the last field is
-    ///    populated with the now stale blocks.
+    /// 2. The imported block(s) caused a chain re-org. In that case, `reverted` is populated
+    ///    with the now stale blocks.
     ///
-    TipChanged(BlockHeader, BlockHash, Height, Vec<BlockHash>),
+    /// Blocks that were connected and disconnected again during the same import are not
+    /// included in either `reverted` or `connected`.
+    TipChanged {
+        /// The header of the imported block that caused the tip to change.
+        header: BlockHeader,
+        /// Hash of the new tip.
+        hash: BlockHash,
+        /// Height of the new tip.
+        height: Height,
+        /// The blocks that were disconnected from the active chain.
+        reverted: Vec<(Height, BlockHeader)>,
+        /// The blocks that were connected to the active chain, in ascending height order.
+        connected: Vec<(Height, BlockHeader)>,
+        /// The hashes of the orphan blocks received before this import, that are now
+        /// connected to the active chain, eg. because a missing block arrived.
+        reconnected: Vec<BlockHash>,
+        /// The hashes of the missing parents of headers that couldn't be connected, like
+        /// [`ImportResult::MissingParents`].
+        missing: Vec<BlockHash>,
+    },
     /// The block headers were imported successfully, but our best block hasn't changed.
     /// This will happen if we imported a duplicate, orphan or stale block.
     TipUnchanged, // TODO: We could add a parameter eg. BlockMissing or DuplicateBlock.
+    /// The block headers were imported successfully, but our best block hasn't changed, and
+    /// some of the headers have unknown parents. Contains the hashes of the missing parents,
+    /// in the order they were first encountered, so that they can be requested.
+    ///
+    /// If the imported headers changed the best block, [`ImportResult::TipChanged`] is
+    /// returned instead, with the missing parents in its `missing` field.
+    MissingParents(Vec<BlockHash>),
 }
 
-/// A chain of block headers that may or may not lead back to genesis.
+impl ImportResult {
+    /// Get the range of heights connected to the active chain, if the tip changed. When the
+    /// active chain is extended, this is the range of new heights. On a re-org, it's the
+    /// range of heights of the connected branch.
+    pub fn connected_range(&self) -> Option<ops::Range<Height>> {
+        match self {
+            Self::TipChanged { connected, .. } => match (connected.first(), connected.last()) {
+                (Some((start, _)), Some((end, _))) => Some(*start..*end + 1),
+                _ => None,
+            },
+            Self::TipUnchanged | Self::MissingParents(_) => None,
+        }
+    }
+
+    /// Get the hashes of the missing parents of the imported headers, whether or not the
+    /// tip changed. See [`ImportResult::MissingParents`].
+    pub fn missing_parents(&self) -> &[BlockHash] {
+        match self {
+            Self::TipChanged { missing, .. } | Self::MissingParents(missing) => missing,
+            Self::TipUnchanged => &[],
+        }
+    }
+}
+
+/// A chain of block headers that may or may not lead back to genesis. Can be used to
+/// compare chains that aren't part of a block tree, eg. chains announced by peers.
 #[derive(Debug, Clone)]
 pub struct Branch<'a, H: Header>(pub &'a [H]);
 
 impl<'a, H: Header> Branch<'a, H> {
     /// Compute the total proof-of-work carried by this branch.
+    ///
+    /// Like [`Work`] addition, the sum wraps around on overflow. This can't happen with
+    /// headers that carry valid proof-of-work, since the work of each header is about the
+    /// number of hashes it took to find it.
+    ///
+    /// ```
+    /// use bitcoin::blockdata::constants;
+    /// use nakamoto_common::block::tree::Branch;
+    /// use nakamoto_common::block::Work;
+    ///
+    /// let genesis = constants::genesis_block(bitcoin::Network::Bitcoin).header;
+    ///
+    /// assert_eq!(Branch(&[genesis; 2]).work(), genesis.work() + genesis.work());
+    /// assert_eq!(Branch::<bitcoin::BlockHeader>(&[]).work(), Work::default());
+    /// ```
     pub fn work(&self) -> Work {
         let mut work = Work::default();
         for header in self.0.iter() {
@@ -95,11 +204,60 @@ impl<'a, H: Header> Branch<'a, H> {
 /// A representation of all known blocks that keeps track of the longest chain.
 pub trait BlockTree {
     /// Import a chain of block headers into the block tree.
+    ///
+    /// If a header is invalid, the import is aborted with [`Error::BlockImportAborted`].
+    /// The headers preceding it stay imported and stored, and the block tree is left as if
+    /// the import had stopped before the offending header. The import can then be resumed
+    /// with the headers following it.
     fn import_blocks<I: Iterator<Item = BlockHeader>, C: Clock>(
         &mut self,
         chain: I,
         context: &C,
     ) -> Result<ImportResult, Error>;
+    /// Import a chain of block headers into the block tree, calling `on_progress` with the
+    /// new height whenever the active chain changes.
+    ///
+    /// The default implementation only reports the height once all headers are imported,
+    /// if the active chain changed.
+    fn import_blocks_with<I, C, F>(
+        &mut self,
+        chain: I,
+        context: &C,
+        mut on_progress: F,
+    ) -> Result<ImportResult, Error>
+    where
+        I: Iterator<Item = BlockHeader>,
+        C: Clock,
+        F: FnMut(Height),
+    {
+        let result = self.import_blocks(chain, context)?;
+
+        if let ImportResult::TipChanged { height, .. } = result {
+            on_progress(height);
+        }
+        Ok(result)
+    }
+    /// Check a header's proof-of-work against its own difficulty target, and check that
+    /// this target is within the network's proof-of-work limit. This is the cheap,
+    /// context-free check done on headers that don't extend the active chain, and can be
+    /// used to reject invalid headers before they are imported.
+    fn check_pow(header: &BlockHeader, params: &Params) -> Result<(), Error>
+    where
+        Self: Sized,
+    {
+        let target = header.target();
+
+        match header.validate_pow(&target) {
+            Ok(_) if target > params.pow_limit => {
+                Err(Error::InvalidBlockTarget(target, params.pow_limit))
+            }
+            Ok(_) => Ok(()),
+            Err(bitcoin::util::Error::BlockBadProofOfWork) => Err(Error::InvalidBlockPoW),
+            // The only way to get a 'bad target' error is to pass a different target
+            // than the one specified in the header.
+            Err(_) => unreachable!(),
+        }
+    }
     /// Attempts to extend the active chain. Returns `Ok` with `ImportResult::TipUnchanged` if
     /// the block didn't connect, and `Err` if the block was invalid.
     fn extend_tip<C: Clock>(
@@ -107,10 +265,21 @@ pub trait BlockTree {
         header: BlockHeader,
         context: &C,
     ) -> Result<ImportResult, Error>;
-    /// Get a block by hash.
+    /// Get a block of the longest chain by hash.
     fn get_block(&self, hash: &BlockHash) -> Option<(Height, &BlockHeader)>;
-    /// Get a block by height.
+    /// Get a block of the longest chain by height.
     fn get_block_by_height(&self, height: Height) -> Option<&BlockHeader>;
+    /// Get up to `count` consecutive headers of the longest chain, starting at the given
+    /// height. Returns fewer headers if the chain isn't long enough.
+    fn headers_at(&self, start: Height, count: usize) -> Vec<BlockHeader> {
+        (start..)
+            .take(count)
+            .map(|height| self.get_block_by_height(height))
+            .take_while(Option::is_some)
+            .flatten()
+            .cloned()
+            .collect()
+    }
     /// Iterate over the longest chain, starting from genesis.
     fn chain<'a>(&'a self) -> Box<dyn Iterator<Item = BlockHeader> + 'a> {
         Box::new(self.iter().map(|(_, h)| h))
@@ -147,10 +316,20 @@ pub trait BlockTree {
         self.get_block_by_height(0)
             .expect("the genesis block is always present")
     }
+    /// Return the genesis block hash.
+    fn genesis_hash(&self) -> BlockHash {
+        self.genesis().block_hash()
+    }
     /// Check whether a block hash is known.
     fn is_known(&self, hash: &BlockHash) -> bool;
     /// Check whether a block hash is part of the active chain.
     fn contains(&self, hash: &BlockHash) -> bool;
+    /// Get the depth of a block in the active chain, ie. its number of confirmations. The tip
+    /// has a depth of `1`. Returns `None` if the block isn't part of the active chain.
+    fn depth(&self, hash: &BlockHash) -> Option<Height> {
+        self.get_block(hash)
+            .map(|(height, _)| self.height() - height + 1)
+    }
     /// Return the headers corresponding to the given locators, up to a maximum.
     fn locate_headers(
         &self,
@@ -158,6 +337,14 @@ pub trait BlockTree {
         stop_hash: BlockHash,
         max_headers: usize,
     ) -> Vec<BlockHeader>;
+    /// Find the highest block of the active chain that is in the given locator, if any.
+    /// This is the counterpart to [`BlockTree::locator_hashes`].
+    fn find_fork(&self, locator: &[BlockHash]) -> Option<(Height, BlockHash)> {
+        locator
+            .iter()
+            .filter_map(|hash| self.get_block(hash).map(|(height, _)| (height, *hash)))
+            .max_by_key(|(height, _)| *height)
+    }
     /// Get the locator hashes starting from the given height and going backwards.
     fn locator_hashes(&self, from: Height) -> Vec<BlockHash>;
     /// Get the next difficulty given a block height, time and bits.
@@ -179,31 +366,7 @@ pub trait BlockTree {
         let last_adjustment_block = self
             .get_block_by_height(last_adjustment_height)
             .unwrap_or_else(|| self.genesis());
-        let last_adjustment_time = last_adjustment_block.time;
-
-        if params.no_pow_retargeting {
-            return last_adjustment_block.bits;
-        }
-
-        let actual_timespan = last_time - last_adjustment_time;
-        let mut adjusted_timespan = actual_timespan;
-
-        if actual_timespan < params.pow_target_timespan as BlockTime / 4 {
-            adjusted_timespan = params.pow_target_timespan as BlockTime / 4;
-        } else if actual_timespan > params.pow_target_timespan as BlockTime * 4 {
-            adjusted_timespan = params.pow_target_timespan as BlockTime * 4;
-        }
-
-        let mut target = last_target;
-
-        target = target.mul_u32(adjusted_timespan);
-        target = target / Target::from_u64(params.pow_target_timespan).unwrap();
-
-        // Ensure a difficulty floor.
-        if target > params.pow_limit {
-            target = params.pow_limit;
-        }
 
-        BlockHeader::compact_target_from_u256(&target)
+        crate::block::retarget(last_adjustment_block, last_time, last_target, params)
     }
 }
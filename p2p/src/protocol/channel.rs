@@ -195,7 +195,9 @@ impl syncmgr::SyncHeaders for Channel {
         debug!(target: self.target, "[sync] {}", &event);
 
         match &event {
-            syncmgr::Event::HeadersImported(ImportResult::TipChanged(_, tip, height, _)) => {
+            syncmgr::Event::HeadersImported(ImportResult::TipChanged {
+                hash: tip, height, ..
+            }) => {
                 info!(target: self.target, "Block height = {}, tip = {}", height, tip);
             }
             _ => {}
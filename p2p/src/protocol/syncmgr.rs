@@ -277,8 +277,24 @@ impl<U: SetTimeout + SyncHeaders + Disconnect> SyncManager<U> {
         tree: &mut T,
     ) -> Result<ImportResult, Error> {
         match tree.import_blocks(blocks, context) {
-            Ok(ImportResult::TipChanged(header, tip, height, reverted)) => {
-                let result = ImportResult::TipChanged(header, tip, height, reverted);
+            Ok(ImportResult::TipChanged {
+                header,
+                hash: tip,
+                height,
+                reverted,
+                connected,
+                reconnected,
+                missing,
+            }) => {
+                let result = ImportResult::TipChanged {
+                    header,
+                    hash: tip,
+                    height,
+                    reverted,
+                    connected,
+                    reconnected,
+                    missing,
+                };
 
                 self.upstream.event(Event::HeadersImported(result.clone()));
                 self.upstream.event(Event::Synced(tip, height));
@@ -286,7 +302,8 @@ impl<U: SetTimeout + SyncHeaders + Disconnect> SyncManager<U> {
 
                 Ok(result)
             }
-            Ok(result @ ImportResult::TipUnchanged) => {
+            Ok(result @ ImportResult::TipUnchanged)
+            | Ok(result @ ImportResult::MissingParents(_)) => {
                 self.upstream.event(Event::HeadersImported(result.clone()));
 
                 Ok(result)
@@ -351,7 +368,10 @@ impl<U: SetTimeout + SyncHeaders + Disconnect> SyncManager<U> {
                         .event(Event::HeadersImported(imported.clone()));
                 }
 
-                if let Ok(ImportResult::TipChanged(_, tip, height, _)) = result {
+                if let Ok(ImportResult::TipChanged {
+                    hash: tip, height, ..
+                }) = result
+                {
                     let peer = self.peers.get_mut(from).unwrap();
 
                     if height > peer.height {
@@ -361,8 +381,17 @@ impl<U: SetTimeout + SyncHeaders + Disconnect> SyncManager<U> {
                 }
 
                 match result {
-                    Ok(ImportResult::TipUnchanged) => Ok(ImportResult::TipUnchanged),
-                    Ok(ImportResult::TipChanged(header, tip, height, reverted)) => {
+                    Ok(result @ ImportResult::TipUnchanged)
+                    | Ok(result @ ImportResult::MissingParents(_)) => Ok(result),
+                    Ok(ImportResult::TipChanged {
+                        header,
+                        hash: tip,
+                        height,
+                        reverted,
+                        connected,
+                        reconnected,
+                        missing,
+                    }) => {
                         // Keep track of when we last updated our tip. This is useful to check
                         // whether our tip is stale.
                         self.last_tip_update = Some(clock.local_time());
@@ -391,7 +420,15 @@ impl<U: SetTimeout + SyncHeaders + Disconnect> SyncManager<U> {
                             );
                         }
 
-                        Ok(ImportResult::TipChanged(header, tip, height, reverted))
+                        Ok(ImportResult::TipChanged {
+                            header,
+                            hash: tip,
+                            height,
+                            reverted,
+                            connected,
+                            reconnected,
+                            missing,
+                        })
                     }
                     Err(err) => self
                         .handle_error(from, err)
@@ -403,7 +440,8 @@ impl<U: SetTimeout + SyncHeaders + Disconnect> SyncManager<U> {
                 let root = headers.first().block_hash();
 
                 match tree.import_blocks(headers.into_iter(), clock) {
-                    Ok(import_result @ ImportResult::TipUnchanged) => {
+                    Ok(import_result @ ImportResult::TipUnchanged)
+                    | Ok(import_result @ ImportResult::MissingParents(_)) => {
                         self.upstream
                             .event(Event::HeadersImported(import_result.clone()));
 
@@ -422,7 +460,15 @@ impl<U: SetTimeout + SyncHeaders + Disconnect> SyncManager<U> {
 
                         Ok(import_result)
                     }
-                    Ok(ImportResult::TipChanged(header, tip, height, reverted)) => {
+                    Ok(ImportResult::TipChanged {
+                        header,
+                        hash: tip,
+                        height,
+                        reverted,
+                        connected,
+                        reconnected,
+                        missing,
+                    }) => {
                         let peer = self.peers.get_mut(from).unwrap();
                         if height > peer.height {
                             peer.tip = tip;
@@ -430,14 +476,25 @@ impl<U: SetTimeout + SyncHeaders + Disconnect> SyncManager<U> {
                         }
 
                         self.upstream
-                            .event(Event::HeadersImported(ImportResult::TipChanged(
+                            .event(Event::HeadersImported(ImportResult::TipChanged {
                                 header,
-                                tip,
+                                hash: tip,
                                 height,
-                                reverted.clone(),
-                            )));
-
-                        Ok(ImportResult::TipChanged(header, tip, height, reverted))
+                                reverted: reverted.clone(),
+                                connected: connected.clone(),
+                                reconnected: reconnected.clone(),
+                                missing: missing.clone(),
+                            }));
+
+                        Ok(ImportResult::TipChanged {
+                            header,
+                            hash: tip,
+                            height,
+                            reverted,
+                            connected,
+                            reconnected,
+                            missing,
+                        })
                     }
                     Err(err) => self
                         .handle_error(from, err)
@@ -489,16 +546,25 @@ impl<U: SetTimeout + SyncHeaders + Disconnect> SyncManager<U> {
         clock: &impl Clock,
         tree: &mut T,
     ) -> Result<ImportResult, Error> {
-        let mut import_result = ImportResult::TipUnchanged;
+        let mut best = None;
+        let mut connected = Vec::new();
 
         for header in headers.into_iter() {
             match tree.extend_tip(header, clock) {
-                Ok(ImportResult::TipChanged(header, tip, height, reverted)) => {
+                Ok(ImportResult::TipChanged {
+                    header,
+                    hash: tip,
+                    height,
+                    reverted,
+                    connected: blocks,
+                    ..
+                }) => {
                     debug_assert!(reverted.is_empty());
 
-                    import_result = ImportResult::TipChanged(header, tip, height, vec![]);
+                    best = Some((header, tip, height));
+                    connected.extend(blocks);
                 }
-                Ok(ImportResult::TipUnchanged) => {
+                Ok(ImportResult::TipUnchanged) | Ok(ImportResult::MissingParents(_)) => {
                     // We must have received headers from a different peer in the meantime,
                     // keep processing in case one of the headers extends our chain.
                     continue;
@@ -512,7 +578,19 @@ impl<U: SetTimeout + SyncHeaders + Disconnect> SyncManager<U> {
             }
         }
 
-        Ok(import_result)
+        if let Some((header, tip, height)) = best {
+            Ok(ImportResult::TipChanged {
+                header,
+                hash: tip,
+                height,
+                reverted: vec![],
+                connected,
+                reconnected: vec![],
+                missing: vec![],
+            })
+        } else {
+            Ok(ImportResult::TipUnchanged)
+        }
     }
 
     /// Called when we received an `inv` message. This will happen if we are out of sync with a
@@ -615,13 +693,17 @@ impl<U: SetTimeout + SyncHeaders + Disconnect> SyncManager<U> {
             // If this is an error with the underlying store, we have to propagate
             // this up, because we can't handle it here.
             Error::Store(e) => Err(e),
+            Error::StoreCorruption { .. } => Err(store::Error::Corruption),
+            // Only returned when the block tree is created, from a store of another network.
+            Error::GenesisMismatch { .. } => Err(store::Error::Corruption),
 
             // If we got a bad block from the peer, we can handle it here.
             Error::InvalidBlockPoW
             | Error::InvalidBlockTarget(_, _)
             | Error::InvalidBlockHash(_, _)
             | Error::InvalidBlockHeight(_)
-            | Error::InvalidBlockTime(_, _) => {
+            | Error::InvalidBlockTime(_, _)
+            | Error::InvalidBlockVersion(_) => {
                 self.record_misbehavior(from);
                 self.upstream
                     .event(Event::InvalidHeadersReceived(*from, Arc::new(err)));
@@ -630,10 +712,12 @@ impl<U: SetTimeout + SyncHeaders + Disconnect> SyncManager<U> {
             }
 
             // Harmless errors can be ignored.
-            Error::DuplicateBlock(_) | Error::BlockMissing(_) => Ok(()),
+            Error::DuplicateBlock(_) | Error::BlockMissing(_) | Error::ReorgTooDeep { .. } => {
+                Ok(())
+            }
 
             // TODO: This will be removed.
-            Error::BlockImportAborted(_, _, _) => Ok(()),
+            Error::BlockImportAborted { .. } => Ok(()),
         }
     }
 
@@ -8,7 +8,7 @@ use nakamoto_common::block::iter::Iter;
 use nakamoto_common::block::tree::{BlockTree, Branch, Error, ImportResult};
 use nakamoto_common::block::Height;
 
-use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
 
 use nonempty::NonEmpty;
 
@@ -21,6 +21,8 @@ pub struct Cache {
     pub chain: NonEmpty<BlockHeader>,
     pub tip: BlockHash,
     pub genesis: BlockHash,
+    /// Arrival order of each block.
+    seen: HashMap<BlockHash, u64>,
 }
 
 impl Cache {
@@ -31,12 +33,15 @@ impl Cache {
 
         headers.insert(hash, genesis);
 
-        Self {
+        let mut cache = Self {
             headers,
             chain,
             tip: hash,
             genesis: hash,
-        }
+            seen: HashMap::new(),
+        };
+        cache.see(&hash);
+        cache
     }
 
     pub fn from(chain: NonEmpty<BlockHeader>) -> Self {
@@ -48,12 +53,23 @@ impl Cache {
             headers.insert(h.block_hash(), *h);
         }
 
-        Self {
+        let mut cache = Self {
             headers,
             chain,
             tip,
             genesis,
+            seen: HashMap::new(),
+        };
+        for h in cache.chain.clone().iter() {
+            cache.see(&h.block_hash());
         }
+        cache
+    }
+
+    /// Record the arrival of a block, if it wasn't seen before.
+    fn see(&mut self, hash: &BlockHash) {
+        let seq = self.seen.len() as u64;
+        self.seen.entry(*hash).or_insert(seq);
     }
 
     pub fn rollback(&mut self, height: Height) -> Result<(), Error> {
@@ -99,7 +115,11 @@ impl Cache {
                     let a_hash = a.last().block_hash();
                     let b_hash = b.last().block_hash();
 
-                    b_hash.cmp(&a_hash)
+                    // The first block seen wins, and then the lowest hash, compared as
+                    // an integer.
+                    self.seen[&b_hash]
+                        .cmp(&self.seen[&a_hash])
+                        .then(b_hash[..].iter().rev().cmp(a_hash[..].iter().rev()))
                 } else {
                     a_work.cmp(&b_work)
                 }
@@ -114,21 +134,44 @@ impl BlockTree for Cache {
         chain: I,
         _context: &C,
     ) -> Result<ImportResult, Error> {
+        let known = self.headers.keys().cloned().collect::<HashSet<_>>();
+
         for header in chain {
             self.headers.insert(header.block_hash(), header);
+            self.see(&header.block_hash());
         }
         let tip = self.tip;
+        let chain = self.longest_chain();
+
+        // Number of blocks shared by the old and the new longest chain.
+        let common = self
+            .chain
+            .iter()
+            .zip(chain.iter())
+            .take_while(|(a, b)| a == b)
+            .count();
+        let reverted = self.iter().skip(common).collect::<Vec<_>>();
 
-        self.chain = self.longest_chain();
+        self.chain = chain;
         self.tip = self.chain.last().block_hash();
 
         if tip != self.tip {
-            Ok(ImportResult::TipChanged(
-                self.chain.last().to_owned(),
-                self.tip,
-                self.height(),
-                vec![],
-            ))
+            let connected = self.iter().skip(common).collect::<Vec<_>>();
+            let reconnected = connected
+                .iter()
+                .map(|(_, h)| h.block_hash())
+                .filter(|h| known.contains(h))
+                .collect();
+
+            Ok(ImportResult::TipChanged {
+                header: self.chain.last().to_owned(),
+                hash: self.tip,
+                height: self.height(),
+                reverted,
+                connected,
+                reconnected,
+                missing: vec![],
+            })
         } else {
             Ok(ImportResult::TipUnchanged)
         }
@@ -139,15 +182,19 @@ impl BlockTree for Cache {
             let hash = header.block_hash();
 
             self.headers.insert(hash, header);
+            self.see(&hash);
             self.chain.push(header);
             self.tip = hash;
 
-            Ok(ImportResult::TipChanged(
+            Ok(ImportResult::TipChanged {
                 header,
-                self.tip,
-                self.height(),
-                vec![],
-            ))
+                hash: self.tip,
+                height: self.height(),
+                reverted: vec![],
+                connected: vec![(self.height(), header)],
+                reconnected: vec![],
+                missing: vec![],
+            })
         } else {
             Ok(ImportResult::TipUnchanged)
         }